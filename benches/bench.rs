@@ -136,6 +136,74 @@ macro_rules! map_iter_bench {
     )
 }
 
+// Looks up a sorted batch of 1000 keys, half of them present, either all at once with `get_many`
+// or one at a time with `get`.
+macro_rules! map_find_batch_bench {
+    ($name: ident, $n: expr, $batched: expr) => (
+        #[bench]
+        fn $name(b: &mut ::test::Bencher) {
+            use rand::{thread_rng, Rng};
+            use test::black_box;
+
+            let n: usize = $n;
+            let map: ParentMap<_, _> = (0..n).map(|i| (i * 2, i)).collect();
+
+            // setup
+            let mut rng = thread_rng();
+            let mut keys: Vec<_> = (0..1000).map(|_| rng.gen::<usize>() % (2 * n)).collect();
+            keys.sort();
+
+            // measure
+            b.iter(|| {
+                if $batched {
+                    black_box(map.get_many(&keys));
+                } else {
+                    for k in &keys {
+                        black_box(map.get(k));
+                    }
+                }
+            })
+        }
+    )
+}
+
+// Inserts a sorted batch of 1000 new keys and then removes them again, either all at once with
+// `insert_sorted_batch` and `remove_sorted_batch` or one at a time with `insert` and `remove`.
+macro_rules! map_insert_remove_batch_bench {
+    ($name: ident, $n: expr, $batched: expr) => (
+        #[bench]
+        fn $name(b: &mut ::test::Bencher) {
+            use rand::{thread_rng, Rng};
+            use test::black_box;
+
+            let n: usize = $n;
+            let mut map: ParentMap<_, _> = (0..n).map(|i| (i * 2, i)).collect();
+
+            // setup
+            let mut rng = thread_rng();
+            let mut keys: Vec<_> = (0..1000).map(|_| rng.gen::<usize>() % n * 2 + 1).collect();
+            keys.sort();
+            keys.dedup();
+
+            // measure
+            b.iter(|| {
+                if $batched {
+                    map.insert_sorted_batch(keys.iter().map(|&k| (k, k)));
+                    map.remove_sorted_batch(&keys);
+                } else {
+                    for &k in &keys {
+                        map.insert(k, k);
+                    }
+                    for k in &keys {
+                        map.remove(k);
+                    }
+                }
+            });
+            black_box(map);
+        }
+    )
+}
+
 use btree_rewrite::map::BTreeMap as ParentMap;
 use std::collections::BTreeMap as StdMap;
 
@@ -173,3 +241,13 @@ map_iter_bench!{iter_1000_parent  ,    1000, ParentMap}
 map_iter_bench!{iter_1000_std     ,    1000, StdMap}
 map_iter_bench!{iter_20_parent    ,      20, ParentMap}
 map_iter_bench!{iter_20_std       ,      20, StdMap}
+
+map_find_batch_bench!{find_batch_100000_get_many, 100_000, true}
+map_find_batch_bench!{find_batch_100000_get     , 100_000, false}
+map_find_batch_bench!{find_batch_10000_get_many ,  10_000, true}
+map_find_batch_bench!{find_batch_10000_get      ,  10_000, false}
+
+map_insert_remove_batch_bench!{insert_remove_batch_100000_batched, 100_000, true}
+map_insert_remove_batch_bench!{insert_remove_batch_100000_each   , 100_000, false}
+map_insert_remove_batch_bench!{insert_remove_batch_10000_batched ,  10_000, true}
+map_insert_remove_batch_bench!{insert_remove_batch_10000_each    ,  10_000, false}
@@ -0,0 +1,589 @@
+// Copyright 2015 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// A B-Tree where every internal node caches, for each of its edges, an aggregate of all the
+// elements in the subtree below that edge. Any contiguous range of elements can then be
+// aggregated by combining O(log n) cached values with the elements along the two paths leading
+// to the ends of the range.
+//
+// The tree is made of the same nodes as `BTreeMap`, with the aggregates kept in an array beside
+// the edges of each internal node (see `node.rs`). The node operations that split, merge and
+// steal between nodes keep those aggregates up to date as edges move, so insertion and removal
+// here are `BTreeMap`'s own `insert_kv` and `remove_kv`. What they leave stale are the aggregates
+// on the path from the nodes they changed up to the root, which `refresh_ancestors` recomputes
+// afterwards.
+
+use core::fmt::Debug;
+use core::iter::FromIterator;
+use core::ops::{Deref, DerefMut, Index};
+use core::{fmt, mem, ptr};
+
+use collections::borrow::Borrow;
+use collections::Bound::{self, Included, Excluded, Unbounded};
+
+use super::compare::Natural;
+use super::node::{self, Handle, NodeRef, marker};
+use super::search::{self, search_linear};
+use super::tree::{first_leaf_edge, next_kv, insert_kv, remove_kv, unwrap_unchecked};
+
+use super::node::ForceResult::*;
+use super::search::SearchResult::*;
+
+/// An associative way to combine the elements of an `AggregateMap`.
+///
+/// Implementations are usually empty marker types, as in the following, which totals the values
+/// in each range of keys:
+///
+/// ```
+/// use btree_rewrite::aggregate::{AggregateMap, Monoid};
+/// use btree_rewrite::{Included, Excluded};
+///
+/// struct Sum;
+///
+/// impl Monoid<u64, u64> for Sum {
+///     type Summary = u64;
+///
+///     fn identity() -> u64 { 0 }
+///     fn lift(_: &u64, bytes: &u64) -> u64 { *bytes }
+///     fn combine(left: &u64, right: &u64) -> u64 { left + right }
+/// }
+///
+/// let mut bytes_at = AggregateMap::<u64, u64, Sum>::new();
+/// bytes_at.insert(10, 100);
+/// bytes_at.insert(20, 200);
+/// bytes_at.insert(30, 300);
+/// assert_eq!(bytes_at.fold_range(Included(&10), Excluded(&30)), 300);
+/// ```
+pub trait Monoid<K, V> {
+    /// The aggregate of a run of elements.
+    type Summary;
+
+    /// The aggregate of no elements at all. Combining it with any other aggregate, on either
+    /// side, must produce that aggregate.
+    fn identity() -> Self::Summary;
+
+    /// The aggregate of a single element.
+    fn lift(key: &K, val: &V) -> Self::Summary;
+
+    /// Combines the aggregates of two adjacent runs of elements, `left` coming before `right`.
+    /// This must be associative, but need not be commutative.
+    fn combine(left: &Self::Summary, right: &Self::Summary) -> Self::Summary;
+}
+
+/// A map based on a B-Tree that can aggregate any range of its elements in O(log n) time.
+///
+/// The aggregation is described by a `Monoid`, which is chosen as part of the map's type. Each
+/// internal node stores the aggregate of every subtree below it, and these are kept up to date
+/// by every modification, so `fold_range` only has to combine the cached aggregates of the
+/// subtrees that lie entirely within the range with the elements on the paths to its two ends.
+///
+/// Keeping the aggregates up to date costs O(B) extra work for each node on the path to a
+/// modified element. Values can be modified in place through `get_mut` or `iter_mut`, both of
+/// which return guards that refresh the aggregates they may have affected when dropped.
+pub struct AggregateMap<K, V, M: Monoid<K, V>> {
+    root: node::Root<K, V, M>,
+    length: usize
+}
+
+impl<K: Ord, V, M: Monoid<K, V>> AggregateMap<K, V, M> {
+    /// Makes a new empty AggregateMap.
+    pub fn new() -> AggregateMap<K, V, M> {
+        AggregateMap {
+            root: node::Root::new_leaf(),
+            length: 0
+        }
+    }
+
+    /// Returns a reference to the value corresponding to the key.
+    ///
+    /// The key may be any borrowed form of the map's key type, but the ordering
+    /// on the borrowed form *must* match the ordering on the key type.
+    pub fn get<Q: ?Sized>(&self, key: &Q) -> Option<&V> where K: Borrow<Q>, Q: Ord {
+        match search::search_tree(self.root.as_ref(), key, &Natural) {
+            Found(handle) => Some(handle.into_kv().1),
+            GoDown(_) => None
+        }
+    }
+
+    /// Returns true if the map contains a value for the specified key.
+    pub fn contains_key<Q: ?Sized>(&self, key: &Q) -> bool where K: Borrow<Q>, Q: Ord {
+        self.get(key).is_some()
+    }
+
+    /// Returns a guard giving mutable access to the value corresponding to the key. When the
+    /// guard is dropped, the aggregates of the subtrees containing the value are recomputed.
+    pub fn get_mut<Q: ?Sized>(&mut self, key: &Q) -> Option<ValueMut<K, V, M>>
+            where K: Borrow<Q>, Q: Ord {
+        match search::search_tree(self.root.as_mut(), key, &Natural) {
+            Found(handle) => Some(ValueMut { handle: handle }),
+            GoDown(_) => None
+        }
+    }
+
+    /// Inserts a key-value pair into the map.
+    ///
+    /// If the map did not have this key present, `None` is returned. If the map did have this key
+    /// present, the key is not updated, the value is updated and the old value is returned.
+    pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+        match search::search_tree(self.root.as_mut(), &key, &Natural) {
+            Found(mut handle) => {
+                let old = mem::replace(handle.kv_mut().1, value);
+                handle.into_node().refresh_ancestors();
+                Some(old)
+            },
+            GoDown(handle) => {
+                self.length += 1;
+                insert_kv(handle, key, value).into_node().refresh_ancestors();
+                None
+            }
+        }
+    }
+
+    /// Removes a key from the map, returning the value at the key if the key
+    /// was previously in the map.
+    pub fn remove<Q: ?Sized>(&mut self, key: &Q) -> Option<V> where K: Borrow<Q>, Q: Ord {
+        match search::search_tree(self.root.as_mut(), key, &Natural) {
+            Found(handle) => {
+                self.length -= 1;
+                let (_, val, survivor) = remove_kv(handle);
+                survivor.refresh_ancestors();
+                Some(val)
+            },
+            GoDown(_) => None
+        }
+    }
+
+    /// Clears the map, removing all values.
+    pub fn clear(&mut self) {
+        *self = AggregateMap::new();
+    }
+
+    /// Aggregates the elements whose keys lie between min and max, in order by key. If min is
+    /// `Unbounded`, then it will be treated as "negative infinity", and if max is `Unbounded`,
+    /// then it will be treated as "positive infinity". Thus fold_range(Unbounded, Unbounded) will
+    /// aggregate the whole map. An empty range produces `M::identity()`.
+    ///
+    /// This takes O(B log<sub>B</sub>n) time.
+    pub fn fold_range<Min: ?Sized + Ord = K, Max: ?Sized + Ord = K>(&self,
+                                                                    min: Bound<&Min>,
+                                                                    max: Bound<&Max>)
+                                                                    -> M::Summary
+            where K: Borrow<Min> + Borrow<Max> {
+        fold_range::<K, V, M, Min, Max>(self.root.as_ref(), min, max)
+    }
+}
+
+impl<K, V, M: Monoid<K, V>> AggregateMap<K, V, M> {
+    /// Gets an iterator over the entries of the map, sorted by key.
+    pub fn iter(&self) -> Iter<K, V, M> {
+        Iter {
+            front: first_leaf_edge(self.root.as_ref()),
+            length: self.length
+        }
+    }
+
+    /// Gets an iterator over the entries of the map that `keep` accepts, sorted by key.
+    ///
+    /// `keep` is called with `arg` and the aggregate of either a single element or a whole
+    /// subtree, and any subtree it rejects is skipped without being looked at. This is only
+    /// correct if `keep` never rejects the aggregate of a run of elements that contains an element
+    /// it would accept, as is the case when searching for elements whose key or value exceeds
+    /// some threshold using a maximum as the aggregate.
+    pub fn iter_pruned<A>(&self, arg: A, keep: fn(&M::Summary, &A) -> bool)
+                          -> Pruned<K, V, M, A> {
+        Pruned {
+            stack: vec![(self.root.as_ref(), 0)],
+            arg: arg,
+            keep: keep
+        }
+    }
+
+    /// Gets a guard for iterating mutably over the entries of the map, sorted by key. When the
+    /// guard is dropped, the aggregates of every subtree it has visited are recomputed, which
+    /// takes time proportional to the number of elements visited.
+    ///
+    /// The guard is iterated through a mutable reference to it, so that none of the references
+    /// it hands out can outlive it and change a value after the aggregates are recomputed.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use btree_rewrite::aggregate::{AggregateMap, Monoid};
+    /// use btree_rewrite::Bound::Unbounded;
+    ///
+    /// struct Sum;
+    ///
+    /// impl Monoid<u32, u32> for Sum {
+    ///     type Summary = u32;
+    ///
+    ///     fn identity() -> u32 { 0 }
+    ///     fn lift(_: &u32, v: &u32) -> u32 { *v }
+    ///     fn combine(left: &u32, right: &u32) -> u32 { left + right }
+    /// }
+    ///
+    /// let mut map: AggregateMap<_, _, Sum> = (0..100).map(|i| (i, 1)).collect();
+    /// for (_, v) in &mut map.iter_mut() {
+    ///     *v *= 2;
+    /// }
+    /// assert_eq!(map.fold_range::<u32, u32>(Unbounded, Unbounded), 200);
+    /// ```
+    pub fn iter_mut(&mut self) -> IterMut<K, V, M> {
+        IterMut {
+            front: first_leaf_edge(self.root.as_mut()),
+            length: self.length
+        }
+    }
+
+    /// Returns the number of elements in the map.
+    pub fn len(&self) -> usize {
+        self.length
+    }
+
+    /// Returns true if the map contains no elements.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<K, V, M: Monoid<K, V>> Drop for AggregateMap<K, V, M> {
+    fn drop(&mut self) {
+        unsafe {
+            drop_subtree(ptr::read(&self.root).into_ref());
+        }
+    }
+}
+
+/// Drops every element in a subtree and frees its nodes.
+unsafe fn drop_subtree<K, V, M: Monoid<K, V>>(
+        node: NodeRef<marker::Owned, K, V, marker::Mut, marker::LeafOrInternal, M>) {
+    for i in 0..node.len() {
+        mem::drop(ptr::read(node.keys().get_unchecked(i)));
+        mem::drop(ptr::read(node.vals().get_unchecked(i)));
+    }
+
+    match node.force() {
+        Leaf(leaf) => {
+            leaf.deallocate_and_ascend();
+        },
+        Internal(internal) => {
+            for i in 0..internal.len() + 1 {
+                drop_subtree(Handle::new(ptr::read(&internal), i).descend());
+            }
+            internal.deallocate_and_ascend();
+        }
+    }
+}
+
+/// Recomputes every aggregate cached in the subtree below an edge, and then the one cached for
+/// the edge itself.
+fn refresh_subtree<'a, K: 'a, V: 'a, M: Monoid<K, V>>(
+        mut edge: Handle<NodeRef<marker::Borrowed<'a>, K, V, marker::Mut, marker::Internal, M>,
+                         marker::Edge>) {
+    if let Internal(mut internal) = unsafe { edge.reborrow_mut() }.descend().force() {
+        for i in 0..internal.len() + 1 {
+            refresh_subtree(unsafe { Handle::new(internal.reborrow_mut(), i) });
+        }
+    }
+    edge.refresh_agg();
+}
+
+/// Finds the leaf edge that immediately follows a key-value pair.
+fn leaf_edge_after<Lifetime, K, V, Mutability, M: Monoid<K, V>>(
+        kv: Handle<NodeRef<Lifetime, K, V, Mutability, marker::LeafOrInternal, M>, marker::KV>
+        ) -> Handle<NodeRef<Lifetime, K, V, Mutability, marker::Leaf, M>, marker::Edge> {
+    match kv.right_edge().force() {
+        Leaf(leaf) => leaf,
+        Internal(internal) => first_leaf_edge(internal.descend())
+    }
+}
+
+/// Aggregates the elements of a subtree that lie between `min` and `max`.
+///
+/// Only the edges containing `min` and `max` can hold elements on both sides of a bound. Every
+/// edge in between lies entirely within the range and is covered by its cached aggregate, and
+/// below the node where the two paths part, each of them only has a single bound left to check,
+/// so only one edge per level is ever descended into.
+fn fold_range<'a, K: 'a, V: 'a, M, Min: ?Sized, Max: ?Sized>(
+        node: NodeRef<marker::Borrowed<'a>, K, V, marker::Immut, marker::LeafOrInternal, M>,
+        min: Bound<&Min>,
+        max: Bound<&Max>
+        ) -> M::Summary
+        where K: Borrow<Min> + Borrow<Max>, M: Monoid<K, V>, Min: Ord, Max: Ord {
+    let (keys, vals) = node.into_slices();
+
+    // The elements in `lo..hi` are exactly the ones in range
+    let lo = match min {
+        Included(key) => search_linear(keys, key).0,
+        Excluded(key) => match search_linear(keys, key) {
+            (idx, true) => idx + 1,
+            (idx, false) => idx
+        },
+        Unbounded => 0
+    };
+    let hi = match max {
+        Included(key) => match search_linear(keys, key) {
+            (idx, true) => idx + 1,
+            (idx, false) => idx
+        },
+        Excluded(key) => search_linear(keys, key).0,
+        Unbounded => keys.len()
+    };
+
+    let internal = match node.force() {
+        Leaf(_) => {
+            let mut acc = M::identity();
+            for i in lo..hi {
+                acc = M::combine(&acc, &M::lift(&keys[i], &vals[i]));
+            }
+            return acc;
+        },
+        Internal(internal) => internal
+    };
+
+    if lo >= hi {
+        // The whole range falls within a single edge
+        let edge = unsafe { Handle::new(internal, lo) };
+        return fold_range::<K, V, M, Min, Max>(edge.descend(), min, max);
+    }
+
+    let edge = unsafe { Handle::new(internal, lo) };
+    let mut acc = fold_range::<K, V, M, Min, Max>(edge.descend(), min, Unbounded);
+    for i in lo..hi {
+        acc = M::combine(&acc, &M::lift(&keys[i], &vals[i]));
+        if i + 1 < hi {
+            let edge = unsafe { Handle::new(internal, i + 1) };
+            acc = M::combine(&acc, edge.into_agg());
+        }
+    }
+    let edge = unsafe { Handle::new(internal, hi) };
+    let right = fold_range::<K, V, M, Min, Max>(edge.descend(), Unbounded, max);
+    M::combine(&acc, &right)
+}
+
+/// A guard giving mutable access to a value in an AggregateMap. When it is dropped, the
+/// aggregates that include the value are recomputed.
+pub struct ValueMut<'a, K: 'a, V: 'a, M: 'a + Monoid<K, V>> {
+    handle: Handle<NodeRef<marker::Borrowed<'a>, K, V, marker::Mut, marker::LeafOrInternal, M>,
+                   marker::KV>
+}
+
+impl<'a, K, V, M: Monoid<K, V>> Deref for ValueMut<'a, K, V, M> {
+    type Target = V;
+
+    fn deref(&self) -> &V {
+        self.handle.reborrow().into_kv().1
+    }
+}
+
+impl<'a, K, V, M: Monoid<K, V>> DerefMut for ValueMut<'a, K, V, M> {
+    fn deref_mut(&mut self) -> &mut V {
+        self.handle.kv_mut().1
+    }
+}
+
+impl<'a, K, V, M: Monoid<K, V>> Drop for ValueMut<'a, K, V, M> {
+    fn drop(&mut self) {
+        unsafe { ptr::read(&self.handle) }.into_node().refresh_ancestors();
+    }
+}
+
+/// An iterator over an AggregateMap's entries.
+pub struct Iter<'a, K: 'a, V: 'a, M: 'a + Monoid<K, V>> {
+    front: Handle<NodeRef<marker::Borrowed<'a>, K, V, marker::Immut, marker::Leaf, M>,
+                  marker::Edge>,
+    length: usize
+}
+
+/// An iterator over the entries of an AggregateMap accepted by a predicate on their aggregates.
+pub struct Pruned<'a, K: 'a, V: 'a, M: 'a + Monoid<K, V>, A> {
+    // Each frame holds a node and the position of the next thing to visit in it. In internal
+    // nodes, even positions refer to edges and odd positions to elements.
+    stack: Vec<(NodeRef<marker::Borrowed<'a>, K, V, marker::Immut, marker::LeafOrInternal, M>,
+                usize)>,
+    arg: A,
+    keep: fn(&M::Summary, &A) -> bool
+}
+
+/// A guard for iterating mutably over an AggregateMap's entries, which recomputes the aggregates
+/// of everything it visited when dropped. It is iterated through a mutable reference.
+pub struct IterMut<'a, K: 'a, V: 'a, M: 'a + Monoid<K, V>> {
+    // Every element before this edge has been visited
+    front: Handle<NodeRef<marker::Borrowed<'a>, K, V, marker::Mut, marker::Leaf, M>,
+                  marker::Edge>,
+    length: usize
+}
+
+unsafe impl<'a, K: Sync, V: Send, M: Monoid<K, V>> Send for IterMut<'a, K, V, M>
+    where M::Summary: Send { }
+unsafe impl<'a, K: Sync, V: Sync, M: Monoid<K, V>> Sync for IterMut<'a, K, V, M>
+    where M::Summary: Sync { }
+
+impl<'a, K: 'a, V: 'a, M: Monoid<K, V>> Iterator for Iter<'a, K, V, M> {
+    type Item = (&'a K, &'a V);
+
+    fn next(&mut self) -> Option<(&'a K, &'a V)> {
+        if self.length == 0 {
+            return None;
+        }
+        self.length -= 1;
+
+        let kv = unsafe { unwrap_unchecked(next_kv(self.front)) };
+        self.front = leaf_edge_after(kv);
+        Some(kv.into_kv())
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.length, Some(self.length))
+    }
+}
+
+impl<'a, K: 'a, V: 'a, M: Monoid<K, V>> ExactSizeIterator for Iter<'a, K, V, M> {
+    fn len(&self) -> usize { self.length }
+}
+
+impl<'a, K, V, M: Monoid<K, V>> Clone for Iter<'a, K, V, M> {
+    fn clone(&self) -> Iter<'a, K, V, M> {
+        Iter {
+            front: self.front,
+            length: self.length
+        }
+    }
+}
+
+impl<'a, K: 'a, V: 'a, M: Monoid<K, V>, A> Iterator for Pruned<'a, K, V, M, A> {
+    type Item = (&'a K, &'a V);
+
+    fn next(&mut self) -> Option<(&'a K, &'a V)> {
+        loop {
+            let (node, pos) = match self.stack.last_mut() {
+                Some(frame) => {
+                    frame.1 += 1;
+                    (frame.0, frame.1 - 1)
+                },
+                None => return None
+            };
+
+            let idx = match node.force() {
+                Leaf(_) => pos,
+                Internal(internal) => if pos % 2 == 0 {
+                    let edge: Handle<_, marker::Edge> = unsafe { Handle::new(internal, pos / 2) };
+                    if (self.keep)(edge.into_agg(), &self.arg) {
+                        self.stack.push((edge.descend(), 0));
+                    }
+                    continue;
+                } else {
+                    pos / 2
+                }
+            };
+
+            if idx < node.len() {
+                let kv: Handle<_, marker::KV> = unsafe { Handle::new(node, idx) };
+                let (k, v) = kv.into_kv();
+                if (self.keep)(&M::lift(k, v), &self.arg) {
+                    return Some((k, v));
+                }
+            } else {
+                self.stack.pop();
+            }
+        }
+    }
+}
+
+impl<'a, 'b, K: 'a, V: 'a, M: Monoid<K, V>> Iterator for &'b mut IterMut<'a, K, V, M> {
+    type Item = (&'b K, &'b mut V);
+
+    fn next(&mut self) -> Option<(&'b K, &'b mut V)> {
+        if self.length == 0 {
+            return None;
+        }
+        self.length -= 1;
+
+        unsafe {
+            let kv = unwrap_unchecked(next_kv(ptr::read(&self.front)));
+            self.front = leaf_edge_after(ptr::read(&kv));
+            // Unlike `into_kv_mut`, this leaves the values handed out earlier from the same node
+            // valid.
+            Some(kv.into_kv_valmut())
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.length, Some(self.length))
+    }
+}
+
+impl<'a, 'b, K: 'a, V: 'a, M: Monoid<K, V>> ExactSizeIterator for &'b mut IterMut<'a, K, V, M> {
+    fn len(&self) -> usize { self.length }
+}
+
+impl<'a, K: 'a, V: 'a, M: Monoid<K, V>> Drop for IterMut<'a, K, V, M> {
+    fn drop(&mut self) {
+        // Every subtree to the left of the path from the root to `front` has been visited in
+        // full. Working up from the bottom of the path, each edge on it is refreshed after the
+        // ones below it, and after the subtrees to its left.
+        let mut parent = unsafe { ptr::read(&self.front) }.into_node().ascend().ok();
+        while let Some(mut edge) = parent {
+            for i in 0..edge.idx() {
+                refresh_subtree(unsafe { Handle::new(edge.reborrow_mut().into_node(), i) });
+            }
+            edge.refresh_agg();
+            parent = edge.into_node().ascend().ok();
+        }
+    }
+}
+
+impl<'a, K: 'a, V: 'a, M: Monoid<K, V>> IntoIterator for &'a AggregateMap<K, V, M> {
+    type Item = (&'a K, &'a V);
+    type IntoIter = Iter<'a, K, V, M>;
+
+    fn into_iter(self) -> Iter<'a, K, V, M> {
+        self.iter()
+    }
+}
+
+impl<K: Ord, V, M: Monoid<K, V>> FromIterator<(K, V)> for AggregateMap<K, V, M> {
+    fn from_iter<T: IntoIterator<Item=(K, V)>>(iter: T) -> AggregateMap<K, V, M> {
+        let mut map = AggregateMap::new();
+        map.extend(iter);
+        map
+    }
+}
+
+impl<K: Ord, V, M: Monoid<K, V>> Extend<(K, V)> for AggregateMap<K, V, M> {
+    #[inline]
+    fn extend<T: IntoIterator<Item=(K, V)>>(&mut self, iter: T) {
+        for (k, v) in iter {
+            self.insert(k, v);
+        }
+    }
+}
+
+impl<K: Ord, V, M: Monoid<K, V>> Default for AggregateMap<K, V, M> {
+    fn default() -> AggregateMap<K, V, M> {
+        AggregateMap::new()
+    }
+}
+
+impl<K: Debug, V: Debug, M: Monoid<K, V>> Debug for AggregateMap<K, V, M> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_map().entries(self.iter()).finish()
+    }
+}
+
+impl<'a, K: Ord, Q: ?Sized, V, M: Monoid<K, V>> Index<&'a Q> for AggregateMap<K, V, M>
+    where K: Borrow<Q>, Q: Ord
+{
+    type Output = V;
+
+    #[inline]
+    fn index(&self, key: &Q) -> &V {
+        self.get(key).expect("no entry found for key")
+    }
+}
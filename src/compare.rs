@@ -0,0 +1,161 @@
+// Copyright 2015 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// Comparators, which let a `BTreeMap` be ordered by something other than its keys' `Ord`
+// implementation without wrapping every key in a newtype, and comparable queries, which let it be
+// searched with something other than a borrowed form of its keys.
+
+use core::cmp::Ordering;
+
+use collections::borrow::Borrow;
+
+/// A total order on values of type `T`, chosen at runtime rather than fixed by `T`'s `Ord`
+/// implementation.
+///
+/// A `Compare<T>` orders keys of type `T` against each other. A `Compare<Q, T>` orders queries of
+/// type `Q` against keys of type `T`, which is what a map uses to look up a key by some other
+/// type; it must agree with the order on the keys themselves.
+///
+/// A comparator must be consistent with itself in the same way `Ord` must: it must be a total
+/// order, and comparing the same two values must always give the same answer.
+///
+/// Any closure taking two references to `T` and returning an `Ordering` is a comparator.
+pub trait Compare<Q: ?Sized, T: ?Sized = Q> {
+    /// Compares a query to a key, returning whether `a` comes before, after, or at the same
+    /// position as `b`.
+    fn compare(&self, a: &Q, b: &T) -> Ordering;
+}
+
+/// A query that can be compared directly against keys of type `K`.
+///
+/// Every `Q: Ord` is comparable with every key type that borrows as `Q`, which covers all the
+/// lookups `Borrow` alone allows. Implementing `Comparable` for a query type makes it possible to
+/// look keys up by types they cannot borrow as, such as a `(String, u32)` key by a `&str` and a
+/// `u32`. The ordering must agree with the keys' `Ord` implementation.
+///
+/// # Examples
+///
+/// ```
+/// use std::cmp::Ordering;
+/// use btree_rewrite::BTreeMap;
+/// use btree_rewrite::compare::Comparable;
+///
+/// struct Query<'a>(&'a str, u32);
+///
+/// impl<'a> Comparable<(String, u32)> for Query<'a> {
+///     fn compare(&self, key: &(String, u32)) -> Ordering {
+///         (self.0, self.1).cmp(&(&key.0[..], key.1))
+///     }
+/// }
+///
+/// let mut map = BTreeMap::new();
+/// map.insert(("a".to_string(), 1), "first");
+/// map.insert(("a".to_string(), 2), "second");
+/// assert_eq!(map.get(&Query("a", 2)), Some(&"second"));
+/// ```
+pub trait Comparable<K: ?Sized> {
+    /// Compares this query to a key.
+    fn compare(&self, key: &K) -> Ordering;
+}
+
+impl<Q: ?Sized + Ord, K: ?Sized + Borrow<Q>> Comparable<K> for Q {
+    #[inline]
+    fn compare(&self, key: &K) -> Ordering {
+        self.cmp(key.borrow())
+    }
+}
+
+/// The comparator that orders values by their `Ord` implementation, and compares queries to keys
+/// through `Comparable`. This is the default for `BTreeMap`.
+#[derive(Clone, Copy, Default, Debug, PartialEq, Eq, Hash)]
+pub struct Natural;
+
+impl<Q: ?Sized + Comparable<T>, T: ?Sized> Compare<Q, T> for Natural {
+    #[inline]
+    fn compare(&self, a: &Q, b: &T) -> Ordering {
+        Comparable::compare(a, b)
+    }
+}
+
+/// A comparator that reverses the order given by another one.
+///
+/// # Examples
+///
+/// ```
+/// use btree_rewrite::BTreeMap;
+/// use btree_rewrite::compare::{Natural, Rev};
+///
+/// let mut map = BTreeMap::with_comparator(Rev(Natural));
+/// map.insert(1, "a");
+/// map.insert(3, "c");
+/// map.insert(2, "b");
+/// assert_eq!(map.keys().cloned().collect::<Vec<_>>(), [3, 2, 1]);
+/// ```
+#[derive(Clone, Copy, Default, Debug, PartialEq, Eq, Hash)]
+pub struct Rev<C>(pub C);
+
+impl<Q: ?Sized, T: ?Sized, C: Compare<Q, T>> Compare<Q, T> for Rev<C> {
+    #[inline]
+    fn compare(&self, a: &Q, b: &T) -> Ordering {
+        self.0.compare(a, b).reverse()
+    }
+}
+
+/// A comparator that lets a map ordered by a comparator of single values look its keys up by any
+/// form they borrow as, the way `Borrow` lookups work with `Natural`.
+///
+/// Lookups compare queries directly against keys, so a map with `String` keys needs a comparator
+/// implementing `Compare<str, String>` to be searched with a `&str`. `Borrowing(c)` implements
+/// `Compare<Q, K>` for every `K: Borrow<Q>` that `c` implements `Compare<Q>` for, comparing the
+/// query to the borrowed key. Keys are ordered against each other by `c` unchanged.
+///
+/// # Examples
+///
+/// ```
+/// use std::cmp::Ordering;
+/// use btree_rewrite::BTreeMap;
+/// use btree_rewrite::compare::{Borrowing, Compare};
+///
+/// struct ByLen;
+///
+/// impl Compare<str> for ByLen {
+///     fn compare(&self, a: &str, b: &str) -> Ordering {
+///         a.len().cmp(&b.len())
+///     }
+/// }
+///
+/// impl Compare<String> for ByLen {
+///     fn compare(&self, a: &String, b: &String) -> Ordering {
+///         a.len().cmp(&b.len())
+///     }
+/// }
+///
+/// let mut map = BTreeMap::with_comparator(Borrowing(ByLen));
+/// map.insert("ccc".to_string(), 3);
+/// map.insert("a".to_string(), 1);
+/// assert_eq!(map.get("bb"), None);
+/// assert_eq!(map.get("zzz"), Some(&3));
+/// ```
+#[derive(Clone, Copy, Default, Debug, PartialEq, Eq, Hash)]
+pub struct Borrowing<C>(pub C);
+
+impl<Q: ?Sized, K: ?Sized + Borrow<Q>, C: Compare<Q>> Compare<Q, K> for Borrowing<C> {
+    #[inline]
+    fn compare(&self, a: &Q, b: &K) -> Ordering {
+        self.0.compare(a, b.borrow())
+    }
+}
+
+impl<T: ?Sized, F> Compare<T> for F where F: Fn(&T, &T) -> Ordering {
+    #[inline]
+    fn compare(&self, a: &T, b: &T) -> Ordering {
+        self(a, b)
+    }
+}
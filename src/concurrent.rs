@@ -0,0 +1,626 @@
+// Copyright 2015 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// A B-Tree that can be read and written by many threads at once.
+//
+// Every node carries its own reader-writer lock, and all operations use lock coupling (also
+// known as crabbing): a thread only ever releases its lock on a node after it has acquired the
+// lock on the child it is descending into. Since locks are always taken top-down (and, for range
+// scans moving between leaves, left to right), no cycle of waiting threads can form.
+//
+// To make this work, the tree differs from the one in `node.rs` in a few ways:
+//
+// - It is a B+Tree. Values only live in leaves, and internal nodes hold copies of keys that
+//   separate their children. This lets a leaf be split without touching the elements of any of
+//   its ancestors other than its parent.
+// - Leaves are linked to their right sibling, so that range scans can move from leaf to leaf
+//   without going back up the tree.
+// - Insertion is optimistic. It first descends like a lookup, with shared locks, and only locks
+//   the leaf exclusively. Only if that leaf is full does it start over, this time locking every
+//   node exclusively and splitting full nodes on the way down rather than on the way back up.
+//   Every node that such an insertion holds a lock on is therefore guaranteed to have room for a
+//   new separator from below, and it never needs to go back up and relock a parent. Since a
+//   leaf only fills up once every B / 2 insertions or so, most writers never hold an exclusive
+//   lock above the leaf level.
+// - Removal never merges or steals. Nodes can underflow, and leaves can even become empty, but
+//   they are never freed while the map is alive. This means that a node pointer read under the
+//   parent's lock stays valid forever, which is what allows range scans to drop their lock
+//   between calls to `next`.
+
+use core::cell::UnsafeCell;
+use core::fmt::Debug;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::{fmt, mem, ptr};
+
+use collections::borrow::Borrow;
+use collections::Bound::{self, Included, Excluded, Unbounded};
+use std::thread;
+
+use super::node::CAPACITY;
+use super::search::search_linear;
+
+/// A simple spinning reader-writer lock. Unlike `std::sync::RwLock`, acquiring and releasing it
+/// are separate calls instead of being tied to the lifetime of a guard, which is needed to hand
+/// locks over from parent to child.
+///
+/// The lock prefers writers: once a writer is waiting, no new readers are let in, so a steady
+/// stream of overlapping readers cannot keep a writer out forever. This can't cause a deadlock,
+/// since every thread waits only for locks below (or, when scanning, to the right of) the ones it
+/// holds.
+struct RawRwLock {
+    // The number of readers, with `WRITER_WAITING` set if a writer is waiting for them to leave,
+    // or `WRITER` if there is a writer
+    state: AtomicUsize
+}
+
+const WRITER: usize = !0;
+const WRITER_WAITING: usize = !(!0 >> 1);
+
+impl RawRwLock {
+    fn new() -> RawRwLock {
+        RawRwLock { state: AtomicUsize::new(0) }
+    }
+
+    fn lock_shared(&self) {
+        let mut spins = 0;
+        loop {
+            // This also keeps readers out while there is a writer, as `WRITER` has every bit set,
+            // and stops the count of readers from overflowing into `WRITER_WAITING`
+            let state = self.state.load(Ordering::Relaxed);
+            if state < WRITER_WAITING - 1 &&
+                    self.state.compare_and_swap(state, state + 1, Ordering::Acquire) == state {
+                return;
+            }
+            backoff(&mut spins);
+        }
+    }
+
+    fn unlock_shared(&self) {
+        self.state.fetch_sub(1, Ordering::Release);
+    }
+
+    fn lock_exclusive(&self) {
+        let mut spins = 0;
+        loop {
+            let state = self.state.load(Ordering::Relaxed);
+            if state == 0 || state == WRITER_WAITING {
+                if self.state.compare_and_swap(state, WRITER, Ordering::Acquire) == state {
+                    return;
+                }
+            } else if state & WRITER_WAITING == 0 {
+                // Readers are in, so shut out any more of them until they have all left
+                self.state.compare_and_swap(state, state | WRITER_WAITING, Ordering::Relaxed);
+            }
+            backoff(&mut spins);
+        }
+    }
+
+    fn unlock_exclusive(&self) {
+        // This also clears `WRITER_WAITING`, which any other writers still waiting will set again
+        self.state.store(0, Ordering::Release);
+    }
+}
+
+fn backoff(spins: &mut usize) {
+    if *spins < 64 {
+        *spins += 1;
+    } else {
+        thread::yield_now();
+    }
+}
+
+struct Node<K, V> {
+    lock: RawRwLock,
+    data: UnsafeCell<NodeData<K, V>>
+}
+
+struct NodeData<K, V> {
+    keys: Vec<K>,
+    // Only used in leaves
+    vals: Vec<V>,
+    next: *mut Node<K, V>,
+    // Only used in internal nodes
+    edges: Vec<*mut Node<K, V>>
+}
+
+impl<K, V> Node<K, V> {
+    fn new_leaf() -> *mut Node<K, V> {
+        Box::into_raw(Box::new(Node {
+            lock: RawRwLock::new(),
+            data: UnsafeCell::new(NodeData {
+                keys: Vec::with_capacity(CAPACITY),
+                vals: Vec::with_capacity(CAPACITY),
+                next: ptr::null_mut(),
+                edges: Vec::new()
+            })
+        }))
+    }
+
+    fn new_internal() -> *mut Node<K, V> {
+        Box::into_raw(Box::new(Node {
+            lock: RawRwLock::new(),
+            data: UnsafeCell::new(NodeData {
+                keys: Vec::with_capacity(CAPACITY),
+                vals: Vec::new(),
+                next: ptr::null_mut(),
+                edges: Vec::with_capacity(CAPACITY + 1)
+            })
+        }))
+    }
+
+    /// Accesses the contents of the node. The caller must hold the node's lock, exclusively if
+    /// the contents are modified.
+    unsafe fn data<'a>(node: *mut Node<K, V>) -> &'a mut NodeData<K, V> {
+        &mut *(*node).data.get()
+    }
+}
+
+/// Finds which child of an internal node may contain `key`.
+fn child_idx<K, Q: ?Sized>(keys: &[K], key: &Q) -> usize where K: Borrow<Q>, Q: Ord {
+    match search_linear(keys, key) {
+        (idx, true) => idx + 1,
+        (idx, false) => idx
+    }
+}
+
+/// Splits a full node, returning the separating key and the new right sibling. The caller must
+/// hold an exclusive lock on the node.
+unsafe fn split<K: Clone, V>(node: *mut Node<K, V>, height: usize) -> (K, *mut Node<K, V>) {
+    let data = Node::data(node);
+
+    if height == 0 {
+        let right = Node::new_leaf();
+        let right_data = Node::data(right);
+        right_data.keys.extend(data.keys.drain(CAPACITY / 2 + 1..));
+        right_data.vals.extend(data.vals.drain(CAPACITY / 2 + 1..));
+        right_data.next = data.next;
+        data.next = right;
+        (right_data.keys[0].clone(), right)
+    } else {
+        let right = Node::new_internal();
+        let right_data = Node::data(right);
+        right_data.keys.extend(data.keys.drain(CAPACITY / 2 + 1..));
+        right_data.edges.extend(data.edges.drain(CAPACITY / 2 + 1..));
+        (data.keys.pop().unwrap(), right)
+    }
+}
+
+/// A map based on a B-Tree that can be shared between and modified by many threads at once.
+///
+/// All operations take `&self`, so the map is typically placed in an `Arc` and handed to each
+/// thread. Rather than protecting the whole map with a single lock, each node has its own
+/// reader-writer lock, and an operation only holds the locks for a parent and a child at any one
+/// time as it moves down the tree. Operations on different parts of the map therefore proceed
+/// in parallel, and readers never block each other.
+///
+/// Since no reference into the map can be held while other threads modify it, lookups return
+/// clones of the values they find, and range scans yield cloned key-value pairs. Range scans are
+/// weakly consistent: they never yield an element twice or out of order, and they see every
+/// element that was present for the whole duration of the scan, but they may or may not see
+/// elements that are concurrently inserted or removed.
+///
+/// Insertions take an exclusive lock only on the leaf they insert into, except when it is full
+/// and has to be split, in which case the insertion starts over and takes exclusive locks on
+/// its way down. Waiting writers take priority over new readers of a node, so writers are not
+/// starved by a constant stream of lookups.
+///
+/// Removal never rebalances the tree: nodes are never merged or freed, and leaves may be left
+/// underfull or empty. A map that shrinks a lot therefore keeps its height, range scans still
+/// step through the emptied leaves, and the memory of those nodes is only reclaimed once the map
+/// is dropped or cleared.
+pub struct ConcurrentBTreeMap<K, V> {
+    // Protects `root` and `height`, which only change when the root is split
+    root_lock: RawRwLock,
+    root: UnsafeCell<(*mut Node<K, V>, usize)>,
+    length: AtomicUsize
+}
+
+unsafe impl<K: Send, V: Send> Send for ConcurrentBTreeMap<K, V> { }
+unsafe impl<K: Send + Sync, V: Send + Sync> Sync for ConcurrentBTreeMap<K, V> { }
+
+impl<K, V> Drop for ConcurrentBTreeMap<K, V> {
+    fn drop(&mut self) {
+        unsafe fn free<K, V>(node: *mut Node<K, V>, height: usize) {
+            let node = Box::from_raw(node);
+            if height > 0 {
+                for &edge in &(*node.data.get()).edges {
+                    free(edge, height - 1);
+                }
+            }
+        }
+
+        unsafe {
+            let (root, height) = *self.root.get();
+            free(root, height);
+        }
+    }
+}
+
+impl<K: Ord + Clone, V> ConcurrentBTreeMap<K, V> {
+    /// Makes a new empty ConcurrentBTreeMap.
+    pub fn new() -> ConcurrentBTreeMap<K, V> {
+        ConcurrentBTreeMap {
+            root_lock: RawRwLock::new(),
+            root: UnsafeCell::new((Node::new_leaf(), 0)),
+            length: AtomicUsize::new(0)
+        }
+    }
+
+    /// Clears the map, removing all values. This requires exclusive access, so unlike the other
+    /// methods it cannot race with anything.
+    pub fn clear(&mut self) {
+        *self = ConcurrentBTreeMap::new();
+    }
+
+    /// Returns a copy of the value corresponding to the key.
+    ///
+    /// The key may be any borrowed form of the map's key type, but the ordering
+    /// on the borrowed form *must* match the ordering on the key type.
+    pub fn get<Q: ?Sized>(&self, key: &Q) -> Option<V>
+            where K: Borrow<Q>, Q: Ord, V: Clone {
+        unsafe {
+            let leaf = self.find_leaf_shared(key);
+            let data = Node::data(leaf);
+            let ret = match search_linear(&data.keys, key) {
+                (idx, true) => Some(data.vals[idx].clone()),
+                (_, false) => None
+            };
+            (*leaf).lock.unlock_shared();
+            ret
+        }
+    }
+
+    /// Returns true if the map contains a value for the specified key.
+    pub fn contains_key<Q: ?Sized>(&self, key: &Q) -> bool where K: Borrow<Q>, Q: Ord {
+        unsafe {
+            let leaf = self.find_leaf_shared(key);
+            let ret = search_linear(&Node::data(leaf).keys, key).1;
+            (*leaf).lock.unlock_shared();
+            ret
+        }
+    }
+
+    /// Inserts a key-value pair into the map.
+    ///
+    /// If the map did not have this key present, `None` is returned. If the map did have this key
+    /// present, the key is not updated, the value is updated and the old value is returned.
+    pub fn insert(&self, key: K, value: V) -> Option<V> {
+        unsafe {
+            match self.insert_in_leaf(key, value) {
+                Ok(ret) => ret,
+                Err((key, value)) => self.insert_splitting(key, value)
+            }
+        }
+    }
+
+    /// Tries to insert a key-value pair while only locking its leaf exclusively. If the key is
+    /// missing and the leaf is full, nothing is changed and the pair is handed back.
+    unsafe fn insert_in_leaf(&self, key: K, value: V) -> Result<Option<V>, (K, V)> {
+        let node = self.find_leaf_exclusive(&key);
+        let leaf = Node::data(node);
+        let ret = match search_linear(&leaf.keys, &key) {
+            (idx, true) => Ok(Some(mem::replace(&mut leaf.vals[idx], value))),
+            (_, false) if leaf.keys.len() == CAPACITY => Err((key, value)),
+            (idx, false) => {
+                leaf.keys.insert(idx, key);
+                leaf.vals.insert(idx, value);
+                self.length.fetch_add(1, Ordering::Relaxed);
+                Ok(None)
+            }
+        };
+        (*node).lock.unlock_exclusive();
+        ret
+    }
+
+    /// Inserts a key-value pair, locking every node on the way down exclusively and splitting the
+    /// full ones, so that the leaf is sure to have room.
+    unsafe fn insert_splitting(&self, key: K, value: V) -> Option<V> {
+        let (mut node, mut height) = self.lock_root_exclusive(&key);
+
+        while height > 0 {
+            let parent = Node::data(node);
+            let idx = child_idx(&parent.keys, &key);
+            let mut child = parent.edges[idx];
+            (*child).lock.lock_exclusive();
+
+            if Node::data(child).keys.len() == CAPACITY {
+                // Split preemptively. The parent is never full, since it was split on our
+                // way down if it was.
+                let (sep, right) = split(child, height - 1);
+                let go_right = key >= sep;
+                parent.keys.insert(idx, sep);
+                parent.edges.insert(idx + 1, right);
+
+                if go_right {
+                    (*right).lock.lock_exclusive();
+                    (*child).lock.unlock_exclusive();
+                    child = right;
+                }
+            }
+
+            (*node).lock.unlock_exclusive();
+            node = child;
+            height -= 1;
+        }
+
+        let leaf = Node::data(node);
+        let ret = match search_linear(&leaf.keys, &key) {
+            (idx, true) => Some(mem::replace(&mut leaf.vals[idx], value)),
+            (idx, false) => {
+                leaf.keys.insert(idx, key);
+                leaf.vals.insert(idx, value);
+                self.length.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+        };
+        (*node).lock.unlock_exclusive();
+        ret
+    }
+
+    /// Locks the root exclusively, growing the tree first if the root is full, and returns the
+    /// node an insertion of `key` should continue down from, along with its height. `root_lock`
+    /// is only taken exclusively if the tree has to grow.
+    unsafe fn lock_root_exclusive(&self, key: &K) -> (*mut Node<K, V>, usize) {
+        self.root_lock.lock_shared();
+        let (node, height) = *self.root.get();
+        (*node).lock.lock_exclusive();
+        self.root_lock.unlock_shared();
+        if Node::data(node).keys.len() < CAPACITY {
+            return (node, height);
+        }
+        (*node).lock.unlock_exclusive();
+
+        self.root_lock.lock_exclusive();
+        let &mut (ref mut root, ref mut root_height) = &mut *self.root.get();
+        let mut node = *root;
+        let height = *root_height;
+        (*node).lock.lock_exclusive();
+
+        if Node::data(node).keys.len() == CAPACITY {
+            // Grow the tree. Nobody else can see the new root until we let go of `root_lock`, so
+            // it is safe to modify without locking it.
+            let (sep, right) = split(node, height);
+            let new_root = Node::new_internal();
+            let new_root_data = Node::data(new_root);
+            new_root_data.keys.push(sep);
+            new_root_data.edges.push(node);
+            new_root_data.edges.push(right);
+            *root = new_root;
+            *root_height += 1;
+
+            if *key >= new_root_data.keys[0] {
+                (*right).lock.lock_exclusive();
+                (*node).lock.unlock_exclusive();
+                node = right;
+            }
+        }
+        self.root_lock.unlock_exclusive();
+        (node, height)
+    }
+
+    /// Removes a key from the map, returning the value at the key if the key
+    /// was previously in the map.
+    pub fn remove<Q: ?Sized>(&self, key: &Q) -> Option<V> where K: Borrow<Q>, Q: Ord {
+        unsafe {
+            // Since removal never restructures the tree, only the leaf needs an exclusive lock.
+            let node = self.find_leaf_exclusive(key);
+            let leaf = Node::data(node);
+            let ret = match search_linear(&leaf.keys, key) {
+                (idx, true) => {
+                    leaf.keys.remove(idx);
+                    self.length.fetch_sub(1, Ordering::Relaxed);
+                    Some(leaf.vals.remove(idx))
+                },
+                (_, false) => None
+            };
+            (*node).lock.unlock_exclusive();
+            ret
+        }
+    }
+
+    /// Constructs an iterator over a sub-range of elements in the map, starting at min, and
+    /// ending at max. If min is `Unbounded`, then it will be treated as "negative infinity", and
+    /// if max is `Unbounded`, then it will be treated as "positive infinity". Thus
+    /// range(Unbounded, Unbounded) will yield the whole collection.
+    ///
+    /// The iterator only holds a lock on a single leaf at a time, and only while it copies that
+    /// leaf's elements out, so a long scan never holds up other threads for long.
+    pub fn range<'a, Min: ?Sized + Ord = K, Max: ?Sized + Ord = K>(&'a self,
+                                                                   min: Bound<&Min>,
+                                                                   max: Bound<&'a Max>)
+                                                                   -> Range<'a, K, V, Max>
+            where K: Borrow<Min> + Borrow<Max>, V: Clone {
+        let leaf = unsafe {
+            match min {
+                Included(key) | Excluded(key) => self.find_leaf_shared(key),
+                Unbounded => self.first_leaf_shared()
+            }
+        };
+
+        let mut range = Range {
+            next_leaf: leaf,
+            buffer: Vec::new().into_iter(),
+            max: max,
+            _map: self
+        };
+
+        // Collect the first leaf now, while it is still locked, and drop anything below `min`.
+        unsafe {
+            range.fill_from_locked(leaf, |k: &K| {
+                let k: &Min = k.borrow();
+                match min {
+                    Included(key) => k >= key,
+                    Excluded(key) => k > key,
+                    Unbounded => true
+                }
+            });
+        }
+        range
+    }
+
+    /// Descends to the leaf that may contain `key`, returning it with a shared lock held.
+    unsafe fn find_leaf_shared<Q: ?Sized>(&self, key: &Q) -> *mut Node<K, V>
+            where K: Borrow<Q>, Q: Ord {
+        self.root_lock.lock_shared();
+        let (mut node, mut height) = *self.root.get();
+        (*node).lock.lock_shared();
+        self.root_lock.unlock_shared();
+
+        while height > 0 {
+            let data = Node::data(node);
+            let child = data.edges[child_idx(&data.keys, key)];
+            (*child).lock.lock_shared();
+            (*node).lock.unlock_shared();
+            node = child;
+            height -= 1;
+        }
+        node
+    }
+
+    /// Descends to the leaf that may contain `key`, returning it with an exclusive lock held. Every
+    /// other node on the way is only locked shared.
+    unsafe fn find_leaf_exclusive<Q: ?Sized>(&self, key: &Q) -> *mut Node<K, V>
+            where K: Borrow<Q>, Q: Ord {
+        self.root_lock.lock_shared();
+        let (mut node, mut height) = *self.root.get();
+        if height == 0 {
+            (*node).lock.lock_exclusive();
+        } else {
+            (*node).lock.lock_shared();
+        }
+        self.root_lock.unlock_shared();
+
+        while height > 0 {
+            let parent = Node::data(node);
+            let child = parent.edges[child_idx(&parent.keys, key)];
+            if height == 1 {
+                (*child).lock.lock_exclusive();
+            } else {
+                (*child).lock.lock_shared();
+            }
+            (*node).lock.unlock_shared();
+            node = child;
+            height -= 1;
+        }
+        node
+    }
+
+    /// Descends to the leftmost leaf, returning it with a shared lock held.
+    unsafe fn first_leaf_shared(&self) -> *mut Node<K, V> {
+        self.root_lock.lock_shared();
+        let (mut node, mut height) = *self.root.get();
+        (*node).lock.lock_shared();
+        self.root_lock.unlock_shared();
+
+        while height > 0 {
+            let child = Node::data(node).edges[0];
+            (*child).lock.lock_shared();
+            (*node).lock.unlock_shared();
+            node = child;
+            height -= 1;
+        }
+        node
+    }
+}
+
+impl<K, V> ConcurrentBTreeMap<K, V> {
+    /// Returns the number of elements in the map. If other threads are modifying the map, this
+    /// may already be out of date by the time it is returned.
+    pub fn len(&self) -> usize {
+        self.length.load(Ordering::Relaxed)
+    }
+
+    /// Returns true if the map contains no elements.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<K: Ord + Clone, V> Default for ConcurrentBTreeMap<K, V> {
+    fn default() -> ConcurrentBTreeMap<K, V> {
+        ConcurrentBTreeMap::new()
+    }
+}
+
+impl<K: Ord + Clone + Debug, V: Clone + Debug> Debug for ConcurrentBTreeMap<K, V> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_map().entries(self.range::<K, K>(Unbounded, Unbounded)).finish()
+    }
+}
+
+/// An iterator over a sub-range of a ConcurrentBTreeMap's entries, yielding copies of them.
+pub struct Range<'a, K: 'a, V: 'a, Max: ?Sized + 'a> {
+    // The leaf to copy from once the buffer runs out, or null if there is none
+    next_leaf: *mut Node<K, V>,
+    buffer: ::std::vec::IntoIter<(K, V)>,
+    max: Bound<&'a Max>,
+    _map: &'a ConcurrentBTreeMap<K, V>
+}
+
+unsafe impl<'a, K: Send + Sync, V: Send + Sync, Max: ?Sized + Sync> Send
+    for Range<'a, K, V, Max> { }
+unsafe impl<'a, K: Send + Sync, V: Send + Sync, Max: ?Sized + Sync> Sync
+    for Range<'a, K, V, Max> { }
+
+impl<'a, K, V, Max: ?Sized> Range<'a, K, V, Max>
+        where K: Clone + Borrow<Max>, V: Clone, Max: Ord {
+    /// Copies the elements of a leaf that are accepted by `start` and within the upper bound into
+    /// the buffer, moves on to the next leaf, and unlocks the leaf.
+    unsafe fn fill_from_locked<F>(&mut self, leaf: *mut Node<K, V>, start: F)
+            where F: Fn(&K) -> bool {
+        let data = Node::data(leaf);
+        let mut buffer = Vec::with_capacity(data.keys.len());
+        let mut done = false;
+
+        for (k, v) in data.keys.iter().zip(data.vals.iter()) {
+            let below_max = match self.max {
+                Included(max) => Borrow::<Max>::borrow(k) <= max,
+                Excluded(max) => Borrow::<Max>::borrow(k) < max,
+                Unbounded => true
+            };
+            if !below_max {
+                done = true;
+                break;
+            }
+            if start(k) {
+                buffer.push((k.clone(), v.clone()));
+            }
+        }
+
+        self.next_leaf = if done { ptr::null_mut() } else { data.next };
+        self.buffer = buffer.into_iter();
+        (*leaf).lock.unlock_shared();
+    }
+}
+
+impl<'a, K, V, Max: ?Sized> Iterator for Range<'a, K, V, Max>
+        where K: Clone + Borrow<Max>, V: Clone, Max: Ord {
+    type Item = (K, V);
+
+    fn next(&mut self) -> Option<(K, V)> {
+        loop {
+            if let Some(kv) = self.buffer.next() {
+                return Some(kv);
+            }
+            if self.next_leaf.is_null() {
+                return None;
+            }
+
+            // Leaves are never freed while the map is borrowed, so this pointer is still valid.
+            // Anything that was split off of the leaf after we read the pointer to it went to its
+            // right, where we will find it by following `next`.
+            unsafe {
+                let leaf = self.next_leaf;
+                (*leaf).lock.lock_shared();
+                self.fill_from_locked(leaf, |_| true);
+            }
+        }
+    }
+}
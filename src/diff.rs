@@ -0,0 +1,97 @@
+// Copyright 2015 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use core::cmp::Ordering;
+use core::iter::Peekable;
+
+use super::compare::Compare;
+
+use self::DiffItem::*;
+
+/// A single difference between two maps, as yielded by `BTreeMap::diff` and
+/// `PersistentMap::diff`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum DiffItem<'a, K: 'a, V: 'a> {
+    /// The key is only present in the second map.
+    Added(&'a K, &'a V),
+
+    /// The key is only present in the first map.
+    Removed(&'a K, &'a V),
+
+    /// The key is present in both maps, with the first and second value respectively.
+    Changed(&'a K, &'a V, &'a V)
+}
+
+/// Compares the next element on each side of a diff, returning the resulting difference (if any)
+/// and whether the left and right sides should be advanced past their element.
+pub fn compare_elems<'a, K, V, C>(left: Option<(&'a K, &'a V)>,
+                                  right: Option<(&'a K, &'a V)>,
+                                  cmp: &C)
+                                  -> (Option<DiffItem<'a, K, V>>, bool, bool)
+        where V: PartialEq, C: Compare<K> {
+    match (left, right) {
+        (None, None) => (None, false, false),
+        (Some((k, v)), None) => (Some(Removed(k, v)), true, false),
+        (None, Some((k, v))) => (Some(Added(k, v)), false, true),
+        (Some((lk, lv)), Some((rk, rv))) => match cmp.compare(lk, rk) {
+            Ordering::Less => (Some(Removed(lk, lv)), true, false),
+            Ordering::Greater => (Some(Added(rk, rv)), false, true),
+            Ordering::Equal => if lv as *const V == rv as *const V || lv == rv {
+                (None, true, true)
+            } else {
+                (Some(Changed(lk, lv, rv)), true, true)
+            }
+        }
+    }
+}
+
+/// Diffs two sequences of key-value pairs, both sorted by the same comparator, by walking them in
+/// lockstep.
+pub struct MergeDiff<'c, I: Iterator, C: 'c> {
+    left: Peekable<I>,
+    right: Peekable<I>,
+    cmp: &'c C
+}
+
+impl<'c, I: Iterator, C> MergeDiff<'c, I, C> {
+    pub fn new(left: I, right: I, cmp: &'c C) -> MergeDiff<'c, I, C> {
+        MergeDiff {
+            left: left.peekable(),
+            right: right.peekable(),
+            cmp: cmp
+        }
+    }
+}
+
+impl<'a, 'c, K: 'a, V: PartialEq + 'a, I, C: Compare<K>> Iterator for MergeDiff<'c, I, C>
+        where I: Iterator<Item=(&'a K, &'a V)> {
+    type Item = DiffItem<'a, K, V>;
+
+    fn next(&mut self) -> Option<DiffItem<'a, K, V>> {
+        loop {
+            let left = self.left.peek().cloned();
+            let right = self.right.peek().cloned();
+            if left.is_none() && right.is_none() {
+                return None;
+            }
+
+            let (item, advance_left, advance_right) = compare_elems(left, right, self.cmp);
+            if advance_left {
+                self.left.next();
+            }
+            if advance_right {
+                self.right.next();
+            }
+            if item.is_some() {
+                return item;
+            }
+        }
+    }
+}
@@ -0,0 +1,367 @@
+// Copyright 2015 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// A small binary encoding for persisting maps, enabled by the `encoding` feature.
+//
+// An encoded map is a header of the magic bytes `BTRM` and a format version byte, then the number
+// of entries as a little-endian `u64`, then every entry in order, each key followed by its value.
+// Keys and values say how they are encoded through `Encode` and `Decode`, which are implemented
+// here for the primitive types, strings, and the obvious containers. Integers are little-endian
+// and fixed width, with `usize` and `isize` widened to 64 bits so that the encoding does not
+// depend on the platform. Lengths are encoded as `u64`.
+//
+// Since an encoded map is already sorted, decoding builds the tree directly with the same bulk
+// load that `BTreeMap::compact` uses rather than inserting entries one at a time. It checks that
+// the keys really are strictly ascending first, as the bulk load would silently build a broken
+// tree otherwise.
+
+use core::{char, cmp, fmt};
+use std::error::Error;
+
+/// The bytes every encoded map starts with.
+pub const MAGIC: &'static [u8] = b"BTRM";
+
+/// The version of the format written by `BTreeMap::encode`. Decoding rejects any other version.
+pub const VERSION: u8 = 1;
+
+/// A type that can be written in the binary format used by `BTreeMap::encode`.
+pub trait Encode {
+    /// Appends the encoding of `self` to `out`.
+    fn encode(&self, out: &mut Vec<u8>);
+}
+
+/// A type that can be read back from the binary format used by `BTreeMap::encode`.
+pub trait Decode: Sized {
+    /// Reads a value from `reader`, which is left just after it.
+    fn decode(reader: &mut Reader) -> Result<Self, DecodeError>;
+}
+
+/// A cursor over encoded bytes, which keeps track of its offset so that errors can say where
+/// they occurred.
+pub struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize
+}
+
+impl<'a> Reader<'a> {
+    /// Makes a reader starting at the beginning of `bytes`.
+    pub fn new(bytes: &'a [u8]) -> Reader<'a> {
+        Reader {
+            bytes: bytes,
+            pos: 0
+        }
+    }
+
+    /// Returns the offset of the next byte to be read.
+    pub fn position(&self) -> usize {
+        self.pos
+    }
+
+    /// Returns the number of bytes left to read.
+    pub fn remaining(&self) -> usize {
+        self.bytes.len() - self.pos
+    }
+
+    /// Reads the next `len` bytes, failing if there are not that many left.
+    pub fn read_bytes(&mut self, len: usize) -> Result<&'a [u8], DecodeError> {
+        if len > self.remaining() {
+            return Err(DecodeError::new(self.pos, DecodeErrorKind::UnexpectedEnd));
+        }
+        let bytes = &self.bytes[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    /// Reads a length, encoded as a `u64`, failing if it does not fit in a `usize`.
+    pub fn read_len(&mut self) -> Result<usize, DecodeError> {
+        let start = self.pos;
+        let len = try!(u64::decode(self));
+        if len > usize::max_value() as u64 {
+            return Err(DecodeError::new(start, DecodeErrorKind::Invalid("length too large")));
+        }
+        Ok(len as usize)
+    }
+}
+
+/// The ways decoding can fail.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum DecodeErrorKind {
+    /// The input does not start with the magic bytes of an encoded map.
+    BadMagic,
+    /// The input was written by an unsupported version of the format.
+    UnsupportedVersion(u8),
+    /// The input ended in the middle of a value.
+    UnexpectedEnd,
+    /// A value was malformed, for the reason given.
+    Invalid(&'static str),
+    /// A key was not strictly greater than the key before it.
+    NotSorted,
+    /// There were bytes left over after the last entry.
+    TrailingBytes
+}
+
+/// An error from decoding, along with the byte offset at which it was detected. For malformed
+/// values and unsorted keys, this is the offset of the start of the offending value.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct DecodeError {
+    offset: usize,
+    kind: DecodeErrorKind
+}
+
+impl DecodeError {
+    /// Makes an error of the given kind at the given byte offset.
+    pub fn new(offset: usize, kind: DecodeErrorKind) -> DecodeError {
+        DecodeError {
+            offset: offset,
+            kind: kind
+        }
+    }
+
+    /// Returns the byte offset at which the error was detected.
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    /// Returns what went wrong.
+    pub fn kind(&self) -> DecodeErrorKind {
+        self.kind
+    }
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.kind {
+            DecodeErrorKind::UnsupportedVersion(version) => {
+                write!(f, "unsupported format version {} at byte {}", version, self.offset)
+            },
+            DecodeErrorKind::Invalid(reason) => {
+                write!(f, "invalid data ({}) at byte {}", reason, self.offset)
+            },
+            _ => write!(f, "{} at byte {}", self.description(), self.offset)
+        }
+    }
+}
+
+impl Error for DecodeError {
+    fn description(&self) -> &str {
+        match self.kind {
+            DecodeErrorKind::BadMagic => "not an encoded map",
+            DecodeErrorKind::UnsupportedVersion(_) => "unsupported format version",
+            DecodeErrorKind::UnexpectedEnd => "unexpected end of input",
+            DecodeErrorKind::Invalid(_) => "invalid data",
+            DecodeErrorKind::NotSorted => "keys not in strictly ascending order",
+            DecodeErrorKind::TrailingBytes => "trailing bytes after the last entry"
+        }
+    }
+}
+
+macro_rules! int_impls {
+    ($($t: ty, $unsigned: ty, $bytes: expr);*) => ($(
+        impl Encode for $t {
+            fn encode(&self, out: &mut Vec<u8>) {
+                let val = *self as $unsigned;
+                for i in 0..$bytes {
+                    out.push((val >> (8 * i)) as u8);
+                }
+            }
+        }
+
+        impl Decode for $t {
+            fn decode(reader: &mut Reader) -> Result<$t, DecodeError> {
+                let bytes = try!(reader.read_bytes($bytes));
+                let mut val: $unsigned = 0;
+                for (i, &byte) in bytes.iter().enumerate() {
+                    val |= (byte as $unsigned) << (8 * i);
+                }
+                Ok(val as $t)
+            }
+        }
+    )*)
+}
+
+int_impls! {
+    u8, u8, 1;
+    u16, u16, 2;
+    u32, u32, 4;
+    u64, u64, 8;
+    i8, u8, 1;
+    i16, u16, 2;
+    i32, u32, 4;
+    i64, u64, 8
+}
+
+impl Encode for usize {
+    fn encode(&self, out: &mut Vec<u8>) {
+        (*self as u64).encode(out);
+    }
+}
+
+impl Decode for usize {
+    fn decode(reader: &mut Reader) -> Result<usize, DecodeError> {
+        reader.read_len()
+    }
+}
+
+impl Encode for isize {
+    fn encode(&self, out: &mut Vec<u8>) {
+        (*self as i64).encode(out);
+    }
+}
+
+impl Decode for isize {
+    fn decode(reader: &mut Reader) -> Result<isize, DecodeError> {
+        let start = reader.position();
+        let val = try!(i64::decode(reader));
+        if val > isize::max_value() as i64 || val < isize::min_value() as i64 {
+            return Err(DecodeError::new(start, DecodeErrorKind::Invalid("isize out of range")));
+        }
+        Ok(val as isize)
+    }
+}
+
+impl Encode for bool {
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.push(*self as u8);
+    }
+}
+
+impl Decode for bool {
+    fn decode(reader: &mut Reader) -> Result<bool, DecodeError> {
+        let start = reader.position();
+        match try!(u8::decode(reader)) {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(DecodeError::new(start, DecodeErrorKind::Invalid("bool not 0 or 1")))
+        }
+    }
+}
+
+impl Encode for char {
+    fn encode(&self, out: &mut Vec<u8>) {
+        (*self as u32).encode(out);
+    }
+}
+
+impl Decode for char {
+    fn decode(reader: &mut Reader) -> Result<char, DecodeError> {
+        let start = reader.position();
+        match char::from_u32(try!(u32::decode(reader))) {
+            Some(c) => Ok(c),
+            None => Err(DecodeError::new(start, DecodeErrorKind::Invalid("invalid char")))
+        }
+    }
+}
+
+impl Encode for () {
+    fn encode(&self, _: &mut Vec<u8>) { }
+}
+
+impl Decode for () {
+    fn decode(_: &mut Reader) -> Result<(), DecodeError> {
+        Ok(())
+    }
+}
+
+impl Encode for str {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.len().encode(out);
+        out.extend(self.as_bytes().iter().cloned());
+    }
+}
+
+impl Encode for String {
+    fn encode(&self, out: &mut Vec<u8>) {
+        (**self).encode(out);
+    }
+}
+
+impl Decode for String {
+    fn decode(reader: &mut Reader) -> Result<String, DecodeError> {
+        let start = reader.position();
+        let len = try!(reader.read_len());
+        let bytes = try!(reader.read_bytes(len));
+        match String::from_utf8(bytes.to_vec()) {
+            Ok(s) => Ok(s),
+            Err(_) => Err(DecodeError::new(start, DecodeErrorKind::Invalid("invalid UTF-8")))
+        }
+    }
+}
+
+impl<T: Encode> Encode for [T] {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.len().encode(out);
+        for item in self {
+            item.encode(out);
+        }
+    }
+}
+
+impl<T: Encode> Encode for Vec<T> {
+    fn encode(&self, out: &mut Vec<u8>) {
+        (**self).encode(out);
+    }
+}
+
+impl<T: Decode> Decode for Vec<T> {
+    fn decode(reader: &mut Reader) -> Result<Vec<T>, DecodeError> {
+        let len = try!(reader.read_len());
+        // A corrupt length should fail with an error once the input runs out, not by trying to
+        // allocate an enormous vector up front.
+        let mut vec = Vec::with_capacity(cmp::min(len, reader.remaining()));
+        for _ in 0..len {
+            vec.push(try!(T::decode(reader)));
+        }
+        Ok(vec)
+    }
+}
+
+impl<T: Encode> Encode for Option<T> {
+    fn encode(&self, out: &mut Vec<u8>) {
+        match *self {
+            None => out.push(0),
+            Some(ref val) => {
+                out.push(1);
+                val.encode(out);
+            }
+        }
+    }
+}
+
+impl<T: Decode> Decode for Option<T> {
+    fn decode(reader: &mut Reader) -> Result<Option<T>, DecodeError> {
+        let start = reader.position();
+        match try!(u8::decode(reader)) {
+            0 => Ok(None),
+            1 => Ok(Some(try!(T::decode(reader)))),
+            _ => Err(DecodeError::new(start, DecodeErrorKind::Invalid("option tag not 0 or 1")))
+        }
+    }
+}
+
+impl<A: Encode, B: Encode> Encode for (A, B) {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.0.encode(out);
+        self.1.encode(out);
+    }
+}
+
+impl<A: Decode, B: Decode> Decode for (A, B) {
+    fn decode(reader: &mut Reader) -> Result<(A, B), DecodeError> {
+        let a = try!(A::decode(reader));
+        let b = try!(B::decode(reader));
+        Ok((a, b))
+    }
+}
+
+impl<'a, T: Encode + ?Sized> Encode for &'a T {
+    fn encode(&self, out: &mut Vec<u8>) {
+        (**self).encode(out);
+    }
+}
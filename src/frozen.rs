@@ -0,0 +1,350 @@
+// Copyright 2015 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// An immutable map laid out as a flat sequence of bytes, enabled by the `encoding` feature.
+//
+// The layout is a header, two tables, and then the entries:
+//
+// ```
+// magic: b"BTRF"
+// version: u8, then three bytes of padding
+// len: u64
+// offsets: [u64; len + 1]     where entry i starts, and finally where the last one ends
+// eytzinger: [u64; len]       the index of the entry at each position of the search tree
+// entries                     each key followed by its value, in ascending order of key
+// ```
+//
+// All integers are little-endian, and all offsets are from the start of the header, so the bytes
+// mean the same thing wherever they are loaded or mapped and need no alignment. Keys and values
+// are written with `Encode`, and a query decodes only the keys it compares against and the
+// entries it returns.
+//
+// Searching with a binary search over the offsets would touch a different part of the tables at
+// every step. Instead, the `eytzinger` table stores the implicit binary search tree of the
+// entries in breadth-first order, with the children of position k at 2k and 2k + 1 (counting
+// from 1), so the first several steps of every search fall in the same few cache lines.
+
+use core::cmp::Ordering;
+use core::iter::FusedIterator;
+use core::marker::PhantomData;
+
+use collections::borrow::Borrow;
+use collections::Bound::{self, Included, Excluded, Unbounded};
+
+use super::encoding::{Decode, DecodeError, DecodeErrorKind, Encode, Reader};
+
+const MAGIC: &'static [u8] = b"BTRF";
+const VERSION: u8 = 1;
+const HEADER_SIZE: usize = 16;
+
+/// A read-only map stored in a single byte buffer, which can be queried in place.
+///
+/// The buffer is built once by `FrozenMap::build` and can then be written to a file, embedded
+/// in a binary, or shared between processes with a memory map. Opening it only checks its header,
+/// and queries decode just the handful of keys a search visits, so there is no need to
+/// deserialize the whole map before using it.
+///
+/// Only the header and the sizes of the tables are checked when the map is opened. If the rest of
+/// the buffer is corrupt, queries may panic, but they never read outside of it.
+///
+/// # Examples
+///
+/// ```
+/// use btree_rewrite::BTreeMap;
+/// use btree_rewrite::Bound::{Included, Excluded};
+/// use btree_rewrite::frozen::FrozenMap;
+///
+/// let map: BTreeMap<u32, String> = (0..100).map(|i| (i * 10, i.to_string())).collect();
+/// let bytes = FrozenMap::build(&map);
+///
+/// let frozen = FrozenMap::<u32, String>::new(&bytes).unwrap();
+/// assert_eq!(frozen.len(), 100);
+/// assert_eq!(frozen.get(&420), Some("42".to_string()));
+/// assert_eq!(frozen.get(&421), None);
+/// assert_eq!(frozen.floor(&425), Some((420, "42".to_string())));
+/// assert_eq!(frozen.range(Excluded(&20), Included(&50)).map(|(k, _)| k).collect::<Vec<_>>(),
+///            [30, 40, 50]);
+/// ```
+pub struct FrozenMap<'a, K, V> {
+    bytes: &'a [u8],
+    len: usize,
+    marker: PhantomData<fn() -> (K, V)>
+}
+
+impl<'a, K, V> Clone for FrozenMap<'a, K, V> {
+    fn clone(&self) -> FrozenMap<'a, K, V> {
+        *self
+    }
+}
+
+impl<'a, K, V> Copy for FrozenMap<'a, K, V> { }
+
+fn read_u64(bytes: &[u8], pos: usize) -> u64 {
+    u64::decode(&mut Reader::new(&bytes[pos..pos + 8])).unwrap()
+}
+
+// Fills `out` with the sorted indices in breadth-first order, by an in-order walk of the tree.
+fn fill_eytzinger(out: &mut [u64], k: usize, next: &mut u64) {
+    if k <= out.len() {
+        fill_eytzinger(out, 2 * k, next);
+        out[k - 1] = *next;
+        *next += 1;
+        fill_eytzinger(out, 2 * k + 1, next);
+    }
+}
+
+impl<'a, K: Encode, V: Encode> FrozenMap<'a, K, V> {
+    /// Lays out the given entries as a frozen map, ready to be queried with `FrozenMap::new`.
+    /// The entries can come from iterating over a `BTreeMap` or a range of one.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the keys are not in strictly ascending order.
+    pub fn build<'b, I>(entries: I) -> Vec<u8>
+            where I: IntoIterator<Item=(&'b K, &'b V)>, K: Ord + 'b, V: 'b {
+        let mut data = Vec::new();
+        let mut offsets = Vec::new();
+        let mut prev: Option<&K> = None;
+        for (key, val) in entries {
+            if let Some(prev) = prev {
+                assert!(prev < key, "keys must be in strictly ascending order");
+            }
+            prev = Some(key);
+            offsets.push(data.len() as u64);
+            key.encode(&mut data);
+            val.encode(&mut data);
+        }
+        offsets.push(data.len() as u64);
+
+        let len = offsets.len() - 1;
+        let mut eytzinger = vec![0; len];
+        fill_eytzinger(&mut eytzinger, 1, &mut 0);
+
+        let data_start = (HEADER_SIZE + 8 * (2 * len + 1)) as u64;
+        let mut out = Vec::with_capacity(data_start as usize + data.len());
+        out.extend(MAGIC.iter().cloned());
+        out.extend([VERSION, 0, 0, 0].iter().cloned());
+        (len as u64).encode(&mut out);
+        for offset in offsets {
+            (data_start + offset).encode(&mut out);
+        }
+        for idx in eytzinger {
+            idx.encode(&mut out);
+        }
+        out.extend(data);
+        out
+    }
+}
+
+impl<'a, K: Decode + Ord, V: Decode> FrozenMap<'a, K, V> {
+    /// Opens a frozen map laid out by `FrozenMap::build`, without copying or decoding it. The
+    /// keys and values must be of the same types the map was built with; this is not checked.
+    pub fn new(bytes: &'a [u8]) -> Result<FrozenMap<'a, K, V>, DecodeError> {
+        let mut reader = Reader::new(bytes);
+        if try!(reader.read_bytes(MAGIC.len())) != MAGIC {
+            return Err(DecodeError::new(0, DecodeErrorKind::BadMagic));
+        }
+        let version = try!(u8::decode(&mut reader));
+        if version != VERSION {
+            return Err(DecodeError::new(4, DecodeErrorKind::UnsupportedVersion(version)));
+        }
+        try!(reader.read_bytes(3));
+        let len = try!(reader.read_len());
+
+        let tables = len.checked_mul(16).and_then(|size| size.checked_add(8));
+        match tables {
+            Some(size) if size <= reader.remaining() => {},
+            _ => return Err(DecodeError::new(bytes.len(), DecodeErrorKind::UnexpectedEnd))
+        }
+
+        let map = FrozenMap {
+            bytes: bytes,
+            len: len,
+            marker: PhantomData
+        };
+        if map.offset(len) > bytes.len() {
+            return Err(DecodeError::new(bytes.len(), DecodeErrorKind::UnexpectedEnd));
+        }
+        Ok(map)
+    }
+
+    /// Returns the number of elements in the map.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns true if the map contains no elements.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns the value corresponding to the key.
+    pub fn get<Q: ?Sized>(&self, key: &Q) -> Option<V> where K: Borrow<Q>, Q: Ord {
+        let idx = self.search(key, false);
+        if idx == self.len {
+            return None;
+        }
+        let mut reader = self.reader(idx);
+        if self.decode::<K>(&mut reader).borrow() == key {
+            Some(self.decode(&mut reader))
+        } else {
+            None
+        }
+    }
+
+    /// Returns true if the map contains a value for the specified key.
+    pub fn contains_key<Q: ?Sized>(&self, key: &Q) -> bool where K: Borrow<Q>, Q: Ord {
+        let idx = self.search(key, false);
+        idx < self.len && self.key(idx).borrow() == key
+    }
+
+    /// Returns the element with the greatest key that is not greater than `key`, if any.
+    pub fn floor<Q: ?Sized>(&self, key: &Q) -> Option<(K, V)> where K: Borrow<Q>, Q: Ord {
+        match self.search(key, true) {
+            0 => None,
+            idx => Some(self.entry(idx - 1))
+        }
+    }
+
+    /// Returns the element with the least key that is not less than `key`, if any.
+    pub fn ceiling<Q: ?Sized>(&self, key: &Q) -> Option<(K, V)> where K: Borrow<Q>, Q: Ord {
+        let idx = self.search(key, false);
+        if idx == self.len { None } else { Some(self.entry(idx)) }
+    }
+
+    /// Constructs a double-ended iterator over a sub-range of elements in the map, in order by
+    /// key. If `min` is after `max`, the iterator is empty.
+    pub fn range<Q: ?Sized = K>(&self, min: Bound<&Q>, max: Bound<&Q>) -> Range<'a, K, V>
+            where K: Borrow<Q>, Q: Ord {
+        let front = match min {
+            Included(key) => self.search(key, false),
+            Excluded(key) => self.search(key, true),
+            Unbounded => 0
+        };
+        let back = match max {
+            Included(key) => self.search(key, true),
+            Excluded(key) => self.search(key, false),
+            Unbounded => self.len
+        };
+        Range {
+            map: *self,
+            front: front,
+            back: if back < front { front } else { back }
+        }
+    }
+
+    /// Gets an iterator over the entries of the map, sorted by key.
+    pub fn iter(&self) -> Range<'a, K, V> {
+        Range {
+            map: *self,
+            front: 0,
+            back: self.len
+        }
+    }
+
+    // Returns the index of the first key greater than `key` if `after` is set, or not less than
+    // it otherwise, using the Eytzinger table.
+    fn search<Q: ?Sized>(&self, key: &Q, after: bool) -> usize where K: Borrow<Q>, Q: Ord {
+        let mut k = 1;
+        while k <= self.len {
+            let idx = self.eytzinger(k - 1);
+            let go_right = match self.key(idx).borrow().cmp(key) {
+                Ordering::Less => true,
+                Ordering::Equal => after,
+                Ordering::Greater => false
+            };
+            k = 2 * k + go_right as usize;
+        }
+        // Undo the right turns taken since the last left turn, and that left turn itself,
+        // leaving the position of the answer.
+        k >>= (!k).trailing_zeros() + 1;
+        if k == 0 { self.len } else { self.eytzinger(k - 1) }
+    }
+
+    fn offset(&self, idx: usize) -> usize {
+        read_u64(self.bytes, HEADER_SIZE + 8 * idx) as usize
+    }
+
+    fn eytzinger(&self, pos: usize) -> usize {
+        let idx = read_u64(self.bytes, HEADER_SIZE + 8 * (self.len + 1 + pos)) as usize;
+        assert!(idx < self.len, "corrupt frozen map");
+        idx
+    }
+
+    fn reader(&self, idx: usize) -> Reader<'a> {
+        let (start, end) = (self.offset(idx), self.offset(idx + 1));
+        assert!(start <= end && end <= self.bytes.len(), "corrupt frozen map");
+        Reader::new(&self.bytes[start..end])
+    }
+
+    fn decode<T: Decode>(&self, reader: &mut Reader) -> T {
+        T::decode(reader).ok().expect("corrupt frozen map")
+    }
+
+    fn key(&self, idx: usize) -> K {
+        self.decode(&mut self.reader(idx))
+    }
+
+    fn entry(&self, idx: usize) -> (K, V) {
+        let mut reader = self.reader(idx);
+        let key = self.decode(&mut reader);
+        (key, self.decode(&mut reader))
+    }
+}
+
+/// An iterator over a sub-range of a FrozenMap's entries, which decodes each one as it is
+/// reached.
+pub struct Range<'a, K, V> {
+    map: FrozenMap<'a, K, V>,
+    front: usize,
+    back: usize
+}
+
+impl<'a, K, V> Clone for Range<'a, K, V> {
+    fn clone(&self) -> Range<'a, K, V> {
+        Range {
+            map: self.map,
+            front: self.front,
+            back: self.back
+        }
+    }
+}
+
+impl<'a, K: Decode + Ord, V: Decode> Iterator for Range<'a, K, V> {
+    type Item = (K, V);
+
+    fn next(&mut self) -> Option<(K, V)> {
+        if self.front == self.back {
+            None
+        } else {
+            self.front += 1;
+            Some(self.map.entry(self.front - 1))
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.back - self.front, Some(self.back - self.front))
+    }
+}
+
+impl<'a, K: Decode + Ord, V: Decode> DoubleEndedIterator for Range<'a, K, V> {
+    fn next_back(&mut self) -> Option<(K, V)> {
+        if self.front == self.back {
+            None
+        } else {
+            self.back -= 1;
+            Some(self.map.entry(self.back))
+        }
+    }
+}
+
+impl<'a, K: Decode + Ord, V: Decode> ExactSizeIterator for Range<'a, K, V> { }
+
+impl<'a, K: Decode + Ord, V: Decode> FusedIterator for Range<'a, K, V> {}
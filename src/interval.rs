@@ -0,0 +1,223 @@
+// Copyright 2015 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// An interval tree on the ordinary B-tree node layout. Intervals are stored as `(start, end)`
+// keys in an `AggregateMap` whose monoid is `MaxEnd`, so every `InternalNode` keeps, next to each
+// of its edges, the largest `end` of any interval below that edge. These are maintained by the
+// node operations themselves, through inserts, splits, merges and steals.
+//
+// An interval `[start, end)` overlaps the query `[lo, hi)` exactly when `start < hi` and
+// `end > lo`. Since the map is sorted by `start`, the first condition holds for a prefix of the
+// map, and iteration simply stops at the first interval that starts too late. The second
+// condition is checked against the maximum `end` cached beside each edge, so that subtrees whose
+// intervals all end at or before `lo` are never descended into.
+
+use core::fmt::Debug;
+use core::marker::PhantomData;
+use core::ops::Range;
+use core::fmt;
+
+use collections::Bound::{self, Included, Excluded};
+
+use super::aggregate::{self, AggregateMap, Monoid};
+
+/// The aggregate used by `IntervalMap`: the largest end of any interval in a subtree, which is
+/// what each internal node caches for each of its edges.
+struct MaxEnd<T>(PhantomData<T>);
+
+impl<T: Ord + Clone, V> Monoid<(T, T), V> for MaxEnd<T> {
+    type Summary = Option<T>;
+
+    fn identity() -> Option<T> {
+        None
+    }
+
+    fn lift(key: &(T, T), _: &V) -> Option<T> {
+        Some(key.1.clone())
+    }
+
+    fn combine(left: &Option<T>, right: &Option<T>) -> Option<T> {
+        if left > right { left.clone() } else { right.clone() }
+    }
+}
+
+fn ends_after<T: Ord>(max_end: &Option<T>, point: &T) -> bool {
+    match *max_end {
+        Some(ref end) => end > point,
+        None => false
+    }
+}
+
+/// A map from half-open intervals `[start, end)` to values, which can efficiently find every
+/// interval overlapping a given point or interval.
+///
+/// Intervals are ordered by their start, and then by their end. Any number of intervals may
+/// overlap each other, or share the same start, but every distinct interval is mapped to only one
+/// value.
+///
+/// Finding the intervals that overlap a query takes O(B (k + 1) log<sub>B</sub>n) time, where k
+/// is the number of intervals found.
+///
+/// # Examples
+///
+/// ```
+/// use btree_rewrite::IntervalMap;
+///
+/// let mut map = IntervalMap::new();
+/// map.insert(0..10, "a");
+/// map.insert(5..8, "b");
+/// map.insert(20..30, "c");
+///
+/// let found: Vec<_> = map.stabbing(&6).map(|(start, end, v)| (*start, *end, *v)).collect();
+/// assert_eq!(found, [(0, 10, "a"), (5, 8, "b")]);
+/// assert_eq!(map.overlapping(&(8..20)).count(), 1);
+/// ```
+pub struct IntervalMap<T: Ord + Clone, V> {
+    inner: AggregateMap<(T, T), V, MaxEnd<T>>
+}
+
+impl<T: Ord + Clone, V> IntervalMap<T, V> {
+    /// Makes a new empty IntervalMap.
+    pub fn new() -> IntervalMap<T, V> {
+        IntervalMap { inner: AggregateMap::new() }
+    }
+
+    /// Maps an interval to a value.
+    ///
+    /// If the map did not have this exact interval present, `None` is returned. If it did, the
+    /// value is updated and the old value is returned.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the interval is empty, that is, if its start is not before its end.
+    pub fn insert(&mut self, interval: Range<T>, value: V) -> Option<V> {
+        assert!(interval.start < interval.end, "cannot insert an empty interval");
+        self.inner.insert((interval.start, interval.end), value)
+    }
+
+    /// Returns a reference to the value mapped to exactly this interval.
+    pub fn get(&self, interval: &Range<T>) -> Option<&V> {
+        self.inner.get(&(interval.start.clone(), interval.end.clone()))
+    }
+
+    /// Removes an interval from the map, returning its value if it was previously in the map.
+    pub fn remove(&mut self, interval: &Range<T>) -> Option<V> {
+        self.inner.remove(&(interval.start.clone(), interval.end.clone()))
+    }
+
+    /// Clears the map, removing all values.
+    pub fn clear(&mut self) {
+        self.inner.clear();
+    }
+
+    /// Gets an iterator over the intervals that contain `point`, sorted by their start.
+    pub fn stabbing<'a>(&'a self, point: &'a T) -> Overlaps<'a, T, V> {
+        Overlaps {
+            inner: self.inner.iter_pruned(point.clone(), ends_after),
+            start_below: Included(point),
+            done: false
+        }
+    }
+
+    /// Gets an iterator over the intervals that share at least one point with `interval`, sorted
+    /// by their start. An empty query overlaps nothing.
+    pub fn overlapping<'a>(&'a self, interval: &'a Range<T>) -> Overlaps<'a, T, V> {
+        Overlaps {
+            inner: self.inner.iter_pruned(interval.start.clone(), ends_after),
+            start_below: Excluded(&interval.end),
+            done: interval.start >= interval.end
+        }
+    }
+
+    /// Gets an iterator over every interval in the map, sorted by their start.
+    pub fn iter(&self) -> Iter<T, V> {
+        Iter { inner: self.inner.iter() }
+    }
+
+    /// Returns the number of intervals in the map.
+    pub fn len(&self) -> usize {
+        self.inner.len()
+    }
+
+    /// Returns true if the map contains no intervals.
+    pub fn is_empty(&self) -> bool {
+        self.inner.is_empty()
+    }
+}
+
+/// An iterator over the intervals in an IntervalMap that overlap a point or interval.
+pub struct Overlaps<'a, T: 'a + Ord + Clone, V: 'a> {
+    inner: aggregate::Pruned<'a, (T, T), V, MaxEnd<T>, T>,
+    // Every interval from the first one that starts too late onwards can be ignored
+    start_below: Bound<&'a T>,
+    done: bool
+}
+
+/// An iterator over an IntervalMap's entries.
+pub struct Iter<'a, T: 'a + Ord + Clone, V: 'a> {
+    inner: aggregate::Iter<'a, (T, T), V, MaxEnd<T>>
+}
+
+impl<'a, T: Ord + Clone, V> Iterator for Overlaps<'a, T, V> {
+    type Item = (&'a T, &'a T, &'a V);
+
+    fn next(&mut self) -> Option<(&'a T, &'a T, &'a V)> {
+        if self.done {
+            return None;
+        }
+
+        match self.inner.next() {
+            Some((&(ref start, ref end), val)) => {
+                let in_range = match self.start_below {
+                    Included(bound) => start <= bound,
+                    Excluded(bound) => start < bound,
+                    _ => unreachable!()
+                };
+                if in_range {
+                    Some((start, end, val))
+                } else {
+                    self.done = true;
+                    None
+                }
+            },
+            None => None
+        }
+    }
+}
+
+impl<'a, T: Ord + Clone, V> Iterator for Iter<'a, T, V> {
+    type Item = (&'a T, &'a T, &'a V);
+
+    fn next(&mut self) -> Option<(&'a T, &'a T, &'a V)> {
+        self.inner.next().map(|(&(ref start, ref end), val)| (start, end, val))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+impl<'a, T: Ord + Clone, V> ExactSizeIterator for Iter<'a, T, V> {
+    fn len(&self) -> usize {
+        self.inner.len()
+    }
+}
+
+impl<T: Ord + Clone, V> Default for IntervalMap<T, V> {
+    fn default() -> IntervalMap<T, V> {
+        IntervalMap::new()
+    }
+}
+
+impl<T: Ord + Clone + Debug, V: Debug> Debug for IntervalMap<T, V> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_map().entries(self.iter().map(|(start, end, val)| (start..end, val))).finish()
+    }
+}
@@ -1,5 +1,5 @@
 #![feature(core, collections, nonzero, collections_bound)]
-#![feature(alloc, heap_api, core_intrinsics, unique)]
+#![feature(alloc, heap_api, core_intrinsics, unique, fused, default_type_parameter_fallback)]
 
 // This is an attempt at an implementation following the ideal
 //
@@ -26,11 +26,35 @@
 extern crate collections;
 extern crate core;
 extern crate alloc;
+#[cfg(feature = "serde")]
+extern crate serde;
 
+mod diff;
 mod node;
 mod search;
+mod tree;
+mod vec_node;
+pub mod aggregate;
+pub mod compare;
+pub mod concurrent;
+#[cfg(feature = "encoding")]
+pub mod encoding;
+#[cfg(feature = "encoding")]
+pub mod frozen;
+pub mod interval;
 pub mod map;
+#[cfg(feature = "paged")]
+pub mod paged;
+pub mod persistent;
+pub mod range_map;
 
+pub use aggregate::AggregateMap;
+pub use concurrent::ConcurrentBTreeMap;
+pub use diff::DiffItem;
+pub use interval::IntervalMap;
 pub use map::BTreeMap;
+pub use map::multimap::BTreeMultiMap;
+pub use persistent::PersistentMap;
+pub use range_map::RangeMap;
 pub use map::Entry::{self, Occupied, Vacant};
 pub use collections::Bound::{self, Included, Excluded};
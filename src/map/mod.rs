@@ -0,0 +1,3082 @@
+// Copyright 2015 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use core::cmp::{self, Ordering};
+use core::fmt::Debug;
+use core::hash::{Hash, Hasher};
+use core::iter::{FromIterator, FusedIterator, Map};
+use core::ops::Index;
+use core::{fmt, mem, ptr};
+
+use alloc::arc::Arc;
+use collections::Bound::{self, Included, Excluded, Unbounded};
+use collections::borrow::Borrow;
+use std::error::Error;
+use std::thread;
+
+use super::compare::{Compare, Natural};
+use super::diff::{DiffItem, MergeDiff};
+#[cfg(feature = "encoding")]
+use super::encoding::{self, Encode, Decode, DecodeError, Reader};
+#[cfg(feature = "encoding")]
+use super::encoding::DecodeErrorKind::*;
+use super::node::{self, NodeRef, Handle, marker};
+use super::search;
+use super::tree::{first_leaf_edge, last_leaf_edge, next_kv, prev_kv, insert_kv, remove_kv};
+use super::tree::{handle_underfull_node, unwrap_unchecked};
+
+use super::node::ForceResult::*;
+use super::search::SearchResult::*;
+use super::tree::UnderflowResult::*;
+use self::Entry::*;
+
+pub mod multimap;
+mod observer;
+#[cfg(feature = "serde")]
+mod serialize;
+mod transaction;
+
+pub use self::observer::{Observer, ObservedMap, ObservedEntry, ObservedOccupiedEntry};
+pub use self::observer::{ObservedVacantEntry, ObservedValueMut};
+#[cfg(feature = "serde")]
+pub use self::serialize::deserialize_unique_keys;
+pub use self::transaction::{Transaction, TxEntry, TxOccupiedEntry, TxVacantEntry};
+
+/// A map based on a B-Tree.
+///
+/// B-Trees represent a fundamental compromise between cache-efficiency and actually minimizing
+/// the amount of work performed in a search. In theory, a binary search tree (BST) is the optimal
+/// choice for a sorted map, as a perfectly balanced BST performs the theoretical minimum amount of
+/// comparisons necessary to find an element (log<sub>2</sub>n). However, in practice the way this
+/// is done is *very* inefficient for modern computer architectures. In particular, every element
+/// is stored in its own individually heap-allocated node. This means that every single insertion
+/// triggers a heap-allocation, and every single comparison should be a cache-miss. Since these
+/// are both notably expensive things to do in practice, we are forced to at very least reconsider
+/// the BST strategy.
+///
+/// A B-Tree instead makes each node contain B-1 to 2B-1 elements in a contiguous array. By doing
+/// this, we reduce the number of allocations by a factor of B, and improve cache efficiency in
+/// searches. However, this does mean that searches will have to do *more* comparisons on average.
+/// The precise number of comparisons depends on the node search strategy used. For optimal cache
+/// efficiency, one could search the nodes linearly. For optimal comparisons, one could search
+/// the node using binary search. As a compromise, one could also perform a linear search
+/// that initially only checks every i<sup>th</sup> element for some choice of i.
+///
+/// Currently, our implementation simply performs naive linear search. This provides excellent
+/// performance on *small* nodes of elements which are cheap to compare. However in the future we
+/// would like to further explore choosing the optimal search strategy based on the choice of B,
+/// and possibly other factors. Using linear search, searching for a random element is expected
+/// to take O(B log<sub>B</sub>n) comparisons, which is generally worse than a BST. In practice,
+/// however, performance is excellent.
+///
+/// Keys are ordered by a comparator, which defaults to `Natural`, the order given by the keys'
+/// `Ord` implementation. A map built with `with_comparator` can instead be ordered by any
+/// `Compare` implementation, such as a closure, without wrapping its keys in a newtype.
+///
+/// It is a logic error for a key to be modified in such a way that the key's ordering relative to
+/// any other key, as determined by the map's comparator, changes while it is in the map. This is
+/// normally only possible through `Cell`, `RefCell`, global state, I/O, or unsafe code.
+//#[stable(feature = "rust1", since = "1.0.0")]
+pub struct BTreeMap<K, V, C = Natural> {
+    root: node::Root<K, V>,
+    length: usize,
+    cmp: C
+}
+
+impl<K, V, C> Drop for BTreeMap<K, V, C> {
+    fn drop(&mut self) {
+        unsafe {
+            for _ in root_into_iter(ptr::read(&self.root), self.length) { }
+        }
+    }
+}
+
+impl<K: Clone, V: Clone, C: Clone> Clone for BTreeMap<K, V, C> {
+    fn clone(&self) -> BTreeMap<K, V, C> {
+        let mut out_root = create_chain(self.root.as_ref().height());
+
+        {
+            let mut in_node = first_leaf_edge(self.root.as_ref()).into_node();
+            let mut out_node = first_leaf_edge(out_root.as_mut()).into_node();
+            'main: loop {
+                // Copy the leaf node
+                let mut in_edge = in_node.first_edge();
+                while let Ok(kv) = in_edge.right_kv() {
+                    let (k, v) = kv.into_kv();
+                    out_node.push(k.clone(), v.clone());
+
+                    in_edge = kv.right_edge();
+                }
+
+                // Find the next leaf node
+                let mut internal_in_edge = match in_node.ascend() {
+                    Ok(parent) => parent,
+                    Err(_) => break
+                };
+                let mut internal_out_node = out_node.ascend().ok().unwrap().into_node();
+                let internal_kv;
+                loop {
+                    match internal_in_edge.right_kv() {
+                        Ok(kv) => {
+                            internal_kv = kv;
+                            break;
+                        },
+                        Err(edge) => {
+                            internal_in_edge = match edge.into_node().ascend() {
+                                Ok(parent) => parent,
+                                Err(_) => break 'main
+                            };
+                            internal_out_node = internal_out_node.ascend()
+                                                                 .ok()
+                                                                 .unwrap()
+                                                                 .into_node();
+                        }
+                    }
+                }
+
+                let (k, v) = internal_kv.into_kv();
+                internal_out_node.push(
+                    k.clone(),
+                    v.clone(),
+                    create_chain(internal_kv.into_node().height() - 1)
+                );
+
+                in_node = first_leaf_edge(internal_kv.right_edge().descend()).into_node();
+                out_node = first_leaf_edge(internal_out_node.last_edge().descend()).into_node();
+            }
+        }
+
+        BTreeMap {
+            root: out_root,
+            length: self.length,
+            cmp: self.cmp.clone()
+        }
+    }
+}
+
+/*
+impl<K, Q: ?Sized, C> super::Recover<Q> for BTreeMap<K, (), C>
+    where C: Compare<K> + Compare<Q, K>
+{
+    type Key = K;
+
+    fn get(&self, key: &Q) -> Option<&K> {
+        match search::search_tree(self.root.as_ref(), key, &self.cmp) {
+            Found(handle) => Some(handle.into_kv().0),
+            GoDown(_) => None
+        }
+    }
+
+    fn take(&mut self, key: &Q) -> Option<K> {
+        match search::search_tree(self.root.as_mut(), key, &self.cmp) {
+            Found(handle) => {
+                Some(OccupiedEntry {
+                    handle: handle,
+                    length: &mut self.length
+                }.remove_entry().0)
+            },
+            GoDown(_) => None
+        }
+    }
+
+    fn replace(&mut self, key: K) -> Option<K> {
+        match self.entry(key) {
+            Occupied(occupied) => Some(occupied.remove_entry().0),
+            Vacant(_) => None
+        }
+    }
+}
+*/
+
+/// An iterator over a BTreeMap's entries.
+//#[stable(feature = "rust1", since = "1.0.0")]
+pub struct Iter<'a, K: 'a, V: 'a> {
+    range: Range<'a, K, V>,
+    length: usize
+}
+
+/// A mutable iterator over a BTreeMap's entries.
+//#[stable(feature = "rust1", since = "1.0.0")]
+pub struct IterMut<'a, K: 'a, V: 'a> {
+    range: RangeMut<'a, K, V>,
+    length: usize
+}
+
+/// An owning iterator over a BTreeMap's entries.
+//#[stable(feature = "rust1", since = "1.0.0")]
+pub struct IntoIter<K, V> {
+    front: Handle<NodeRef<marker::Owned, K, V, marker::Mut, marker::Leaf>, marker::Edge>,
+    back: Handle<NodeRef<marker::Owned, K, V, marker::Mut, marker::Leaf>, marker::Edge>,
+    length: usize,
+    // If this iterator is one half of a split, the spine of the split at its front or back. The
+    // iterator never frees the nodes on these, since the other half may still need them.
+    front_spine: Option<Arc<Spine<K, V>>>,
+    back_spine: Option<Arc<Spine<K, V>>>
+}
+
+// The nodes on the path from the root down to the leaf edge at which an `IntoIter` was split. Both
+// halves may have to read them, so neither half frees them. They are freed instead when the
+// `Spine` is dropped, once both halves and anything split from them are gone, except for the
+// ones that also lie on a spine bounding the iterator that was split, which are left to that one.
+struct Spine<K, V> {
+    edge: Handle<NodeRef<marker::Owned, K, V, marker::Mut, marker::Leaf>, marker::Edge>,
+    front: Option<Arc<Spine<K, V>>>,
+    back: Option<Arc<Spine<K, V>>>
+}
+
+// Every element has been moved out of a spine's nodes by the time anything but its halves looks
+// at it, so sharing one between threads only shares the shape of the tree.
+unsafe impl<K: Send, V: Send> Sync for Spine<K, V> { }
+
+/// An iterator over a BTreeMap's keys.
+//#[stable(feature = "rust1", since = "1.0.0")]
+pub struct Keys<'a, K: 'a, V: 'a> {
+    inner: Map<Iter<'a, K, V>, fn((&'a K, &'a V)) -> &'a K>,
+}
+
+/// An iterator over a BTreeMap's values.
+//#[stable(feature = "rust1", since = "1.0.0")]
+pub struct Values<'a, K: 'a, V: 'a> {
+    inner: Map<Iter<'a, K, V>, fn((&'a K, &'a V)) -> &'a V>,
+}
+
+/// A mutable iterator over a BTreeMap's values.
+pub struct ValuesMut<'a, K: 'a, V: 'a> {
+    inner: IterMut<'a, K, V>
+}
+
+/// An owning iterator over a BTreeMap's keys.
+pub struct IntoKeys<K, V> {
+    inner: IntoIter<K, V>
+}
+
+/// An owning iterator over a BTreeMap's values.
+pub struct IntoValues<K, V> {
+    inner: IntoIter<K, V>
+}
+
+/// An iterator over the keys of a sub-range of BTreeMap's entries.
+pub struct RangeKeys<'a, K: 'a, V: 'a> {
+    inner: Range<'a, K, V>
+}
+
+/// An iterator over the values of a sub-range of BTreeMap's entries.
+pub struct RangeValues<'a, K: 'a, V: 'a> {
+    inner: Range<'a, K, V>
+}
+
+/// An iterator over a sub-range of BTreeMap's entries.
+pub struct Range<'a, K: 'a, V: 'a> {
+    front: Handle<NodeRef<marker::Borrowed<'a>, K, V, marker::Immut, marker::Leaf>, marker::Edge>,
+    back: Handle<NodeRef<marker::Borrowed<'a>, K, V, marker::Immut, marker::Leaf>, marker::Edge>
+}
+
+/// A mutable iterator over a sub-range of BTreeMap's entries.
+pub struct RangeMut<'a, K: 'a, V: 'a> {
+    front: Handle<NodeRef<marker::Borrowed<'a>, K, V, marker::Mut, marker::Leaf>, marker::Edge>,
+    back: Handle<NodeRef<marker::Borrowed<'a>, K, V, marker::Mut, marker::Leaf>, marker::Edge>
+}
+
+/// The error returned by `try_range` and `try_range_mut` when the start of the range lies after
+/// its end, or both ends exclude the same key.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct InvertedRange;
+
+impl fmt::Display for InvertedRange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.description())
+    }
+}
+
+impl Error for InvertedRange {
+    fn description(&self) -> &str {
+        "range start is after range end in BTreeMap"
+    }
+}
+
+/// An iterator over the differences between two BTreeMaps, in order by key.
+pub struct Diff<'a, K: 'a, V: 'a, C: 'a = Natural> {
+    inner: MergeDiff<'a, Range<'a, K, V>, C>
+}
+
+/// A view into a single entry in a map, which may either be vacant or occupied.
+//#[stable(feature = "rust1", since = "1.0.0")]
+pub enum Entry<'a, K: 'a, V: 'a> {
+    /// A vacant Entry
+    //#[stable(feature = "rust1", since = "1.0.0")]
+    Vacant(VacantEntry<'a, K, V>),
+
+    /// An occupied Entry
+    //#[stable(feature = "rust1", since = "1.0.0")]
+    Occupied(OccupiedEntry<'a, K, V>),
+}
+
+/// A vacant Entry.
+//#[stable(feature = "rust1", since = "1.0.0")]
+pub struct VacantEntry<'a, K: 'a, V: 'a> {
+    key: K,
+    handle: Handle<NodeRef<marker::Borrowed<'a>, K, V, marker::Mut, marker::Leaf>, marker::Edge>,
+    length: &'a mut usize
+}
+
+/// A view into a single entry in a map, looked up by a reference to a key, which may either be
+/// vacant or occupied. Unlike an `Entry`, this only needs an owned key if one is inserted.
+pub enum EntryRef<'a, 'q, K: 'a, V: 'a, Q: 'q + ?Sized> {
+    /// A vacant EntryRef
+    Vacant(VacantEntryRef<'a, 'q, K, V, Q>),
+
+    /// An occupied EntryRef
+    Occupied(OccupiedEntry<'a, K, V>),
+}
+
+/// A vacant EntryRef, which converts its borrowed key into an owned one when a value is
+/// inserted.
+pub struct VacantEntryRef<'a, 'q, K: 'a, V: 'a, Q: 'q + ?Sized> {
+    key: &'q Q,
+    handle: Handle<NodeRef<marker::Borrowed<'a>, K, V, marker::Mut, marker::Leaf>, marker::Edge>,
+    length: &'a mut usize
+}
+
+/// An occupied Entry.
+//#[stable(feature = "rust1", since = "1.0.0")]
+pub struct OccupiedEntry<'a, K: 'a, V: 'a> {
+    handle: Handle<NodeRef<
+        marker::Borrowed<'a>,
+        K, V,
+        marker::Mut,
+        marker::LeafOrInternal
+    >, marker::KV>,
+
+    length: &'a mut usize
+}
+
+impl<K: Ord, V> BTreeMap<K, V> {
+    /// Makes a new empty BTreeMap with a reasonable choice for B.
+    //#[stable(feature = "rust1", since = "1.0.0")]
+    pub fn new() -> BTreeMap<K, V> {
+        BTreeMap::with_comparator(Natural)
+    }
+}
+
+impl<K, V, C: Compare<K>> BTreeMap<K, V, C> {
+    /// Makes a new empty BTreeMap whose keys are ordered by the given comparator.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use btree_rewrite::BTreeMap;
+    ///
+    /// let mut map = BTreeMap::with_comparator(|a: &&str, b: &&str| {
+    ///     a.to_lowercase().cmp(&b.to_lowercase())
+    /// });
+    /// map.insert("b", 2);
+    /// map.insert("A", 1);
+    /// map.insert("C", 3);
+    /// assert_eq!(map.get(&"a"), Some(&1));
+    /// assert_eq!(map.keys().cloned().collect::<Vec<_>>(), ["A", "b", "C"]);
+    /// ```
+    pub fn with_comparator(cmp: C) -> BTreeMap<K, V, C> {
+        BTreeMap {
+            root: node::Root::new_leaf(),
+            length: 0,
+            cmp: cmp
+        }
+    }
+
+    /// Returns a reference to the map's comparator.
+    pub fn comparator(&self) -> &C {
+        &self.cmp
+    }
+
+    /// Clears the map, removing all values.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::collections::BTreeMap;
+    ///
+    /// let mut a = BTreeMap::new();
+    /// a.insert(1, "a");
+    /// a.clear();
+    /// assert!(a.is_empty());
+    /// ```
+    //#[stable(feature = "rust1", since = "1.0.0")]
+    pub fn clear(&mut self) {
+        drop(self.take_all());
+    }
+
+    /// Returns a reference to the value corresponding to the key.
+    ///
+    /// The key may be any borrowed form of the map's key type, or any other type the map's
+    /// comparator can compare with keys (see `Comparable`), but the ordering on it *must* match
+    /// the ordering on the key type.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::collections::BTreeMap;
+    ///
+    /// let mut map = BTreeMap::new();
+    /// map.insert(1, "a");
+    /// assert_eq!(map.get(&1), Some(&"a"));
+    /// assert_eq!(map.get(&2), None);
+    /// ```
+    //#[stable(feature = "rust1", since = "1.0.0")]
+    pub fn get<Q: ?Sized>(&self, key: &Q) -> Option<&V> where C: Compare<Q, K> {
+        match search::search_tree(self.root.as_ref(), key, &self.cmp) {
+            Found(handle) => Some(handle.into_kv().1),
+            GoDown(_) => None
+        }
+    }
+
+    /// Returns true if the map contains a value for the specified key.
+    ///
+    /// The key may be any borrowed form of the map's key type, or any other type the map's
+    /// comparator can compare with keys (see `Comparable`), but the ordering on it *must* match
+    /// the ordering on the key type.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::collections::BTreeMap;
+    ///
+    /// let mut map = BTreeMap::new();
+    /// map.insert(1, "a");
+    /// assert_eq!(map.contains_key(&1), true);
+    /// assert_eq!(map.contains_key(&2), false);
+    /// ```
+    //#[stable(feature = "rust1", since = "1.0.0")]
+    pub fn contains_key<Q: ?Sized>(&self, key: &Q) -> bool where C: Compare<Q, K> {
+        self.get(key).is_some()
+    }
+
+    /// Returns a mutable reference to the value corresponding to the key.
+    ///
+    /// The key may be any borrowed form of the map's key type, or any other type the map's
+    /// comparator can compare with keys (see `Comparable`), but the ordering on it *must* match
+    /// the ordering on the key type.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::collections::BTreeMap;
+    ///
+    /// let mut map = BTreeMap::new();
+    /// map.insert(1, "a");
+    /// if let Some(x) = map.get_mut(&1) {
+    ///     *x = "b";
+    /// }
+    /// assert_eq!(map[&1], "b");
+    /// ```
+    // See `get` for implementation notes, this is basically a copy-paste with mut's added
+    //#[stable(feature = "rust1", since = "1.0.0")]
+    pub fn get_mut<Q: ?Sized>(&mut self, key: &Q) -> Option<&mut V>
+            where C: Compare<Q, K> {
+        match search::search_tree(self.root.as_mut(), key, &self.cmp) {
+            Found(handle) => Some(handle.into_kv_mut().1),
+            GoDown(_) => None
+        }
+    }
+
+    /// Returns mutable references to the values of several keys at once, in the order the keys
+    /// were given. Returns `None` if any key is missing, or if two of the keys refer to the same
+    /// element, since that would mean handing out two mutable references to one value.
+    ///
+    /// This takes O(N log n + N log N) time for N keys, and does not allocate.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use btree_rewrite::BTreeMap;
+    ///
+    /// let mut map: BTreeMap<_, _> = (0..10).map(|i| (i, i)).collect();
+    /// {
+    ///     let values = map.get_many_mut([&1, &5, &9]).unwrap();
+    ///     for v in values {
+    ///         *v *= 100;
+    ///     }
+    /// }
+    /// assert_eq!(map[&5], 500);
+    ///
+    /// assert!(map.get_many_mut([&1, &1]).is_none());
+    /// assert!(map.get_many_mut([&1, &10]).is_none());
+    /// ```
+    pub fn get_many_mut<Q: ?Sized, const N: usize>(&mut self, keys: [&Q; N])
+                                                   -> Option<[&mut V; N]>
+            where C: Compare<Q, K> {
+        // Every pointer refers to a different element, so handing out a reference to each is
+        // no different from borrowing disjoint fields.
+        self.find_val_ptrs(keys).map(|ptrs| ptrs.map(|v| unsafe { &mut *v }))
+    }
+
+    /// Returns mutable references to the values of two different keys at once. Returns `None` if
+    /// either key is missing or both refer to the same element.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use btree_rewrite::BTreeMap;
+    ///
+    /// let mut balances = BTreeMap::new();
+    /// balances.insert("alice", 100);
+    /// balances.insert("bob", 20);
+    ///
+    /// if let Some((from, to)) = balances.get_pair_mut("alice", "bob") {
+    ///     *from -= 30;
+    ///     *to += 30;
+    /// }
+    /// assert_eq!(balances["alice"], 70);
+    /// assert_eq!(balances["bob"], 50);
+    /// ```
+    pub fn get_pair_mut<Q: ?Sized>(&mut self, a: &Q, b: &Q) -> Option<(&mut V, &mut V)>
+            where C: Compare<Q, K> {
+        match self.get_many_mut([a, b]) {
+            Some([a_val, b_val]) => Some((a_val, b_val)),
+            None => None
+        }
+    }
+
+    /// Looks up several keys, returning pointers to their values in the order the keys were
+    /// given, or `None` if any key is missing or two of them find the same element.
+    ///
+    /// The searches are all made through shared borrows, and only once they are over are the
+    /// value pointers derived, with a single borrow of each node that holds any of them. Taking
+    /// `&mut V`s one search at a time instead would have each search's borrow of a node
+    /// invalidate the pointers already taken into it.
+    ///
+    /// Since every element has its own key slot, comparing key pointers tells whether two
+    /// searches found the same element. The value pointers cannot be used for that, as they all
+    /// coincide when `V` is zero-sized.
+    fn find_val_ptrs<Q: ?Sized, const N: usize>(&mut self, keys: [&Q; N])
+                                                -> Option<[*mut V; N]>
+            where C: Compare<Q, K> {
+        let mut found = [(ptr::null(), 0, None); N];
+        for (i, key) in keys.iter().enumerate() {
+            match search::search_tree(self.root.as_ref(), *key, &self.cmp) {
+                Found(handle) => found[i] = (handle.into_kv().0 as *const K, i, Some(handle)),
+                GoDown(_) => return None
+            }
+        }
+
+        // Sorting by key pointer brings together both repeated elements and elements in the
+        // same node, since each node's keys are laid out in order in one array.
+        found.sort_unstable_by_key(|&(key, _, _)| key);
+        if found.windows(2).any(|pair| pair[0].0 == pair[1].0) {
+            return None;
+        }
+
+        let mut ptrs = [ptr::null_mut(); N];
+        let mut vals = None;
+        for &(_, i, handle) in &found {
+            let handle = unsafe { unwrap_unchecked(handle) };
+            let idx = handle.idx();
+            let node = handle.into_node();
+            let base = match vals {
+                Some((last_node, base)) if last_node == node => base,
+                _ => unsafe { node.into_vals_mut_ptr() }
+            };
+            vals = Some((node, base));
+            ptrs[i] = unsafe { base.offset(idx as isize) };
+        }
+        Some(ptrs)
+    }
+
+    /// Inserts a key-value pair into the map.
+    ///
+    /// If the map did not have this key present, `None` is returned.
+    ///
+    /// If the map did have this key present, the key is not updated, the
+    /// value is updated and the old value is returned.
+    /// See the [module-level documentation] for more.
+    ///
+    /// [module-level documentation]: index.html#insert-and-complex-keys
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::collections::BTreeMap;
+    ///
+    /// let mut map = BTreeMap::new();
+    /// assert_eq!(map.insert(37, "a"), None);
+    /// assert_eq!(map.is_empty(), false);
+    ///
+    /// map.insert(37, "b");
+    /// assert_eq!(map.insert(37, "c"), Some("b"));
+    /// assert_eq!(map[&37], "c");
+    /// ```
+    //#[stable(feature = "rust1", since = "1.0.0")]
+    pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+        match self.entry(key) {
+            Occupied(mut entry) => Some(entry.insert(value)),
+            Vacant(entry) => {
+                entry.insert(value);
+                None
+            }
+        }
+    }
+
+    /// Removes a key from the map, returning the value at the key if the key
+    /// was previously in the map.
+    ///
+    /// The key may be any borrowed form of the map's key type, or any other type the map's
+    /// comparator can compare with keys (see `Comparable`), but the ordering on it *must* match
+    /// the ordering on the key type.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::collections::BTreeMap;
+    ///
+    /// let mut map = BTreeMap::new();
+    /// map.insert(1, "a");
+    /// assert_eq!(map.remove(&1), Some("a"));
+    /// assert_eq!(map.remove(&1), None);
+    /// ```
+    //#[stable(feature = "rust1", since = "1.0.0")]
+    pub fn remove<Q: ?Sized>(&mut self, key: &Q) -> Option<V> where C: Compare<Q, K> {
+        match search::search_tree(self.root.as_mut(), key, &self.cmp) {
+            Found(handle) => {
+                Some(OccupiedEntry {
+                    handle: handle,
+                    length: &mut self.length
+                }.remove())
+            },
+            GoDown(_) => None
+        }
+    }
+
+    /// Constructs a double-ended iterator over a sub-range of elements in the map, starting
+    /// at min, and ending at max. If min is `Unbounded`, then it will be treated as "negative
+    /// infinity", and if max is `Unbounded`, then it will be treated as "positive infinity".
+    /// Thus range(Unbounded, Unbounded) will yield the whole collection.
+    ///
+    /// The bounds may be of any types the map's comparator can compare with keys, and need not be
+    /// the same. The type of an `Unbounded` end falls back to the key type.
+    ///
+    /// If the start of the range lies after its end, or both ends exclude the same key, the range
+    /// is empty, whatever the map contains. Use `try_range` to have this reported instead.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// #![feature(btree_range, collections_bound)]
+    ///
+    /// use std::collections::BTreeMap;
+    /// use std::collections::Bound::{Included, Unbounded};
+    ///
+    /// let mut map = BTreeMap::new();
+    /// map.insert(3, "a");
+    /// map.insert(5, "b");
+    /// map.insert(8, "c");
+    /// for (&key, &value) in map.range(Included(&4), Included(&8)) {
+    ///     println!("{}: {}", key, value);
+    /// }
+    /// assert_eq!(Some((&5, &"b")), map.range(Included(&4), Unbounded).next());
+    /// ```
+    /*#[unstable(feature = "btree_range",
+               reason = "matches collection reform specification, waiting for dust to settle",
+               issue = "27787")]*/
+    pub fn range<Min: ?Sized = K, Max: ?Sized = K>(&self, min: Bound<&Min>, max: Bound<&Max>)
+            -> Range<K, V>
+        where C: Compare<Min, K> + Compare<Max, K>
+    {
+        let root = self.root.as_ref();
+        let (front, back) = range_edges(root, root, min, max, &self.cmp);
+        Range {
+            front: front,
+            back: back
+        }
+    }
+
+    /// Like `range`, but returns an error if the bounds are inverted: if min comes after max, or
+    /// if both are `Excluded` and equal. This is decided by comparing the bounds with each other,
+    /// so it does not depend on what is in the map. Any other bounds give the same range as
+    /// `range` would, which may be empty, like `Included(&x)` to `Excluded(&x)`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use btree_rewrite::BTreeMap;
+    /// use btree_rewrite::Bound::{Included, Excluded};
+    /// use btree_rewrite::map::InvertedRange;
+    ///
+    /// let map: BTreeMap<_, _> = (0..10).map(|i| (i, i)).collect();
+    /// assert_eq!(map.try_range(Included(&3), Excluded(&5)).unwrap().count(), 2);
+    /// assert_eq!(map.try_range(Excluded(&4), Excluded(&4)).err(), Some(InvertedRange));
+    /// assert_eq!(map.try_range(Included(&7), Included(&2)).err(), Some(InvertedRange));
+    ///
+    /// // Inverted even though no key lies between the bounds
+    /// assert_eq!(map.try_range(Excluded(&20), Excluded(&20)).err(), Some(InvertedRange));
+    /// assert_eq!(map.range(Excluded(&20), Excluded(&20)).count(), 0);
+    /// ```
+    pub fn try_range<Min: ?Sized = K, Max: ?Sized = K>(&self, min: Bound<&Min>, max: Bound<&Max>)
+            -> Result<Range<K, V>, InvertedRange>
+        where C: Compare<Min, K> + Compare<Max, K> + Compare<Min, Max>
+    {
+        if bounds_inverted(min, max, &self.cmp) {
+            Err(InvertedRange)
+        } else {
+            Ok(self.range(min, max))
+        }
+    }
+
+    /// Constructs a mutable double-ended iterator over a sub-range of elements in the map, starting
+    /// at min, and ending at max. If min is `Unbounded`, then it will be treated as "negative
+    /// infinity", and if max is `Unbounded`, then it will be treated as "positive infinity".
+    /// Thus range(Unbounded, Unbounded) will yield the whole collection.
+    ///
+    /// Inverted bounds give an empty range, as in `range`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// #![feature(btree_range, collections_bound)]
+    ///
+    /// use std::collections::BTreeMap;
+    /// use std::collections::Bound::{Included, Excluded};
+    ///
+    /// let mut map: BTreeMap<&str, i32> = ["Alice", "Bob", "Carol", "Cheryl"].iter()
+    ///                                                                       .map(|&s| (s, 0))
+    ///                                                                       .collect();
+    /// for (_, balance) in map.range_mut(Included("B"), Excluded("Cheryl")) {
+    ///     *balance += 100;
+    /// }
+    /// for (name, balance) in &map {
+    ///     println!("{} => {}", name, balance);
+    /// }
+    /// ```
+    /*#[unstable(feature = "btree_range",
+               reason = "matches collection reform specification, waiting for dust to settle",
+               issue = "27787")]*/
+    pub fn range_mut<Min: ?Sized = K, Max: ?Sized = K>(&mut self,
+                                                       min: Bound<&Min>,
+                                                       max: Bound<&Max>)
+                                                       -> RangeMut<K, V>
+        where C: Compare<Min, K> + Compare<Max, K>
+    {
+        let root1 = self.root.as_mut();
+        let root2 = unsafe { ptr::read(&root1) };
+        let (front, back) = range_edges(root1, root2, min, max, &self.cmp);
+        RangeMut {
+            front: front,
+            back: back
+        }
+    }
+
+    /// Like `range_mut`, but returns an error if the bounds are inverted. See `try_range` for
+    /// when they are.
+    pub fn try_range_mut<Min: ?Sized = K, Max: ?Sized = K>(&mut self,
+                                                           min: Bound<&Min>,
+                                                           max: Bound<&Max>)
+            -> Result<RangeMut<K, V>, InvertedRange>
+        where C: Compare<Min, K> + Compare<Max, K> + Compare<Min, Max>
+    {
+        if bounds_inverted(min, max, &self.cmp) {
+            Err(InvertedRange)
+        } else {
+            Ok(self.range_mut(min, max))
+        }
+    }
+
+    /// Consumes the map, returning an owning iterator over just the elements between min and
+    /// max, with the bounds treated as in `range`. Everything outside the bounds is dropped.
+    ///
+    /// The elements outside the bounds are moved out and dropped by the returned iterator itself
+    /// before it is handed back, so each node is freed as soon as it has been emptied and whole
+    /// subtrees outside the bounds go without comparing a single key. An inverted range, with min
+    /// after max, drops everything and yields nothing.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use btree_rewrite::BTreeMap;
+    /// use btree_rewrite::Bound::{Included, Excluded};
+    ///
+    /// let map: BTreeMap<_, _> = (0..100).map(|i| (i, i * i)).collect();
+    /// let window: Vec<_> = map.into_range(Included(&40), Excluded(&43)).collect();
+    /// assert_eq!(window, [(40, 1600), (41, 1681), (42, 1764)]);
+    /// ```
+    pub fn into_range<Min: ?Sized = K, Max: ?Sized = K>(self, min: Bound<&Min>, max: Bound<&Max>)
+            -> IntoIter<K, V>
+        where C: Compare<Min, K> + Compare<Max, K>
+    {
+        let (front, back) = unsafe {
+            let root1 = ptr::read(&self.root).into_ref();
+            let root2 = ptr::read(&root1);
+            range_edges(root1, root2, min, max, &self.cmp)
+        };
+
+        let mut iter = self.into_iter();
+        while iter.front != front {
+            iter.next();
+        }
+        while iter.back != back {
+            iter.next_back();
+        }
+        iter
+    }
+
+    /// Gets the given key's corresponding entry in the map for in-place manipulation.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::collections::BTreeMap;
+    ///
+    /// let mut count: BTreeMap<&str, usize> = BTreeMap::new();
+    ///
+    /// // count the number of occurrences of letters in the vec
+    /// for x in vec!["a","b","a","c","a","b"] {
+    ///     *count.entry(x).or_insert(0) += 1;
+    /// }
+    ///
+    /// assert_eq!(count["a"], 3);
+    /// ```
+    //#[stable(feature = "rust1", since = "1.0.0")]
+    pub fn entry(&mut self, key: K) -> Entry<K, V> {
+        match search::search_tree(self.root.as_mut(), &key, &self.cmp) {
+            Found(handle) => Occupied(OccupiedEntry {
+                handle: handle,
+                length: &mut self.length
+            }),
+            GoDown(handle) => Vacant(VacantEntry {
+                key: key,
+                handle: handle,
+                length: &mut self.length
+            })
+        }
+    }
+
+    /// Gets the entry for a key in the map for in-place manipulation, looking it up by reference.
+    ///
+    /// The key is only converted into an owned `K`, through `Into`, if the entry is vacant and
+    /// a value is inserted into it. This avoids allocating a new key for every lookup when the
+    /// key usually exists already.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use btree_rewrite::BTreeMap;
+    ///
+    /// let mut count: BTreeMap<String, usize> = BTreeMap::new();
+    ///
+    /// // Only the first occurrence of each word allocates a `String`
+    /// for word in "the cat and the hat".split(' ') {
+    ///     *count.entry_ref(word).or_insert(0) += 1;
+    /// }
+    ///
+    /// assert_eq!(count["the"], 2);
+    /// assert_eq!(count.len(), 4);
+    /// ```
+    pub fn entry_ref<'a, 'q, Q: ?Sized>(&'a mut self, key: &'q Q) -> EntryRef<'a, 'q, K, V, Q>
+            where C: Compare<Q, K> {
+        match search::search_tree(self.root.as_mut(), key, &self.cmp) {
+            Found(handle) => EntryRef::Occupied(OccupiedEntry {
+                handle: handle,
+                length: &mut self.length
+            }),
+            GoDown(handle) => EntryRef::Vacant(VacantEntryRef {
+                key: key,
+                handle: handle,
+                length: &mut self.length
+            })
+        }
+    }
+
+    /// Looks up a batch of keys, returning the value for each one, or `None` if it is absent, in
+    /// the order the keys were given.
+    ///
+    /// Rather than searching from the root for every key, each search starts from the node the
+    /// previous one ended in and only climbs as far as it has to. The keys may come in any order,
+    /// but when they are sorted by the map's ordering, most of them are found without leaving the
+    /// leaf the previous key was in, and the whole batch takes far fewer comparisons than looking
+    /// each key up with `get`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use btree_rewrite::BTreeMap;
+    ///
+    /// let map: BTreeMap<_, _> = (0..100).map(|i| (i * 2, i)).collect();
+    /// assert_eq!(map.get_many(&[4, 5, 6, 198, 200]), [Some(&2), None, Some(&3), Some(&99), None]);
+    /// ```
+    pub fn get_many<'q, I, Q: ?Sized + 'q>(&self, keys: I) -> Vec<Option<&V>>
+            where I: IntoIterator<Item=&'q Q>, C: Compare<Q, K> {
+        let mut values = Vec::new();
+        let mut finger = self.root.as_ref();
+        for key in keys {
+            match search::search_tree_from(finger, key, &self.cmp) {
+                Found(handle) => {
+                    finger = handle.into_node();
+                    values.push(Some(handle.into_kv().1));
+                },
+                GoDown(handle) => {
+                    finger = handle.into_node().forget_type();
+                    values.push(None);
+                }
+            }
+        }
+        values
+    }
+
+    /// Inserts a batch of key-value pairs, returning what `insert` would have returned for each
+    /// one, in the order they were given.
+    ///
+    /// Like `get_many`, each search starts where the previous insertion left off, which makes
+    /// this much faster than calling `insert` in a loop when the pairs are sorted by key. Pairs
+    /// in any order are still inserted correctly. When a key appears more than once, the last
+    /// value given for it wins.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use btree_rewrite::BTreeMap;
+    ///
+    /// let mut map = BTreeMap::new();
+    /// map.insert(2, "b");
+    /// let old = map.insert_sorted_batch(vec![(1, "a"), (2, "B"), (3, "c")]);
+    /// assert_eq!(old, [None, Some("b"), None]);
+    /// assert_eq!(map.len(), 3);
+    /// assert_eq!(map[&2], "B");
+    /// ```
+    pub fn insert_sorted_batch<I>(&mut self, iter: I) -> Vec<Option<V>>
+            where I: IntoIterator<Item=(K, V)> {
+        let mut old_values = Vec::new();
+        let mut finger = self.root.as_mut();
+        for (key, value) in iter {
+            match search::search_tree_from(finger, &key, &self.cmp) {
+                Found(mut handle) => {
+                    old_values.push(Some(mem::replace(handle.kv_mut().1, value)));
+                    finger = handle.into_node();
+                },
+                GoDown(handle) => {
+                    self.length += 1;
+                    finger = insert_kv(handle, key, value).into_node();
+                    old_values.push(None);
+                }
+            }
+        }
+        old_values
+    }
+
+    /// Removes a batch of keys, returning the value removed for each one, or `None` if it was
+    /// absent, in the order the keys were given.
+    ///
+    /// Like `get_many`, each search starts from a node near where the previous removal left off,
+    /// which makes this much faster than calling `remove` in a loop when the keys are sorted.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use btree_rewrite::BTreeMap;
+    ///
+    /// let mut map: BTreeMap<_, _> = (0..100).map(|i| (i, i * 10)).collect();
+    /// assert_eq!(map.remove_sorted_batch(&[10, 11, 200]), [Some(100), Some(110), None]);
+    /// assert_eq!(map.len(), 98);
+    /// ```
+    pub fn remove_sorted_batch<'q, I, Q: ?Sized + 'q>(&mut self, keys: I) -> Vec<Option<V>>
+            where I: IntoIterator<Item=&'q Q>, C: Compare<Q, K> {
+        let mut values = Vec::new();
+        let mut finger = self.root.as_mut();
+        for key in keys {
+            match search::search_tree_from(finger, key, &self.cmp) {
+                Found(handle) => {
+                    self.length -= 1;
+                    let (_, value, survivor) = remove_kv(handle);
+                    finger = survivor;
+                    values.push(Some(value));
+                },
+                GoDown(handle) => {
+                    finger = handle.into_node().forget_type();
+                    values.push(None);
+                }
+            }
+        }
+        values
+    }
+
+    /// Rebuilds the map so that its nodes are packed densely again, filling each to 90% of its
+    /// capacity.
+    ///
+    /// Removal only rebalances a node once it drops below half full, so after heavy deletion
+    /// most nodes can be left sitting just above that minimum. This moves every element, in
+    /// order, into nodes filled to the target, freeing the old nodes as it goes. It takes O(n)
+    /// time. Nodes are not filled completely, so that the next few insertions into each do not
+    /// immediately split it again. Use `compact_to` to choose another fill factor.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use btree_rewrite::BTreeMap;
+    ///
+    /// let mut map: BTreeMap<_, _> = (0..1000).map(|i| (i, i)).collect();
+    /// for i in 0..900 {
+    ///     map.remove(&i);
+    /// }
+    ///
+    /// map.compact();
+    /// assert_eq!(map.len(), 100);
+    /// assert_eq!(map.iter().next(), Some((&900, &900)));
+    /// ```
+    pub fn compact(&mut self) {
+        self.compact_to(90)
+    }
+
+    /// Rebuilds the map so that its nodes are filled to `percent` percent of their capacity,
+    /// rounded down to a whole number of elements, as `compact` does for 90%.
+    ///
+    /// Nodes are never filled to less than half their capacity, which the tree needs to stay
+    /// balanced, so any `percent` at or below 50 gives half-full nodes. Only the nodes along the
+    /// right edge of the tree can end up with fewer elements than the target, since they hold
+    /// whatever is left over.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `percent` is greater than 100.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use btree_rewrite::BTreeMap;
+    ///
+    /// let mut map: BTreeMap<_, _> = (0..1000).map(|i| (i, i)).collect();
+    /// for i in 0..1000 {
+    ///     if i % 2 == 0 {
+    ///         map.remove(&i);
+    ///     }
+    /// }
+    ///
+    /// let sparse = map.node_count();
+    /// map.compact_to(100);
+    /// assert!(map.node_count() < sparse);
+    /// assert_eq!(map.len(), 500);
+    /// ```
+    pub fn compact_to(&mut self, percent: usize) {
+        assert!(percent <= 100, "fill factor is over 100%");
+        let fill = cmp::max(node::CAPACITY * percent / 100, node::MIN_LEN);
+        let old = self.take_all();
+        bulk_push(&mut self.root, &mut self.length, old, fill);
+    }
+
+    /// Returns the number of nodes the map's elements are stored in.
+    ///
+    /// Together with `len`, this tells how densely the map is packed, which `compact` and
+    /// `compact_to` restore after heavy deletion.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use btree_rewrite::BTreeMap;
+    ///
+    /// let mut map = BTreeMap::new();
+    /// assert_eq!(map.node_count(), 1);
+    /// map.insert(1, "a");
+    /// assert_eq!(map.node_count(), 1);
+    /// ```
+    pub fn node_count(&self) -> usize {
+        count_nodes(self.root.as_ref())
+    }
+
+    /// Gets an iterator over the differences between this map and `other`, in order by key.
+    ///
+    /// Keys only present in `self` are reported as `Removed` and keys only present in `other` as
+    /// `Added`. Keys present in both with unequal values are reported as `Changed`. Since the
+    /// nodes of a `BTreeMap` are never shared, this always walks both maps in their entirety; see
+    /// `PersistentMap::diff` for a version that can skip shared subtrees.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use btree_rewrite::{BTreeMap, DiffItem};
+    ///
+    /// let a: BTreeMap<_, _> = vec![(1, "a"), (2, "b"), (3, "c")].into_iter().collect();
+    /// let b: BTreeMap<_, _> = vec![(2, "b"), (3, "d"), (4, "e")].into_iter().collect();
+    ///
+    /// let diff: Vec<_> = a.diff(&b).collect();
+    /// assert_eq!(diff, [DiffItem::Removed(&1, &"a"),
+    ///                   DiffItem::Changed(&3, &"c", &"d"),
+    ///                   DiffItem::Added(&4, &"e")]);
+    /// ```
+    ///
+    /// Both maps are walked in the order given by `self`'s comparator, which is assumed to order
+    /// `other` in the same way.
+    pub fn diff<'a>(&'a self, other: &'a BTreeMap<K, V, C>) -> Diff<'a, K, V, C>
+            where V: PartialEq {
+        let left = Range {
+            front: first_leaf_edge(self.root.as_ref()),
+            back: last_leaf_edge(self.root.as_ref())
+        };
+        let right = Range {
+            front: first_leaf_edge(other.root.as_ref()),
+            back: last_leaf_edge(other.root.as_ref())
+        };
+
+        Diff {
+            inner: MergeDiff::new(left, right, &self.cmp)
+        }
+    }
+}
+
+impl<'a, K: 'a, V: 'a, C> IntoIterator for &'a BTreeMap<K, V, C> {
+    type Item = (&'a K, &'a V);
+    type IntoIter = Iter<'a, K, V>;
+
+    fn into_iter(self) -> Iter<'a, K, V> {
+        self.iter()
+    }
+}
+
+impl<'a, K: 'a, V: 'a> Iterator for Iter<'a, K, V> {
+    type Item = (&'a K, &'a V);
+
+    fn next(&mut self) -> Option<(&'a K, &'a V)> {
+        if self.length == 0 {
+            None
+        } else {
+            self.length -= 1;
+            unsafe { Some(self.range.next_unchecked()) }
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.length, Some(self.length))
+    }
+}
+
+impl<'a, K: 'a, V: 'a> DoubleEndedIterator for Iter<'a, K, V> {
+    fn next_back(&mut self) -> Option<(&'a K, &'a V)> {
+        if self.length == 0 {
+            None
+        } else {
+            self.length -= 1;
+            unsafe { Some(self.range.next_back_unchecked()) }
+        }
+    }
+}
+
+impl<'a, K: 'a, V: 'a> ExactSizeIterator for Iter<'a, K, V> {
+    fn len(&self) -> usize { self.length }
+}
+
+impl<'a, K, V> FusedIterator for Iter<'a, K, V> {}
+
+impl<'a, K, V> Clone for Iter<'a, K, V> {
+    fn clone(&self) -> Iter<'a, K, V> {
+        Iter {
+            range: self.range.clone(),
+            length: self.length
+        }
+    }
+}
+
+impl<'a, K: 'a, V: 'a> Iter<'a, K, V> {
+    /// Splits the remaining elements into two iterators whose lengths differ by at most one, the
+    /// first yielding the lower half and the second the upper half. This takes O(n / B) time,
+    /// since the subtrees skipped on the way to the middle have to be counted.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use btree_rewrite::BTreeMap;
+    ///
+    /// let map: BTreeMap<_, _> = (0..1000).map(|i| (i, i)).collect();
+    /// let (left, right) = map.iter().split_at_middle();
+    /// assert_eq!(left.len() + right.len(), 1000);
+    /// assert!(left.last().unwrap().0 < right.clone().next().unwrap().0);
+    /// ```
+    pub fn split_at_middle(self) -> (Iter<'a, K, V>, Iter<'a, K, V>) {
+        let left_length = self.length / 2;
+        let middle = unsafe { skip_elements(self.range.front, left_length) };
+        (Iter { range: Range { front: self.range.front, back: middle }, length: left_length },
+         Iter { range: Range { front: middle, back: self.range.back },
+                length: self.length - left_length })
+    }
+
+    /// Calls `f` on every remaining element, spread over several threads. The elements are split
+    /// in half recursively until either there are at most `grain_size` left or there is a piece
+    /// for each thread the machine can run in parallel, and each piece is then handed to its own
+    /// thread. The order in which `f` sees the elements is unspecified.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use btree_rewrite::BTreeMap;
+    /// use std::sync::atomic::{AtomicUsize, Ordering};
+    ///
+    /// let map: BTreeMap<_, _> = (0..1000).map(|i| (i, i)).collect();
+    /// let sum = AtomicUsize::new(0);
+    /// map.iter().par_for_each(100, |(_, &v)| { sum.fetch_add(v, Ordering::Relaxed); });
+    /// assert_eq!(sum.load(Ordering::Relaxed), 999 * 1000 / 2);
+    /// ```
+    pub fn par_for_each<F>(self, grain_size: usize, f: F)
+            where K: Sync, V: Sync, F: Fn((&'a K, &'a V)) + Sync {
+        self.par_for_each_ref(grain_size, par_split_depth(), &f)
+    }
+
+    fn par_for_each_ref<F>(self, grain_size: usize, depth: usize, f: &F)
+            where K: Sync, V: Sync, F: Fn((&'a K, &'a V)) + Sync {
+        if depth == 0 || self.length <= cmp::max(grain_size, 1) {
+            for kv in self {
+                f(kv);
+            }
+            return;
+        }
+
+        let (left, right) = self.split_at_middle();
+        thread::scope(|scope| {
+            scope.spawn(move || left.par_for_each_ref(grain_size, depth - 1, f));
+            right.par_for_each_ref(grain_size, depth - 1, f);
+        });
+    }
+}
+
+impl<'a, K: 'a, V: 'a, C> IntoIterator for &'a mut BTreeMap<K, V, C> {
+    type Item = (&'a K, &'a mut V);
+    type IntoIter = IterMut<'a, K, V>;
+
+    fn into_iter(self) -> IterMut<'a, K, V> {
+        self.iter_mut()
+    }
+}
+
+impl<'a, K: 'a, V: 'a> Iterator for IterMut<'a, K, V> {
+    type Item = (&'a K, &'a mut V);
+
+    fn next(&mut self) -> Option<(&'a K, &'a mut V)> {
+        if self.length == 0 {
+            None
+        } else {
+            self.length -= 1;
+            unsafe { Some(self.range.next_unchecked()) }
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.length, Some(self.length))
+    }
+}
+
+impl<'a, K: 'a, V: 'a> DoubleEndedIterator for IterMut<'a, K, V> {
+    fn next_back(&mut self) -> Option<(&'a K, &'a mut V)> {
+        if self.length == 0 {
+            None
+        } else {
+            self.length -= 1;
+            unsafe { Some(self.range.next_back_unchecked()) }
+        }
+    }
+}
+
+impl<'a, K: 'a, V: 'a> ExactSizeIterator for IterMut<'a, K, V> {
+    fn len(&self) -> usize { self.length }
+}
+
+impl<'a, K, V> FusedIterator for IterMut<'a, K, V> {}
+
+impl<'a, K: 'a, V: 'a> IterMut<'a, K, V> {
+    /// Splits the remaining elements into two iterators whose lengths differ by at most one, the
+    /// first yielding the lower half and the second the upper half. This takes O(n / B) time,
+    /// since the subtrees skipped on the way to the middle have to be counted.
+    pub fn split_at_middle(self) -> (IterMut<'a, K, V>, IterMut<'a, K, V>) {
+        let left_length = self.length / 2;
+        unsafe {
+            let middle = skip_elements(ptr::read(&self.range.front), left_length);
+            let middle2 = ptr::read(&middle);
+            let RangeMut { front, back } = self.range;
+            (IterMut { range: RangeMut { front: front, back: middle }, length: left_length },
+             IterMut { range: RangeMut { front: middle2, back: back },
+                       length: self.length - left_length })
+        }
+    }
+
+    /// Calls `f` on every remaining element, spread over several threads. The elements are split
+    /// in half recursively until either there are at most `grain_size` left or there is a piece
+    /// for each thread the machine can run in parallel, and each piece is then handed to its own
+    /// thread. The order in which `f` sees the elements is unspecified.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use btree_rewrite::BTreeMap;
+    ///
+    /// let mut map: BTreeMap<_, _> = (0..1000).map(|i| (i, i)).collect();
+    /// map.iter_mut().par_for_each(100, |(_, v)| *v *= 2);
+    /// assert_eq!(map[&999], 1998);
+    /// ```
+    pub fn par_for_each<F>(self, grain_size: usize, f: F)
+            where K: Sync, V: Send, F: Fn((&'a K, &'a mut V)) + Sync {
+        self.par_for_each_ref(grain_size, par_split_depth(), &f)
+    }
+
+    fn par_for_each_ref<F>(self, grain_size: usize, depth: usize, f: &F)
+            where K: Sync, V: Send, F: Fn((&'a K, &'a mut V)) + Sync {
+        if depth == 0 || self.length <= cmp::max(grain_size, 1) {
+            for kv in self {
+                f(kv);
+            }
+            return;
+        }
+
+        let (left, right) = self.split_at_middle();
+        thread::scope(|scope| {
+            scope.spawn(move || left.par_for_each_ref(grain_size, depth - 1, f));
+            right.par_for_each_ref(grain_size, depth - 1, f);
+        });
+    }
+}
+
+// Mutable iterators only ever lend their keys out immutably, so moving one to another thread only
+// shares the keys, and the two halves of a split never hold references to the same element.
+unsafe impl<'a, K: Sync, V: Send> Send for IterMut<'a, K, V> { }
+unsafe impl<'a, K: Sync, V: Send> Send for RangeMut<'a, K, V> { }
+
+/// Returns how many times `par_for_each` may split its elements in half, which is enough to give
+/// each thread the machine can run in parallel a piece of its own.
+fn par_split_depth() -> usize {
+    let threads = thread::available_parallelism().map(|n| n.get()).unwrap_or(1);
+    let mut depth = 0;
+    while 1 << depth < threads {
+        depth += 1;
+    }
+    depth
+}
+
+impl<K, V, C> IntoIterator for BTreeMap<K, V, C> {
+    type Item = (K, V);
+    type IntoIter = IntoIter<K, V>;
+
+    fn into_iter(self) -> IntoIter<K, V> {
+        let iter = unsafe { root_into_iter(ptr::read(&self.root), self.length) };
+        // The nodes now belong to the iterator, but the comparator still needs dropping.
+        let cmp = unsafe { ptr::read(&self.cmp) };
+        mem::forget(self);
+        drop(cmp);
+        iter
+    }
+}
+
+impl<K, V> Drop for IntoIter<K, V> {
+    fn drop(&mut self) {
+        for _ in &mut *self { }
+        unsafe {
+            let node = ptr::read(&self.front).into_node().forget_type();
+            free_path(node, &self.front_spine, &self.back_spine);
+        }
+    }
+}
+
+impl<K, V> Drop for Spine<K, V> {
+    fn drop(&mut self) {
+        unsafe {
+            let node = ptr::read(&self.edge).into_node().forget_type();
+            free_path(node, &self.front, &self.back);
+        }
+    }
+}
+
+/// Returns whether `node` lies on the path from the root down to `spine`'s leaf edge.
+unsafe fn on_spine<K, V>(node: &NodeRef<marker::Owned, K, V, marker::Mut, marker::LeafOrInternal>,
+                         spine: &Option<Arc<Spine<K, V>>>) -> bool {
+    let spine = match *spine {
+        Some(ref spine) => spine,
+        None => return false
+    };
+    let mut cur = ptr::read(&spine.edge).into_node().forget_type();
+    while cur.height() < node.height() {
+        cur = match cur.ascend() {
+            Ok(parent) => parent.into_node().forget_type(),
+            Err(_) => return false
+        };
+    }
+    cur == *node
+}
+
+/// Moves up from a node that an `IntoIter` has moved past, freeing it unless it lies on `spine`.
+unsafe fn leave_node<K, V>(node: NodeRef<marker::Owned, K, V, marker::Mut, marker::LeafOrInternal>,
+                           spine: &Option<Arc<Spine<K, V>>>)
+                           -> Option<Handle<NodeRef<marker::Owned, K, V, marker::Mut,
+                                                    marker::Internal>,
+                                            marker::Edge>> {
+    if on_spine(&node, spine) {
+        return node.ascend().ok();
+    }
+    match node.force() {
+        Leaf(leaf) => leaf.deallocate_and_ascend(),
+        Internal(internal) => internal.deallocate_and_ascend()
+    }
+}
+
+/// Frees `node` and its ancestors, stopping at the first one that lies on either spine. All the
+/// ancestors of that one lie on the same spine, which frees them itself.
+unsafe fn free_path<K, V>(mut node: NodeRef<marker::Owned, K, V, marker::Mut,
+                                            marker::LeafOrInternal>,
+                          front_spine: &Option<Arc<Spine<K, V>>>,
+                          back_spine: &Option<Arc<Spine<K, V>>>) {
+    while !on_spine(&node, front_spine) && !on_spine(&node, back_spine) {
+        node = match leave_node(node, &None) {
+            Some(parent) => parent.into_node().forget_type(),
+            None => return
+        };
+    }
+}
+
+impl<K, V> IntoIter<K, V> {
+    /// Splits the remaining elements into two iterators whose lengths differ by at most one, the
+    /// first yielding the lower half and the second the upper half. This takes O(n / B) time,
+    /// since the subtrees skipped on the way to the middle have to be counted.
+    ///
+    /// Each half frees the nodes it is done with as it goes, except for those on the path from
+    /// the root to the point where they were split, which both halves may still need. These are
+    /// only freed once both halves have been dropped.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use btree_rewrite::BTreeMap;
+    /// use std::thread;
+    ///
+    /// let map: BTreeMap<_, _> = (0..1000).map(|i| (i, i.to_string())).collect();
+    /// let (left, right) = map.into_iter().split_at_middle();
+    /// let right = thread::spawn(move || right.map(|(_, v)| v).collect::<Vec<_>>());
+    /// let left: Vec<_> = left.map(|(_, v)| v).collect();
+    /// assert_eq!(left.len() + right.join().unwrap().len(), 1000);
+    /// ```
+    pub fn split_at_middle(self) -> (IntoIter<K, V>, IntoIter<K, V>) {
+        let left_length = self.length / 2;
+        unsafe {
+            let middle = skip_elements(ptr::read(&self.front), left_length);
+            let front = ptr::read(&self.front);
+            let back = ptr::read(&self.back);
+            let front_spine = ptr::read(&self.front_spine);
+            let back_spine = ptr::read(&self.back_spine);
+            let length = self.length;
+            mem::forget(self);
+
+            let spine = Arc::new(Spine {
+                edge: ptr::read(&middle),
+                front: front_spine.clone(),
+                back: back_spine.clone()
+            });
+            (IntoIter {
+                front: front,
+                back: ptr::read(&middle),
+                length: left_length,
+                front_spine: front_spine,
+                back_spine: Some(spine.clone())
+            },
+             IntoIter {
+                front: middle,
+                back: back,
+                length: length - left_length,
+                front_spine: Some(spine),
+                back_spine: back_spine
+            })
+        }
+    }
+}
+
+impl<K, V> Iterator for IntoIter<K, V> {
+    type Item = (K, V);
+
+    fn next(&mut self) -> Option<(K, V)> {
+        if self.length == 0 {
+            return None;
+        } else {
+            self.length -= 1;
+        }
+
+        let handle = unsafe { ptr::read(&self.front) };
+
+        let mut cur_handle = match handle.right_kv() {
+            Ok(kv) => {
+                let k = unsafe { ptr::read(kv.reborrow().into_kv().0) };
+                let v = unsafe { ptr::read(kv.reborrow().into_kv().1) };
+                self.front = kv.right_edge();
+                return Some((k, v));
+            },
+            Err(last_edge) => unsafe {
+                let node = last_edge.into_node().forget_type();
+                unwrap_unchecked(leave_node(node, &self.front_spine))
+            }
+        };
+
+        loop {
+            match cur_handle.right_kv() {
+                Ok(kv) => {
+                    let k = unsafe { ptr::read(kv.reborrow().into_kv().0) };
+                    let v = unsafe { ptr::read(kv.reborrow().into_kv().1) };
+                    self.front = first_leaf_edge(kv.right_edge().descend());
+                    return Some((k, v));
+                },
+                Err(last_edge) => unsafe {
+                    let node = last_edge.into_node().forget_type();
+                    cur_handle = unwrap_unchecked(leave_node(node, &self.front_spine));
+                }
+            }
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.length, Some(self.length))
+    }
+}
+
+impl<K, V> DoubleEndedIterator for IntoIter<K, V> {
+    fn next_back(&mut self) -> Option<(K, V)> {
+        if self.length == 0 {
+            return None;
+        } else {
+            self.length -= 1;
+        }
+
+        let handle = unsafe { ptr::read(&self.back) };
+
+        let mut cur_handle = match handle.left_kv() {
+            Ok(kv) => {
+                let k = unsafe { ptr::read(kv.reborrow().into_kv().0) };
+                let v = unsafe { ptr::read(kv.reborrow().into_kv().1) };
+                self.back = kv.left_edge();
+                return Some((k, v));
+            },
+            Err(first_edge) => unsafe {
+                let node = first_edge.into_node().forget_type();
+                unwrap_unchecked(leave_node(node, &self.back_spine))
+            }
+        };
+
+        loop {
+            match cur_handle.left_kv() {
+                Ok(kv) => {
+                    let k = unsafe { ptr::read(kv.reborrow().into_kv().0) };
+                    let v = unsafe { ptr::read(kv.reborrow().into_kv().1) };
+                    self.back = last_leaf_edge(kv.left_edge().descend());
+                    return Some((k, v));
+                },
+                Err(first_edge) => unsafe {
+                    let node = first_edge.into_node().forget_type();
+                    cur_handle = unwrap_unchecked(leave_node(node, &self.back_spine));
+                }
+            }
+        }
+    }
+}
+
+impl<K, V> ExactSizeIterator for IntoIter<K, V> {
+    fn len(&self) -> usize { self.length }
+}
+
+impl<K, V> FusedIterator for IntoIter<K, V> {}
+
+impl<'a, K, V> Iterator for Keys<'a, K, V> {
+    type Item = &'a K;
+
+    fn next(&mut self) -> Option<&'a K> {
+        self.inner.next()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+impl<'a, K, V> DoubleEndedIterator for Keys<'a, K, V> {
+    fn next_back(&mut self) -> Option<&'a K> {
+        self.inner.next_back()
+    }
+}
+
+impl<'a, K, V> ExactSizeIterator for Keys<'a, K, V> {
+    fn len(&self) -> usize {
+        self.inner.len()
+    }
+}
+
+impl<'a, K, V> FusedIterator for Keys<'a, K, V> {}
+
+impl<'a, K, V> Clone for Keys<'a, K, V> {
+    fn clone(&self) -> Keys<'a, K, V> {
+        Keys {
+            inner: self.inner.clone()
+        }
+    }
+}
+
+impl<'a, K, V> Iterator for Values<'a, K, V> {
+    type Item = &'a V;
+
+    fn next(&mut self) -> Option<&'a V> {
+        self.inner.next()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+impl<'a, K, V> DoubleEndedIterator for Values<'a, K, V> {
+    fn next_back(&mut self) -> Option<&'a V> {
+        self.inner.next_back()
+    }
+}
+
+impl<'a, K, V> ExactSizeIterator for Values<'a, K, V> {
+    fn len(&self) -> usize {
+        self.inner.len()
+    }
+}
+
+impl<'a, K, V> FusedIterator for Values<'a, K, V> {}
+
+impl<'a, K, V> Clone for Values<'a, K, V> {
+    fn clone(&self) -> Values<'a, K, V> {
+        Values {
+            inner: self.inner.clone()
+        }
+    }
+}
+
+impl<'a, K, V> Iterator for ValuesMut<'a, K, V> {
+    type Item = &'a mut V;
+
+    fn next(&mut self) -> Option<&'a mut V> {
+        self.inner.next().map(|(_, v)| v)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+impl<'a, K, V> DoubleEndedIterator for ValuesMut<'a, K, V> {
+    fn next_back(&mut self) -> Option<&'a mut V> {
+        self.inner.next_back().map(|(_, v)| v)
+    }
+}
+
+impl<'a, K, V> ExactSizeIterator for ValuesMut<'a, K, V> {
+    fn len(&self) -> usize {
+        self.inner.len()
+    }
+}
+
+impl<'a, K, V> FusedIterator for ValuesMut<'a, K, V> {}
+
+impl<K, V> Iterator for IntoKeys<K, V> {
+    type Item = K;
+
+    fn next(&mut self) -> Option<K> {
+        self.inner.next().map(|(k, _)| k)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+impl<K, V> DoubleEndedIterator for IntoKeys<K, V> {
+    fn next_back(&mut self) -> Option<K> {
+        self.inner.next_back().map(|(k, _)| k)
+    }
+}
+
+impl<K, V> ExactSizeIterator for IntoKeys<K, V> {
+    fn len(&self) -> usize {
+        self.inner.len()
+    }
+}
+
+impl<K, V> FusedIterator for IntoKeys<K, V> {}
+
+impl<K, V> Iterator for IntoValues<K, V> {
+    type Item = V;
+
+    fn next(&mut self) -> Option<V> {
+        self.inner.next().map(|(_, v)| v)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+impl<K, V> DoubleEndedIterator for IntoValues<K, V> {
+    fn next_back(&mut self) -> Option<V> {
+        self.inner.next_back().map(|(_, v)| v)
+    }
+}
+
+impl<K, V> ExactSizeIterator for IntoValues<K, V> {
+    fn len(&self) -> usize {
+        self.inner.len()
+    }
+}
+
+impl<K, V> FusedIterator for IntoValues<K, V> {}
+
+impl<'a, K, V> Iterator for RangeKeys<'a, K, V> {
+    type Item = &'a K;
+
+    fn next(&mut self) -> Option<&'a K> {
+        self.inner.next().map(|(k, _)| k)
+    }
+}
+
+impl<'a, K, V> DoubleEndedIterator for RangeKeys<'a, K, V> {
+    fn next_back(&mut self) -> Option<&'a K> {
+        self.inner.next_back().map(|(k, _)| k)
+    }
+}
+
+impl<'a, K, V> FusedIterator for RangeKeys<'a, K, V> {}
+
+impl<'a, K, V> Clone for RangeKeys<'a, K, V> {
+    fn clone(&self) -> RangeKeys<'a, K, V> {
+        RangeKeys {
+            inner: self.inner.clone()
+        }
+    }
+}
+
+impl<'a, K, V> Iterator for RangeValues<'a, K, V> {
+    type Item = &'a V;
+
+    fn next(&mut self) -> Option<&'a V> {
+        self.inner.next().map(|(_, v)| v)
+    }
+}
+
+impl<'a, K, V> DoubleEndedIterator for RangeValues<'a, K, V> {
+    fn next_back(&mut self) -> Option<&'a V> {
+        self.inner.next_back().map(|(_, v)| v)
+    }
+}
+
+impl<'a, K, V> FusedIterator for RangeValues<'a, K, V> {}
+
+impl<'a, K, V> Clone for RangeValues<'a, K, V> {
+    fn clone(&self) -> RangeValues<'a, K, V> {
+        RangeValues {
+            inner: self.inner.clone()
+        }
+    }
+}
+
+impl<'a, K, V> Iterator for Range<'a, K, V> {
+    type Item = (&'a K, &'a V);
+
+    fn next(&mut self) -> Option<(&'a K, &'a V)> {
+        if self.front == self.back {
+            None
+        } else {
+            unsafe { Some(self.next_unchecked()) }
+        }
+    }
+}
+
+impl<'a, K, V> Range<'a, K, V> {
+    unsafe fn next_unchecked(&mut self) -> (&'a K, &'a V) {
+        let handle = self.front;
+
+        let mut cur_handle = match handle.right_kv() {
+            Ok(kv) => {
+                let ret = kv.into_kv();
+                self.front = kv.right_edge();
+                return ret;
+            },
+            Err(last_edge) => {
+                let next_level = last_edge.into_node().ascend().ok();
+                unwrap_unchecked(next_level)
+            }
+        };
+
+        loop {
+            match cur_handle.right_kv() {
+                Ok(kv) => {
+                    let ret = kv.into_kv();
+                    self.front = first_leaf_edge(kv.right_edge().descend());
+                    return ret;
+                },
+                Err(last_edge) => {
+                    let next_level = last_edge.into_node().ascend().ok();
+                    cur_handle = unwrap_unchecked(next_level);
+                }
+            }
+        }
+    }
+}
+
+impl<'a, K, V> DoubleEndedIterator for Range<'a, K, V> {
+    fn next_back(&mut self) -> Option<(&'a K, &'a V)> {
+        if self.front == self.back {
+            None
+        } else {
+            unsafe { Some(self.next_back_unchecked()) }
+        }
+    }
+}
+
+impl<'a, K, V> FusedIterator for Range<'a, K, V> {}
+
+impl<'a, K, V> Range<'a, K, V> {
+    unsafe fn next_back_unchecked(&mut self) -> (&'a K, &'a V) {
+        let handle = self.back;
+
+        let mut cur_handle = match handle.left_kv() {
+            Ok(kv) => {
+                let ret = kv.into_kv();
+                self.back = kv.left_edge();
+                return ret;
+            },
+            Err(last_edge) => {
+                let next_level = last_edge.into_node().ascend().ok();
+                unwrap_unchecked(next_level)
+            }
+        };
+
+        loop {
+            match cur_handle.left_kv() {
+                Ok(kv) => {
+                    let ret = kv.into_kv();
+                    self.back = last_leaf_edge(kv.left_edge().descend());
+                    return ret;
+                },
+                Err(last_edge) => {
+                    let next_level = last_edge.into_node().ascend().ok();
+                    cur_handle = unwrap_unchecked(next_level);
+                }
+            }
+        }
+    }
+}
+
+impl<'a, K, V> Clone for Range<'a, K, V> {
+    fn clone(&self) -> Range<'a, K, V> {
+        Range {
+            front: self.front,
+            back: self.back
+        }
+    }
+}
+
+impl<'a, K, V> Range<'a, K, V> {
+    /// Splits the range into two adjacent ranges, the first covering the lower part of its
+    /// elements and the second the upper part.
+    ///
+    /// The halves differ in length by at most one. Finding the split point means counting the
+    /// elements in the range and then the subtrees skipped on the way from its front to the
+    /// middle, so this takes O(n / B) time. Each half can be split again, and as long as a range
+    /// has at least two elements, both halves are nonempty.
+    pub fn split_at_middle(self) -> (Range<'a, K, V>, Range<'a, K, V>) {
+        let middle = unsafe { middle_leaf_edge(self.front, self.back) };
+        (Range { front: self.front, back: middle }, Range { front: middle, back: self.back })
+    }
+
+    /// Converts the range into an iterator over just its keys.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use btree_rewrite::BTreeMap;
+    /// use btree_rewrite::Bound::{Included, Excluded};
+    ///
+    /// let map: BTreeMap<_, _> = (0..10).map(|i| (i, i * i)).collect();
+    /// let keys: Vec<_> = map.range(Included(&3), Excluded(&6)).keys().cloned().collect();
+    /// assert_eq!(keys, [3, 4, 5]);
+    /// ```
+    pub fn keys(self) -> RangeKeys<'a, K, V> {
+        RangeKeys { inner: self }
+    }
+
+    /// Converts the range into an iterator over just its values.
+    pub fn values(self) -> RangeValues<'a, K, V> {
+        RangeValues { inner: self }
+    }
+}
+
+impl<'a, K, V> Iterator for RangeMut<'a, K, V> {
+    type Item = (&'a K, &'a mut V);
+
+    fn next(&mut self) -> Option<(&'a K, &'a mut V)> {
+        if self.front == self.back {
+            None
+        } else {
+            unsafe { Some (self.next_unchecked()) }
+        }
+    }
+}
+
+impl<'a, K, V> RangeMut<'a, K, V> {
+    unsafe fn next_unchecked(&mut self) -> (&'a K, &'a mut V) {
+        let handle = ptr::read(&self.front);
+
+        let mut cur_handle = match handle.right_kv() {
+            Ok(kv) => {
+                let (k, v) = ptr::read(&kv).into_kv_valmut();
+                self.front = kv.right_edge();
+                return (k, v);
+            },
+            Err(last_edge) => {
+                let next_level = last_edge.into_node().ascend().ok();
+                unwrap_unchecked(next_level)
+            }
+        };
+
+        loop {
+            match cur_handle.right_kv() {
+                Ok(kv) => {
+                    let (k, v) = ptr::read(&kv).into_kv_valmut();
+                    self.front = first_leaf_edge(kv.right_edge().descend());
+                    return (k, v);
+                },
+                Err(last_edge) => {
+                    let next_level = last_edge.into_node().ascend().ok();
+                    cur_handle = unwrap_unchecked(next_level);
+                }
+            }
+        }
+    }
+}
+
+impl<'a, K, V> DoubleEndedIterator for RangeMut<'a, K, V> {
+    fn next_back(&mut self) -> Option<(&'a K, &'a mut V)> {
+        if self.front == self.back {
+            None
+        } else {
+            unsafe { Some(self.next_back_unchecked()) }
+        }
+    }
+}
+
+impl<'a, K, V> FusedIterator for RangeMut<'a, K, V> {}
+
+impl<'a, K, V> RangeMut<'a, K, V> {
+    unsafe fn next_back_unchecked(&mut self) -> (&'a K, &'a mut V) {
+        let handle = ptr::read(&self.back);
+
+        let mut cur_handle = match handle.left_kv() {
+            Ok(kv) => {
+                let (k, v) = ptr::read(&kv).into_kv_valmut();
+                self.back = kv.left_edge();
+                return (k, v);
+            },
+            Err(last_edge) => {
+                let next_level = last_edge.into_node().ascend().ok();
+                unwrap_unchecked(next_level)
+            }
+        };
+
+        loop {
+            match cur_handle.left_kv() {
+                Ok(kv) => {
+                    let (k, v) = ptr::read(&kv).into_kv_valmut();
+                    self.back = last_leaf_edge(kv.left_edge().descend());
+                    return (k, v);
+                },
+                Err(last_edge) => {
+                    let next_level = last_edge.into_node().ascend().ok();
+                    cur_handle = unwrap_unchecked(next_level);
+                }
+            }
+        }
+    }
+}
+
+impl<'a, K, V> RangeMut<'a, K, V> {
+    /// Splits the range into two adjacent ranges, the first covering the lower part of its
+    /// elements and the second the upper part. See `Range::split_at_middle` for details.
+    pub fn split_at_middle(self) -> (RangeMut<'a, K, V>, RangeMut<'a, K, V>) {
+        unsafe {
+            let middle = middle_leaf_edge(ptr::read(&self.front), ptr::read(&self.back));
+            let middle2 = ptr::read(&middle);
+            (RangeMut { front: self.front, back: middle },
+             RangeMut { front: middle2, back: self.back })
+        }
+    }
+}
+
+impl<'a, K, V: PartialEq, C: Compare<K>> Iterator for Diff<'a, K, V, C> {
+    type Item = DiffItem<'a, K, V>;
+
+    fn next(&mut self) -> Option<DiffItem<'a, K, V>> {
+        self.inner.next()
+    }
+}
+
+impl<K, V, C: Compare<K> + Default> FromIterator<(K, V)> for BTreeMap<K, V, C> {
+    fn from_iter<T: IntoIterator<Item=(K, V)>>(iter: T) -> BTreeMap<K, V, C> {
+        let mut map = BTreeMap::with_comparator(Default::default());
+        map.extend(iter);
+        map
+    }
+}
+
+impl<K, V, C: Compare<K>> Extend<(K, V)> for BTreeMap<K, V, C> {
+    #[inline]
+    fn extend<T: IntoIterator<Item=(K, V)>>(&mut self, iter: T) {
+        for (k, v) in iter {
+            self.insert(k, v);
+        }
+    }
+}
+
+impl<'a, K: Copy, V: Copy, C: Compare<K>> Extend<(&'a K, &'a V)> for BTreeMap<K, V, C> {
+    fn extend<I: IntoIterator<Item=(&'a K, &'a V)>>(&mut self, iter: I) {
+        self.extend(iter.into_iter().map(|(&key, &value)| (key, value)));
+    }
+}
+
+impl<K: Hash, V: Hash, C> Hash for BTreeMap<K, V, C> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        for elt in self {
+            elt.hash(state);
+        }
+    }
+}
+
+impl<K, V, C: Compare<K> + Default> Default for BTreeMap<K, V, C> {
+    fn default() -> BTreeMap<K, V, C> {
+        BTreeMap::with_comparator(Default::default())
+    }
+}
+
+impl<K: PartialEq, V: PartialEq, C> PartialEq for BTreeMap<K, V, C> {
+    fn eq(&self, other: &BTreeMap<K, V, C>) -> bool {
+        self.len() == other.len() &&
+            self.iter().zip(other).all(|(a, b)| a == b)
+    }
+}
+
+impl<K: Eq, V: Eq, C> Eq for BTreeMap<K, V, C> {}
+
+impl<K: PartialOrd, V: PartialOrd, C> PartialOrd for BTreeMap<K, V, C> {
+    #[inline]
+    fn partial_cmp(&self, other: &BTreeMap<K, V, C>) -> Option<Ordering> {
+        self.iter().partial_cmp(other.iter())
+    }
+}
+
+impl<K: Ord, V: Ord, C> Ord for BTreeMap<K, V, C> {
+    #[inline]
+    fn cmp(&self, other: &BTreeMap<K, V, C>) -> Ordering {
+        self.iter().cmp(other.iter())
+    }
+}
+
+impl<K: Debug, V: Debug, C> Debug for BTreeMap<K, V, C> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_map().entries(self.iter()).finish()
+    }
+}
+
+impl<'a, K, Q: ?Sized, V, C> Index<&'a Q> for BTreeMap<K, V, C>
+    where C: Compare<K> + Compare<Q, K>
+{
+    type Output = V;
+
+    #[inline]
+    fn index(&self, key: &Q) -> &V {
+        self.get(key).expect("no entry found for key")
+    }
+}
+
+#[cfg(feature = "encoding")]
+impl<K: Encode, V: Encode, C> BTreeMap<K, V, C> {
+    /// Appends an encoding of the map to `out`, which `decode` can later turn back into an
+    /// equal map. See the `encoding` module for the format.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use btree_rewrite::BTreeMap;
+    ///
+    /// let map: BTreeMap<u32, String> = (0..3).map(|i| (i, i.to_string())).collect();
+    ///
+    /// let mut bytes = Vec::new();
+    /// map.encode(&mut bytes);
+    /// assert_eq!(BTreeMap::decode(&bytes), Ok(map));
+    /// ```
+    pub fn encode(&self, out: &mut Vec<u8>) {
+        out.extend(encoding::MAGIC.iter().cloned());
+        out.push(encoding::VERSION);
+        self.length.encode(out);
+        for (key, value) in self.iter() {
+            key.encode(out);
+            value.encode(out);
+        }
+    }
+}
+
+#[cfg(feature = "encoding")]
+impl<K: Ord + Decode, V: Decode> BTreeMap<K, V> {
+    /// Decodes a map written by `encode`. The whole of `bytes` must be a single encoded map.
+    ///
+    /// The entries are loaded straight into densely packed nodes, in O(n) time, rather than
+    /// inserted one at a time. Every key must be strictly greater than the one before it, or
+    /// decoding fails with a `NotSorted` error at the offending key.
+    pub fn decode(bytes: &[u8]) -> Result<BTreeMap<K, V>, DecodeError> {
+        BTreeMap::decode_with_comparator(bytes, Natural)
+    }
+}
+
+#[cfg(feature = "encoding")]
+impl<K: Decode, V: Decode, C: Compare<K>> BTreeMap<K, V, C> {
+    /// Like `decode`, but for a map ordered by the given comparator, which must order the keys
+    /// the same way as the comparator of the map that was encoded.
+    pub fn decode_with_comparator(bytes: &[u8], comparator: C)
+            -> Result<BTreeMap<K, V, C>, DecodeError> {
+        let mut reader = Reader::new(bytes);
+
+        match reader.read_bytes(encoding::MAGIC.len()) {
+            Ok(magic) if magic == encoding::MAGIC => {},
+            _ => return Err(DecodeError::new(0, BadMagic))
+        }
+        let version_start = reader.position();
+        let version = try!(u8::decode(&mut reader));
+        if version != encoding::VERSION {
+            return Err(DecodeError::new(version_start, UnsupportedVersion(version)));
+        }
+
+        let len = try!(reader.read_len());
+        let mut map = BTreeMap::with_comparator(comparator);
+        let error = {
+            let mut entries = DecodeEntries {
+                reader: &mut reader,
+                remaining: len,
+                pending: None,
+                cmp: &map.cmp,
+                error: None
+            };
+            // Decodes the first entry, which is held back like all the others
+            entries.next();
+            bulk_push(&mut map.root, &mut map.length, &mut entries, node::CAPACITY);
+            entries.error
+        };
+        // On failure, whatever was loaded is dropped along with the map
+        if let Some(err) = error {
+            return Err(err);
+        }
+        if reader.remaining() != 0 {
+            return Err(DecodeError::new(reader.position(), TrailingBytes));
+        }
+        Ok(map)
+    }
+}
+
+/// Decodes the entries of an encoded map one at a time for `bulk_push` to load straight into a
+/// tree, checking that the keys are strictly ascending. Since an entry cannot be looked at again
+/// once it has been handed over, each one is held back until the key after it has been decoded
+/// and compared with it. Iteration stops at the first error, which is kept in `error`.
+#[cfg(feature = "encoding")]
+struct DecodeEntries<'r, 'b: 'r, K, V, C: 'r> {
+    reader: &'r mut Reader<'b>,
+    // The number of entries not yet decoded
+    remaining: usize,
+    pending: Option<(K, V)>,
+    cmp: &'r C,
+    error: Option<DecodeError>
+}
+
+#[cfg(feature = "encoding")]
+impl<'r, 'b, K: Decode, V: Decode, C: Compare<K>> DecodeEntries<'r, 'b, K, V, C> {
+    fn decode_entry(&mut self) -> Result<(K, V), DecodeError> {
+        let key_start = self.reader.position();
+        let key = try!(K::decode(self.reader));
+        if let Some((ref last, _)) = self.pending {
+            if self.cmp.compare(last, &key) != Ordering::Less {
+                return Err(DecodeError::new(key_start, NotSorted));
+            }
+        }
+        let value = try!(V::decode(self.reader));
+        Ok((key, value))
+    }
+}
+
+#[cfg(feature = "encoding")]
+impl<'r, 'b, K: Decode, V: Decode, C: Compare<K>> Iterator for DecodeEntries<'r, 'b, K, V, C> {
+    type Item = (K, V);
+
+    fn next(&mut self) -> Option<(K, V)> {
+        if self.error.is_some() {
+            return None;
+        }
+
+        let next = if self.remaining == 0 {
+            None
+        } else {
+            self.remaining -= 1;
+            match self.decode_entry() {
+                Ok(entry) => Some(entry),
+                Err(err) => {
+                    self.error = Some(err);
+                    return None;
+                }
+            }
+        };
+        mem::replace(&mut self.pending, next)
+    }
+}
+
+/// Takes ownership of a tree's nodes, returning an iterator that moves out its elements and frees
+/// the nodes as it goes.
+fn root_into_iter<K, V>(root: node::Root<K, V>, length: usize) -> IntoIter<K, V> {
+    let root2 = unsafe { ptr::read(&root) };
+
+    IntoIter {
+        front: first_leaf_edge(root.into_ref()),
+        back: last_leaf_edge(root2.into_ref()),
+        length: length,
+        front_spine: None,
+        back_spine: None
+    }
+}
+
+/// Finds the leaf edges just before the first element within the bounds and just after the last
+/// one. The root is passed in twice, once for each search, since only immutable borrowed node
+/// references can be copied.
+///
+/// If the bounds are inverted so that the edges would cross, iterating from one to the other would
+/// walk off the end of the tree, so both edges returned are the front one, giving an empty range.
+fn range_edges<Lifetime, K, V, Mutability, Min: ?Sized, Max: ?Sized, C>(
+        root1: NodeRef<Lifetime, K, V, Mutability, marker::LeafOrInternal>,
+        root2: NodeRef<Lifetime, K, V, Mutability, marker::LeafOrInternal>,
+        min: Bound<&Min>,
+        max: Bound<&Max>,
+        cmp: &C
+        ) -> (Handle<NodeRef<Lifetime, K, V, Mutability, marker::Leaf>, marker::Edge>,
+              Handle<NodeRef<Lifetime, K, V, Mutability, marker::Leaf>, marker::Edge>)
+        where C: Compare<Min, K> + Compare<Max, K> {
+    let front = match min {
+        Included(key) => match search::search_tree(root1, key, cmp) {
+            Found(kv_handle) => match kv_handle.left_edge().force() {
+                Leaf(bottom) => bottom,
+                Internal(internal) => last_leaf_edge(internal.descend())
+            },
+            GoDown(bottom) => bottom
+        },
+        Excluded(key) => match search::search_tree(root1, key, cmp) {
+            Found(kv_handle) => match kv_handle.right_edge().force() {
+                Leaf(bottom) => bottom,
+                Internal(internal) => first_leaf_edge(internal.descend())
+            },
+            GoDown(bottom) => bottom
+        },
+        Unbounded => first_leaf_edge(root1)
+    };
+
+    let back = match max {
+        Included(key) => match search::search_tree(root2, key, cmp) {
+            Found(kv_handle) => match kv_handle.right_edge().force() {
+                Leaf(bottom) => bottom,
+                Internal(internal) => first_leaf_edge(internal.descend())
+            },
+            GoDown(bottom) => bottom
+        },
+        Excluded(key) => match search::search_tree(root2, key, cmp) {
+            Found(kv_handle) => match kv_handle.left_edge().force() {
+                Leaf(bottom) => bottom,
+                Internal(internal) => last_leaf_edge(internal.descend())
+            },
+            GoDown(bottom) => bottom
+        },
+        Unbounded => last_leaf_edge(root2)
+    };
+
+    if is_after(front.reborrow(), back.reborrow()) {
+        // The bounds are inverted, so the range is empty. Walking from `front` would never reach
+        // `back`, so both ends are put at `front` instead.
+        let back = unsafe { ptr::read(&front) };
+        (front, back)
+    } else {
+        (front, back)
+    }
+}
+
+/// Returns true if the bounds of a range are inverted: if min comes after max, or if both are
+/// `Excluded` and equal.
+fn bounds_inverted<Min: ?Sized, Max: ?Sized, C>(min: Bound<&Min>, max: Bound<&Max>, cmp: &C)
+        -> bool
+        where C: Compare<Min, Max> {
+    match (min, max) {
+        (Excluded(min), Excluded(max)) => cmp.compare(min, max) != Ordering::Less,
+        (Included(min), Included(max)) |
+        (Included(min), Excluded(max)) |
+        (Excluded(min), Included(max)) => cmp.compare(min, max) == Ordering::Greater,
+        _ => false
+    }
+}
+
+/// Returns true if the leaf edge `front` comes after the leaf edge `back` of the same tree.
+fn is_after<'a, K: 'a, V: 'a>(
+        front: Handle<NodeRef<marker::Borrowed<'a>, K, V, marker::Immut, marker::Leaf>,
+                      marker::Edge>,
+        back: Handle<NodeRef<marker::Borrowed<'a>, K, V, marker::Immut, marker::Leaf>,
+                     marker::Edge>
+        ) -> bool {
+    let mut front_idx = front.idx();
+    let mut back_idx = back.idx();
+    let mut front_node = front.into_node().forget_type();
+    let mut back_node = back.into_node().forget_type();
+
+    // Both edges are in leaves, so they reach their lowest common ancestor at the same time
+    while front_node != back_node {
+        let front_parent = unsafe { unwrap_unchecked(front_node.ascend().ok()) };
+        let back_parent = unsafe { unwrap_unchecked(back_node.ascend().ok()) };
+        front_idx = front_parent.idx();
+        back_idx = back_parent.idx();
+        front_node = front_parent.into_node().forget_type();
+        back_node = back_parent.into_node().forget_type();
+    }
+
+    front_idx > back_idx
+}
+
+/// Creates a tree of the given height in which every node is empty except for the single edge
+/// linking each internal node to the one below it.
+fn create_chain<K, V>(height: usize) -> node::Root<K, V> {
+    let mut ret = node::Root::new_leaf();
+    for _ in 0..height {
+        ret.enlarge();
+    }
+    ret
+}
+
+/// Finds a leaf edge between `front` and `back`, which must be leaf edges of the same tree with
+/// `front` not coming after `back`, that splits the elements between them in half, with the
+/// smaller half first.
+unsafe fn middle_leaf_edge<Lifetime, K, V, Mutability>(
+        front: Handle<NodeRef<Lifetime, K, V, Mutability, marker::Leaf>, marker::Edge>,
+        back: Handle<NodeRef<Lifetime, K, V, Mutability, marker::Leaf>, marker::Edge>
+        ) -> Handle<NodeRef<Lifetime, K, V, Mutability, marker::Leaf>, marker::Edge> {
+    let len = count_between(front.reborrow(), back.reborrow());
+    skip_elements(front, len / 2)
+}
+
+/// Finds the leaf edge that lies `n` elements after `edge`, of which there must be at least `n`
+/// in the tree. This climbs from `edge` to the lowest ancestor whose subtree holds the element,
+/// then descends from there, counting the subtrees it skips over node by node.
+unsafe fn skip_elements<Lifetime, K, V, Mutability>(
+        edge: Handle<NodeRef<Lifetime, K, V, Mutability, marker::Leaf>, marker::Edge>,
+        mut n: usize
+        ) -> Handle<NodeRef<Lifetime, K, V, Mutability, marker::Leaf>, marker::Edge> {
+    let rest = edge.reborrow().into_node().len() - edge.idx();
+    if n <= rest {
+        let idx = edge.idx() + n;
+        return Handle::new(edge.into_node(), idx);
+    }
+    n -= rest;
+
+    let mut parent = unwrap_unchecked(edge.into_node().forget_type().ascend().ok());
+    loop {
+        let mut idx = parent.idx();
+        let mut node = parent.into_node();
+        while idx < node.len() {
+            // Skip the key-value pair after the subtree we came from, then the subtree after it
+            n -= 1;
+            let next = Handle::new(node, idx + 1);
+            let size = subtree_len(next.reborrow().descend());
+            if n <= size {
+                return leaf_edge_at(next.descend(), n);
+            }
+            n -= size;
+            idx += 1;
+            node = next.into_node();
+        }
+        parent = unwrap_unchecked(node.forget_type().ascend().ok());
+    }
+}
+
+/// Finds the leaf edge of `node`'s subtree that has `n` of the subtree's elements before it.
+unsafe fn leaf_edge_at<Lifetime, K, V, Mutability>(
+        mut node: NodeRef<Lifetime, K, V, Mutability, marker::LeafOrInternal>,
+        mut n: usize
+        ) -> Handle<NodeRef<Lifetime, K, V, Mutability, marker::Leaf>, marker::Edge> {
+    loop {
+        match node.force() {
+            Leaf(leaf) => return Handle::new(leaf, n),
+            Internal(internal) => {
+                let mut edge = internal.first_edge();
+                loop {
+                    let size = subtree_len(edge.reborrow().descend());
+                    if n <= size {
+                        break;
+                    }
+                    n -= size + 1;
+                    let idx = edge.idx() + 1;
+                    edge = Handle::new(edge.into_node(), idx);
+                }
+                node = edge.descend();
+            }
+        }
+    }
+}
+
+/// Counts the elements in a subtree, node by node.
+fn subtree_len<'a, K: 'a, V: 'a>(
+        node: NodeRef<marker::Borrowed<'a>, K, V, marker::Immut, marker::LeafOrInternal>
+        ) -> usize {
+    match node.force() {
+        Leaf(leaf) => leaf.len(),
+        Internal(internal) => {
+            let mut len = internal.len();
+            for i in 0..internal.len() + 1 {
+                len += subtree_len(unsafe { Handle::new(internal, i) }.descend());
+            }
+            len
+        }
+    }
+}
+
+/// Counts the elements between two leaf edges of the same tree, with `front` not coming after
+/// `back`. Subtrees that lie entirely between them are counted node by node.
+fn count_between<'a, K: 'a, V: 'a>(
+        front: Handle<NodeRef<marker::Borrowed<'a>, K, V, marker::Immut, marker::Leaf>,
+                      marker::Edge>,
+        back: Handle<NodeRef<marker::Borrowed<'a>, K, V, marker::Immut, marker::Leaf>,
+                     marker::Edge>
+        ) -> usize {
+    fn edges_len<'a, K: 'a, V: 'a>(
+            node: NodeRef<marker::Borrowed<'a>, K, V, marker::Immut, marker::LeafOrInternal>,
+            from: usize,
+            to: usize) -> usize {
+        match node.force() {
+            Leaf(_) => 0,
+            Internal(internal) => (from..to).fold(0, |len, i| {
+                len + subtree_len(unsafe { Handle::new(internal, i) }.descend())
+            })
+        }
+    }
+
+    let mut front_idx = front.idx();
+    let mut back_idx = back.idx();
+    let mut front_node = front.into_node().forget_type();
+    let mut back_node = back.into_node().forget_type();
+    let mut count = 0;
+
+    while front_node != back_node {
+        // Everything to the right of `front` and to the left of `back` in the current nodes
+        count += front_node.len() - front_idx + back_idx;
+        count += edges_len(front_node, front_idx + 1, front_node.len() + 1);
+        count += edges_len(back_node, 0, back_idx);
+
+        let front_parent = unsafe { unwrap_unchecked(front_node.ascend().ok()) };
+        let back_parent = unsafe { unwrap_unchecked(back_node.ascend().ok()) };
+        front_idx = front_parent.idx();
+        back_idx = back_parent.idx();
+        front_node = front_parent.into_node().forget_type();
+        back_node = back_parent.into_node().forget_type();
+    }
+
+    count + back_idx - front_idx + edges_len(front_node, front_idx + 1, back_idx)
+}
+
+impl<K, V, C> BTreeMap<K, V, C> {
+    /// Gets an iterator over the entries of the map.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::collections::BTreeMap;
+    ///
+    /// let mut map = BTreeMap::new();
+    /// map.insert(1, "a");
+    /// map.insert(2, "b");
+    /// map.insert(3, "c");
+    ///
+    /// for (key, value) in map.iter() {
+    ///     println!("{}: {}", key, value);
+    /// }
+    ///
+    /// let (first_key, first_value) = map.iter().next().unwrap();
+    /// assert_eq!((*first_key, *first_value), (1, "a"));
+    /// ```
+    //#[stable(feature = "rust1", since = "1.0.0")]
+    pub fn iter(&self) -> Iter<K, V> {
+        Iter {
+            range: Range {
+                front: first_leaf_edge(self.root.as_ref()),
+                back: last_leaf_edge(self.root.as_ref())
+            },
+            length: self.length
+        }
+    }
+
+    /// Gets a mutable iterator over the entries of the map.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::collections::BTreeMap;
+    ///
+    /// let mut map = BTreeMap::new();
+    /// map.insert("a", 1);
+    /// map.insert("b", 2);
+    /// map.insert("c", 3);
+    ///
+    /// // add 10 to the value if the key isn't "a"
+    /// for (key, value) in map.iter_mut() {
+    ///     if key != &"a" {
+    ///         *value += 10;
+    ///     }
+    /// }
+    /// ```
+    //#[stable(feature = "rust1", since = "1.0.0")]
+    pub fn iter_mut(&mut self) -> IterMut<K, V> {
+        let root1 = self.root.as_mut();
+        let root2 = unsafe { ptr::read(&root1) };
+        IterMut {
+            range: RangeMut {
+                front: first_leaf_edge(root1),
+                back: last_leaf_edge(root2),
+            },
+            length: self.length
+        }
+    }
+
+    /// Gets an iterator over the keys of the map.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::collections::BTreeMap;
+    ///
+    /// let mut a = BTreeMap::new();
+    /// a.insert(1, "a");
+    /// a.insert(2, "b");
+    ///
+    /// let keys: Vec<_> = a.keys().cloned().collect();
+    /// assert_eq!(keys, [1, 2]);
+    /// ```
+    //#[stable(feature = "rust1", since = "1.0.0")]
+    pub fn keys<'a>(&'a self) -> Keys<'a, K, V> {
+        fn first<A, B>((a, _): (A, B)) -> A {
+            a
+        }
+        let first: fn((&'a K, &'a V)) -> &'a K = first; // coerce to fn pointer
+
+        Keys { inner: self.iter().map(first) }
+    }
+
+    /// Gets an iterator over the values of the map.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::collections::BTreeMap;
+    ///
+    /// let mut a = BTreeMap::new();
+    /// a.insert(1, "a");
+    /// a.insert(2, "b");
+    ///
+    /// let values: Vec<&str> = a.values().cloned().collect();
+    /// assert_eq!(values, ["a", "b"]);
+    /// ```
+    //#[stable(feature = "rust1", since = "1.0.0")]
+    pub fn values<'a>(&'a self) -> Values<'a, K, V> {
+        fn second<A, B>((_, b): (A, B)) -> B {
+            b
+        }
+        let second: fn((&'a K, &'a V)) -> &'a V = second; // coerce to fn pointer
+
+        Values { inner: self.iter().map(second) }
+    }
+
+    /// Gets a mutable iterator over the values of the map, in order by key.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use btree_rewrite::BTreeMap;
+    ///
+    /// let mut a = BTreeMap::new();
+    /// a.insert(1, String::from("hello"));
+    /// a.insert(2, String::from("goodbye"));
+    ///
+    /// for value in a.values_mut() {
+    ///     value.push_str("!");
+    /// }
+    ///
+    /// let values: Vec<_> = a.values().cloned().collect();
+    /// assert_eq!(values, ["hello!", "goodbye!"]);
+    /// ```
+    pub fn values_mut(&mut self) -> ValuesMut<K, V> {
+        ValuesMut { inner: self.iter_mut() }
+    }
+
+    /// Consumes the map, returning an iterator over its keys in order.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use btree_rewrite::BTreeMap;
+    ///
+    /// let mut a = BTreeMap::new();
+    /// a.insert(2, "b");
+    /// a.insert(1, "a");
+    ///
+    /// let keys: Vec<i32> = a.into_keys().collect();
+    /// assert_eq!(keys, [1, 2]);
+    /// ```
+    pub fn into_keys(self) -> IntoKeys<K, V> {
+        IntoKeys { inner: self.into_iter() }
+    }
+
+    /// Consumes the map, returning an iterator over its values in order by key.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use btree_rewrite::BTreeMap;
+    ///
+    /// let mut a = BTreeMap::new();
+    /// a.insert(1, "hello");
+    /// a.insert(2, "goodbye");
+    ///
+    /// let values: Vec<&str> = a.into_values().collect();
+    /// assert_eq!(values, ["hello", "goodbye"]);
+    /// ```
+    pub fn into_values(self) -> IntoValues<K, V> {
+        IntoValues { inner: self.into_iter() }
+    }
+
+    /// Returns the number of elements in the map.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::collections::BTreeMap;
+    ///
+    /// let mut a = BTreeMap::new();
+    /// assert_eq!(a.len(), 0);
+    /// a.insert(1, "a");
+    /// assert_eq!(a.len(), 1);
+    /// ```
+    //#[stable(feature = "rust1", since = "1.0.0")]
+    pub fn len(&self) -> usize {
+        self.length
+    }
+
+    /// Returns true if the map contains no elements.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::collections::BTreeMap;
+    ///
+    /// let mut a = BTreeMap::new();
+    /// assert!(a.is_empty());
+    /// a.insert(1, "a");
+    /// assert!(!a.is_empty());
+    /// ```
+    //#[stable(feature = "rust1", since = "1.0.0")]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Moves every element out of the map into an owning iterator, leaving the map empty but
+    /// keeping its comparator.
+    fn take_all(&mut self) -> IntoIter<K, V> {
+        let root = mem::replace(&mut self.root, node::Root::new_leaf());
+        let length = mem::replace(&mut self.length, 0);
+        root_into_iter(root, length)
+    }
+
+    /// Appends the elements of an iterator to the right edge of the tree. The iterator must yield
+    /// keys in strictly ascending order, all of which are greater than any key already in the map.
+    ///
+    /// Every node that is finished along the way is filled to capacity, so afterwards only the
+    /// nodes along the right edge can be underfull. Those are topped up from their left siblings
+    /// once the iterator is exhausted.
+    fn bulk_push<I: Iterator<Item=(K, V)>>(&mut self, iter: I) {
+        bulk_push(&mut self.root, &mut self.length, iter, node::CAPACITY)
+    }
+}
+
+/// Does the work of `BTreeMap::bulk_push` on a map's root and length, which lets the iterator
+/// borrow the map's other fields. Nodes are filled to `fill` elements rather than to capacity,
+/// which must be at least `MIN_LEN`.
+fn bulk_push<K, V, I: Iterator<Item=(K, V)>>(root: &mut node::Root<K, V>,
+                                             length: &mut usize,
+                                             iter: I,
+                                             fill: usize) {
+    debug_assert!(fill >= node::MIN_LEN && fill <= node::CAPACITY);
+
+    {
+        let mut cur_node = last_leaf_edge(root.as_mut()).into_node();
+
+        for (key, value) in iter {
+            if cur_node.len() < fill {
+                cur_node.push(key, value);
+            } else {
+                // The leaf is full, so find the lowest ancestor with room to spare, growing
+                // the tree if there is none, and hang a fresh chain of nodes off of it.
+                let mut open_node;
+                let mut test_node = cur_node.forget_type();
+                loop {
+                    match test_node.ascend() {
+                        Ok(parent) => {
+                            let parent = parent.into_node();
+                            if parent.len() < fill {
+                                open_node = parent;
+                                break;
+                            } else {
+                                test_node = parent.forget_type();
+                            }
+                        },
+                        Err(node) => {
+                            open_node = node.into_root_mut().enlarge();
+                            break;
+                        }
+                    }
+                }
+
+                let chain_height = open_node.height() - 1;
+                open_node.push(key, value, create_chain(chain_height));
+                cur_node = last_leaf_edge(open_node.forget_type()).into_node();
+            }
+
+            *length += 1;
+        }
+    }
+
+    // Merging two nodes on the way down takes an element from a node that has already been
+    // topped up, which may leave it underfull again, or empty the root. Each merge shrinks the
+    // tree, so starting again from the top whenever that happens soon finishes.
+    loop {
+        let restart = fix_right_edge(root.as_mut());
+        if root.as_ref().len() == 0 && root.as_ref().height() > 0 {
+            root.shrink();
+        }
+        if !restart {
+            break;
+        }
+    }
+}
+
+/// Tops up the underfull nodes along the right edge of the tree below `cur_node`, working down
+/// from it. Returns true if it stopped early because a merge left a node above underfull.
+fn fix_right_edge<'a, K, V>(mut cur_node: NodeRef<marker::Borrowed<'a>, K, V, marker::Mut,
+                                                  marker::LeafOrInternal>) -> bool {
+    // Every left sibling of a node on the right edge holds at least `MIN_LEN` elements, so the
+    // node is either merged with it or stolen from until it is no longer underfull. When the
+    // siblings are full, as they are unless a lower fill was asked for, these steals never merge.
+    while let Internal(internal) = cur_node.force() {
+        let mut last_child = internal.last_edge().descend();
+        while last_child.len() < last_child.capacity() / 2 {
+            last_child = match handle_underfull_node(last_child) {
+                Stole(parent) => parent.last_edge().descend(),
+                Merged(parent) => if parent.len() < parent.capacity() / 2 {
+                    return true;
+                } else {
+                    parent.last_edge().descend()
+                },
+                _ => unreachable!()
+            };
+        }
+        cur_node = last_child;
+    }
+    false
+}
+
+/// Counts the nodes in the subtree below `node`, including itself.
+fn count_nodes<'a, K: 'a, V: 'a>(node: NodeRef<marker::Borrowed<'a>, K, V, marker::Immut,
+                                                marker::LeafOrInternal>) -> usize {
+    match node.force() {
+        Leaf(_) => 1,
+        Internal(internal) => {
+            let mut count = 1;
+            let mut edge = internal.first_edge();
+            loop {
+                count += count_nodes(edge.descend());
+                match edge.right_kv() {
+                    Ok(kv) => edge = kv.right_edge(),
+                    Err(_) => return count
+                }
+            }
+        }
+    }
+}
+
+impl<'a, K, V> Entry<'a, K, V> {
+    /// Ensures a value is in the entry by inserting the default if empty, and returns
+    /// a mutable reference to the value in the entry.
+    pub fn or_insert(self, default: V) -> &'a mut V {
+        match self {
+            Occupied(entry) => entry.into_mut(),
+            Vacant(entry) => entry.insert(default),
+        }
+    }
+
+    /// Ensures a value is in the entry by inserting the result of the default function if empty,
+    /// and returns a mutable reference to the value in the entry.
+    pub fn or_insert_with<F: FnOnce() -> V>(self, default: F) -> &'a mut V {
+        match self {
+            Occupied(entry) => entry.into_mut(),
+            Vacant(entry) => entry.insert(default()),
+        }
+    }
+
+    /// Ensures a value is in the entry by inserting the result of the default function if empty,
+    /// passing it the entry's key, and returns a mutable reference to the value in the entry.
+    pub fn or_insert_with_key<F: FnOnce(&K) -> V>(self, default: F) -> &'a mut V {
+        match self {
+            Occupied(entry) => entry.into_mut(),
+            Vacant(entry) => {
+                let value = default(entry.key());
+                entry.insert(value)
+            }
+        }
+    }
+
+    /// Ensures a value is in the entry by inserting `V::default()` if empty, and returns
+    /// a mutable reference to the value in the entry.
+    pub fn or_default(self) -> &'a mut V where V: Default {
+        self.or_insert_with(Default::default)
+    }
+
+    /// Calls `f` on the value if the entry is occupied, and returns the entry so that it can be
+    /// chained with one of the `or_insert` methods.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use btree_rewrite::BTreeMap;
+    ///
+    /// let mut counts = BTreeMap::new();
+    /// for word in vec!["a", "b", "a"] {
+    ///     counts.entry(word).and_modify(|n| *n += 1).or_insert(1);
+    /// }
+    /// assert_eq!(counts["a"], 2);
+    /// assert_eq!(counts["b"], 1);
+    /// ```
+    pub fn and_modify<F: FnOnce(&mut V)>(self, f: F) -> Self {
+        match self {
+            Occupied(mut entry) => {
+                f(entry.get_mut());
+                Occupied(entry)
+            },
+            Vacant(entry) => Vacant(entry)
+        }
+    }
+
+    /// Gets a reference to the entry's key.
+    pub fn key(&self) -> &K {
+        match *self {
+            Occupied(ref entry) => entry.key(),
+            Vacant(ref entry) => entry.key()
+        }
+    }
+
+    /// Sets the value of the entry, whether or not it was occupied, and returns an OccupiedEntry
+    /// for it. If the entry was already occupied, its key is left untouched.
+    pub fn insert_entry(self, value: V) -> OccupiedEntry<'a, K, V> {
+        match self {
+            Occupied(mut entry) => {
+                entry.insert(value);
+                entry
+            },
+            Vacant(entry) => entry.insert_entry(value)
+        }
+    }
+}
+
+impl<'a, 'q, K, V, Q: ?Sized> EntryRef<'a, 'q, K, V, Q> {
+    /// Calls `f` on the value if the entry is occupied, and returns the entry so that it can be
+    /// chained with one of the `or_insert` methods.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use btree_rewrite::BTreeMap;
+    ///
+    /// let mut counts: BTreeMap<String, usize> = BTreeMap::new();
+    /// for word in vec!["a", "b", "a"] {
+    ///     counts.entry_ref(word).and_modify(|n| *n += 1).or_insert(1);
+    /// }
+    /// assert_eq!(counts["a"], 2);
+    /// assert_eq!(counts["b"], 1);
+    /// ```
+    pub fn and_modify<F: FnOnce(&mut V)>(self, f: F) -> Self {
+        match self {
+            EntryRef::Occupied(mut entry) => {
+                f(entry.get_mut());
+                EntryRef::Occupied(entry)
+            },
+            EntryRef::Vacant(entry) => EntryRef::Vacant(entry)
+        }
+    }
+
+    /// Gets a reference to the entry's key, borrowed as the type it was looked up by.
+    pub fn key(&self) -> &Q where K: Borrow<Q> {
+        match *self {
+            EntryRef::Occupied(ref entry) => entry.key().borrow(),
+            EntryRef::Vacant(ref entry) => entry.key()
+        }
+    }
+}
+
+impl<'a, 'q, K, V, Q: ?Sized> EntryRef<'a, 'q, K, V, Q> where &'q Q: Into<K> {
+    /// Ensures a value is in the entry by inserting the default if empty, and returns
+    /// a mutable reference to the value in the entry.
+    pub fn or_insert(self, default: V) -> &'a mut V {
+        match self {
+            EntryRef::Occupied(entry) => entry.into_mut(),
+            EntryRef::Vacant(entry) => entry.insert(default),
+        }
+    }
+
+    /// Ensures a value is in the entry by inserting the result of the default function if empty,
+    /// and returns a mutable reference to the value in the entry.
+    pub fn or_insert_with<F: FnOnce() -> V>(self, default: F) -> &'a mut V {
+        match self {
+            EntryRef::Occupied(entry) => entry.into_mut(),
+            EntryRef::Vacant(entry) => entry.insert(default()),
+        }
+    }
+
+    /// Ensures a value is in the entry by inserting `V::default()` if empty, and returns
+    /// a mutable reference to the value in the entry.
+    pub fn or_default(self) -> &'a mut V where V: Default {
+        self.or_insert_with(Default::default)
+    }
+}
+
+impl<'a, 'q, K, V, Q: ?Sized> VacantEntryRef<'a, 'q, K, V, Q> {
+    /// Gets a reference to the borrowed key that would be used when inserting a value.
+    pub fn key(&self) -> &'q Q {
+        self.key
+    }
+
+    /// Converts the borrowed key into an owned one and sets it as the key of the entry, with the
+    /// given value, returning a mutable reference to the value.
+    pub fn insert(self, value: V) -> &'a mut V where &'q Q: Into<K> {
+        VacantEntry {
+            key: self.key.into(),
+            handle: self.handle,
+            length: self.length
+        }.insert(value)
+    }
+}
+
+impl<'a, K, V> VacantEntry<'a, K, V> {
+    /// Gets a reference to the key that would be used when inserting a value.
+    pub fn key(&self) -> &K {
+        &self.key
+    }
+
+    /// Takes ownership of the key, leaving the map unchanged.
+    pub fn into_key(self) -> K {
+        self.key
+    }
+
+    /// Sets the value of the entry with the VacantEntry's key,
+    /// and returns a mutable reference to it.
+    pub fn insert(self, value: V) -> &'a mut V {
+        self.insert_entry(value).into_mut()
+    }
+
+    /// Sets the value of the entry with the VacantEntry's key, and returns an OccupiedEntry for
+    /// the newly inserted element.
+    pub fn insert_entry(self, value: V) -> OccupiedEntry<'a, K, V> {
+        *self.length += 1;
+        OccupiedEntry {
+            handle: insert_kv(self.handle, self.key, value),
+            length: self.length
+        }
+    }
+}
+
+impl<'a, K, V> OccupiedEntry<'a, K, V> {
+    /// Gets a reference to the key in the entry.
+    pub fn key(&self) -> &K {
+        self.handle.reborrow().into_kv().0
+    }
+
+    /// Gets a reference to the value in the entry.
+    pub fn get(&self) -> &V {
+        self.handle.reborrow().into_kv().1
+    }
+
+    /// Gets a mutable reference to the value in the entry.
+    pub fn get_mut(&mut self) -> &mut V {
+        self.handle.kv_mut().1
+    }
+
+    /// Converts the entry into a mutable reference to its value.
+    pub fn into_mut(self) -> &'a mut V {
+        self.handle.into_kv_mut().1
+    }
+
+    /// Sets the value of the entry with the OccupiedEntry's key,
+    /// and returns the entry's old value.
+    pub fn insert(&mut self, value: V) -> V {
+        mem::replace(self.get_mut(), value)
+    }
+
+    /// Replaces the key stored in the map with `key`, returning the old one. This is useful when
+    /// keys that compare equal can still be told apart, such as strings compared without regard
+    /// to case.
+    ///
+    /// The new key must compare equal to the old one; otherwise the map's ordering is broken and
+    /// later lookups may fail to find elements.
+    pub fn replace_key(&mut self, key: K) -> K {
+        mem::replace(self.handle.kv_mut().0, key)
+    }
+
+    /// Replaces both the key and the value stored in the map, returning the old pair. The same
+    /// requirement on the new key applies as for `replace_key`.
+    pub fn replace_entry(&mut self, key: K, value: V) -> (K, V) {
+        let (k, v) = self.handle.kv_mut();
+        (mem::replace(k, key), mem::replace(v, value))
+    }
+
+    /// Moves to the entry with the next larger key, without searching the tree again. If this is
+    /// the last entry in the map, it is handed back unchanged.
+    pub fn next(self) -> Result<OccupiedEntry<'a, K, V>, Self> {
+        let edge = match unsafe { ptr::read(&self.handle) }.right_edge().force() {
+            Leaf(leaf) => leaf,
+            Internal(internal) => first_leaf_edge(internal.descend())
+        };
+        match next_kv(edge) {
+            Some(handle) => Ok(OccupiedEntry {
+                handle: handle,
+                length: self.length
+            }),
+            None => Err(self)
+        }
+    }
+
+    /// Moves to the entry with the next smaller key, without searching the tree again. If this is
+    /// the first entry in the map, it is handed back unchanged.
+    pub fn prev(self) -> Result<OccupiedEntry<'a, K, V>, Self> {
+        let edge = match unsafe { ptr::read(&self.handle) }.left_edge().force() {
+            Leaf(leaf) => leaf,
+            Internal(internal) => last_leaf_edge(internal.descend())
+        };
+        match prev_kv(edge) {
+            Some(handle) => Ok(OccupiedEntry {
+                handle: handle,
+                length: self.length
+            }),
+            None => Err(self)
+        }
+    }
+
+    /// Takes the value of the entry out of the map, and returns it.
+    pub fn remove(self) -> V {
+        self.remove_entry().1
+    }
+
+    /// Takes the key-value pair of the entry out of the map, and returns it.
+    pub fn remove_entry(self) -> (K, V) {
+        *self.length -= 1;
+        let (old_key, old_val, _) = remove_kv(self.handle);
+        (old_key, old_val)
+    }
+}
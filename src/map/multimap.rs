@@ -0,0 +1,192 @@
+// Copyright 2015 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// A multimap that stores every key-value pair directly in the nodes of an ordinary `BTreeMap`,
+// allowing the same key to appear any number of times.
+//
+// Nothing about the tree's structure requires keys to be distinct: splits, merges and steals all
+// preserve the order of the elements, equal or not. Only searching needs care, since
+// `search_tree` stops at whichever equal key it happens to meet first. Instead, the multimap
+// descends with `search::lower_bound` and `search::upper_bound`, which always continue to a leaf
+// and so find the edges just before and just after the whole run of equal keys. New values are
+// inserted at the upper bound, so the values for each key are kept in insertion order.
+
+use core::cmp::Ordering;
+use core::fmt::Debug;
+use core::iter::FromIterator;
+use core::fmt;
+
+use super::super::compare::Comparable;
+use super::super::search;
+use super::super::tree::next_kv;
+use super::{BTreeMap, Iter, Range, VacantEntry, OccupiedEntry, count_between};
+
+/// An ordered multimap based on a B-Tree, which can map each key to any number of values.
+///
+/// Rather than keeping a `Vec` of values for each key, every key-value pair is stored as its own
+/// element of the tree, so a key with a single value costs no more than it would in a
+/// `BTreeMap`. The values for a key are kept in the order they were inserted.
+///
+/// # Examples
+///
+/// ```
+/// use btree_rewrite::BTreeMultiMap;
+///
+/// let mut map = BTreeMultiMap::new();
+/// map.insert("fruit", "apple");
+/// map.insert("vegetable", "carrot");
+/// map.insert("fruit", "banana");
+///
+/// assert_eq!(map.count("fruit"), 2);
+/// assert_eq!(map.get_all("fruit").map(|(_, v)| *v).collect::<Vec<_>>(), ["apple", "banana"]);
+///
+/// assert_eq!(map.remove_one("fruit"), Some("apple"));
+/// assert_eq!(map.remove_all("fruit"), ["banana"]);
+/// assert_eq!(map.len(), 1);
+/// ```
+pub struct BTreeMultiMap<K, V> {
+    // An ordinary map, except that it may contain equal keys
+    map: BTreeMap<K, V>
+}
+
+impl<K: Ord, V> BTreeMultiMap<K, V> {
+    /// Makes a new empty BTreeMultiMap.
+    pub fn new() -> BTreeMultiMap<K, V> {
+        BTreeMultiMap { map: BTreeMap::new() }
+    }
+
+    /// Clears the map, removing all values.
+    pub fn clear(&mut self) {
+        self.map.clear();
+    }
+
+    /// Returns a reference to the first value inserted for the key, if any.
+    pub fn get<Q: ?Sized>(&self, key: &Q) -> Option<&V> where Q: Comparable<K> {
+        self.get_all(key).next().map(|(_, val)| val)
+    }
+
+    /// Gets an iterator over every element with the given key, with the values in the order they
+    /// were inserted.
+    pub fn get_all<Q: ?Sized>(&self, key: &Q) -> Range<K, V> where Q: Comparable<K> {
+        Range {
+            front: search::lower_bound(self.map.root.as_ref(), key, &self.map.cmp),
+            back: search::upper_bound(self.map.root.as_ref(), key, &self.map.cmp)
+        }
+    }
+
+    /// Returns the number of values mapped to the key.
+    pub fn count<Q: ?Sized>(&self, key: &Q) -> usize where Q: Comparable<K> {
+        count_between(search::lower_bound(self.map.root.as_ref(), key, &self.map.cmp),
+                      search::upper_bound(self.map.root.as_ref(), key, &self.map.cmp))
+    }
+
+    /// Returns true if at least one value is mapped to the key.
+    pub fn contains_key<Q: ?Sized>(&self, key: &Q) -> bool where Q: Comparable<K> {
+        self.get(key).is_some()
+    }
+
+    /// Maps the key to a value, in addition to any values it is already mapped to. The new value
+    /// comes after all of the key's existing values.
+    pub fn insert(&mut self, key: K, value: V) {
+        let handle = search::upper_bound(self.map.root.as_mut(), &key, &self.map.cmp);
+        VacantEntry {
+            key: key,
+            handle: handle,
+            length: &mut self.map.length
+        }.insert(value);
+    }
+
+    /// Removes the first value inserted for the key, returning it if there was one.
+    pub fn remove_one<Q: ?Sized>(&mut self, key: &Q) -> Option<V> where Q: Comparable<K> {
+        let edge = search::lower_bound(self.map.root.as_mut(), key, &self.map.cmp);
+        match next_kv(edge) {
+            Some(handle) => {
+                if key.compare(handle.reborrow().into_kv().0) != Ordering::Equal {
+                    return None;
+                }
+                Some(OccupiedEntry {
+                    handle: handle,
+                    length: &mut self.map.length
+                }.remove())
+            },
+            None => None
+        }
+    }
+
+    /// Removes every value mapped to the key, returning them in the order they were inserted.
+    pub fn remove_all<Q: ?Sized>(&mut self, key: &Q) -> Vec<V> where Q: Comparable<K> {
+        let mut removed = Vec::new();
+        while let Some(val) = self.remove_one(key) {
+            removed.push(val);
+        }
+        removed
+    }
+}
+
+impl<K, V> BTreeMultiMap<K, V> {
+    /// Gets an iterator over every element of the map, sorted by key. Elements with equal keys
+    /// are yielded in the order they were inserted.
+    pub fn iter(&self) -> Iter<K, V> {
+        self.map.iter()
+    }
+
+    /// Returns the total number of values in the map, counting every value of every key.
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    /// Returns true if the map contains no elements.
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+}
+
+impl<K: Clone, V: Clone> Clone for BTreeMultiMap<K, V> {
+    fn clone(&self) -> BTreeMultiMap<K, V> {
+        BTreeMultiMap { map: self.map.clone() }
+    }
+}
+
+impl<K: Ord, V> FromIterator<(K, V)> for BTreeMultiMap<K, V> {
+    fn from_iter<T: IntoIterator<Item=(K, V)>>(iter: T) -> BTreeMultiMap<K, V> {
+        let mut map = BTreeMultiMap::new();
+        map.extend(iter);
+        map
+    }
+}
+
+impl<K: Ord, V> Extend<(K, V)> for BTreeMultiMap<K, V> {
+    #[inline]
+    fn extend<T: IntoIterator<Item=(K, V)>>(&mut self, iter: T) {
+        for (k, v) in iter {
+            self.insert(k, v);
+        }
+    }
+}
+
+impl<K: Ord, V> Default for BTreeMultiMap<K, V> {
+    fn default() -> BTreeMultiMap<K, V> {
+        BTreeMultiMap::new()
+    }
+}
+
+impl<K: PartialEq, V: PartialEq> PartialEq for BTreeMultiMap<K, V> {
+    fn eq(&self, other: &BTreeMultiMap<K, V>) -> bool {
+        self.map == other.map
+    }
+}
+
+impl<K: Eq, V: Eq> Eq for BTreeMultiMap<K, V> {}
+
+impl<K: Debug, V: Debug> Debug for BTreeMultiMap<K, V> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_map().entries(self.iter()).finish()
+    }
+}
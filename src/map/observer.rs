@@ -0,0 +1,433 @@
+// Copyright 2015 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// A `BTreeMap` that reports every change to its contents to an observer.
+//
+// `ObservedMap` only hands out shared access to the map inside it, so every change has to go
+// through one of its own methods, each of which tells the observer what it did. Values changed
+// in place are handed out behind a guard, like `AggregateMap`'s, which keeps a copy of the old
+// value and reports the replacement when it is dropped. Mutable iteration is not offered at all,
+// since there would be nowhere to report the changes from short of copying every value.
+
+use core::ops::{Deref, DerefMut};
+
+use super::super::compare::{Compare, Natural};
+use super::super::node::ForceResult::*;
+use super::super::search::{self, SearchResult};
+use super::super::tree::{first_leaf_edge, next_kv};
+use super::{BTreeMap, Entry, EntryRef, OccupiedEntry, VacantEntry};
+
+/// Something that is told about every change to the contents of an `ObservedMap`, such as a
+/// secondary index that has to be kept in step with it.
+///
+/// Each method is called after the change has been made, so a lookup in the map from within it
+/// would already see the change, although the map itself is not passed in.
+pub trait Observer<K, V> {
+    /// Called when a key that was not in the map is inserted along with `value`.
+    fn inserted(&mut self, key: &K, value: &V);
+
+    /// Called when the value of a key already in the map changes from `old` to `new`.
+    fn replaced(&mut self, key: &K, old: &V, new: &V);
+
+    /// Called when a key is removed from the map, along with the value it had.
+    fn removed(&mut self, key: &K, value: &V);
+}
+
+/// An observer that ignores every change.
+impl<K, V> Observer<K, V> for () {
+    fn inserted(&mut self, _: &K, _: &V) {}
+    fn replaced(&mut self, _: &K, _: &V, _: &V) {}
+    fn removed(&mut self, _: &K, _: &V) {}
+}
+
+/// A `BTreeMap` that calls an `Observer` whenever an element is inserted, has its value
+/// replaced, or is removed.
+///
+/// The map can be read through `Deref`, which gives access to all of `BTreeMap`'s methods that
+/// take `&self`, but can only be changed through the methods here, so that no change goes
+/// unreported. In particular there is no `iter_mut` or `values_mut`: a value can be changed in
+/// place through `get_mut` or an entry, which return a guard that reports the change once it is
+/// dropped, and anything more must be done with `insert`.
+///
+/// # Examples
+///
+/// ```
+/// use btree_rewrite::BTreeMap;
+/// use btree_rewrite::map::{ObservedMap, Observer};
+///
+/// // Keeps the people living in each city, by (city, name)
+/// struct ByCity(BTreeMap<(&'static str, &'static str), ()>);
+///
+/// impl Observer<&'static str, &'static str> for ByCity {
+///     fn inserted(&mut self, name: &&'static str, city: &&'static str) {
+///         self.0.insert((*city, *name), ());
+///     }
+///     fn replaced(&mut self, name: &&'static str, old: &&'static str, new: &&'static str) {
+///         self.0.remove(&(*old, *name));
+///         self.0.insert((*new, *name), ());
+///     }
+///     fn removed(&mut self, name: &&'static str, city: &&'static str) {
+///         self.0.remove(&(*city, *name));
+///     }
+/// }
+///
+/// let mut people = ObservedMap::new(ByCity(BTreeMap::new()));
+/// people.insert("alice", "paris");
+/// people.insert("bob", "oslo");
+/// people.insert("carol", "paris");
+/// people.insert("alice", "oslo");
+/// people.remove(&"bob");
+///
+/// assert_eq!(people.len(), 2);
+/// let index: Vec<_> = people.observer().0.keys().cloned().collect();
+/// assert_eq!(index, [("oslo", "alice"), ("paris", "carol")]);
+/// ```
+pub struct ObservedMap<K, V, O, C = Natural> {
+    map: BTreeMap<K, V, C>,
+    observer: O
+}
+
+impl<K: Ord, V, O: Observer<K, V>> ObservedMap<K, V, O> {
+    /// Makes a new empty map that reports its changes to `observer`.
+    pub fn new(observer: O) -> ObservedMap<K, V, O> {
+        ObservedMap::with_comparator(Natural, observer)
+    }
+}
+
+impl<K, V, O: Observer<K, V>, C: Compare<K>> ObservedMap<K, V, O, C> {
+    /// Makes a new empty map ordered by the given comparator, which reports its changes to
+    /// `observer`.
+    pub fn with_comparator(cmp: C, observer: O) -> ObservedMap<K, V, O, C> {
+        ObservedMap {
+            map: BTreeMap::with_comparator(cmp),
+            observer: observer
+        }
+    }
+
+    /// Starts observing an existing map. The observer is told about every element already in
+    /// it, in order, as though each had just been inserted.
+    pub fn from_map(map: BTreeMap<K, V, C>, mut observer: O) -> ObservedMap<K, V, O, C> {
+        for (key, value) in &map {
+            observer.inserted(key, value);
+        }
+        ObservedMap {
+            map: map,
+            observer: observer
+        }
+    }
+
+    /// Returns a reference to the observer.
+    pub fn observer(&self) -> &O {
+        &self.observer
+    }
+
+    /// Returns a mutable reference to the observer.
+    pub fn observer_mut(&mut self) -> &mut O {
+        &mut self.observer
+    }
+
+    /// Stops observing the map, returning it along with the observer.
+    pub fn into_inner(self) -> (BTreeMap<K, V, C>, O) {
+        (self.map, self.observer)
+    }
+
+    /// Inserts a key-value pair into the map, returning the old value if the key was already
+    /// present. The observer is told about either the insertion or the replacement.
+    pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+        match self.map.entry(key) {
+            Entry::Vacant(entry) => {
+                let entry = entry.insert_entry(value);
+                self.observer.inserted(entry.key(), entry.get());
+                None
+            },
+            Entry::Occupied(mut entry) => {
+                let old = entry.insert(value);
+                self.observer.replaced(entry.key(), &old, entry.get());
+                Some(old)
+            }
+        }
+    }
+
+    /// Removes a key from the map, returning its value if it was present, and tells the observer.
+    pub fn remove<Q: ?Sized>(&mut self, key: &Q) -> Option<V> where C: Compare<Q, K> {
+        match self.map.entry_ref(key) {
+            EntryRef::Occupied(entry) => {
+                let (key, value) = entry.remove_entry();
+                self.observer.removed(&key, &value);
+                Some(value)
+            },
+            EntryRef::Vacant(_) => None
+        }
+    }
+
+    /// Returns a guard giving mutable access to the value corresponding to the key. The value
+    /// is cloned first, and the observer is told of the change when the guard is dropped.
+    pub fn get_mut<Q: ?Sized>(&mut self, key: &Q) -> Option<ObservedValueMut<K, V, O>>
+            where C: Compare<Q, K>, V: Clone {
+        match self.map.entry_ref(key) {
+            EntryRef::Occupied(entry) => Some(ObservedValueMut {
+                old: Some(entry.get().clone()),
+                entry: entry,
+                observer: &mut self.observer
+            }),
+            EntryRef::Vacant(_) => None
+        }
+    }
+
+    /// Gets the given key's corresponding entry in the map for in-place manipulation.
+    pub fn entry(&mut self, key: K) -> ObservedEntry<K, V, O> {
+        match self.map.entry(key) {
+            Entry::Vacant(entry) => ObservedEntry::Vacant(ObservedVacantEntry {
+                entry: entry,
+                observer: &mut self.observer
+            }),
+            Entry::Occupied(entry) => ObservedEntry::Occupied(ObservedOccupiedEntry {
+                entry: entry,
+                observer: &mut self.observer
+            })
+        }
+    }
+
+    /// Removes every element, telling the observer about each one in order.
+    pub fn clear(&mut self) {
+        for (key, value) in self.map.take_all() {
+            self.observer.removed(&key, &value);
+        }
+    }
+
+    /// Keeps only the elements for which `f` returns true, telling the observer about each one
+    /// that is removed, in order.
+    ///
+    /// Elements are removed one at a time as they are visited, so if `f` or the observer panics,
+    /// the map still holds everything that has not been reported as removed. This takes O(n)
+    /// time plus O(log n) for each element removed.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use btree_rewrite::map::ObservedMap;
+    ///
+    /// let mut map = ObservedMap::new(());
+    /// map.extend((0..10).map(|i| (i, i * 10)));
+    /// map.retain(|&k, _| k % 3 == 0);
+    /// assert!(map.keys().eq(&[0, 3, 6, 9]));
+    /// ```
+    pub fn retain<F: FnMut(&K, &V) -> bool>(&mut self, mut f: F) {
+        let mut entry = match first_entry_after(&mut self.map, None) {
+            Some(entry) => entry,
+            None => return
+        };
+        loop {
+            if f(entry.key(), entry.get()) {
+                entry = match entry.next() {
+                    Ok(next) => next,
+                    Err(_) => return
+                };
+            } else {
+                // Removing may rebalance the tree, so the next entry is found by searching again
+                let (key, value) = entry.remove_entry();
+                self.observer.removed(&key, &value);
+                entry = match first_entry_after(&mut self.map, Some(&key)) {
+                    Some(entry) => entry,
+                    None => return
+                };
+            }
+        }
+    }
+}
+
+/// Returns the entry with the smallest key greater than `key`, or the first entry in the map if
+/// `key` is `None`.
+fn first_entry_after<'a, K, V, C: Compare<K>>(map: &'a mut BTreeMap<K, V, C>, key: Option<&K>)
+        -> Option<OccupiedEntry<'a, K, V>> {
+    let root = map.root.as_mut();
+    let edge = match key {
+        Some(key) => match search::search_tree(root, key, &map.cmp) {
+            SearchResult::Found(handle) => match handle.right_edge().force() {
+                Leaf(leaf) => leaf,
+                Internal(internal) => first_leaf_edge(internal.descend())
+            },
+            SearchResult::GoDown(edge) => edge
+        },
+        None => first_leaf_edge(root)
+    };
+    let length = &mut map.length;
+    next_kv(edge).map(move |handle| OccupiedEntry {
+        handle: handle,
+        length: length
+    })
+}
+
+impl<K, V, O, C> Deref for ObservedMap<K, V, O, C> {
+    type Target = BTreeMap<K, V, C>;
+
+    fn deref(&self) -> &BTreeMap<K, V, C> {
+        &self.map
+    }
+}
+
+impl<K, V, O: Observer<K, V>, C: Compare<K>> Extend<(K, V)> for ObservedMap<K, V, O, C> {
+    #[inline]
+    fn extend<T: IntoIterator<Item=(K, V)>>(&mut self, iter: T) {
+        for (k, v) in iter {
+            self.insert(k, v);
+        }
+    }
+}
+
+/// A guard giving mutable access to a value in an ObservedMap. When it is dropped, the observer
+/// is told that the value has been replaced, or inserted if the entry was vacant.
+pub struct ObservedValueMut<'a, K: 'a, V: 'a, O: 'a + Observer<K, V>> {
+    entry: OccupiedEntry<'a, K, V>,
+    observer: &'a mut O,
+    // The value before the guard was handed out, or `None` if it has only just been inserted
+    old: Option<V>
+}
+
+impl<'a, K, V, O: Observer<K, V>> Deref for ObservedValueMut<'a, K, V, O> {
+    type Target = V;
+
+    fn deref(&self) -> &V {
+        self.entry.get()
+    }
+}
+
+impl<'a, K, V, O: Observer<K, V>> DerefMut for ObservedValueMut<'a, K, V, O> {
+    fn deref_mut(&mut self) -> &mut V {
+        self.entry.get_mut()
+    }
+}
+
+impl<'a, K, V, O: Observer<K, V>> Drop for ObservedValueMut<'a, K, V, O> {
+    fn drop(&mut self) {
+        match self.old.take() {
+            Some(old) => self.observer.replaced(self.entry.key(), &old, self.entry.get()),
+            None => self.observer.inserted(self.entry.key(), self.entry.get())
+        }
+    }
+}
+
+/// A view into a single entry of an ObservedMap.
+pub enum ObservedEntry<'a, K: 'a, V: 'a, O: 'a + Observer<K, V>> {
+    /// A vacant entry.
+    Vacant(ObservedVacantEntry<'a, K, V, O>),
+    /// An occupied entry.
+    Occupied(ObservedOccupiedEntry<'a, K, V, O>)
+}
+
+/// A vacant entry of an ObservedMap.
+pub struct ObservedVacantEntry<'a, K: 'a, V: 'a, O: 'a + Observer<K, V>> {
+    entry: VacantEntry<'a, K, V>,
+    observer: &'a mut O
+}
+
+/// An occupied entry of an ObservedMap.
+pub struct ObservedOccupiedEntry<'a, K: 'a, V: 'a, O: 'a + Observer<K, V>> {
+    entry: OccupiedEntry<'a, K, V>,
+    observer: &'a mut O
+}
+
+impl<'a, K, V: Clone, O: Observer<K, V>> ObservedEntry<'a, K, V, O> {
+    /// Ensures a value is in the entry by inserting the default if empty, and returns a guard
+    /// giving mutable access to the value in the entry. The observer is told of the insertion or
+    /// change when the guard is dropped.
+    pub fn or_insert(self, default: V) -> ObservedValueMut<'a, K, V, O> {
+        match self {
+            ObservedEntry::Vacant(entry) => entry.insert_mut(default),
+            ObservedEntry::Occupied(entry) => entry.into_mut()
+        }
+    }
+
+    /// Ensures a value is in the entry by inserting the result of the default function if empty,
+    /// and returns a guard giving mutable access to the value in the entry. The observer is told
+    /// of the insertion or change when the guard is dropped.
+    pub fn or_insert_with<F: FnOnce() -> V>(self, default: F) -> ObservedValueMut<'a, K, V, O> {
+        match self {
+            ObservedEntry::Vacant(entry) => entry.insert_mut(default()),
+            ObservedEntry::Occupied(entry) => entry.into_mut()
+        }
+    }
+}
+
+impl<'a, K, V, O: Observer<K, V>> ObservedEntry<'a, K, V, O> {
+    /// Returns a reference to this entry's key.
+    pub fn key(&self) -> &K {
+        match *self {
+            ObservedEntry::Vacant(ref entry) => entry.key(),
+            ObservedEntry::Occupied(ref entry) => entry.key()
+        }
+    }
+}
+
+impl<'a, K, V, O: Observer<K, V>> ObservedVacantEntry<'a, K, V, O> {
+    /// Gets a reference to the key that would be used when inserting a value through the
+    /// entry.
+    pub fn key(&self) -> &K {
+        self.entry.key()
+    }
+
+    /// Sets the value of the entry, tells the observer, and returns a reference to the value.
+    pub fn insert(self, value: V) -> &'a V {
+        let entry = self.entry.insert_entry(value);
+        self.observer.inserted(entry.key(), entry.get());
+        entry.into_mut()
+    }
+
+    /// Sets the value of the entry, and returns a guard giving mutable access to it. The
+    /// observer is told of the insertion when the guard is dropped.
+    pub fn insert_mut(self, value: V) -> ObservedValueMut<'a, K, V, O> {
+        ObservedValueMut {
+            entry: self.entry.insert_entry(value),
+            observer: self.observer,
+            old: None
+        }
+    }
+}
+
+impl<'a, K, V, O: Observer<K, V>> ObservedOccupiedEntry<'a, K, V, O> {
+    /// Gets a reference to the key in the entry.
+    pub fn key(&self) -> &K {
+        self.entry.key()
+    }
+
+    /// Gets a reference to the value in the entry.
+    pub fn get(&self) -> &V {
+        self.entry.get()
+    }
+
+    /// Converts the entry into a guard giving mutable access to its value. The value is cloned
+    /// first, and the observer is told of the change when the guard is dropped.
+    pub fn into_mut(self) -> ObservedValueMut<'a, K, V, O> where V: Clone {
+        ObservedValueMut {
+            old: Some(self.entry.get().clone()),
+            entry: self.entry,
+            observer: self.observer
+        }
+    }
+
+    /// Sets the value of the entry, tells the observer, and returns the entry's old value.
+    pub fn insert(&mut self, value: V) -> V {
+        let old = self.entry.insert(value);
+        self.observer.replaced(self.entry.key(), &old, self.entry.get());
+        old
+    }
+
+    /// Takes the value of the entry out of the map, tells the observer, and returns it.
+    pub fn remove(self) -> V {
+        self.remove_entry().1
+    }
+
+    /// Takes the key-value pair of the entry out of the map, tells the observer, and returns it.
+    pub fn remove_entry(self) -> (K, V) {
+        let (key, value) = self.entry.remove_entry();
+        self.observer.removed(&key, &value);
+        (key, value)
+    }
+}
@@ -0,0 +1,137 @@
+// Copyright 2015 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// Serde support, enabled by the `serde` feature. A map serializes as a serde map, just like std's
+// `BTreeMap`, so the two are interchangeable in serialized data.
+//
+// Serialized maps almost always come from something that iterates in order, so deserializing
+// collects entries into a vector for as long as they keep arriving in strictly ascending order,
+// which costs one comparison per entry, and then bulk loads them into densely packed nodes. The
+// first entry that arrives out of order ends this fast path: everything so far is bulk loaded and
+// the rest is inserted one entry at a time.
+
+use core::cmp::{self, Ordering};
+use core::fmt;
+use core::marker::PhantomData;
+
+use serde::de::{self, Deserialize, Deserializer, MapAccess, Visitor};
+use serde::ser::{Serialize, SerializeMap, Serializer};
+
+use super::super::compare::Compare;
+use super::BTreeMap;
+
+impl<K: Serialize, V: Serialize, C> Serialize for BTreeMap<K, V, C> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = try!(serializer.serialize_map(Some(self.len())));
+        for (key, value) in self.iter() {
+            try!(map.serialize_entry(key, value));
+        }
+        map.end()
+    }
+}
+
+/// When a key appears more than once, the last value given for it wins, just as if the entries had
+/// been inserted in order. Use `deserialize_unique_keys` to reject such input instead.
+impl<'de, K, V, C> Deserialize<'de> for BTreeMap<K, V, C>
+        where K: Deserialize<'de>, V: Deserialize<'de>, C: Compare<K> + Default {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<BTreeMap<K, V, C>, D::Error> {
+        deserializer.deserialize_map(MapVisitor {
+            reject_duplicates: false,
+            _marker: PhantomData
+        })
+    }
+}
+
+/// Deserializes a map like its `Deserialize` implementation does, but fails if any key appears
+/// more than once rather than keeping the last value given for it. This is meant for use with
+/// serde's `deserialize_with` attribute:
+///
+/// ```ignore
+/// #[derive(Deserialize)]
+/// struct Config {
+///     #[serde(deserialize_with = "btree_rewrite::map::deserialize_unique_keys")]
+///     servers: BTreeMap<String, Server>
+/// }
+/// ```
+pub fn deserialize_unique_keys<'de, D, K, V, C>(deserializer: D)
+        -> Result<BTreeMap<K, V, C>, D::Error>
+        where D: Deserializer<'de>, K: Deserialize<'de>, V: Deserialize<'de>,
+              C: Compare<K> + Default {
+    deserializer.deserialize_map(MapVisitor {
+        reject_duplicates: true,
+        _marker: PhantomData
+    })
+}
+
+struct MapVisitor<K, V, C> {
+    reject_duplicates: bool,
+    _marker: PhantomData<BTreeMap<K, V, C>>
+}
+
+impl<K, V, C> MapVisitor<K, V, C> {
+    fn duplicate<E: de::Error>(&self) -> E {
+        E::custom("duplicate key in map")
+    }
+}
+
+impl<'de, K, V, C> Visitor<'de> for MapVisitor<K, V, C>
+        where K: Deserialize<'de>, V: Deserialize<'de>, C: Compare<K> + Default {
+    type Value = BTreeMap<K, V, C>;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a map")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut access: A) -> Result<BTreeMap<K, V, C>, A::Error> {
+        let mut map = BTreeMap::with_comparator(C::default());
+
+        // Don't trust the size hint too far, as it may come straight from the input
+        let capacity = cmp::min(access.size_hint().unwrap_or(0), 4096);
+        let mut sorted: Vec<(K, V)> = Vec::with_capacity(capacity);
+
+        loop {
+            let (key, value) = match try!(access.next_entry()) {
+                Some(entry) => entry,
+                None => {
+                    map.bulk_push(sorted.into_iter());
+                    return Ok(map);
+                }
+            };
+
+            let order = match sorted.last() {
+                Some(&(ref last, _)) => map.cmp.compare(last, &key),
+                None => Ordering::Less
+            };
+            match order {
+                Ordering::Less => sorted.push((key, value)),
+                Ordering::Equal => if self.reject_duplicates {
+                    return Err(self.duplicate());
+                } else {
+                    let last = sorted.len() - 1;
+                    sorted[last].1 = value;
+                },
+                Ordering::Greater => {
+                    map.bulk_push(sorted.into_iter());
+                    if map.insert(key, value).is_some() && self.reject_duplicates {
+                        return Err(self.duplicate());
+                    }
+                    break;
+                }
+            }
+        }
+
+        while let Some((key, value)) = try!(access.next_entry()) {
+            if map.insert(key, value).is_some() && self.reject_duplicates {
+                return Err(self.duplicate());
+            }
+        }
+        Ok(map)
+    }
+}
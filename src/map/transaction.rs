@@ -0,0 +1,325 @@
+// Copyright 2015 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// All-or-nothing batches of changes to a `BTreeMap`.
+//
+// A transaction applies every change to the map straight away, so reads within it see its own
+// writes for free, and records in an undo log how to reverse each one: the key of every element
+// it inserted, the old value of every element whose value it replaced, and every element it
+// removed. Rolling back works through the log from the end, so that a key changed several times
+// ends up with the value it had before the first change.
+//
+// Rolling back happens when the `Transaction` is dropped with anything still in its log, which
+// committing empties. That covers both the closure returning an error and the closure panicking,
+// as the transaction is dropped while unwinding either way.
+//
+// Each undo step finds its key again by searching the map, because the positions of elements
+// shift as the transaction inserts and removes others. The comparator therefore runs during
+// rollback, and if it panics while the thread is already unwinding, the process aborts.
+
+use super::super::compare::{Compare, Natural};
+use super::{BTreeMap, Entry, EntryRef, OccupiedEntry, VacantEntry};
+
+enum Undo<K, V> {
+    Inserted(K),
+    Replaced(K, V),
+    Removed(K, V)
+}
+
+impl<K, V> Undo<K, V> {
+    fn value(&self) -> &V {
+        match *self {
+            Undo::Replaced(_, ref val) | Undo::Removed(_, ref val) => val,
+            Undo::Inserted(_) => unreachable!()
+        }
+    }
+}
+
+/// A set of changes being made to a `BTreeMap`, which will either all be kept or all be undone.
+/// See `BTreeMap::transaction`.
+///
+/// Changes are made to the map immediately and are visible to the transaction's own reads. Since
+/// the values a transaction replaces or removes must be kept until it is known whether they are
+/// needed to roll back, `insert` and `remove` only lend them out rather than returning them.
+pub struct Transaction<'a, K: 'a, V: 'a, C: 'a + Compare<K> = Natural> {
+    map: &'a mut BTreeMap<K, V, C>,
+    undo: Vec<Undo<K, V>>
+}
+
+impl<K: Clone, V, C: Compare<K>> BTreeMap<K, V, C> {
+    /// Makes a batch of changes to the map that either all happen or none do.
+    ///
+    /// `f` is given a `Transaction` through which it can read and modify the map, seeing its own
+    /// changes as it goes. If it returns `Ok`, the changes are kept. If it returns `Err` or
+    /// panics, every change it made is undone, leaving the map with exactly the elements it had
+    /// before, and the error is returned or the panic carries on.
+    ///
+    /// Keys are cloned as they are inserted or replaced, so that they can be found again when
+    /// rolling back.
+    ///
+    /// Rolling back searches the map for each changed key, so it relies on the comparator (or
+    /// the keys' `Ord` implementation) not panicking. If `f` panics from inside the comparator
+    /// and the comparator panics again during the rollback, the process aborts, as it does for
+    /// any panic while unwinding. If the comparator panics in the middle of a rollback that
+    /// began because `f` returned `Err`, the panic propagates, and the changes not yet undone
+    /// are left in the map.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use btree_rewrite::BTreeMap;
+    ///
+    /// let mut accounts = BTreeMap::new();
+    /// accounts.insert("alice", 100);
+    /// accounts.insert("bob", 50);
+    ///
+    /// let transfer = |accounts: &mut BTreeMap<&'static str, i32>, from, to, amount| {
+    ///     accounts.transaction(|tx| {
+    ///         *tx.entry(to).or_insert(0) += amount;
+    ///         let balance = *tx.get(&from).unwrap() - amount;
+    ///         if balance < 0 {
+    ///             return Err("insufficient funds");
+    ///         }
+    ///         tx.insert(from, balance);
+    ///         Ok(())
+    ///     })
+    /// };
+    ///
+    /// assert_eq!(transfer(&mut accounts, "alice", "carol", 30), Ok(()));
+    /// assert_eq!(transfer(&mut accounts, "bob", "carol", 80), Err("insufficient funds"));
+    ///
+    /// assert_eq!(accounts[&"alice"], 70);
+    /// assert_eq!(accounts[&"bob"], 50);
+    /// assert_eq!(accounts[&"carol"], 30);
+    /// ```
+    pub fn transaction<F, R, E>(&mut self, f: F) -> Result<R, E>
+            where F: FnOnce(&mut Transaction<K, V, C>) -> Result<R, E> {
+        let mut tx = Transaction {
+            map: self,
+            undo: Vec::new()
+        };
+        let result = f(&mut tx);
+        if result.is_ok() {
+            tx.undo.clear();
+        }
+        result
+    }
+}
+
+impl<'a, K: Clone, V, C: Compare<K>> Transaction<'a, K, V, C> {
+    /// Returns a reference to the value corresponding to the key, including any changes made
+    /// by this transaction.
+    pub fn get<Q: ?Sized>(&self, key: &Q) -> Option<&V> where C: Compare<Q, K> {
+        self.map.get(key)
+    }
+
+    /// Returns true if the map contains a value for the specified key.
+    pub fn contains_key<Q: ?Sized>(&self, key: &Q) -> bool where C: Compare<Q, K> {
+        self.map.contains_key(key)
+    }
+
+    /// Returns the number of elements in the map, including any changes made by this
+    /// transaction.
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    /// Returns true if the map contains no elements.
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    /// Returns a mutable reference to the value corresponding to the key. The value is cloned
+    /// first, in case it needs to be restored.
+    pub fn get_mut<Q: ?Sized>(&mut self, key: &Q) -> Option<&mut V>
+            where C: Compare<Q, K>, V: Clone {
+        match self.map.entry_ref(key) {
+            EntryRef::Occupied(entry) => {
+                self.undo.push(Undo::Replaced(entry.key().clone(), entry.get().clone()));
+                Some(entry.into_mut())
+            },
+            EntryRef::Vacant(_) => None
+        }
+    }
+
+    /// Inserts a key-value pair into the map.
+    ///
+    /// If the map did not have this key present, `None` is returned. If it did, the key is not
+    /// updated, the value is updated, and a reference to the old value is returned. The old value
+    /// itself is kept until the transaction ends.
+    pub fn insert(&mut self, key: K, value: V) -> Option<&V> {
+        match self.map.entry(key) {
+            Entry::Vacant(entry) => {
+                self.undo.push(Undo::Inserted(entry.key().clone()));
+                entry.insert(value);
+                None
+            },
+            Entry::Occupied(mut entry) => {
+                let old = entry.insert(value);
+                self.undo.push(Undo::Replaced(entry.key().clone(), old));
+                Some(self.undo.last().unwrap().value())
+            }
+        }
+    }
+
+    /// Removes a key from the map, returning a reference to the value at the key if the key was
+    /// previously in the map. The element itself is kept until the transaction ends.
+    pub fn remove<Q: ?Sized>(&mut self, key: &Q) -> Option<&V> where C: Compare<Q, K> {
+        match self.map.entry_ref(key) {
+            EntryRef::Occupied(entry) => {
+                let (key, val) = entry.remove_entry();
+                self.undo.push(Undo::Removed(key, val));
+                Some(self.undo.last().unwrap().value())
+            },
+            EntryRef::Vacant(_) => None
+        }
+    }
+
+    /// Gets the given key's corresponding entry in the map for in-place manipulation.
+    pub fn entry(&mut self, key: K) -> TxEntry<K, V> {
+        let undo = &mut self.undo;
+        match self.map.entry(key) {
+            Entry::Vacant(entry) => TxEntry::Vacant(TxVacantEntry {
+                entry: entry,
+                undo: undo
+            }),
+            Entry::Occupied(entry) => TxEntry::Occupied(TxOccupiedEntry {
+                entry: entry,
+                undo: undo
+            })
+        }
+    }
+}
+
+impl<'a, K, V, C: Compare<K>> Drop for Transaction<'a, K, V, C> {
+    fn drop(&mut self) {
+        while let Some(undo) = self.undo.pop() {
+            match undo {
+                Undo::Inserted(key) => {
+                    self.map.remove(&key);
+                },
+                Undo::Replaced(key, val) => {
+                    if let Some(cur) = self.map.get_mut(&key) {
+                        *cur = val;
+                    }
+                },
+                Undo::Removed(key, val) => {
+                    self.map.insert(key, val);
+                }
+            }
+        }
+    }
+}
+
+/// A view into a single entry of a map within a transaction.
+pub enum TxEntry<'a, K: 'a, V: 'a> {
+    /// A vacant entry.
+    Vacant(TxVacantEntry<'a, K, V>),
+    /// An occupied entry.
+    Occupied(TxOccupiedEntry<'a, K, V>)
+}
+
+/// A vacant entry of a map within a transaction.
+pub struct TxVacantEntry<'a, K: 'a, V: 'a> {
+    entry: VacantEntry<'a, K, V>,
+    undo: &'a mut Vec<Undo<K, V>>
+}
+
+/// An occupied entry of a map within a transaction.
+pub struct TxOccupiedEntry<'a, K: 'a, V: 'a> {
+    entry: OccupiedEntry<'a, K, V>,
+    undo: &'a mut Vec<Undo<K, V>>
+}
+
+impl<'a, K: Clone, V> TxEntry<'a, K, V> {
+    /// Ensures a value is in the entry by inserting the default if empty, and returns a mutable
+    /// reference to the value in the entry. An existing value is cloned first, in case it needs
+    /// to be restored.
+    pub fn or_insert(self, default: V) -> &'a mut V where V: Clone {
+        match self {
+            TxEntry::Vacant(entry) => entry.insert(default),
+            TxEntry::Occupied(entry) => entry.into_mut()
+        }
+    }
+
+    /// Ensures a value is in the entry by inserting the result of the default function if empty,
+    /// and returns a mutable reference to the value in the entry. An existing value is cloned
+    /// first, in case it needs to be restored.
+    pub fn or_insert_with<F: FnOnce() -> V>(self, default: F) -> &'a mut V where V: Clone {
+        match self {
+            TxEntry::Vacant(entry) => entry.insert(default()),
+            TxEntry::Occupied(entry) => entry.into_mut()
+        }
+    }
+
+    /// Returns a reference to this entry's key.
+    pub fn key(&self) -> &K {
+        match *self {
+            TxEntry::Vacant(ref entry) => entry.key(),
+            TxEntry::Occupied(ref entry) => entry.key()
+        }
+    }
+}
+
+impl<'a, K: Clone, V> TxVacantEntry<'a, K, V> {
+    /// Gets a reference to the key that would be used when inserting a value through the
+    /// entry.
+    pub fn key(&self) -> &K {
+        self.entry.key()
+    }
+
+    /// Sets the value of the entry, and returns a mutable reference to it.
+    pub fn insert(self, value: V) -> &'a mut V {
+        self.undo.push(Undo::Inserted(self.entry.key().clone()));
+        self.entry.insert(value)
+    }
+}
+
+impl<'a, K: Clone, V> TxOccupiedEntry<'a, K, V> {
+    /// Gets a reference to the key in the entry.
+    pub fn key(&self) -> &K {
+        self.entry.key()
+    }
+
+    /// Gets a reference to the value in the entry.
+    pub fn get(&self) -> &V {
+        self.entry.get()
+    }
+
+    /// Gets a mutable reference to the value in the entry. The value is cloned first, in case it
+    /// needs to be restored.
+    pub fn get_mut(&mut self) -> &mut V where V: Clone {
+        self.undo.push(Undo::Replaced(self.entry.key().clone(), self.entry.get().clone()));
+        self.entry.get_mut()
+    }
+
+    /// Converts the entry into a mutable reference to its value. The value is cloned first, in
+    /// case it needs to be restored.
+    pub fn into_mut(self) -> &'a mut V where V: Clone {
+        self.undo.push(Undo::Replaced(self.entry.key().clone(), self.entry.get().clone()));
+        self.entry.into_mut()
+    }
+
+    /// Sets the value of the entry, and returns a reference to the old value, which is kept
+    /// until the transaction ends.
+    pub fn insert(&mut self, value: V) -> &V {
+        let old = self.entry.insert(value);
+        self.undo.push(Undo::Replaced(self.entry.key().clone(), old));
+        self.undo.last().unwrap().value()
+    }
+
+    /// Takes the value out of the entry, and returns a reference to it. The element itself is
+    /// kept until the transaction ends.
+    pub fn remove(self) -> &'a V {
+        let (key, val) = self.entry.remove_entry();
+        self.undo.push(Undo::Removed(key, val));
+        self.undo.last().unwrap().value()
+    }
+}
@@ -37,70 +37,107 @@ use core::mem;
 use core::nonzero::NonZero;
 use core::ptr::{self, Unique};
 use core::slice;
+use core::sync::atomic::{self, AtomicUsize, Ordering};
 
 use collections::boxed::Box;
 
+use super::aggregate::Monoid;
+
 const T: usize = 6;
 
-struct LeafNode<K, V> {
+/// The maximum number of elements a node can hold.
+pub const CAPACITY: usize = 2 * T - 1;
+
+/// The minimum number of elements a node other than the root must hold.
+pub const MIN_LEN: usize = T - 1;
+
+/// The `Monoid` of trees that cache no aggregates, which is the default for every type here. Its
+/// aggregates are all `()`, so the arrays of them in internal nodes take up no space and keeping
+/// them up to date compiles down to nothing.
+pub enum NoAggregate { }
+
+impl<K, V> Monoid<K, V> for NoAggregate {
+    type Summary = ();
+
+    #[inline]
+    fn identity() { }
+    #[inline]
+    fn lift(_: &K, _: &V) { }
+    #[inline]
+    fn combine(_: &(), _: &()) { }
+}
+
+struct LeafNode<K, V, M: Monoid<K, V>> {
     keys: [K; 2 * T - 1],
     vals: [V; 2 * T - 1],
-    parent: *mut InternalNode<K, V>,
+    parent: *mut InternalNode<K, V, M>,
     parent_idx: u16,
     len: u16,
+    // The number of edges and roots that point to this node. This is always 1, except in the
+    // trees of `PersistentMap`, which share nodes between every map cloned from the same original.
+    refs: AtomicUsize,
 }
 
-impl<K, V> LeafNode<K, V> {
+impl<K, V, M: Monoid<K, V>> LeafNode<K, V, M> {
     unsafe fn new() -> Self {
         LeafNode {
             keys: mem::uninitialized(),
             vals: mem::uninitialized(),
             parent: ptr::null_mut(),
             parent_idx: mem::uninitialized(),
-            len: 0
+            len: 0,
+            refs: AtomicUsize::new(1)
         }
     }
 }
 
 // We use repr(C) so that a pointer to an internal node can be
 // directly used as a pointer to a leaf node
+//
+// `aggs[i]` is the aggregate of every element in the subtree below `edges[i]`. Every operation
+// here that moves edges between nodes, or adds or removes them, keeps these in step, so the only
+// aggregates that can go stale are those on the path above a node whose elements were changed
+// directly, and `refresh_ancestors` brings those back up to date.
 #[repr(C)]
-struct InternalNode<K, V> {
-    data: LeafNode<K, V>,
-    edges: [BoxedNode<K, V>; 2 * T],
+struct InternalNode<K, V, M: Monoid<K, V>> {
+    data: LeafNode<K, V, M>,
+    edges: [BoxedNode<K, V, M>; 2 * T],
+    aggs: [M::Summary; 2 * T],
 }
 
-impl<K, V> InternalNode<K, V> {
+impl<K, V, M: Monoid<K, V>> InternalNode<K, V, M> {
     unsafe fn new() -> Self {
         InternalNode {
             data: LeafNode::new(),
-            edges: mem::uninitialized()
+            edges: mem::uninitialized(),
+            aggs: mem::uninitialized()
         }
     }
 }
 
-struct BoxedNode<K, V> {
-    ptr: Unique<LeafNode<K, V>> // we don't know if this points to a leaf node or an internal node
+struct BoxedNode<K, V, M: Monoid<K, V>> {
+    // we don't know if this points to a leaf node or an internal node
+    ptr: Unique<LeafNode<K, V, M>>
 }
 
-impl<K, V> BoxedNode<K, V> {
-    fn from_leaf(node: Box<LeafNode<K, V>>) -> Self {
+impl<K, V, M: Monoid<K, V>> BoxedNode<K, V, M> {
+    fn from_leaf(node: Box<LeafNode<K, V, M>>) -> Self {
         unsafe {
             BoxedNode { ptr: Unique::new(Box::into_raw(node)) }
         }
     }
 
-    fn from_internal(node: Box<InternalNode<K, V>>) -> Self {
+    fn from_internal(node: Box<InternalNode<K, V, M>>) -> Self {
         unsafe {
-            BoxedNode { ptr: Unique::new(Box::into_raw(node) as *mut LeafNode<K, V>) }
+            BoxedNode { ptr: Unique::new(Box::into_raw(node) as *mut LeafNode<K, V, M>) }
         }
     }
 
-    unsafe fn from_ptr(ptr: NonZero<*mut LeafNode<K, V>>) -> Self {
+    unsafe fn from_ptr(ptr: NonZero<*mut LeafNode<K, V, M>>) -> Self {
         BoxedNode { ptr: Unique::new(*ptr) }
     }
 
-    fn as_ptr(&self) -> NonZero<*mut LeafNode<K, V>> {
+    fn as_ptr(&self) -> NonZero<*mut LeafNode<K, V, M>> {
         unsafe {
             NonZero::new(*self.ptr)
         }
@@ -109,15 +146,15 @@ impl<K, V> BoxedNode<K, V> {
 
 /// An owned tree. Note that despite being owned, this does not have a destructor,
 /// and must be cleaned up manually.
-pub struct Root<K, V> {
-    node: BoxedNode<K, V>,
+pub struct Root<K, V, M: Monoid<K, V> = NoAggregate> {
+    node: BoxedNode<K, V, M>,
     height: usize
 }
 
-unsafe impl<K: Sync, V: Sync> Sync for Root<K, V> { }
-unsafe impl<K: Send, V: Send> Send for Root<K, V> { }
+unsafe impl<K: Sync, V: Sync, M: Monoid<K, V>> Sync for Root<K, V, M> where M::Summary: Sync { }
+unsafe impl<K: Send, V: Send, M: Monoid<K, V>> Send for Root<K, V, M> where M::Summary: Send { }
 
-impl<K, V> Root<K, V> {
+impl<K, V, M: Monoid<K, V>> Root<K, V, M> {
     pub fn new_leaf() -> Self {
         Root {
             node: BoxedNode::from_leaf(Box::new(unsafe { LeafNode::new() })),
@@ -126,7 +163,7 @@ impl<K, V> Root<K, V> {
     }
 
     pub fn as_ref(&self)
-            -> NodeRef<marker::Borrowed, K, V, marker::Immut, marker::LeafOrInternal> {
+            -> NodeRef<marker::Borrowed, K, V, marker::Immut, marker::LeafOrInternal, M> {
         NodeRef {
             height: self.height,
             node: self.node.as_ptr(),
@@ -136,7 +173,7 @@ impl<K, V> Root<K, V> {
     }
 
     pub fn as_mut(&mut self)
-            -> NodeRef<marker::Borrowed, K, V, marker::Mut, marker::LeafOrInternal> {
+            -> NodeRef<marker::Borrowed, K, V, marker::Mut, marker::LeafOrInternal, M> {
         NodeRef {
             height: self.height,
             node: self.node.as_ptr(),
@@ -146,7 +183,7 @@ impl<K, V> Root<K, V> {
     }
 
     pub fn into_ref(self)
-            -> NodeRef<marker::Owned, K, V, marker::Mut, marker::LeafOrInternal> {
+            -> NodeRef<marker::Owned, K, V, marker::Mut, marker::LeafOrInternal, M> {
         NodeRef {
             height: self.height,
             node: self.node.as_ptr(),
@@ -158,8 +195,11 @@ impl<K, V> Root<K, V> {
     /// Add a new internal node with a single edge, pointing to the previous root, and make that
     /// new node the root. This increases the height by 1 and is the opposite of `shrink`.
     pub fn enlarge(&mut self)
-            -> NodeRef<marker::Borrowed, K, V, marker::Mut, marker::Internal> {
+            -> NodeRef<marker::Borrowed, K, V, marker::Mut, marker::Internal, M> {
         let mut new_node = Box::new(unsafe { InternalNode::new() });
+        unsafe {
+            ptr::write(&mut new_node.aggs[0], self.as_ref().summarize());
+        }
         new_node.edges[0] = unsafe { BoxedNode::from_ptr(self.node.as_ptr()) };
 
         self.node = BoxedNode::from_internal(new_node);
@@ -196,18 +236,59 @@ impl<K, V> Root<K, V> {
                                     .node)
         };
         self.height -= 1;
-        self.as_mut().as_leaf_mut().parent = ptr::null_mut();
 
         unsafe {
+            set_parent_link(*self.node.as_ptr(), ptr::null_mut(), 0);
+            mem::drop(ptr::read(&(*(top as *const InternalNode<K, V, M>)).aggs[0]));
             heap::deallocate(
                 top,
-                mem::size_of::<InternalNode<K, V>>(),
-                mem::align_of::<InternalNode<K, V>>()
+                mem::size_of::<InternalNode<K, V, M>>(),
+                mem::align_of::<InternalNode<K, V, M>>()
             );
         }
     }
 }
 
+// Trees whose nodes are shared, as those of `PersistentMap` are, hold their roots through `share`
+// and give them up through `release`, and must call `make_unique` on every node from the root
+// down before changing it. Since a shared node has a parent in each tree it belongs to, its parent
+// link is never written, and is only valid once `make_unique` has set it again.
+impl<K, V, M: Monoid<K, V>> Root<K, V, M> {
+    /// Makes another root for the same tree, which then belongs to both roots.
+    pub fn share(&self) -> Self {
+        unsafe {
+            (**self.node.as_ptr()).refs.fetch_add(1, Ordering::Relaxed);
+            Root {
+                node: BoxedNode::from_ptr(self.node.as_ptr()),
+                height: self.height
+            }
+        }
+    }
+
+    /// Gives up this root's share of the tree, dropping every node that no other root still
+    /// shares, along with its elements.
+    pub fn release(self) {
+        unsafe {
+            release_node(self.node.as_ptr(), self.height);
+        }
+    }
+}
+
+impl<K: Clone, V: Clone, M: Monoid<K, V>> Root<K, V, M> where M::Summary: Clone {
+    /// Makes sure the root node belongs to this tree alone, replacing it with a copy if it is
+    /// shared. The copy shares every child of the original.
+    pub fn make_unique(&mut self) {
+        unsafe {
+            if is_shared(*self.node.as_ptr()) {
+                let copy = copy_node(self.node.as_ptr(), self.height);
+                release_node(self.node.as_ptr(), self.height);
+                self.node = copy;
+            }
+            set_parent_link(*self.node.as_ptr(), ptr::null_mut(), 0);
+        }
+    }
+}
+
 /// A reference to a node.
 ///
 /// This type has a number of paramaters that controls how it acts:
@@ -222,48 +303,65 @@ impl<K, V> Root<K, V> {
 ///   `Leaf`, the `NodeRef` points to a leaf node, when this is `Internal` the
 ///   `NodeRef` points to an internal node, and when this is `LeafOrInternal` the
 ///   `NodeRef` could be pointing to either type of node.
-pub struct NodeRef<Lifetime, K, V, Mutability, Type> {
+/// - `M`: The `Monoid` whose aggregates internal nodes cache for each of their edges. Plain maps
+///   use `NoAggregate`, which caches nothing.
+pub struct NodeRef<Lifetime, K, V, Mutability, Type, M: Monoid<K, V> = NoAggregate> {
     height: usize,
-    node: NonZero<*mut LeafNode<K, V>>,
-    root: *mut Root<K, V>,
+    node: NonZero<*mut LeafNode<K, V, M>>,
+    root: *mut Root<K, V, M>,
     _marker: PhantomData<(Lifetime, Mutability, Type)>
 }
 
-impl<'a, K: 'a, V: 'a, Type> Copy for NodeRef<marker::Borrowed<'a>, K, V, marker::Immut, Type> { }
-impl<'a, K: 'a, V: 'a, Type> Clone for NodeRef<marker::Borrowed<'a>, K, V, marker::Immut, Type> {
+impl<'a, K: 'a, V: 'a, Type, M: Monoid<K, V>> Copy
+    for NodeRef<marker::Borrowed<'a>, K, V, marker::Immut, Type, M> { }
+impl<'a, K: 'a, V: 'a, Type, M: Monoid<K, V>> Clone
+        for NodeRef<marker::Borrowed<'a>, K, V, marker::Immut, Type, M> {
+
     fn clone(&self) -> Self {
         *self
     }
 }
 
-unsafe impl<Lifetime, K: Sync, V: Sync, Mutability, Type> Sync
-    for NodeRef<Lifetime, K, V, Mutability, Type> { }
+impl<Lifetime, K, V, Mutability, Type, M: Monoid<K, V>> PartialEq
+        for NodeRef<Lifetime, K, V, Mutability, Type, M> {
+
+    fn eq(&self, other: &Self) -> bool {
+        self.node == other.node
+    }
+}
+
+unsafe impl<Lifetime, K: Sync, V: Sync, Mutability, Type, M: Monoid<K, V>> Sync
+    for NodeRef<Lifetime, K, V, Mutability, Type, M> where M::Summary: Sync { }
+
+unsafe impl<'a, K: Sync + 'a, V: Sync + 'a, Type, M: Monoid<K, V>> Send
+   for NodeRef<marker::Borrowed<'a>, K, V, marker::Immut, Type, M> where M::Summary: Sync { }
+unsafe impl<'a, K: Send + 'a, V: Send + 'a, Type, M: Monoid<K, V>> Send
+   for NodeRef<marker::Borrowed<'a>, K, V, marker::Mut, Type, M> where M::Summary: Send { }
+unsafe impl<K: Send, V: Send, Mutability, Type, M: Monoid<K, V>> Send
+   for NodeRef<marker::Owned, K, V, Mutability, Type, M> where M::Summary: Send { }
 
-unsafe impl<'a, K: Sync + 'a, V: Sync + 'a, Type> Send
-   for NodeRef<marker::Borrowed<'a>, K, V, marker::Immut, Type> { }
-unsafe impl<'a, K: Send + 'a, V: Send + 'a, Type> Send
-   for NodeRef<marker::Borrowed<'a>, K, V, marker::Mut, Type> { }
-unsafe impl<K: Send, V: Send, Mutability, Type> Send
-   for NodeRef<marker::Owned, K, V, Mutability, Type> { }
+impl<Lifetime, K, V, Mutability, M: Monoid<K, V>>
+        NodeRef<Lifetime, K, V, Mutability, marker::Internal, M> {
 
-impl<Lifetime, K, V, Mutability> NodeRef<Lifetime, K, V, Mutability, marker::Internal> {
-    fn as_internal(&self) -> &InternalNode<K, V> {
+    fn as_internal(&self) -> &InternalNode<K, V, M> {
         unsafe {
-            &*(*self.node as *const InternalNode<K, V>)
+            &*(*self.node as *const InternalNode<K, V, M>)
         }
     }
 }
 
-impl<Lifetime, K, V> NodeRef<Lifetime, K, V, marker::Mut, marker::Internal> {
-    fn as_internal_mut(&mut self) -> &mut InternalNode<K, V> {
+impl<Lifetime, K, V, M: Monoid<K, V>> NodeRef<Lifetime, K, V, marker::Mut, marker::Internal, M> {
+    fn as_internal_mut(&mut self) -> &mut InternalNode<K, V, M> {
         unsafe {
-            &mut *(*self.node as *mut InternalNode<K, V>)
+            &mut *(*self.node as *mut InternalNode<K, V, M>)
         }
     }
 }
 
 
-impl<Lifetime, K, V, Mutability, Type> NodeRef<Lifetime, K, V, Mutability, Type> {
+impl<Lifetime, K, V, Mutability, Type, M: Monoid<K, V>>
+        NodeRef<Lifetime, K, V, Mutability, Type, M> {
+
     pub fn height(&self) -> usize {
         self.height
     }
@@ -273,15 +371,19 @@ impl<Lifetime, K, V, Mutability, Type> NodeRef<Lifetime, K, V, Mutability, Type>
         self.as_leaf().parent_idx as usize
     }
 
+    // This and the other accessors that only navigate the tree (`ascend` and `descend`) read
+    // the fields they need straight through the node pointer, without making a reference to the
+    // whole node. They can therefore be used on a node whose values are mutably borrowed
+    // elsewhere, as they are while several halves of a split `IterMut` run on different threads.
     pub fn len(&self) -> usize {
-        self.as_leaf().len as usize
+        unsafe { (**self.node).len as usize }
     }
 
     pub fn capacity(&self) -> usize {
-        2 * T - 1
+        CAPACITY
     }
 
-    pub fn forget_type(self) -> NodeRef<Lifetime, K, V, Mutability, marker::LeafOrInternal> {
+    pub fn forget_type(self) -> NodeRef<Lifetime, K, V, Mutability, marker::LeafOrInternal, M> {
         NodeRef {
             height: self.height,
             node: self.node,
@@ -290,7 +392,7 @@ impl<Lifetime, K, V, Mutability, Type> NodeRef<Lifetime, K, V, Mutability, Type>
         }
     }
 
-    fn reborrow<'a>(&'a self) -> NodeRef<marker::Borrowed<'a>, K, V, marker::Immut, Type> {
+    fn reborrow<'a>(&'a self) -> NodeRef<marker::Borrowed<'a>, K, V, marker::Immut, Type, M> {
         NodeRef {
             height: self.height,
             node: self.node,
@@ -299,7 +401,7 @@ impl<Lifetime, K, V, Mutability, Type> NodeRef<Lifetime, K, V, Mutability, Type>
         }
     }
 
-    fn as_leaf(&self) -> &LeafNode<K, V> {
+    fn as_leaf(&self) -> &LeafNode<K, V, M> {
         unsafe {
             &**self.node
         }
@@ -313,31 +415,59 @@ impl<Lifetime, K, V, Mutability, Type> NodeRef<Lifetime, K, V, Mutability, Type>
         self.reborrow().into_slices().1
     }
 
+    /// Aggregates every element in the subtree below this node, using the aggregates it caches
+    /// for its children rather than looking at them.
+    pub fn summarize(&self) -> M::Summary {
+        let (keys, vals) = self.reborrow().into_slices();
+        let aggs = if self.height > 0 {
+            unsafe {
+                let internal = *self.node as *const InternalNode<K, V, M>;
+                Some(slice::from_raw_parts((*internal).aggs.as_ptr(), keys.len() + 1))
+            }
+        } else {
+            None
+        };
+
+        let mut acc = M::identity();
+        for i in 0..keys.len() {
+            if let Some(aggs) = aggs {
+                acc = M::combine(&acc, &aggs[i]);
+            }
+            acc = M::combine(&acc, &M::lift(&keys[i], &vals[i]));
+        }
+        if let Some(aggs) = aggs {
+            acc = M::combine(&acc, &aggs[keys.len()]);
+        }
+        acc
+    }
+
     pub fn ascend(self) -> Result<
         Handle<
             NodeRef<
                 Lifetime,
                 K, V,
                 Mutability,
-                marker::Internal
+                marker::Internal,
+                M
             >,
             marker::Edge
         >,
         Self
     > {
-        if self.as_leaf().parent.is_null() {
+        let (parent, parent_idx) = unsafe { ((**self.node).parent, (**self.node).parent_idx) };
+        if parent.is_null() {
             Err(self)
         } else {
             Ok(Handle {
                 node: NodeRef {
                     height: self.height + 1,
                     node: unsafe {
-                        NonZero::new(self.as_leaf().parent as *mut LeafNode<K, V>)
+                        NonZero::new(parent as *mut LeafNode<K, V, M>)
                     },
                     root: self.root,
                     _marker: PhantomData
                 },
-                idx: self.as_leaf().parent_idx as usize,
+                idx: parent_idx as usize,
                 _marker: PhantomData
             })
         }
@@ -353,51 +483,61 @@ impl<Lifetime, K, V, Mutability, Type> NodeRef<Lifetime, K, V, Mutability, Type>
     }
 }
 
-impl<K, V> NodeRef<marker::Owned, K, V, marker::Mut, marker::Leaf> {
+impl<K, V, M: Monoid<K, V>> NodeRef<marker::Owned, K, V, marker::Mut, marker::Leaf, M> {
     pub unsafe fn deallocate_and_ascend(mut self) -> Option<
         Handle<
             NodeRef<
                 marker::Owned,
                 K, V,
                 marker::Mut,
-                marker::Internal
+                marker::Internal,
+                M
             >,
             marker::Edge
         >
     > {
-        let ptr = self.as_leaf_mut() as *mut LeafNode<K, V> as *mut u8;
+        let ptr = self.as_leaf_mut() as *mut LeafNode<K, V, M> as *mut u8;
         let ret = self.ascend().ok();
-        heap::deallocate(ptr, mem::size_of::<LeafNode<K, V>>(), mem::align_of::<LeafNode<K, V>>());
+        heap::deallocate(
+            ptr,
+            mem::size_of::<LeafNode<K, V, M>>(),
+            mem::align_of::<LeafNode<K, V, M>>()
+        );
         ret
     }
 }
 
-impl<K, V> NodeRef<marker::Owned, K, V, marker::Mut, marker::Internal> {
+impl<K, V, M: Monoid<K, V>> NodeRef<marker::Owned, K, V, marker::Mut, marker::Internal, M> {
     pub unsafe fn deallocate_and_ascend(mut self) -> Option<
         Handle<
             NodeRef<
                 marker::Owned,
                 K, V,
                 marker::Mut,
-                marker::Internal
+                marker::Internal,
+                M
             >,
             marker::Edge
         >
     > {
-        let ptr = self.as_internal_mut() as *mut InternalNode<K, V> as *mut u8;
+        let len = self.len();
+        for agg in &mut self.as_internal_mut().aggs[..len + 1] {
+            mem::drop(ptr::read(agg));
+        }
+        let ptr = self.as_internal_mut() as *mut InternalNode<K, V, M> as *mut u8;
         let ret = self.ascend().ok();
         heap::deallocate(
             ptr,
-            mem::size_of::<InternalNode<K, V>>(),
-            mem::align_of::<InternalNode<K, V>>()
+            mem::size_of::<InternalNode<K, V, M>>(),
+            mem::align_of::<InternalNode<K, V, M>>()
         );
         ret
     }
 }
 
-impl<Lifetime, K, V, Type> NodeRef<Lifetime, K, V, marker::Mut, Type> {
+impl<Lifetime, K, V, Type, M: Monoid<K, V>> NodeRef<Lifetime, K, V, marker::Mut, Type, M> {
     unsafe fn cast_unchecked<NewType>(&mut self)
-            -> NodeRef<marker::Borrowed, K, V, marker::Mut, NewType> {
+            -> NodeRef<marker::Borrowed, K, V, marker::Mut, NewType, M> {
 
         NodeRef {
             height: self.height,
@@ -407,7 +547,9 @@ impl<Lifetime, K, V, Type> NodeRef<Lifetime, K, V, marker::Mut, Type> {
         }
     }
 
-    unsafe fn reborrow_mut(&mut self) -> NodeRef<marker::Borrowed, K, V, marker::Mut, Type> {
+    pub unsafe fn reborrow_mut(&mut self)
+            -> NodeRef<marker::Borrowed, K, V, marker::Mut, Type, M> {
+
         NodeRef {
             height: self.height,
             node: self.node,
@@ -416,7 +558,7 @@ impl<Lifetime, K, V, Type> NodeRef<Lifetime, K, V, marker::Mut, Type> {
         }
     }
 
-    fn as_leaf_mut(&mut self) -> &mut LeafNode<K, V> {
+    fn as_leaf_mut(&mut self) -> &mut LeafNode<K, V, M> {
         unsafe {
             &mut **self.node
         }
@@ -431,7 +573,9 @@ impl<Lifetime, K, V, Type> NodeRef<Lifetime, K, V, marker::Mut, Type> {
     }
 }
 
-impl<'a, K: 'a, V: 'a, Mutability, Type> NodeRef<marker::Borrowed<'a>, K, V, Mutability, Type> {
+impl<'a, K: 'a, V: 'a, Mutability, Type, M: Monoid<K, V>>
+        NodeRef<marker::Borrowed<'a>, K, V, Mutability, Type, M> {
+
     pub fn into_slices(self) -> (&'a [K], &'a [V]) {
         unsafe {
             (
@@ -448,13 +592,39 @@ impl<'a, K: 'a, V: 'a, Mutability, Type> NodeRef<marker::Borrowed<'a>, K, V, Mut
     }
 }
 
-impl<'a, K: 'a, V: 'a, Type> NodeRef<marker::Borrowed<'a>, K, V, marker::Mut, Type> {
-    pub fn into_root_mut(self) -> &'a mut Root<K, V> {
+impl<'a, K: 'a, V: 'a, Type, M: Monoid<K, V>>
+        NodeRef<marker::Borrowed<'a>, K, V, marker::Immut, Type, M> {
+
+    /// Returns a pointer to the first of the node's values, through which any of them may be
+    /// changed. Unlike references taken one at a time through `into_kv_mut`, which each borrow
+    /// the whole node, pointers to several values derived from this one stay valid together.
+    ///
+    /// This is unsafe because the caller must hold the tree mutably borrowed for as long as the
+    /// pointer is used, and must not borrow the node again in the meantime.
+    pub unsafe fn into_vals_mut_ptr(self) -> *mut V {
+        ptr::addr_of_mut!((**self.node).vals) as *mut V
+    }
+}
+
+impl<'a, K: 'a, V: 'a, Type, M: Monoid<K, V>>
+        NodeRef<marker::Borrowed<'a>, K, V, marker::Mut, Type, M> {
+
+    pub fn into_root_mut(self) -> &'a mut Root<K, V, M> {
         unsafe {
             &mut *self.root
         }
     }
 
+    /// Recomputes the aggregates cached for this node's subtree and for the subtree of each of
+    /// its ancestors, which is needed after its elements have been changed directly.
+    pub fn refresh_ancestors(self) {
+        let mut parent = self.ascend().ok();
+        while let Some(mut edge) = parent {
+            edge.refresh_agg();
+            parent = edge.into_node().ascend().ok();
+        }
+    }
+
     pub fn into_slices_mut(mut self) -> (&'a mut [K], &'a mut [V]) {
         unsafe {
             (
@@ -471,7 +641,7 @@ impl<'a, K: 'a, V: 'a, Type> NodeRef<marker::Borrowed<'a>, K, V, marker::Mut, Ty
     }
 }
 
-impl<Lifetime, K, V> NodeRef<Lifetime, K, V, marker::Mut, marker::Leaf> {
+impl<Lifetime, K, V, M: Monoid<K, V>> NodeRef<Lifetime, K, V, marker::Mut, marker::Leaf, M> {
     pub fn push(&mut self, key: K, val: V) {
         // Necessary for correctness, but this is an internal module
         debug_assert!(self.len() < self.capacity());
@@ -499,18 +669,20 @@ impl<Lifetime, K, V> NodeRef<Lifetime, K, V, marker::Mut, marker::Leaf> {
     }
 }
 
-impl<Lifetime, K, V> NodeRef<Lifetime, K, V, marker::Mut, marker::Internal> {
-    pub fn push(&mut self, key: K, val: V, edge: Root<K, V>) {
+impl<Lifetime, K, V, M: Monoid<K, V>> NodeRef<Lifetime, K, V, marker::Mut, marker::Internal, M> {
+    pub fn push(&mut self, key: K, val: V, edge: Root<K, V, M>) {
         // Necessary for correctness, but this is an internal module
         debug_assert!(edge.height == self.height - 1);
         debug_assert!(self.len() < self.capacity());
 
         let idx = self.len();
+        let agg = edge.as_ref().summarize();
 
         unsafe {
             ptr::write(self.keys_mut().get_unchecked_mut(idx), key);
             ptr::write(self.vals_mut().get_unchecked_mut(idx), val);
             ptr::write(self.as_internal_mut().edges.get_unchecked_mut(idx + 1), edge.node);
+            ptr::write(self.as_internal_mut().aggs.get_unchecked_mut(idx + 1), agg);
 
             Handle::new(self.reborrow_mut(), idx + 1).correct_parent_link();
         }
@@ -518,11 +690,13 @@ impl<Lifetime, K, V> NodeRef<Lifetime, K, V, marker::Mut, marker::Internal> {
         self.as_leaf_mut().len += 1;
     }
 
-    pub fn push_front(&mut self, key: K, val: V, edge: Root<K, V>) {
+    pub fn push_front(&mut self, key: K, val: V, edge: Root<K, V, M>) {
         // Necessary for correctness, but this is an internal module
         debug_assert!(edge.height == self.height - 1);
         debug_assert!(self.len() < self.capacity());
 
+        let agg = edge.as_ref().summarize();
+
         unsafe {
             slice_insert(self.keys_mut(), 0, key);
             slice_insert(self.vals_mut(), 0, val);
@@ -534,6 +708,14 @@ impl<Lifetime, K, V> NodeRef<Lifetime, K, V, marker::Mut, marker::Internal> {
                 0,
                 edge.node
             );
+            slice_insert(
+                slice::from_raw_parts_mut(
+                    self.as_internal_mut().aggs.as_mut_ptr(),
+                    self.len()+1
+                ),
+                0,
+                agg
+            );
 
             self.as_leaf_mut().len += 1;
 
@@ -545,8 +727,10 @@ impl<Lifetime, K, V> NodeRef<Lifetime, K, V, marker::Mut, marker::Internal> {
     }
 }
 
-impl<Lifetime, K, V> NodeRef<Lifetime, K, V, marker::Mut, marker::LeafOrInternal> {
-    pub fn pop(&mut self) -> (K, V, Option<Root<K, V>>) {
+impl<Lifetime, K, V, M: Monoid<K, V>>
+        NodeRef<Lifetime, K, V, marker::Mut, marker::LeafOrInternal, M> {
+
+    pub fn pop(&mut self) -> (K, V, Option<Root<K, V, M>>) {
         // Necessary for correctness, but this is an internal module
         debug_assert!(self.len() > self.capacity()/2);
 
@@ -559,8 +743,9 @@ impl<Lifetime, K, V> NodeRef<Lifetime, K, V, marker::Mut, marker::LeafOrInternal
                 ForceResult::Leaf(_) => None,
                 ForceResult::Internal(internal) => {
                     let edge = ptr::read(internal.as_internal().edges.get_unchecked(idx + 1));
-                    let mut new_root = Root { node: edge, height: internal.height - 1 };
-                    new_root.as_mut().as_leaf_mut().parent = ptr::null_mut();
+                    mem::drop(ptr::read(internal.as_internal().aggs.get_unchecked(idx + 1)));
+                    let new_root = Root { node: edge, height: internal.height - 1 };
+                    set_parent_link(*new_root.node.as_ptr(), ptr::null_mut(), 0);
                     Some(new_root)
                 }
             };
@@ -570,7 +755,7 @@ impl<Lifetime, K, V> NodeRef<Lifetime, K, V, marker::Mut, marker::LeafOrInternal
         }
     }
 
-    pub fn pop_front(&mut self) -> (K, V, Option<Root<K, V>>) {
+    pub fn pop_front(&mut self) -> (K, V, Option<Root<K, V, M>>) {
         // Necessary for correctness, but this is an internal module
         debug_assert!(self.len() > self.capacity()/2);
 
@@ -589,9 +774,16 @@ impl<Lifetime, K, V> NodeRef<Lifetime, K, V, marker::Mut, marker::LeafOrInternal
                         ),
                         0
                     );
+                    mem::drop(slice_remove(
+                        slice::from_raw_parts_mut(
+                            internal.as_internal_mut().aggs.as_mut_ptr(),
+                            old_len+1
+                        ),
+                        0
+                    ));
 
-                    let mut new_root = Root { node: edge, height: internal.height - 1 };
-                    new_root.as_mut().as_leaf_mut().parent = ptr::null_mut();
+                    let new_root = Root { node: edge, height: internal.height - 1 };
+                    set_parent_link(*new_root.node.as_ptr(), ptr::null_mut(), 0);
 
                     for i in 0..old_len {
                         Handle::new(internal.reborrow_mut(), i).correct_parent_link();
@@ -608,10 +800,12 @@ impl<Lifetime, K, V> NodeRef<Lifetime, K, V, marker::Mut, marker::LeafOrInternal
     }
 }
 
-impl<Lifetime, K, V, Mutability> NodeRef<Lifetime, K, V, Mutability, marker::LeafOrInternal> {
+impl<Lifetime, K, V, Mutability, M: Monoid<K, V>>
+        NodeRef<Lifetime, K, V, Mutability, marker::LeafOrInternal, M> {
+
     pub fn force(self) -> ForceResult<
-        NodeRef<Lifetime, K, V, Mutability, marker::Leaf>,
-        NodeRef<Lifetime, K, V, Mutability, marker::Internal>
+        NodeRef<Lifetime, K, V, Mutability, marker::Leaf, M>,
+        NodeRef<Lifetime, K, V, Mutability, marker::Internal, M>
     > {
         if self.height == 0 {
             ForceResult::Leaf(NodeRef {
@@ -656,6 +850,19 @@ impl<Node, Type> Handle<Node, Type> {
     pub fn into_node(self) -> Node {
         self.node
     }
+
+    pub fn idx(&self) -> usize {
+        self.idx
+    }
+}
+
+impl<Lifetime, K, V, Mutability, HandleType, M: Monoid<K, V>>
+        Handle<NodeRef<Lifetime, K, V, Mutability, marker::Leaf, M>, HandleType> {
+
+    pub fn forget_node_type(self)
+            -> Handle<NodeRef<Lifetime, K, V, Mutability, marker::LeafOrInternal, M>, HandleType> {
+        unsafe { Handle::new(self.node.forget_type(), self.idx) }
+    }
 }
 
 impl<Node> Handle<Node, marker::KV> {
@@ -668,39 +875,39 @@ impl<Node> Handle<Node, marker::KV> {
     }
 }
 
-impl<Lifetime, K, V, Mutability, NodeType, HandleType> PartialEq
-        for Handle<NodeRef<Lifetime, K, V, Mutability, NodeType>, HandleType> {
+impl<Lifetime, K, V, Mutability, NodeType, HandleType, M: Monoid<K, V>> PartialEq
+        for Handle<NodeRef<Lifetime, K, V, Mutability, NodeType, M>, HandleType> {
 
     fn eq(&self, other: &Self) -> bool {
         self.node.node == other.node.node && self.idx == other.idx
     }
 }
 
-impl<Lifetime, K, V, Mutability, NodeType, HandleType>
-        Handle<NodeRef<Lifetime, K, V, Mutability, NodeType>, HandleType> {
+impl<Lifetime, K, V, Mutability, NodeType, HandleType, M: Monoid<K, V>>
+        Handle<NodeRef<Lifetime, K, V, Mutability, NodeType, M>, HandleType> {
 
     pub fn reborrow(&self)
-            -> Handle<NodeRef<marker::Borrowed, K, V, marker::Immut, NodeType>, HandleType> {
+            -> Handle<NodeRef<marker::Borrowed, K, V, marker::Immut, NodeType, M>, HandleType> {
 
         unsafe { Handle::new(self.node.reborrow(), self.idx) }
     }
 }
 
-impl<Lifetime, K, V, NodeType, HandleType>
-        Handle<NodeRef<Lifetime, K, V, marker::Mut, NodeType>, HandleType> {
+impl<Lifetime, K, V, NodeType, HandleType, M: Monoid<K, V>>
+        Handle<NodeRef<Lifetime, K, V, marker::Mut, NodeType, M>, HandleType> {
 
     pub unsafe fn reborrow_mut(&mut self)
-            -> Handle<NodeRef<marker::Borrowed, K, V, marker::Mut, NodeType>, HandleType> {
+            -> Handle<NodeRef<marker::Borrowed, K, V, marker::Mut, NodeType, M>, HandleType> {
 
         Handle::new(self.node.reborrow_mut(), self.idx)
     }
 }
 
-impl<Lifetime, K, V, Mutability, NodeType>
-        Handle<NodeRef<Lifetime, K, V, Mutability, NodeType>, marker::Edge> {
+impl<Lifetime, K, V, Mutability, NodeType, M: Monoid<K, V>>
+        Handle<NodeRef<Lifetime, K, V, Mutability, NodeType, M>, marker::Edge> {
 
     pub fn left_kv(self)
-            -> Result<Handle<NodeRef<Lifetime, K, V, Mutability, NodeType>, marker::KV>, Self> {
+            -> Result<Handle<NodeRef<Lifetime, K, V, Mutability, NodeType, M>, marker::KV>, Self> {
 
         if self.idx > 0 {
             unsafe {
@@ -712,7 +919,7 @@ impl<Lifetime, K, V, Mutability, NodeType>
     }
 
     pub fn right_kv(self)
-            -> Result<Handle<NodeRef<Lifetime, K, V, Mutability, NodeType>, marker::KV>, Self> {
+            -> Result<Handle<NodeRef<Lifetime, K, V, Mutability, NodeType, M>, marker::KV>, Self> {
 
         if self.idx < self.node.len() {
             unsafe {
@@ -724,60 +931,75 @@ impl<Lifetime, K, V, Mutability, NodeType>
     }
 }
 
-impl<Lifetime, K, V> Handle<NodeRef<Lifetime, K, V, marker::Mut, marker::Leaf>, marker::Edge> {
-    unsafe fn insert_unchecked(&mut self, key: K, val: V) -> *mut V {
+impl<Lifetime, K, V, M: Monoid<K, V>>
+        Handle<NodeRef<Lifetime, K, V, marker::Mut, marker::Leaf, M>, marker::Edge> {
+
+    unsafe fn insert_unchecked(&mut self, key: K, val: V) {
         slice_insert(self.node.keys_mut(), self.idx, key);
         slice_insert(self.node.vals_mut(), self.idx, val);
 
         self.node.as_leaf_mut().len += 1;
-
-        self.node.vals_mut().get_unchecked_mut(self.idx)
     }
 
+    /// Inserts a key-value pair at this edge, splitting the leaf if it is full. Along with the
+    /// result, this returns a handle to the newly inserted pair, which stays valid however the
+    /// split is propagated upwards, since leaves never move.
     pub fn insert(mut self, key: K, val: V)
-            -> (InsertResult<Lifetime, K, V, marker::Leaf>, *mut V) {
+            -> (InsertResult<Lifetime, K, V, marker::Leaf, M>,
+                Handle<NodeRef<Lifetime, K, V, marker::Mut, marker::Leaf, M>, marker::KV>) {
 
         if self.node.len() < self.node.capacity() {
             unsafe {
-                let ptr = self.insert_unchecked(key, val);
-                (InsertResult::Fit(Handle::new(self.node, self.idx)), ptr)
+                self.insert_unchecked(key, val);
+                let inserted = Handle::new(ptr::read(&self.node), self.idx);
+                (InsertResult::Fit(Handle::new(self.node, self.idx)), inserted)
             }
         } else {
             let middle = unsafe { Handle::new(self.node, T) };
-            let (mut left, k, v, mut right) = middle.split();
-            let ptr = if self.idx <= T {
-                unsafe {
-                    Handle::new(left.reborrow_mut(), self.idx).insert_unchecked(key, val)
-                }
+            let (left, k, v, right) = middle.split();
+            let mut edge = if self.idx <= T {
+                unsafe { Handle::new(ptr::read(&left), self.idx) }
             } else {
-                unsafe {
-                    Handle::new(
-                        right.as_mut().cast_unchecked::<marker::Leaf>(),
-                        self.idx - T - 1
-                    ).insert_unchecked(key, val)
-                }
+                let right_leaf = NodeRef {
+                    height: 0,
+                    node: right.node.as_ptr(),
+                    root: left.root,
+                    _marker: PhantomData
+                };
+                unsafe { Handle::new(right_leaf, self.idx - T - 1) }
             };
-            (InsertResult::Split(left, k, v, right), ptr)
+            let inserted = unsafe {
+                edge.insert_unchecked(key, val);
+                Handle::new(edge.node, edge.idx)
+            };
+            (InsertResult::Split(left, k, v, right), inserted)
         }
     }
 }
 
-impl<Lifetime, K, V> Handle<NodeRef<Lifetime, K, V, marker::Mut, marker::Internal>, marker::Edge> {
+impl<Lifetime, K, V, M: Monoid<K, V>>
+        Handle<NodeRef<Lifetime, K, V, marker::Mut, marker::Internal, M>, marker::Edge> {
+
     fn correct_parent_link(mut self) {
         let idx = self.idx as u16;
         let ptr = self.node.as_internal_mut() as *mut _;
-        let mut child = self.descend();
-        child.as_leaf_mut().parent = ptr;
-        child.as_leaf_mut().parent_idx = idx;
+        let child = self.descend();
+        unsafe {
+            set_parent_link(*child.node, ptr, idx);
+        }
     }
 
     unsafe fn cast_unchecked<NewType>(&mut self)
-            -> Handle<NodeRef<marker::Borrowed, K, V, marker::Mut, NewType>, marker::Edge> {
+            -> Handle<NodeRef<marker::Borrowed, K, V, marker::Mut, NewType, M>, marker::Edge> {
 
         Handle::new(self.node.cast_unchecked(), self.idx)
     }
 
-    unsafe fn insert_unchecked(&mut self, key: K, val: V, edge: Root<K, V>) {
+    // The edge to the left of the new one is assumed to lead to the node that was split to make
+    // it, so its aggregate is recomputed as well.
+    unsafe fn insert_unchecked(&mut self, key: K, val: V, edge: Root<K, V, M>) {
+        let agg = edge.as_ref().summarize();
+
         self.cast_unchecked::<marker::Leaf>().insert_unchecked(key, val);
 
         slice_insert(
@@ -788,14 +1010,33 @@ impl<Lifetime, K, V> Handle<NodeRef<Lifetime, K, V, marker::Mut, marker::Interna
             self.idx + 1,
             edge.node
         );
+        slice_insert(
+            slice::from_raw_parts_mut(
+                self.node.as_internal_mut().aggs.as_mut_ptr(),
+                self.node.len()
+            ),
+            self.idx + 1,
+            agg
+        );
 
         for i in (self.idx+1)..(self.node.len()+1) {
             Handle::new(self.node.reborrow_mut(), i).correct_parent_link();
         }
+        self.refresh_agg();
+    }
+
+    /// Recomputes the aggregate cached for the subtree below this edge, from the aggregates
+    /// cached inside it.
+    pub fn refresh_agg(&mut self) {
+        let agg = self.reborrow().descend().summarize();
+        let idx = self.idx;
+        unsafe {
+            *self.node.as_internal_mut().aggs.get_unchecked_mut(idx) = agg;
+        }
     }
 
-    pub fn insert(mut self, key: K, val: V, edge: Root<K, V>)
-            -> InsertResult<Lifetime, K, V, marker::Internal> {
+    pub fn insert(mut self, key: K, val: V, edge: Root<K, V, M>)
+            -> InsertResult<Lifetime, K, V, marker::Internal, M> {
 
         // Necessary for correctness, but this is an internal module
         debug_assert!(edge.height == self.node.height - 1);
@@ -825,21 +1066,57 @@ impl<Lifetime, K, V> Handle<NodeRef<Lifetime, K, V, marker::Mut, marker::Interna
     }
 }
 
-impl<Lifetime, K, V, Mutability>
-        Handle<NodeRef<Lifetime, K, V, Mutability, marker::Internal>, marker::Edge> {
+impl<Lifetime, K: Clone, V: Clone, M: Monoid<K, V>>
+        Handle<NodeRef<Lifetime, K, V, marker::Mut, marker::Internal, M>, marker::Edge>
+        where M::Summary: Clone {
 
-    pub fn descend(self) -> NodeRef<Lifetime, K, V, Mutability, marker::LeafOrInternal> {
+    /// Makes sure the child below this edge belongs to this tree alone, like `Root::make_unique`,
+    /// and points its parent link back here.
+    pub fn make_unique(&mut self) {
+        let height = self.node.height - 1;
+        let idx = self.idx;
+        unsafe {
+            let edge = self.node.as_internal_mut().edges.get_unchecked_mut(idx);
+            if is_shared(*edge.as_ptr()) {
+                let copy = copy_node(edge.as_ptr(), height);
+                release_node(edge.as_ptr(), height);
+                *edge = copy;
+            }
+            Handle::new(self.node.reborrow_mut(), idx).correct_parent_link();
+        }
+    }
+}
+
+impl<Lifetime, K, V, Mutability, M: Monoid<K, V>>
+        Handle<NodeRef<Lifetime, K, V, Mutability, marker::Internal, M>, marker::Edge> {
+
+    pub fn descend(self) -> NodeRef<Lifetime, K, V, Mutability, marker::LeafOrInternal, M> {
         NodeRef {
             height: self.node.height - 1,
-            node: unsafe { self.node.as_internal().edges.get_unchecked(self.idx).as_ptr() },
+            node: unsafe {
+                let internal = *self.node.node as *const InternalNode<K, V, M>;
+                (*internal).edges.get_unchecked(self.idx).as_ptr()
+            },
             root: self.node.root,
             _marker: PhantomData
         }
     }
 }
 
-impl<'a, K: 'a, V: 'a, Mutability, NodeType>
-        Handle<NodeRef<marker::Borrowed<'a>, K, V, Mutability, NodeType>, marker::KV> {
+impl<'a, K: 'a, V: 'a, Mutability, M: Monoid<K, V>>
+        Handle<NodeRef<marker::Borrowed<'a>, K, V, Mutability, marker::Internal, M>, marker::Edge> {
+
+    /// Returns the aggregate cached for the subtree below this edge.
+    pub fn into_agg(self) -> &'a M::Summary {
+        unsafe {
+            let internal = *self.node.node as *const InternalNode<K, V, M>;
+            &*(*internal).aggs.as_ptr().offset(self.idx as isize)
+        }
+    }
+}
+
+impl<'a, K: 'a, V: 'a, Mutability, NodeType, M: Monoid<K, V>>
+        Handle<NodeRef<marker::Borrowed<'a>, K, V, Mutability, NodeType, M>, marker::KV> {
 
     pub fn into_kv(self) -> (&'a K, &'a V) {
         let (keys, vals) = self.node.into_slices();
@@ -849,8 +1126,8 @@ impl<'a, K: 'a, V: 'a, Mutability, NodeType>
     }
 }
 
-impl<'a, K: 'a, V: 'a, NodeType>
-        Handle<NodeRef<marker::Borrowed<'a>, K, V, marker::Mut, NodeType>, marker::KV> {
+impl<'a, K: 'a, V: 'a, NodeType, M: Monoid<K, V>>
+        Handle<NodeRef<marker::Borrowed<'a>, K, V, marker::Mut, NodeType, M>, marker::KV> {
 
     pub fn into_kv_mut(self) -> (&'a mut K, &'a mut V) {
         let (mut keys, mut vals) = self.node.into_slices_mut();
@@ -858,9 +1135,24 @@ impl<'a, K: 'a, V: 'a, NodeType>
             (keys.get_unchecked_mut(self.idx), vals.get_unchecked_mut(self.idx))
         }
     }
+
+    /// Like `into_kv_mut`, but only lends the key out immutably, and makes both references from
+    /// the addresses of the key and value alone rather than from slices covering the whole node.
+    /// References to different elements of one node obtained this way may therefore be used at
+    /// the same time, even on different threads.
+    pub fn into_kv_valmut(self) -> (&'a K, &'a mut V) {
+        unsafe {
+            let leaf = *self.node.node;
+            let key = (ptr::addr_of!((*leaf).keys) as *const K).offset(self.idx as isize);
+            let val = (ptr::addr_of_mut!((*leaf).vals) as *mut V).offset(self.idx as isize);
+            (&*key, &mut *val)
+        }
+    }
 }
 
-impl<Lifetime, K, V, NodeType> Handle<NodeRef<Lifetime, K, V, marker::Mut, NodeType>, marker::KV> {
+impl<Lifetime, K, V, NodeType, M: Monoid<K, V>>
+        Handle<NodeRef<Lifetime, K, V, marker::Mut, NodeType, M>, marker::KV> {
+
     pub fn kv_mut(&mut self) -> (&mut K, &mut V) {
         unsafe {
             let (mut keys, mut vals) = self.node.reborrow_mut().into_slices_mut();
@@ -869,9 +1161,11 @@ impl<Lifetime, K, V, NodeType> Handle<NodeRef<Lifetime, K, V, marker::Mut, NodeT
     }
 }
 
-impl<Lifetime, K, V> Handle<NodeRef<Lifetime, K, V, marker::Mut, marker::Leaf>, marker::KV> {
+impl<Lifetime, K, V, M: Monoid<K, V>>
+        Handle<NodeRef<Lifetime, K, V, marker::Mut, marker::Leaf, M>, marker::KV> {
+
     pub fn split(mut self)
-            -> (NodeRef<Lifetime, K, V, marker::Mut, marker::Leaf>, K, V, Root<K, V>) {
+            -> (NodeRef<Lifetime, K, V, marker::Mut, marker::Leaf, M>, K, V, Root<K, V, M>) {
         unsafe {
             let mut new_node = Box::new(LeafNode::new());
 
@@ -906,7 +1200,7 @@ impl<Lifetime, K, V> Handle<NodeRef<Lifetime, K, V, marker::Mut, marker::Leaf>,
     }
 
     pub fn remove(mut self)
-            -> (Handle<NodeRef<Lifetime, K, V, marker::Mut, marker::Leaf>, marker::Edge>, K, V) {
+            -> (Handle<NodeRef<Lifetime, K, V, marker::Mut, marker::Leaf, M>, marker::Edge>, K, V) {
         unsafe {
             let k = slice_remove(self.node.keys_mut(), self.idx);
             let v = slice_remove(self.node.vals_mut(), self.idx);
@@ -916,9 +1210,11 @@ impl<Lifetime, K, V> Handle<NodeRef<Lifetime, K, V, marker::Mut, marker::Leaf>,
     }
 }
 
-impl<Lifetime, K, V> Handle<NodeRef<Lifetime, K, V, marker::Mut, marker::Internal>, marker::KV> {
+impl<Lifetime, K, V, M: Monoid<K, V>>
+        Handle<NodeRef<Lifetime, K, V, marker::Mut, marker::Internal, M>, marker::KV> {
+
     pub fn split(mut self)
-            -> (NodeRef<Lifetime, K, V, marker::Mut, marker::Internal>, K, V, Root<K, V>) {
+            -> (NodeRef<Lifetime, K, V, marker::Mut, marker::Internal, M>, K, V, Root<K, V, M>) {
         unsafe {
             let mut new_node = Box::new(InternalNode::new());
 
@@ -943,6 +1239,11 @@ impl<Lifetime, K, V> Handle<NodeRef<Lifetime, K, V, marker::Mut, marker::Interna
                 new_node.edges.as_mut_ptr(),
                 new_len + 1
             );
+            ptr::copy_nonoverlapping(
+                self.node.as_internal().aggs.as_ptr().offset(self.idx as isize + 1),
+                new_node.aggs.as_mut_ptr(),
+                new_len + 1
+            );
 
             self.node.as_leaf_mut().len = self.idx as u16;
             new_node.data.len = new_len as u16;
@@ -979,7 +1280,7 @@ impl<Lifetime, K, V> Handle<NodeRef<Lifetime, K, V, marker::Mut, marker::Interna
     }
 
     pub fn merge(mut self)
-            -> Handle<NodeRef<Lifetime, K, V, marker::Mut, marker::Internal>, marker::Edge> {
+            -> Handle<NodeRef<Lifetime, K, V, marker::Mut, marker::Internal, M>, marker::Edge> {
         let self1 = unsafe { ptr::read(&self) };
         let self2 = unsafe { ptr::read(&self) };
         let mut left_node = self1.left_edge().descend();
@@ -991,15 +1292,15 @@ impl<Lifetime, K, V> Handle<NodeRef<Lifetime, K, V, marker::Mut, marker::Interna
         debug_assert!(left_len + right_len + 1 <= left_node.capacity());
 
         unsafe {
-            *left_node.keys_mut().get_unchecked_mut(left_len)
-                = slice_remove(self.node.keys_mut(), self.idx);
+            ptr::write(left_node.keys_mut().get_unchecked_mut(left_len),
+                       slice_remove(self.node.keys_mut(), self.idx));
             ptr::copy_nonoverlapping(
                 right_node.keys().as_ptr(),
                 left_node.keys_mut().as_mut_ptr().offset(left_len as isize + 1),
                 right_len
             );
-            *left_node.vals_mut().get_unchecked_mut(left_len)
-                = slice_remove(self.node.vals_mut(), self.idx);
+            ptr::write(left_node.vals_mut().get_unchecked_mut(left_len),
+                       slice_remove(self.node.vals_mut(), self.idx));
             ptr::copy_nonoverlapping(
                 right_node.vals().as_ptr(),
                 left_node.vals_mut().as_mut_ptr().offset(left_len as isize + 1),
@@ -1007,6 +1308,7 @@ impl<Lifetime, K, V> Handle<NodeRef<Lifetime, K, V, marker::Mut, marker::Interna
             );
 
             slice_remove(&mut self.node.as_internal_mut().edges, self.idx + 1);
+            mem::drop(slice_remove(&mut self.node.as_internal_mut().aggs, self.idx + 1));
             for i in self.idx+1..self.node.len() {
                 Handle::new(self.node.reborrow_mut(), i).correct_parent_link();
             }
@@ -1022,6 +1324,15 @@ impl<Lifetime, K, V> Handle<NodeRef<Lifetime, K, V, marker::Mut, marker::Interna
                              .offset(left_len as isize + 1),
                     right_len + 1
                 );
+                ptr::copy_nonoverlapping(
+                    right_node.cast_unchecked().as_internal().aggs.as_ptr(),
+                    left_node.cast_unchecked()
+                             .as_internal_mut()
+                             .aggs
+                             .as_mut_ptr()
+                             .offset(left_len as isize + 1),
+                    right_len + 1
+                );
 
                 for i in left_len+1..left_len+right_len+2 {
                     Handle::new(left_node.cast_unchecked().reborrow_mut(), i).correct_parent_link();
@@ -1029,30 +1340,32 @@ impl<Lifetime, K, V> Handle<NodeRef<Lifetime, K, V, marker::Mut, marker::Interna
 
                 heap::deallocate(
                     *right_node.node as *mut u8,
-                    mem::size_of::<InternalNode<K, V>>(),
-                    mem::align_of::<InternalNode<K, V>>()
+                    mem::size_of::<InternalNode<K, V, M>>(),
+                    mem::align_of::<InternalNode<K, V, M>>()
                 );
             } else {
                 heap::deallocate(
                     *right_node.node as *mut u8,
-                    mem::size_of::<LeafNode<K, V>>(),
-                    mem::align_of::<LeafNode<K, V>>()
+                    mem::size_of::<LeafNode<K, V, M>>(),
+                    mem::align_of::<LeafNode<K, V, M>>()
                 );
             }
 
             left_node.as_leaf_mut().len += right_len as u16 + 1;
 
-            Handle::new(self.node, self.idx)
+            let mut merged = Handle::new(self.node, self.idx);
+            merged.refresh_agg();
+            merged
         }
     }
 }
 
-impl<Lifetime, K, V, Mutability, HandleType>
-        Handle<NodeRef<Lifetime, K, V, Mutability, marker::LeafOrInternal>, HandleType> {
+impl<Lifetime, K, V, Mutability, HandleType, M: Monoid<K, V>>
+        Handle<NodeRef<Lifetime, K, V, Mutability, marker::LeafOrInternal, M>, HandleType> {
 
     pub fn force(self) -> ForceResult<
-        Handle<NodeRef<Lifetime, K, V, Mutability, marker::Leaf>, HandleType>,
-        Handle<NodeRef<Lifetime, K, V, Mutability, marker::Internal>, HandleType>
+        Handle<NodeRef<Lifetime, K, V, Mutability, marker::Leaf, M>, HandleType>,
+        Handle<NodeRef<Lifetime, K, V, Mutability, marker::Internal, M>, HandleType>
     > {
         match self.node.force() {
             ForceResult::Leaf(node) => ForceResult::Leaf(Handle {
@@ -1074,9 +1387,9 @@ pub enum ForceResult<Leaf, Internal> {
     Internal(Internal)
 }
 
-pub enum InsertResult<Lifetime, K, V, Type> {
-    Fit(Handle<NodeRef<Lifetime, K, V, marker::Mut, Type>, marker::KV>),
-    Split(NodeRef<Lifetime, K, V, marker::Mut, Type>, K, V, Root<K, V>)
+pub enum InsertResult<Lifetime, K, V, Type, M: Monoid<K, V> = NoAggregate> {
+    Fit(Handle<NodeRef<Lifetime, K, V, marker::Mut, Type, M>, marker::KV>),
+    Split(NodeRef<Lifetime, K, V, marker::Mut, Type, M>, K, V, Root<K, V, M>)
 }
 
 pub mod marker {
@@ -1096,6 +1409,88 @@ pub mod marker {
     pub enum Owned { }
 }
 
+unsafe fn is_shared<K, V, M: Monoid<K, V>>(node: *const LeafNode<K, V, M>) -> bool {
+    (*node).refs.load(Ordering::Acquire) != 1
+}
+
+// Points a node's parent link at `parent`, unless the node is shared.
+unsafe fn set_parent_link<K, V, M: Monoid<K, V>>(node: *mut LeafNode<K, V, M>,
+                                                 parent: *mut InternalNode<K, V, M>,
+                                                 idx: u16) {
+    if !is_shared(node) {
+        (*node).parent = parent;
+        (*node).parent_idx = idx;
+    }
+}
+
+// Makes a new node holding clones of the elements of `node`, and sharing all of its children.
+unsafe fn copy_node<K: Clone, V: Clone, M: Monoid<K, V>>(node: NonZero<*mut LeafNode<K, V, M>>,
+                                                        height: usize) -> BoxedNode<K, V, M>
+        where M::Summary: Clone {
+    let len = (**node).len as usize;
+
+    // The copy is only boxed up while it is still empty, so that a panicking `clone` leaks it
+    // rather than dropping uninitialized elements.
+    let copy = if height == 0 {
+        BoxedNode::from_leaf(Box::new(LeafNode::new()))
+    } else {
+        let copy = BoxedNode::from_internal(Box::new(InternalNode::new()));
+        let from = *node as *const InternalNode<K, V, M>;
+        let to = *copy.as_ptr() as *mut InternalNode<K, V, M>;
+        for i in 0..len + 1 {
+            let edge = (*from).edges.get_unchecked(i).as_ptr();
+            (**edge).refs.fetch_add(1, Ordering::Relaxed);
+            ptr::write((*to).edges.get_unchecked_mut(i), BoxedNode::from_ptr(edge));
+            ptr::write((*to).aggs.get_unchecked_mut(i), (*from).aggs.get_unchecked(i).clone());
+        }
+        copy
+    };
+
+    let to = *copy.as_ptr();
+    for i in 0..len {
+        ptr::write((*to).keys.get_unchecked_mut(i), (**node).keys.get_unchecked(i).clone());
+        ptr::write((*to).vals.get_unchecked_mut(i), (**node).vals.get_unchecked(i).clone());
+    }
+    (*to).len = len as u16;
+
+    copy
+}
+
+// Gives up one reference to `node`. If that was the last one, this drops its elements, releases
+// its children and frees it.
+unsafe fn release_node<K, V, M: Monoid<K, V>>(node: NonZero<*mut LeafNode<K, V, M>>,
+                                              height: usize) {
+    if (**node).refs.fetch_sub(1, Ordering::Release) != 1 {
+        return;
+    }
+    atomic::fence(Ordering::Acquire);
+
+    let len = (**node).len as usize;
+    for i in 0..len {
+        mem::drop(ptr::read((**node).keys.get_unchecked(i)));
+        mem::drop(ptr::read((**node).vals.get_unchecked(i)));
+    }
+
+    if height == 0 {
+        heap::deallocate(
+            *node as *mut u8,
+            mem::size_of::<LeafNode<K, V, M>>(),
+            mem::align_of::<LeafNode<K, V, M>>()
+        );
+    } else {
+        let internal = *node as *mut InternalNode<K, V, M>;
+        for i in 0..len + 1 {
+            release_node((*internal).edges.get_unchecked(i).as_ptr(), height - 1);
+            mem::drop(ptr::read((*internal).aggs.get_unchecked(i)));
+        }
+        heap::deallocate(
+            internal as *mut u8,
+            mem::size_of::<InternalNode<K, V, M>>(),
+            mem::align_of::<InternalNode<K, V, M>>()
+        );
+    }
+}
+
 unsafe fn slice_insert<T>(slice: &mut [T], idx: usize, val: T) {
     ptr::copy(
         slice.as_ptr().offset(idx as isize),
@@ -0,0 +1,683 @@
+// Copyright 2015 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// A B-Tree whose nodes live in the pages of a file, enabled by the `paged` feature.
+//
+// Each node occupies exactly one page and is encoded with the `Encode` and `Decode` traits from
+// the `encoding` module: a byte saying whether the node is a leaf, its length as a `u16`, the
+// page numbers of its children if it has any, and then its keys and values. Nodes are decoded
+// into an ordinary `Node` whenever they are needed and encoded back when they change, with the
+// pager keeping the recently used pages in memory.
+//
+// A page can only be reached from its parent, so nodes do not record their parents and the
+// algorithms are recursive, fixing up each level on the way back out. The decoded nodes are those
+// of `vec_node.rs`, with page numbers as their edges. To guarantee that a full node always fits in
+// its page, the encoded size of each key-value pair is limited to `MAX_ENTRY_SIZE`.
+//
+// Every `insert` and `remove` is recorded in a write-ahead log kept in a second file, next to the
+// first, before it returns. The map's own file only changes at checkpoints, when the pager copies
+// all modified pages into it by way of the log, so after a crash the file can always be brought
+// back to the last checkpoint and the operations logged since replayed on top of it. Since no
+// page reaches the file without going through a checkpoint, it is enough to log an operation
+// after applying it in memory, which lets `remove` log the key it actually removed.
+
+use core::cell::RefCell;
+use core::marker::PhantomData;
+use core::mem;
+use std::fs::OpenOptions;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use collections::borrow::Borrow;
+use collections::Bound::{self, Included, Excluded, Unbounded};
+use collections::vec;
+
+use super::encoding::{Decode, DecodeError, Encode, Reader};
+use super::node::{CAPACITY, MIN_LEN};
+use super::search::search_linear;
+use super::vec_node::{self, can_merge, steal_left, steal_right};
+
+use self::pager::{Meta, Pager, PageId};
+use self::wal::Wal;
+use self::InsertResult::*;
+
+mod pager;
+mod wal;
+
+pub use self::pager::PAGE_SIZE;
+
+// One byte saying whether the node is a leaf, then the length as a `u16`
+const NODE_HEADER_SIZE: usize = 3;
+
+/// The largest encoded size, in bytes, of a key and its value together. Larger entries are
+/// rejected by `PagedMap::insert`.
+pub const MAX_ENTRY_SIZE: usize = (PAGE_SIZE - NODE_HEADER_SIZE - 8 * (CAPACITY + 1)) / CAPACITY;
+
+// The first byte of each logged operation
+const OP_INSERT: u8 = 0;
+const OP_REMOVE: u8 = 1;
+
+const DEFAULT_CHECKPOINT_INTERVAL: usize = 1000;
+
+type Node<K, V> = vec_node::Node<K, V, PageId>;
+
+impl<K: Encode, V: Encode> Node<K, V> {
+    fn encode(&self, out: &mut Vec<u8>) {
+        (!self.is_leaf()).encode(out);
+        (self.len() as u16).encode(out);
+        for edge in &self.edges {
+            edge.encode(out);
+        }
+        for (key, val) in self.keys.iter().zip(&self.vals) {
+            key.encode(out);
+            val.encode(out);
+        }
+    }
+}
+
+impl<K: Decode, V: Decode> Node<K, V> {
+    fn decode(reader: &mut Reader) -> Result<Node<K, V>, DecodeError> {
+        let internal = try!(bool::decode(reader));
+        let len = try!(u16::decode(reader)) as usize;
+        let mut node = if internal { Node::new_internal() } else { Node::new_leaf() };
+        if internal {
+            for _ in 0..len + 1 {
+                node.edges.push(try!(u64::decode(reader)));
+            }
+        }
+        for _ in 0..len {
+            node.keys.push(try!(K::decode(reader)));
+            node.vals.push(try!(V::decode(reader)));
+        }
+        Ok(node)
+    }
+}
+
+enum InsertResult<K, V> {
+    Replaced(V),
+    Fit,
+    Split(K, V, PageId)
+}
+
+/// A map based on a B-Tree stored in a file.
+///
+/// Only the pages of the file that are actually in use are kept in memory, up to a fixed number
+/// of them chosen when the map is created or opened. Whenever more are needed, the least recently
+/// used page that has not been modified is evicted. The map is therefore limited by the size of
+/// the disk rather than the size of memory, at the cost of every operation possibly having to do
+/// I/O, which is why they all return `io::Result`.
+///
+/// Keys and values are stored in their `Encode` representation and decoded again when read, so
+/// lookups return owned values rather than references. No key-value pair may take more than
+/// `MAX_ENTRY_SIZE` bytes when encoded.
+///
+/// Every `insert` and `remove` is appended to a write-ahead log, kept in a file with `.wal` added
+/// to the map's path, and by default synced to disk before it returns. Modified pages are only
+/// written to the map's file at checkpoints, which happen every thousand operations, whenever
+/// the cache fills up with modified pages, on a call to `flush`, and, ignoring any errors, when
+/// the map is dropped. Opening a map that was not closed cleanly replays the log, restoring it
+/// to how it was after the last operation whose record reached the disk.
+///
+/// # Examples
+///
+/// ```
+/// use btree_rewrite::paged::PagedMap;
+/// use btree_rewrite::Bound::{Included, Unbounded};
+///
+/// let path = std::env::temp_dir().join("btree_rewrite_paged_doc_example");
+///
+/// {
+///     let mut map = PagedMap::create(&path, 16).unwrap();
+///     for i in 0..1000u32 {
+///         map.insert(i, format!("value {}", i)).unwrap();
+///     }
+///     assert_eq!(map.remove(&500).unwrap(), Some("value 500".to_string()));
+///     map.flush().unwrap();
+/// }
+///
+/// let map = PagedMap::<u32, String>::open(&path, 16).unwrap();
+/// assert_eq!(map.len(), 999);
+/// assert_eq!(map.get(&7).unwrap(), Some("value 7".to_string()));
+/// let keys: Vec<u32> = map.range(Included(&498), Unbounded).unwrap()
+///                         .take(3).map(|entry| entry.unwrap().0).collect();
+/// assert_eq!(keys, [498, 499, 501]);
+/// # std::fs::remove_file(&path).unwrap();
+/// # std::fs::remove_file(path.with_extension("wal")).unwrap();
+/// ```
+pub struct PagedMap<K: Encode, V: Encode> {
+    pager: RefCell<Pager>,
+    root: PageId,
+    length: usize,
+    // The number of operations logged since the last checkpoint
+    logged: usize,
+    checkpoint_interval: usize,
+    marker: PhantomData<(K, V)>
+}
+
+fn decode_error(err: DecodeError) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err)
+}
+
+fn wal_path(path: &Path) -> PathBuf {
+    let mut wal_path = path.as_os_str().to_owned();
+    wal_path.push(".wal");
+    PathBuf::from(wal_path)
+}
+
+impl<K: Ord + Encode + Decode, V: Encode + Decode> PagedMap<K, V> {
+    /// Creates a new empty map in the file at `path`, replacing the file and its log if they
+    /// already exist. At most `cache_pages` pages of the file are kept in memory at once.
+    pub fn create<P: AsRef<Path>>(path: P, cache_pages: usize) -> io::Result<PagedMap<K, V>> {
+        let path = path.as_ref();
+        let file = try!(OpenOptions::new().read(true)
+                                          .write(true)
+                                          .create(true)
+                                          .truncate(true)
+                                          .open(path));
+        let wal = try!(Wal::create(&wal_path(path)));
+        let mut pager = Pager::create(file, wal, cache_pages);
+        let root = try!(pager.allocate());
+
+        let mut map = PagedMap {
+            pager: RefCell::new(pager),
+            root: root,
+            length: 0,
+            logged: 0,
+            checkpoint_interval: DEFAULT_CHECKPOINT_INTERVAL,
+            marker: PhantomData
+        };
+        map.store(root, &Node::new_leaf());
+        try!(map.flush());
+        Ok(map)
+    }
+
+    /// Opens a map previously created in the file at `path`. At most `cache_pages` pages of the
+    /// file are kept in memory at once.
+    ///
+    /// If the map was not closed cleanly, this recovers it from its log, bringing it back to how
+    /// it was after the last operation that was completely logged, and then checkpoints.
+    ///
+    /// The keys and values must be of the same types the map was created with; this is not
+    /// checked.
+    pub fn open<P: AsRef<Path>>(path: P, cache_pages: usize) -> io::Result<PagedMap<K, V>> {
+        let path = path.as_ref();
+        let file = try!(OpenOptions::new().read(true).write(true).open(path));
+        let wal = try!(Wal::open(&wal_path(path)));
+        let (pager, meta, ops) = try!(Pager::open(file, wal, cache_pages));
+
+        let mut map = PagedMap {
+            pager: RefCell::new(pager),
+            root: meta.root,
+            length: meta.length as usize,
+            logged: 0,
+            checkpoint_interval: DEFAULT_CHECKPOINT_INTERVAL,
+            marker: PhantomData
+        };
+        if !ops.is_empty() {
+            for op in &ops {
+                try!(map.replay(op));
+            }
+            try!(map.flush());
+        }
+        Ok(map)
+    }
+
+    fn replay(&mut self, op: &[u8]) -> io::Result<()> {
+        let mut reader = Reader::new(op);
+        match try!(u8::decode(&mut reader).map_err(decode_error)) {
+            OP_INSERT => {
+                let key = try!(K::decode(&mut reader).map_err(decode_error));
+                let value = try!(V::decode(&mut reader).map_err(decode_error));
+                try!(self.insert_unlogged(key, value));
+            },
+            OP_REMOVE => {
+                let key = try!(K::decode(&mut reader).map_err(decode_error));
+                try!(self.remove_unlogged(&key));
+            },
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "unknown logged operation"))
+        }
+        Ok(())
+    }
+
+    /// Returns the value corresponding to the key.
+    pub fn get<Q: ?Sized>(&self, key: &Q) -> io::Result<Option<V>> where K: Borrow<Q>, Q: Ord {
+        let mut id = self.root;
+        loop {
+            let mut node = try!(self.load(id));
+            match search_linear(&node.keys, key) {
+                (idx, true) => return Ok(Some(node.vals.swap_remove(idx))),
+                (_, false) if node.is_leaf() => return Ok(None),
+                (idx, false) => id = node.edges[idx]
+            }
+        }
+    }
+
+    /// Returns true if the map contains a value for the specified key.
+    pub fn contains_key<Q: ?Sized>(&self, key: &Q) -> io::Result<bool>
+            where K: Borrow<Q>, Q: Ord {
+        self.get(key).map(|val| val.is_some())
+    }
+
+    /// Inserts a key-value pair into the map.
+    ///
+    /// If the map did not have this key present, `None` is returned. If the map did have this key
+    /// present, the key is not updated, the value is updated and the old value is returned.
+    ///
+    /// Fails with `InvalidInput`, leaving the map unchanged, if the key and value together take
+    /// more than `MAX_ENTRY_SIZE` bytes to encode.
+    pub fn insert(&mut self, key: K, value: V) -> io::Result<Option<V>> {
+        let mut op = vec![OP_INSERT];
+        key.encode(&mut op);
+        value.encode(&mut op);
+        if op.len() - 1 > MAX_ENTRY_SIZE {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "entry too large to store in a page"));
+        }
+
+        let old = try!(self.insert_unlogged(key, value));
+        try!(self.log(&op));
+        Ok(old)
+    }
+
+    fn insert_unlogged(&mut self, key: K, value: V) -> io::Result<Option<V>> {
+        let root = self.root;
+        match try!(self.insert_into(root, key, value)) {
+            Replaced(old) => Ok(Some(old)),
+            Fit => {
+                self.length += 1;
+                Ok(None)
+            },
+            Split(k, v, right) => {
+                let mut new_root = Node::new_internal();
+                new_root.keys.push(k);
+                new_root.vals.push(v);
+                new_root.edges.push(root);
+                new_root.edges.push(right);
+                let id = try!(self.pager.borrow_mut().allocate());
+                self.store(id, &new_root);
+                self.root = id;
+                self.length += 1;
+                Ok(None)
+            }
+        }
+    }
+
+    /// Removes a key from the map, returning the value at the key if the key
+    /// was previously in the map.
+    pub fn remove<Q: ?Sized>(&mut self, key: &Q) -> io::Result<Option<V>>
+            where K: Borrow<Q>, Q: Ord {
+        match try!(self.remove_unlogged(key)) {
+            Some((key, val)) => {
+                let mut op = vec![OP_REMOVE];
+                key.encode(&mut op);
+                try!(self.log(&op));
+                Ok(Some(val))
+            },
+            None => Ok(None)
+        }
+    }
+
+    fn remove_unlogged<Q: ?Sized>(&mut self, key: &Q) -> io::Result<Option<(K, V)>>
+            where K: Borrow<Q>, Q: Ord {
+        let root = self.root;
+        let kv = match try!(self.remove_from(root, key)) {
+            Some(kv) => kv,
+            None => return Ok(None)
+        };
+        self.length -= 1;
+
+        let node = try!(self.load(root));
+        if node.len() == 0 && !node.is_leaf() {
+            // The root was emptied by a merge, so its only child takes over.
+            self.root = node.edges[0];
+            self.pager.borrow_mut().free(root);
+        }
+
+        Ok(Some(kv))
+    }
+
+    /// Constructs an iterator over a sub-range of elements in the map, in order by key. The
+    /// entries are read from the file lazily, as the iterator advances. If `min` is after `max`,
+    /// the iterator is empty.
+    ///
+    /// Since reading the file can fail, the iterator yields `io::Result`s. After yielding an
+    /// error, it stops.
+    pub fn range<'a, Q: ?Sized = K>(&'a self, min: Bound<&Q>, max: Bound<&'a Q>)
+            -> io::Result<Range<'a, K, V, Q>> where K: Borrow<Q>, Q: Ord {
+        let mut range = Range {
+            map: self,
+            stack: Vec::new(),
+            max: max,
+            done: false
+        };
+
+        let mut id = self.root;
+        loop {
+            let node = try!(self.load(id));
+            let idx = match min {
+                Included(key) => search_linear(&node.keys, key).0,
+                Excluded(key) => match search_linear(&node.keys, key) {
+                    (idx, true) => idx + 1,
+                    (idx, false) => idx
+                },
+                Unbounded => 0
+            };
+
+            let mut frame = Frame::new(node);
+            frame.skip(idx);
+            let child = frame.edges.next();
+            range.stack.push(frame);
+            match child {
+                Some(child) => id = child,
+                None => return Ok(range)
+            }
+        }
+    }
+
+    /// Checkpoints, writing all changes to the map's file and waiting for them to reach the
+    /// disk, so that the log is no longer needed to recover them.
+    pub fn flush(&mut self) -> io::Result<()> {
+        try!(self.pager.borrow_mut().flush(&Meta {
+            root: self.root,
+            length: self.length as u64
+        }));
+        self.logged = 0;
+        Ok(())
+    }
+
+    /// Sets whether each operation waits for its log record to be synced to disk before
+    /// returning. This is on by default. Turning it off makes operations much faster, but a crash
+    /// of the whole system, rather than just the program, may then lose the operations made
+    /// since the last checkpoint. The map is still recovered to a consistent state.
+    pub fn set_sync_log(&mut self, sync: bool) {
+        self.pager.borrow_mut().wal().set_sync(sync);
+    }
+
+    /// Sets the number of operations after which the map checkpoints, which is 1000 by default.
+    /// More frequent checkpoints keep the log shorter, and so recovery faster, at the cost of
+    /// writing pages to the file more often.
+    pub fn set_checkpoint_interval(&mut self, ops: usize) {
+        self.checkpoint_interval = ops;
+    }
+
+    /// Returns the number of pages of the file currently held in memory.
+    pub fn cached_pages(&self) -> usize {
+        self.pager.borrow().cached()
+    }
+
+    fn load(&self, id: PageId) -> io::Result<Node<K, V>> {
+        let mut pager = self.pager.borrow_mut();
+        let page = try!(pager.read(id));
+        Node::decode(&mut Reader::new(page)).map_err(decode_error)
+    }
+
+    fn store(&self, id: PageId, node: &Node<K, V>) {
+        let mut page = Vec::with_capacity(PAGE_SIZE);
+        node.encode(&mut page);
+        self.pager.borrow_mut().write(id, page);
+    }
+
+    fn log(&mut self, op: &[u8]) -> io::Result<()> {
+        try!(self.pager.borrow_mut().wal().append_op(op));
+        self.logged += 1;
+        if self.logged >= self.checkpoint_interval || self.pager.borrow().is_full() {
+            try!(self.flush());
+        }
+        Ok(())
+    }
+
+    fn insert_into(&mut self, id: PageId, key: K, value: V) -> io::Result<InsertResult<K, V>> {
+        let mut node = try!(self.load(id));
+
+        let idx = match search_linear(&node.keys, &key) {
+            (idx, true) => {
+                let old = mem::replace(&mut node.vals[idx], value);
+                self.store(id, &node);
+                return Ok(Replaced(old));
+            },
+            (idx, false) => idx
+        };
+
+        if node.is_leaf() {
+            node.keys.insert(idx, key);
+            node.vals.insert(idx, value);
+        } else {
+            match try!(self.insert_into(node.edges[idx], key, value)) {
+                Split(k, v, right) => {
+                    node.keys.insert(idx, k);
+                    node.vals.insert(idx, v);
+                    node.edges.insert(idx + 1, right);
+                },
+                result => return Ok(result)
+            }
+        }
+
+        if node.len() <= CAPACITY {
+            self.store(id, &node);
+            return Ok(Fit);
+        }
+
+        let (k, v, right) = node.split();
+        let right_id = try!(self.pager.borrow_mut().allocate());
+        self.store(id, &node);
+        self.store(right_id, &right);
+        Ok(Split(k, v, right_id))
+    }
+
+    fn remove_from<Q: ?Sized>(&mut self, id: PageId, key: &Q) -> io::Result<Option<(K, V)>>
+            where K: Borrow<Q>, Q: Ord {
+        let mut node = try!(self.load(id));
+
+        let ret = match search_linear(&node.keys, key) {
+            (idx, true) => if node.is_leaf() {
+                (node.keys.remove(idx), node.vals.remove(idx))
+            } else {
+                // Swap in the successor, which is always in a leaf.
+                let (k, v) = try!(self.remove_first(node.edges[idx + 1]));
+                let old_key = mem::replace(&mut node.keys[idx], k);
+                let old_val = mem::replace(&mut node.vals[idx], v);
+                try!(self.handle_underfull_child(&mut node, idx + 1));
+                (old_key, old_val)
+            },
+            (_, false) if node.is_leaf() => return Ok(None),
+            (idx, false) => match try!(self.remove_from(node.edges[idx], key)) {
+                Some(ret) => {
+                    try!(self.handle_underfull_child(&mut node, idx));
+                    ret
+                },
+                // Nothing changed, so there is nothing to write back
+                None => return Ok(None)
+            }
+        };
+
+        self.store(id, &node);
+        Ok(Some(ret))
+    }
+
+    fn remove_first(&mut self, id: PageId) -> io::Result<(K, V)> {
+        let mut node = try!(self.load(id));
+
+        let ret = if node.is_leaf() {
+            (node.keys.remove(0), node.vals.remove(0))
+        } else {
+            let ret = try!(self.remove_first(node.edges[0]));
+            try!(self.handle_underfull_child(&mut node, 0));
+            ret
+        };
+
+        self.store(id, &node);
+        Ok(ret)
+    }
+
+    /// Restores the minimum length of the child at `idx`, if necessary, by either merging it with
+    /// a sibling or stealing an element from one. The left sibling is preferred, just as in
+    /// `map::handle_underfull_node`. The caller is responsible for storing `node` afterwards.
+    fn handle_underfull_child(&mut self, node: &mut Node<K, V>, idx: usize) -> io::Result<()> {
+        // Most removals leave the child with enough elements, so check it before reading the
+        // sibling's page.
+        let child = try!(self.load(node.edges[idx]));
+        if child.len() >= MIN_LEN {
+            return Ok(());
+        }
+
+        let (is_left, kv_idx) = if idx > 0 { (true, idx - 1) } else { (false, idx) };
+        let (left_id, right_id) = (node.edges[kv_idx], node.edges[kv_idx + 1]);
+        let (mut left, mut right) = if is_left {
+            (try!(self.load(left_id)), child)
+        } else {
+            (child, try!(self.load(right_id)))
+        };
+
+        if can_merge(&left, &right) {
+            let k = node.keys.remove(kv_idx);
+            let v = node.vals.remove(kv_idx);
+            node.edges.remove(kv_idx + 1);
+            left.merge(k, v, right);
+            self.store(left_id, &left);
+            self.pager.borrow_mut().free(right_id);
+            return Ok(());
+        }
+
+        if is_left {
+            steal_left(&mut node.keys[kv_idx], &mut node.vals[kv_idx], &mut left, &mut right);
+        } else {
+            steal_right(&mut node.keys[kv_idx], &mut node.vals[kv_idx], &mut left, &mut right);
+        }
+
+        self.store(left_id, &left);
+        self.store(right_id, &right);
+        Ok(())
+    }
+}
+
+impl<K: Encode, V: Encode> PagedMap<K, V> {
+    /// Returns the number of elements in the map.
+    pub fn len(&self) -> usize {
+        self.length
+    }
+
+    /// Returns true if the map contains no elements.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<K: Encode, V: Encode> Drop for PagedMap<K, V> {
+    fn drop(&mut self) {
+        let meta = Meta {
+            root: self.root,
+            length: self.length as u64
+        };
+        let _ = self.pager.borrow_mut().flush(&meta);
+    }
+}
+
+// A node on the path to the current position of a `Range`, with the elements and children that
+// have already been passed over removed from the front.
+struct Frame<K, V> {
+    keys: vec::IntoIter<K>,
+    vals: vec::IntoIter<V>,
+    edges: vec::IntoIter<PageId>
+}
+
+impl<K, V> Frame<K, V> {
+    fn new(node: Node<K, V>) -> Frame<K, V> {
+        Frame {
+            keys: node.keys.into_iter(),
+            vals: node.vals.into_iter(),
+            edges: node.edges.into_iter()
+        }
+    }
+
+    fn skip(&mut self, n: usize) {
+        for _ in 0..n {
+            self.keys.next();
+            self.vals.next();
+            self.edges.next();
+        }
+    }
+}
+
+/// An iterator over a sub-range of a PagedMap's entries.
+pub struct Range<'a, K: Encode + 'a, V: Encode + 'a, Q: ?Sized + 'a> {
+    map: &'a PagedMap<K, V>,
+    // The path from the root to the current position. In each frame, the next key is the one
+    // just after the current position and the next edge is the one just after that key. If the
+    // stack is empty, there are no more entries.
+    stack: Vec<Frame<K, V>>,
+    max: Bound<&'a Q>,
+    done: bool
+}
+
+impl<'a, K, V, Q: ?Sized> Range<'a, K, V, Q>
+        where K: Ord + Encode + Decode + Borrow<Q>, V: Encode + Decode, Q: Ord {
+    // Moves to the next entry, descending to the leftmost leaf of the subtree after it.
+    fn advance(&mut self) -> io::Result<Option<(K, V)>> {
+        loop {
+            let (kv, child) = match self.stack.last_mut() {
+                Some(frame) => (frame.keys.next().map(|k| (k, frame.vals.next().unwrap())),
+                                frame.edges.next()),
+                None => return Ok(None)
+            };
+
+            let kv = match kv {
+                Some(kv) => kv,
+                None => {
+                    self.stack.pop();
+                    continue;
+                }
+            };
+
+            let mut child = child;
+            while let Some(id) = child {
+                let mut frame = Frame::new(try!(self.map.load(id)));
+                child = frame.edges.next();
+                self.stack.push(frame);
+            }
+
+            return Ok(Some(kv));
+        }
+    }
+}
+
+impl<'a, K, V, Q: ?Sized> Iterator for Range<'a, K, V, Q>
+        where K: Ord + Encode + Decode + Borrow<Q>, V: Encode + Decode, Q: Ord {
+    type Item = io::Result<(K, V)>;
+
+    fn next(&mut self) -> Option<io::Result<(K, V)>> {
+        if self.done {
+            return None;
+        }
+
+        match self.advance() {
+            Ok(Some((k, v))) => {
+                let within = match self.max {
+                    Included(max) => k.borrow() <= max,
+                    Excluded(max) => k.borrow() < max,
+                    Unbounded => true
+                };
+                if within {
+                    Some(Ok((k, v)))
+                } else {
+                    self.done = true;
+                    None
+                }
+            },
+            Ok(None) => {
+                self.done = true;
+                None
+            },
+            Err(err) => {
+                self.done = true;
+                Some(Err(err))
+            }
+        }
+    }
+}
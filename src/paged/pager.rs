@@ -0,0 +1,337 @@
+// Copyright 2015 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// The file underneath a `PagedMap`, divided into pages of `PAGE_SIZE` bytes and read and written
+// through a fixed number of in-memory frames.
+//
+// Page 0 is the header, which records the layout of the file along with the root and length of
+// the tree. Every other page is either a node or on the free list, which is threaded through the
+// first eight bytes of each free page and reused before the file is grown.
+//
+// The file is only ever written by a checkpoint, which first puts the images of every dirty page
+// and of the new header in the write-ahead log and only then copies them into the file. Until a
+// checkpoint, modified pages stay in memory, so the file always holds the tree as of the last
+// checkpoint, or can be brought there by copying the pages from the log again if a checkpoint was
+// interrupted. The operations logged since then are replayed by the map itself.
+//
+// When every frame is in use, loading another page evicts whichever clean page was used least
+// recently. Dirty pages cannot be evicted, so if there are no clean ones the cache grows past its
+// capacity until the map next checkpoints. Recency is tracked with a `BTreeMap` from the time of
+// each frame's last use to its page, so finding the victim is a matter of walking from the front.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+use super::super::encoding::{Decode, DecodeError, Encode, Reader};
+use super::super::map::BTreeMap;
+use super::wal::{Record, Wal};
+
+/// The identifier of a page, which is its index within the file.
+pub type PageId = u64;
+
+/// The size in bytes of every page in the file.
+pub const PAGE_SIZE: usize = 4096;
+
+const MAGIC: &'static [u8] = b"BTRP";
+const VERSION: u8 = 2;
+
+struct Frame {
+    data: Vec<u8>,
+    dirty: bool,
+    last_used: u64
+}
+
+pub struct Pager {
+    file: File,
+    wal: Wal,
+    // The number of pages in the file, counting the header and any pages not yet checkpointed
+    page_count: u64,
+    // The first page of the free list, or 0 if it is empty
+    free_head: PageId,
+    // The header as of the last checkpoint
+    header: Vec<u8>,
+    frames: HashMap<PageId, Frame>,
+    dirty: usize,
+    lru: BTreeMap<u64, PageId>,
+    clock: u64,
+    capacity: usize
+}
+
+/// What the header says about the tree stored in the file.
+pub struct Meta {
+    pub root: PageId,
+    pub length: u64
+}
+
+fn invalid_data(msg: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn read_header(header: &[u8]) -> Result<(&[u8], u8, u32, u64, u64, u64, u64), DecodeError> {
+    let mut reader = Reader::new(header);
+    let magic = try!(reader.read_bytes(MAGIC.len()));
+    let version = try!(u8::decode(&mut reader));
+    let page_size = try!(u32::decode(&mut reader));
+    let page_count = try!(u64::decode(&mut reader));
+    let free_head = try!(u64::decode(&mut reader));
+    let root = try!(u64::decode(&mut reader));
+    let length = try!(u64::decode(&mut reader));
+    Ok((magic, version, page_size, page_count, free_head, root, length))
+}
+
+fn write_page(file: &mut File, id: PageId, data: &[u8]) -> io::Result<()> {
+    try!(file.seek(SeekFrom::Start(id * PAGE_SIZE as u64)));
+    file.write_all(data)
+}
+
+impl Pager {
+    /// Starts a new file, which must be empty, with room for `capacity` pages in memory.
+    pub fn create(file: File, wal: Wal, capacity: usize) -> Pager {
+        Pager {
+            file: file,
+            wal: wal,
+            page_count: 1,
+            free_head: 0,
+            header: Vec::new(),
+            frames: HashMap::new(),
+            dirty: 0,
+            lru: BTreeMap::new(),
+            clock: 0,
+            capacity: if capacity == 0 { 1 } else { capacity }
+        }
+    }
+
+    /// Opens an existing file, first finishing any checkpoint that the log shows was
+    /// interrupted. Returns what the header says about the tree, along with the operations logged
+    /// since the last checkpoint, which the caller must replay.
+    pub fn open(mut file: File, wal: (Wal, Vec<Record>), capacity: usize)
+            -> io::Result<(Pager, Meta, Vec<Vec<u8>>)> {
+        let (wal, records) = wal;
+
+        let mut ops = Vec::new();
+        let mut pages = Vec::new();
+        for record in records {
+            match record {
+                Record::Op(op) => ops.push(op),
+                Record::Page(id, data) => pages.push((id, data)),
+                Record::Commit => {
+                    // Everything up to here is in the checkpoint, which may not have made it
+                    // into the file, so copy it there again.
+                    for (id, data) in pages.drain(..) {
+                        try!(write_page(&mut file, id, &data));
+                    }
+                    try!(file.sync_all());
+                    ops.clear();
+                }
+            }
+        }
+
+        let mut header = vec![0; PAGE_SIZE];
+        try!(file.seek(SeekFrom::Start(0)));
+        try!(file.read_exact(&mut header));
+
+        let (magic, version, page_size, page_count, free_head, root, length) =
+            match read_header(&header) {
+                Ok(fields) => fields,
+                Err(err) => return Err(io::Error::new(io::ErrorKind::InvalidData, err))
+            };
+
+        if magic != MAGIC {
+            return Err(invalid_data("not a paged map"));
+        }
+        if version != VERSION {
+            return Err(invalid_data("unsupported paged map version"));
+        }
+        if page_size as usize != PAGE_SIZE {
+            return Err(invalid_data("unsupported page size"));
+        }
+        if root == 0 || root >= page_count || free_head >= page_count {
+            return Err(invalid_data("page number out of range"));
+        }
+
+        let mut pager = Pager::create(file, wal, capacity);
+        pager.page_count = page_count;
+        pager.free_head = free_head;
+        pager.header = header;
+        Ok((pager, Meta { root: root, length: length }, ops))
+    }
+
+    /// Returns the write-ahead log.
+    pub fn wal(&mut self) -> &mut Wal {
+        &mut self.wal
+    }
+
+    /// Returns the number of pages currently held in memory.
+    pub fn cached(&self) -> usize {
+        self.frames.len()
+    }
+
+    /// Returns true if the cache is full of dirty pages, so that the map should checkpoint.
+    pub fn is_full(&self) -> bool {
+        self.dirty >= self.capacity
+    }
+
+    /// Returns the contents of a page, reading it from the file if it is not in memory.
+    pub fn read(&mut self, id: PageId) -> io::Result<&[u8]> {
+        if id == 0 || id >= self.page_count {
+            return Err(invalid_data("page number out of range"));
+        }
+
+        if self.frames.contains_key(&id) {
+            self.touch(id);
+        } else {
+            let mut data = vec![0; PAGE_SIZE];
+            try!(self.file.seek(SeekFrom::Start(id * PAGE_SIZE as u64)));
+            try!(self.file.read_exact(&mut data));
+            self.add_frame(id, data, false);
+        }
+
+        Ok(&self.frames[&id].data)
+    }
+
+    /// Replaces the contents of a page. The new contents are only written to the file by the
+    /// next checkpoint.
+    pub fn write(&mut self, id: PageId, mut data: Vec<u8>) {
+        assert!(data.len() <= PAGE_SIZE, "page overflow");
+        data.resize(PAGE_SIZE, 0);
+
+        if self.frames.contains_key(&id) {
+            self.touch(id);
+            let frame = self.frames.get_mut(&id).unwrap();
+            if !frame.dirty {
+                self.dirty += 1;
+            }
+            frame.data = data;
+            frame.dirty = true;
+        } else {
+            self.dirty += 1;
+            self.add_frame(id, data, true);
+        }
+    }
+
+    /// Finds a page for a new node, either from the free list or by growing the file. The caller
+    /// is expected to write the page straight away.
+    pub fn allocate(&mut self) -> io::Result<PageId> {
+        if self.free_head == 0 {
+            self.page_count += 1;
+            return Ok(self.page_count - 1);
+        }
+
+        let id = self.free_head;
+        let next = {
+            let page = try!(self.read(id));
+            match u64::decode(&mut Reader::new(page)) {
+                Ok(next) => next,
+                Err(err) => return Err(io::Error::new(io::ErrorKind::InvalidData, err))
+            }
+        };
+        if next >= self.page_count {
+            return Err(invalid_data("page number out of range"));
+        }
+        self.free_head = next;
+        Ok(id)
+    }
+
+    /// Puts a page that is no longer used onto the free list.
+    pub fn free(&mut self, id: PageId) {
+        let mut data = Vec::new();
+        self.free_head.encode(&mut data);
+        self.write(id, data);
+        self.free_head = id;
+    }
+
+    /// Checkpoints: logs every dirty page along with a header recording `meta`, then writes them
+    /// all to the file and waits for them to reach the disk. Does nothing if nothing has changed
+    /// since the last checkpoint.
+    pub fn flush(&mut self, meta: &Meta) -> io::Result<()> {
+        let mut header = Vec::with_capacity(PAGE_SIZE);
+        header.extend(MAGIC.iter().cloned());
+        VERSION.encode(&mut header);
+        (PAGE_SIZE as u32).encode(&mut header);
+        self.page_count.encode(&mut header);
+        self.free_head.encode(&mut header);
+        meta.root.encode(&mut header);
+        meta.length.encode(&mut header);
+        header.resize(PAGE_SIZE, 0);
+
+        if self.dirty == 0 && header == self.header {
+            return Ok(());
+        }
+
+        let mut dirty: Vec<PageId> = self.frames.iter()
+                                                .filter(|&(_, frame)| frame.dirty)
+                                                .map(|(&id, _)| id)
+                                                .collect();
+        // Writing in file order keeps the writes sequential
+        dirty.sort();
+
+        {
+            let frames = &self.frames;
+            let mut pages: Vec<(PageId, &[u8])> = dirty.iter()
+                                                        .map(|id| (*id, &frames[id].data[..]))
+                                                        .collect();
+            pages.push((0, &header));
+            try!(self.wal.append_checkpoint(&pages));
+        }
+
+        for id in dirty {
+            let frame = self.frames.get_mut(&id).unwrap();
+            try!(write_page(&mut self.file, id, &frame.data));
+            frame.dirty = false;
+        }
+        try!(write_page(&mut self.file, 0, &header));
+        try!(self.file.sync_all());
+
+        self.wal.checkpoint_done();
+        self.dirty = 0;
+        self.header = header;
+
+        // The cache may have grown while every page in it was dirty.
+        while self.frames.len() > self.capacity {
+            self.evict();
+        }
+        Ok(())
+    }
+
+    fn touch(&mut self, id: PageId) {
+        let frame = self.frames.get_mut(&id).unwrap();
+        self.lru.remove(&frame.last_used);
+        self.clock += 1;
+        frame.last_used = self.clock;
+        self.lru.insert(self.clock, id);
+    }
+
+    fn add_frame(&mut self, id: PageId, data: Vec<u8>, dirty: bool) {
+        if self.frames.len() >= self.capacity {
+            self.evict();
+        }
+
+        self.clock += 1;
+        self.frames.insert(id, Frame {
+            data: data,
+            dirty: dirty,
+            last_used: self.clock
+        });
+        self.lru.insert(self.clock, id);
+    }
+
+    // Evicts the least recently used clean page, if there is one.
+    fn evict(&mut self) {
+        let victim = {
+            let frames = &self.frames;
+            self.lru.values().cloned().find(|id| !frames[id].dirty)
+        };
+        if let Some(victim) = victim {
+            let frame = self.frames.remove(&victim).unwrap();
+            self.lru.remove(&frame.last_used);
+        }
+    }
+}
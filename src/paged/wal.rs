@@ -0,0 +1,189 @@
+// Copyright 2015 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// The write-ahead log kept next to the file of a `PagedMap`.
+//
+// The log holds three kinds of record. An operation record is an encoded `insert` or `remove`,
+// written as each one completes; the map decides what they contain. A page record is the new
+// image of one page, written by a checkpoint for every dirty page and for the header, and a
+// commit record marks the end of a checkpoint.
+//
+// Every record is a tag byte, the length of its payload as a `u32`, a checksum of the tag and
+// payload, and then the payload, so a record that was only partly written when the program
+// stopped can always be recognised and ignored, along with everything after it. A checkpoint is
+// only worth anything once its commit record is in the log, so pages logged without a commit
+// after them are also discarded on opening.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use super::super::encoding::{Decode, Encode, Reader};
+use super::pager::PageId;
+
+const TAG_OP: u8 = 1;
+const TAG_PAGE: u8 = 2;
+const TAG_COMMIT: u8 = 3;
+
+// The tag, the length and the checksum
+const RECORD_HEADER_SIZE: usize = 9;
+
+pub enum Record {
+    Op(Vec<u8>),
+    Page(PageId, Vec<u8>),
+    Commit
+}
+
+pub struct Wal {
+    file: File,
+    // Whether the log ends with a checkpoint that has been fully written to the map's file, so
+    // that nothing before it is needed any more
+    stale: bool,
+    sync: bool
+}
+
+// 32-bit FNV-1a, which is plenty to tell a torn record from a complete one.
+fn checksum(tag: u8, payload: &[u8]) -> u32 {
+    let mut hash = 0x811c9dc5u32;
+    for &byte in Some(&tag).into_iter().chain(payload) {
+        hash ^= byte as u32;
+        hash = hash.wrapping_mul(0x01000193);
+    }
+    hash
+}
+
+fn encode_record(tag: u8, payload: &[u8], out: &mut Vec<u8>) {
+    out.push(tag);
+    (payload.len() as u32).encode(out);
+    checksum(tag, payload).encode(out);
+    out.extend(payload.iter().cloned());
+}
+
+// Reads the record at the start of `bytes`, returning it and its total length, or `None` if
+// there is no complete, intact record there.
+fn decode_record(bytes: &[u8]) -> Option<(Record, usize)> {
+    if bytes.len() < RECORD_HEADER_SIZE {
+        return None;
+    }
+    let mut reader = Reader::new(bytes);
+    let tag = u8::decode(&mut reader).unwrap();
+    let len = u32::decode(&mut reader).unwrap() as usize;
+    let sum = u32::decode(&mut reader).unwrap();
+    let payload = match reader.read_bytes(len) {
+        Ok(payload) => payload,
+        Err(_) => return None
+    };
+    if checksum(tag, payload) != sum {
+        return None;
+    }
+
+    let record = match tag {
+        TAG_OP => Record::Op(payload.to_vec()),
+        TAG_PAGE if len >= 8 => {
+            let id = u64::decode(&mut Reader::new(payload)).unwrap();
+            Record::Page(id, payload[8..].to_vec())
+        },
+        TAG_COMMIT => Record::Commit,
+        _ => return None
+    };
+    Some((record, RECORD_HEADER_SIZE + len))
+}
+
+impl Wal {
+    /// Starts a new, empty log at `path`, replacing any file already there.
+    pub fn create(path: &Path) -> io::Result<Wal> {
+        let file = try!(OpenOptions::new().read(true)
+                                          .write(true)
+                                          .create(true)
+                                          .truncate(true)
+                                          .open(path));
+        Ok(Wal {
+            file: file,
+            stale: false,
+            sync: true
+        })
+    }
+
+    /// Opens the log at `path`, creating it if it does not exist, and returns every record in it
+    /// up to the last operation or commit. Anything after that is removed from the file.
+    pub fn open(path: &Path) -> io::Result<(Wal, Vec<Record>)> {
+        let mut file = try!(OpenOptions::new().read(true).write(true).create(true).open(path));
+        let mut bytes = Vec::new();
+        try!(file.read_to_end(&mut bytes));
+
+        let mut records = Vec::new();
+        let (mut pos, mut keep, mut kept) = (0, 0, 0);
+        while let Some((record, len)) = decode_record(&bytes[pos..]) {
+            pos += len;
+            match record {
+                Record::Page(..) => {},
+                _ => {
+                    keep = pos;
+                    kept = records.len() + 1;
+                }
+            }
+            records.push(record);
+        }
+        records.truncate(kept);
+
+        try!(file.set_len(keep as u64));
+        try!(file.seek(SeekFrom::End(0)));
+        Ok((Wal {
+            file: file,
+            stale: false,
+            sync: true
+        }, records))
+    }
+
+    /// Sets whether each operation record is synced to disk as soon as it is written.
+    pub fn set_sync(&mut self, sync: bool) {
+        self.sync = sync;
+    }
+
+    /// Appends an operation record.
+    pub fn append_op(&mut self, payload: &[u8]) -> io::Result<()> {
+        if self.stale {
+            try!(self.file.set_len(0));
+            try!(self.file.seek(SeekFrom::Start(0)));
+            self.stale = false;
+        }
+
+        let mut record = Vec::with_capacity(RECORD_HEADER_SIZE + payload.len());
+        encode_record(TAG_OP, payload, &mut record);
+        try!(self.file.write_all(&record));
+        if self.sync {
+            try!(self.file.sync_data());
+        }
+        Ok(())
+    }
+
+    /// Appends the images of the given pages followed by a commit record, and waits for them to
+    /// reach the disk. Only once this returns may the pages be written to the map's file.
+    pub fn append_checkpoint(&mut self, pages: &[(PageId, &[u8])]) -> io::Result<()> {
+        let mut records = Vec::new();
+        let mut payload = Vec::new();
+        for &(id, data) in pages {
+            payload.clear();
+            id.encode(&mut payload);
+            payload.extend(data.iter().cloned());
+            encode_record(TAG_PAGE, &payload, &mut records);
+        }
+        encode_record(TAG_COMMIT, &[], &mut records);
+
+        try!(self.file.write_all(&records));
+        self.file.sync_data()
+    }
+
+    /// Records that the last checkpoint has been written to the map's file, so that the log can
+    /// be emptied. This is put off until the next operation is logged.
+    pub fn checkpoint_done(&mut self) {
+        self.stale = true;
+    }
+}
@@ -0,0 +1,588 @@
+// Copyright 2015 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// A persistent B-Tree, built out of the same nodes as `BTreeMap`. Nodes are reference counted and
+// shared between every map cloned from the same original. Before a map changes anything, it makes
+// the nodes involved its own with `make_unique`, which copies a node only if some other map still
+// shares it. That is the path from the root down to the affected leaf, and any sibling that a
+// merge or steal is about to change. Everything else stays shared.
+//
+// Once those nodes are unique, the tree is changed by the same code as `BTreeMap`, in `tree.rs`
+// and `node.rs`. Shared nodes have a parent in every map they belong to, so their parent links
+// are left stale, and iteration and diffing keep a stack of the nodes above them instead of
+// ascending.
+
+use core::cmp::Ordering;
+use core::fmt::Debug;
+use core::iter::FromIterator;
+use core::ops::Index;
+use core::{fmt, mem, ptr};
+
+use collections::borrow::Borrow;
+
+use super::compare::Natural;
+use super::diff::{self, DiffItem};
+use super::node::{self, Handle, NodeRef, marker};
+use super::search::{self, search_node, search_tree};
+use super::tree::{insert_kv, remove_kv_with};
+
+use super::node::ForceResult::*;
+use super::search::SearchResult::*;
+use self::Pending::*;
+
+type Ref<'a, K, V> = NodeRef<marker::Borrowed<'a>, K, V, marker::Immut, marker::LeafOrInternal>;
+
+/// A map based on a persistent B-Tree.
+///
+/// This has the same layout and search strategy as `BTreeMap`, but its nodes are reference
+/// counted instead of uniquely owned. Cloning a `PersistentMap` is therefore O(1): the clone
+/// simply shares the root of the original. When either copy is later modified, only the nodes
+/// on the path from the root to the modified element (and any siblings involved in rebalancing)
+/// are copied, so the cost of a modification is O(B log<sub>B</sub>n) regardless of how many
+/// snapshots are alive.
+///
+/// This makes it cheap to hand out consistent snapshots of a large map to readers, possibly on
+/// other threads, while a writer keeps modifying its own copy.
+pub struct PersistentMap<K, V> {
+    root: node::Root<K, V>,
+    length: usize
+}
+
+// Maps on different threads may share nodes, so, as with `Arc`, sending a map to another thread
+// shares its elements as well.
+unsafe impl<K: Send + Sync, V: Send + Sync> Send for PersistentMap<K, V> { }
+unsafe impl<K: Send + Sync, V: Send + Sync> Sync for PersistentMap<K, V> { }
+
+impl<K, V> Drop for PersistentMap<K, V> {
+    fn drop(&mut self) {
+        unsafe {
+            ptr::read(&self.root).release();
+        }
+    }
+}
+
+impl<K, V> Clone for PersistentMap<K, V> {
+    fn clone(&self) -> PersistentMap<K, V> {
+        PersistentMap {
+            root: self.root.share(),
+            length: self.length
+        }
+    }
+}
+
+impl<K: Ord, V> PersistentMap<K, V> {
+    /// Makes a new empty PersistentMap.
+    pub fn new() -> PersistentMap<K, V> {
+        PersistentMap {
+            root: node::Root::new_leaf(),
+            length: 0
+        }
+    }
+
+    /// Returns a reference to the value corresponding to the key.
+    ///
+    /// The key may be any borrowed form of the map's key type, but the ordering
+    /// on the borrowed form *must* match the ordering on the key type.
+    pub fn get<Q: ?Sized>(&self, key: &Q) -> Option<&V> where K: Borrow<Q>, Q: Ord {
+        match search_tree(self.root.as_ref(), key, &Natural) {
+            Found(handle) => Some(handle.into_kv().1),
+            GoDown(_) => None
+        }
+    }
+
+    /// Returns true if the map contains a value for the specified key.
+    pub fn contains_key<Q: ?Sized>(&self, key: &Q) -> bool where K: Borrow<Q>, Q: Ord {
+        self.get(key).is_some()
+    }
+}
+
+impl<K: Ord + Clone, V: Clone> PersistentMap<K, V> {
+    /// Returns a mutable reference to the value corresponding to the key.
+    ///
+    /// If any other map shares the nodes leading to the key, they are copied first, so this never
+    /// affects other snapshots.
+    pub fn get_mut<Q: ?Sized>(&mut self, key: &Q) -> Option<&mut V> where K: Borrow<Q>, Q: Ord {
+        // Don't copy anything if there is nothing to return
+        if !self.contains_key(key) {
+            return None;
+        }
+
+        match search_unique(&mut self.root, key) {
+            Found(handle) => Some(handle.into_kv_mut().1),
+            GoDown(_) => None
+        }
+    }
+
+    /// Inserts a key-value pair into the map.
+    ///
+    /// If the map did not have this key present, `None` is returned. If the map did have this key
+    /// present, the key is not updated, the value is updated and the old value is returned.
+    pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+        match search_unique(&mut self.root, &key) {
+            Found(handle) => Some(mem::replace(handle.into_kv_mut().1, value)),
+            GoDown(handle) => {
+                insert_kv(handle, key, value);
+                self.length += 1;
+                None
+            }
+        }
+    }
+
+    /// Removes a key from the map, returning the value at the key if the key
+    /// was previously in the map.
+    pub fn remove<Q: ?Sized>(&mut self, key: &Q) -> Option<V> where K: Borrow<Q>, Q: Ord {
+        // Don't copy anything if there is nothing to remove
+        if !self.contains_key(key) {
+            return None;
+        }
+
+        let handle = match search_unique(&mut self.root, key) {
+            Found(handle) => handle,
+            GoDown(_) => return None
+        };
+
+        let handle = match handle.force() {
+            Leaf(leaf) => leaf.forget_node_type(),
+            Internal(mut internal) => {
+                // `remove_kv` replaces the pair with its successor, the first pair in the leftmost
+                // leaf of the subtree to its right, so the path down to that leaf is changed too.
+                let mut edge = unsafe { internal.reborrow_mut() }.right_edge();
+                loop {
+                    edge.make_unique();
+                    match edge.descend().force() {
+                        Leaf(_) => break,
+                        Internal(child) => edge = child.first_edge()
+                    }
+                }
+                let idx = internal.idx();
+                unsafe { Handle::new(internal.into_node().forget_type(), idx) }
+            }
+        };
+
+        let (_, val, _) = remove_kv_with(handle, |parent| unsafe {
+            parent.reborrow_mut().left_edge().make_unique();
+            parent.reborrow_mut().right_edge().make_unique();
+        });
+        self.length -= 1;
+
+        Some(val)
+    }
+
+    /// Clears the map, removing all values.
+    pub fn clear(&mut self) {
+        *self = PersistentMap::new();
+    }
+}
+
+impl<K, V> PersistentMap<K, V> {
+    /// Gets an iterator over the entries of the map, sorted by key.
+    pub fn iter(&self) -> Iter<K, V> {
+        let mut iter = Iter {
+            front: Vec::new(),
+            back: Vec::new(),
+            length: self.length
+        };
+        push_first_path(&mut iter.front, self.root.as_ref());
+        push_last_path(&mut iter.back, self.root.as_ref());
+        iter
+    }
+
+    /// Gets an iterator over the keys of the map, in sorted order.
+    pub fn keys<'a>(&'a self) -> Keys<'a, K, V> {
+        Keys { inner: self.iter() }
+    }
+
+    /// Gets an iterator over the values of the map, in order by key.
+    pub fn values<'a>(&'a self) -> Values<'a, K, V> {
+        Values { inner: self.iter() }
+    }
+
+    /// Gets an iterator over the differences between this map and `other`, in order by key.
+    ///
+    /// Keys only present in `self` are reported as `Removed` and keys only present in `other` as
+    /// `Added`. Keys present in both with unequal values are reported as `Changed`.
+    ///
+    /// Whenever both maps share a subtree, that whole subtree is skipped without being looked at,
+    /// so diffing two snapshots that descend from a common ancestor takes time proportional to
+    /// the parts of the trees that have been modified since, not to the size of the maps.
+    pub fn diff<'a>(&'a self, other: &'a PersistentMap<K, V>) -> Diff<'a, K, V>
+            where K: Ord, V: PartialEq {
+        Diff {
+            left: vec![Tree(self.root.as_ref())],
+            right: vec![Tree(other.root.as_ref())]
+        }
+    }
+
+    /// Returns the number of elements in the map.
+    pub fn len(&self) -> usize {
+        self.length
+    }
+
+    /// Returns true if the map contains no elements.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Searches for `key` like `search::search_tree`, but makes every node on the way down unique
+/// first, so that the search ends in nodes that may be changed.
+fn search_unique<'a, K, V, Q: ?Sized>(root: &'a mut node::Root<K, V>, key: &Q)
+        -> search::SearchResult<marker::Borrowed<'a>, K, V, marker::Mut,
+                                marker::LeafOrInternal, marker::Leaf>
+        where K: Clone + Borrow<Q>, V: Clone, Q: Ord {
+    root.make_unique();
+    let mut node = root.as_mut();
+    loop {
+        match search_node(node, key, &Natural) {
+            Found(handle) => return Found(handle),
+            GoDown(handle) => match handle.force() {
+                Leaf(leaf) => return GoDown(leaf),
+                Internal(mut internal) => {
+                    internal.make_unique();
+                    node = internal.descend();
+                }
+            }
+        }
+    }
+}
+
+/// Returns the child below edge `idx` of `node`, if it has children.
+fn child<'a, K: 'a, V: 'a>(node: Ref<'a, K, V>, idx: usize) -> Option<Ref<'a, K, V>> {
+    match node.force() {
+        Leaf(_) => None,
+        Internal(internal) => Some(unsafe { Handle::new(internal, idx) }.descend())
+    }
+}
+
+fn push_first_path<'a, K: 'a, V: 'a>(stack: &mut Vec<(Ref<'a, K, V>, usize)>,
+                                     node: Ref<'a, K, V>) {
+    let mut next = Some(node);
+    while let Some(node) = next {
+        stack.push((node, 0));
+        next = child(node, 0);
+    }
+}
+
+fn push_last_path<'a, K: 'a, V: 'a>(stack: &mut Vec<(Ref<'a, K, V>, usize)>,
+                                    node: Ref<'a, K, V>) {
+    let mut next = Some(node);
+    while let Some(node) = next {
+        stack.push((node, node.len()));
+        next = child(node, node.len());
+    }
+}
+
+/// An iterator over a PersistentMap's entries.
+pub struct Iter<'a, K: 'a, V: 'a> {
+    // Each frame holds a node on the path to the next element and the index of the next element
+    // to be yielded from that node, counting from the left for `front` and from the right for
+    // `back`.
+    front: Vec<(Ref<'a, K, V>, usize)>,
+    back: Vec<(Ref<'a, K, V>, usize)>,
+    length: usize
+}
+
+/// An iterator over a PersistentMap's keys.
+pub struct Keys<'a, K: 'a, V: 'a> {
+    inner: Iter<'a, K, V>
+}
+
+/// An iterator over a PersistentMap's values.
+pub struct Values<'a, K: 'a, V: 'a> {
+    inner: Iter<'a, K, V>
+}
+
+impl<'a, K: 'a, V: 'a> Iterator for Iter<'a, K, V> {
+    type Item = (&'a K, &'a V);
+
+    fn next(&mut self) -> Option<(&'a K, &'a V)> {
+        if self.length == 0 {
+            return None;
+        }
+        self.length -= 1;
+
+        loop {
+            let (node, idx) = {
+                let frame = self.front.last_mut().unwrap();
+                frame.1 += 1;
+                (frame.0, frame.1 - 1)
+            };
+
+            if idx < node.len() {
+                if let Some(child) = child(node, idx + 1) {
+                    push_first_path(&mut self.front, child);
+                }
+                let (keys, vals) = node.into_slices();
+                return Some((&keys[idx], &vals[idx]));
+            } else {
+                self.front.pop();
+            }
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.length, Some(self.length))
+    }
+}
+
+impl<'a, K: 'a, V: 'a> DoubleEndedIterator for Iter<'a, K, V> {
+    fn next_back(&mut self) -> Option<(&'a K, &'a V)> {
+        if self.length == 0 {
+            return None;
+        }
+        self.length -= 1;
+
+        loop {
+            let (node, idx) = {
+                let frame = self.back.last_mut().unwrap();
+                let idx = frame.1;
+                if idx > 0 {
+                    frame.1 -= 1;
+                }
+                (frame.0, idx)
+            };
+
+            if idx > 0 {
+                if let Some(child) = child(node, idx - 1) {
+                    push_last_path(&mut self.back, child);
+                }
+                let (keys, vals) = node.into_slices();
+                return Some((&keys[idx - 1], &vals[idx - 1]));
+            } else {
+                self.back.pop();
+            }
+        }
+    }
+}
+
+impl<'a, K: 'a, V: 'a> ExactSizeIterator for Iter<'a, K, V> {
+    fn len(&self) -> usize { self.length }
+}
+
+impl<'a, K, V> Clone for Iter<'a, K, V> {
+    fn clone(&self) -> Iter<'a, K, V> {
+        Iter {
+            front: self.front.clone(),
+            back: self.back.clone(),
+            length: self.length
+        }
+    }
+}
+
+impl<'a, K, V> Iterator for Keys<'a, K, V> {
+    type Item = &'a K;
+
+    fn next(&mut self) -> Option<&'a K> {
+        self.inner.next().map(|(k, _)| k)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+impl<'a, K, V> DoubleEndedIterator for Keys<'a, K, V> {
+    fn next_back(&mut self) -> Option<&'a K> {
+        self.inner.next_back().map(|(k, _)| k)
+    }
+}
+
+impl<'a, K, V> ExactSizeIterator for Keys<'a, K, V> {
+    fn len(&self) -> usize {
+        self.inner.len()
+    }
+}
+
+impl<'a, K, V> Iterator for Values<'a, K, V> {
+    type Item = &'a V;
+
+    fn next(&mut self) -> Option<&'a V> {
+        self.inner.next().map(|(_, v)| v)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+impl<'a, K, V> DoubleEndedIterator for Values<'a, K, V> {
+    fn next_back(&mut self) -> Option<&'a V> {
+        self.inner.next_back().map(|(_, v)| v)
+    }
+}
+
+impl<'a, K, V> ExactSizeIterator for Values<'a, K, V> {
+    fn len(&self) -> usize {
+        self.inner.len()
+    }
+}
+
+/// An iterator over the differences between two PersistentMaps, in order by key.
+pub struct Diff<'a, K: 'a, V: 'a> {
+    // The remainder of each map, in reverse order, with subtrees only expanded when necessary
+    left: Vec<Pending<'a, K, V>>,
+    right: Vec<Pending<'a, K, V>>
+}
+
+enum Pending<'a, K: 'a, V: 'a> {
+    Tree(Ref<'a, K, V>),
+    Elem(&'a K, &'a V)
+}
+
+impl<'a, K, V> Clone for Pending<'a, K, V> {
+    fn clone(&self) -> Pending<'a, K, V> {
+        match *self {
+            Tree(node) => Tree(node),
+            Elem(k, v) => Elem(k, v)
+        }
+    }
+}
+
+impl<'a, K, V> Copy for Pending<'a, K, V> { }
+
+/// Replaces the subtree on top of the stack with its contents.
+fn expand<'a, K: 'a, V: 'a>(stack: &mut Vec<Pending<'a, K, V>>) {
+    if let Some(Tree(node)) = stack.pop() {
+        let (keys, vals) = node.into_slices();
+        for i in (0..node.len()).rev() {
+            if let Some(child) = child(node, i + 1) {
+                stack.push(Tree(child));
+            }
+            stack.push(Elem(&keys[i], &vals[i]));
+        }
+        if let Some(child) = child(node, 0) {
+            stack.push(Tree(child));
+        }
+    }
+}
+
+impl<'a, K: Ord, V: PartialEq> Iterator for Diff<'a, K, V> {
+    type Item = DiffItem<'a, K, V>;
+
+    fn next(&mut self) -> Option<DiffItem<'a, K, V>> {
+        loop {
+            match (self.left.last().cloned(), self.right.last().cloned()) {
+                (Some(Tree(left)), Some(Tree(right))) => {
+                    if left == right {
+                        // Shared, and so necessarily identical
+                        self.left.pop();
+                        self.right.pop();
+                    } else if left.height() > right.height() {
+                        expand(&mut self.left);
+                    } else if left.height() < right.height() {
+                        expand(&mut self.right);
+                    } else {
+                        expand(&mut self.left);
+                        expand(&mut self.right);
+                    }
+                },
+                (Some(Tree(..)), _) => expand(&mut self.left),
+                (_, Some(Tree(..))) => expand(&mut self.right),
+                (left, right) => {
+                    let left = left.map(|elem| match elem {
+                        Elem(k, v) => (k, v),
+                        Tree(..) => unreachable!()
+                    });
+                    let right = right.map(|elem| match elem {
+                        Elem(k, v) => (k, v),
+                        Tree(..) => unreachable!()
+                    });
+                    if left.is_none() && right.is_none() {
+                        return None;
+                    }
+
+                    let (item, advance_left, advance_right) =
+                        diff::compare_elems(left, right, &Natural);
+                    if advance_left {
+                        self.left.pop();
+                    }
+                    if advance_right {
+                        self.right.pop();
+                    }
+                    if item.is_some() {
+                        return item;
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl<'a, K: 'a, V: 'a> IntoIterator for &'a PersistentMap<K, V> {
+    type Item = (&'a K, &'a V);
+    type IntoIter = Iter<'a, K, V>;
+
+    fn into_iter(self) -> Iter<'a, K, V> {
+        self.iter()
+    }
+}
+
+impl<K: Ord + Clone, V: Clone> FromIterator<(K, V)> for PersistentMap<K, V> {
+    fn from_iter<T: IntoIterator<Item=(K, V)>>(iter: T) -> PersistentMap<K, V> {
+        let mut map = PersistentMap::new();
+        map.extend(iter);
+        map
+    }
+}
+
+impl<K: Ord + Clone, V: Clone> Extend<(K, V)> for PersistentMap<K, V> {
+    #[inline]
+    fn extend<T: IntoIterator<Item=(K, V)>>(&mut self, iter: T) {
+        for (k, v) in iter {
+            self.insert(k, v);
+        }
+    }
+}
+
+impl<K: Ord, V> Default for PersistentMap<K, V> {
+    fn default() -> PersistentMap<K, V> {
+        PersistentMap::new()
+    }
+}
+
+impl<K: PartialEq, V: PartialEq> PartialEq for PersistentMap<K, V> {
+    fn eq(&self, other: &PersistentMap<K, V>) -> bool {
+        self.len() == other.len() &&
+            self.iter().zip(other).all(|(a, b)| a == b)
+    }
+}
+
+impl<K: Eq, V: Eq> Eq for PersistentMap<K, V> {}
+
+impl<K: PartialOrd, V: PartialOrd> PartialOrd for PersistentMap<K, V> {
+    #[inline]
+    fn partial_cmp(&self, other: &PersistentMap<K, V>) -> Option<Ordering> {
+        self.iter().partial_cmp(other.iter())
+    }
+}
+
+impl<K: Ord, V: Ord> Ord for PersistentMap<K, V> {
+    #[inline]
+    fn cmp(&self, other: &PersistentMap<K, V>) -> Ordering {
+        self.iter().cmp(other.iter())
+    }
+}
+
+impl<K: Debug, V: Debug> Debug for PersistentMap<K, V> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_map().entries(self.iter()).finish()
+    }
+}
+
+impl<'a, K: Ord, Q: ?Sized, V> Index<&'a Q> for PersistentMap<K, V>
+    where K: Borrow<Q>, Q: Ord
+{
+    type Output = V;
+
+    #[inline]
+    fn index(&self, key: &Q) -> &V {
+        self.get(key).expect("no entry found for key")
+    }
+}
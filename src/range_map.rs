@@ -0,0 +1,301 @@
+// Copyright 2015 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// A map from disjoint half-open ranges to values, stored as a `BTreeMap` from the start of each
+// range to its end and value.
+//
+// The map maintains two invariants: the stored ranges are nonempty and never overlap, and two
+// ranges that touch (one ending exactly where the next starts) never have equal values, since
+// they would have been coalesced into one. Because the ranges are disjoint, the range containing
+// a point, if any, is always the one with the greatest start not after the point, which
+// `BTreeMap::range` finds in O(log n) time by searching for the point and stepping back.
+
+use core::fmt::Debug;
+use core::iter::FromIterator;
+use core::ops::Range;
+use core::fmt;
+
+use collections::Bound::{Included, Excluded, Unbounded};
+
+use super::map::{self, BTreeMap};
+
+/// A map from half-open ranges `[start, end)` of keys to values.
+///
+/// Inserting a range overwrites whatever parts of existing ranges it overlaps, splitting them if
+/// necessary, and adjacent ranges that end up with equal values are merged into one. This makes
+/// it suitable for tracking, say, which regions of memory or address space are in use and by
+/// whom.
+///
+/// # Examples
+///
+/// ```
+/// use btree_rewrite::RangeMap;
+///
+/// let mut map = RangeMap::new();
+/// map.insert_range(0..10, "a");
+/// map.insert_range(3..5, "b");
+/// map.insert_range(10..20, "a");
+///
+/// assert_eq!(map.get(&4), Some(&"b"));
+/// assert_eq!(map.get(&15), Some(&"a"));
+/// assert_eq!(map.iter().map(|(s, e, v)| (*s, *e, *v)).collect::<Vec<_>>(),
+///            [(0, 3, "a"), (3, 5, "b"), (5, 20, "a")]);
+///
+/// map.remove_range(8..12);
+/// assert_eq!(map.gaps(&(0..30)).collect::<Vec<_>>(), [8..12, 20..30]);
+/// ```
+pub struct RangeMap<K, V> {
+    // Maps the start of every range to its end and value
+    map: BTreeMap<K, (K, V)>
+}
+
+impl<K: Ord + Clone, V: Clone + PartialEq> RangeMap<K, V> {
+    /// Makes a new empty RangeMap.
+    pub fn new() -> RangeMap<K, V> {
+        RangeMap { map: BTreeMap::new() }
+    }
+
+    /// Returns a reference to the value of the range containing `point`, if any.
+    pub fn get(&self, point: &K) -> Option<&V> {
+        self.get_range(point).map(|(_, _, val)| val)
+    }
+
+    /// Returns the start, end and value of the range containing `point`, if any.
+    pub fn get_range(&self, point: &K) -> Option<(&K, &K, &V)> {
+        match self.map.range::<K, K>(Unbounded, Included(point)).next_back() {
+            Some((start, &(ref end, ref val))) if point < end => Some((start, end, val)),
+            _ => None
+        }
+    }
+
+    /// Returns true if some range contains `point`.
+    pub fn contains(&self, point: &K) -> bool {
+        self.get(point).is_some()
+    }
+
+    /// Maps every key in `range` to `value`, overwriting any values previously mapped to keys in
+    /// the range. The result is merged with any neighbouring range that has an equal value and
+    /// touches it. Inserting an empty range does nothing.
+    pub fn insert_range(&mut self, range: Range<K>, value: V) {
+        if range.start >= range.end {
+            return;
+        }
+        self.remove_range(range.clone());
+
+        let mut start = range.start;
+        let mut end = range.end;
+
+        let merge_left = match self.map.range::<K, K>(Unbounded, Excluded(&start)).next_back() {
+            Some((left_start, &(ref left_end, ref left_val))) => {
+                if *left_end == start && *left_val == value {
+                    Some(left_start.clone())
+                } else {
+                    None
+                }
+            },
+            None => None
+        };
+        if let Some(left_start) = merge_left {
+            self.map.remove(&left_start);
+            start = left_start;
+        }
+
+        let merge_right = match self.map.get(&end) {
+            Some(&(_, ref right_val)) => *right_val == value,
+            None => false
+        };
+        if merge_right {
+            let (right_end, _) = self.map.remove(&end).unwrap();
+            end = right_end;
+        }
+
+        self.map.insert(start, (end, value));
+    }
+
+    /// Unmaps every key in `range`, shrinking or splitting any ranges that only partially
+    /// overlap it.
+    pub fn remove_range(&mut self, range: Range<K>) {
+        if range.start >= range.end {
+            return;
+        }
+
+        // A range starting before the removed one may stick out on either side of it.
+        let mut tail = None;
+        if let Some((_, &mut (ref mut end, ref val))) =
+                self.map.range_mut::<K, K>(Unbounded, Excluded(&range.start)).next_back() {
+            if *end > range.start {
+                if *end > range.end {
+                    tail = Some((end.clone(), val.clone()));
+                }
+                *end = range.start.clone();
+            }
+        }
+
+        let starts: Vec<K> = self.map.range(Included(&range.start), Excluded(&range.end))
+                                     .map(|(start, _)| start.clone())
+                                     .collect();
+        for start in starts {
+            let (end, val) = self.map.remove(&start).unwrap();
+            if end > range.end {
+                // Only the last range can stick out past the end.
+                tail = Some((end, val));
+            }
+        }
+
+        if let Some(tail) = tail {
+            self.map.insert(range.end, tail);
+        }
+    }
+
+    /// Gets an iterator over the parts of `range` that are not covered by any range in the map,
+    /// in ascending order.
+    pub fn gaps<'a>(&'a self, range: &'a Range<K>) -> Gaps<'a, K, V> {
+        // Skip past whatever part of the range is covered by a range starting before it.
+        let cursor = match self.map.range::<K, K>(Unbounded, Excluded(&range.start)).next_back() {
+            Some((_, &(ref end, _))) if *end > range.start => end.clone(),
+            _ => range.start.clone()
+        };
+
+        Gaps {
+            inner: self.map.range(Included(&range.start), Excluded(&range.end)),
+            cursor: cursor,
+            end: &range.end,
+            done: range.start >= range.end
+        }
+    }
+
+    /// Removes every range from the map.
+    pub fn clear(&mut self) {
+        self.map.clear();
+    }
+}
+
+impl<K, V> RangeMap<K, V> {
+    /// Gets an iterator over the start, end and value of every range in the map, in ascending
+    /// order.
+    pub fn iter(&self) -> Iter<K, V> {
+        Iter { inner: self.map.iter() }
+    }
+
+    /// Returns the number of distinct ranges in the map, after coalescing.
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    /// Returns true if the map contains no ranges.
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+}
+
+/// An iterator over a RangeMap's ranges.
+pub struct Iter<'a, K: 'a, V: 'a> {
+    inner: map::Iter<'a, K, (K, V)>
+}
+
+/// An iterator over the gaps between the ranges in a RangeMap.
+pub struct Gaps<'a, K: 'a, V: 'a> {
+    // The ranges starting inside the query
+    inner: map::Range<'a, K, (K, V)>,
+    // Everything before this has been covered or yielded
+    cursor: K,
+    end: &'a K,
+    done: bool
+}
+
+impl<'a, K, V> Iterator for Iter<'a, K, V> {
+    type Item = (&'a K, &'a K, &'a V);
+
+    fn next(&mut self) -> Option<(&'a K, &'a K, &'a V)> {
+        self.inner.next().map(|(start, &(ref end, ref val))| (start, end, val))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+impl<'a, K, V> DoubleEndedIterator for Iter<'a, K, V> {
+    fn next_back(&mut self) -> Option<(&'a K, &'a K, &'a V)> {
+        self.inner.next_back().map(|(start, &(ref end, ref val))| (start, end, val))
+    }
+}
+
+impl<'a, K, V> ExactSizeIterator for Iter<'a, K, V> {
+    fn len(&self) -> usize {
+        self.inner.len()
+    }
+}
+
+impl<'a, K: Ord + Clone, V> Iterator for Gaps<'a, K, V> {
+    type Item = Range<K>;
+
+    fn next(&mut self) -> Option<Range<K>> {
+        while !self.done {
+            match self.inner.next() {
+                Some((start, &(ref end, _))) => {
+                    let gap = if *start > self.cursor {
+                        Some(self.cursor.clone()..start.clone())
+                    } else {
+                        None
+                    };
+                    self.cursor = end.clone();
+                    if gap.is_some() {
+                        return gap;
+                    }
+                },
+                None => {
+                    self.done = true;
+                    if self.cursor < *self.end {
+                        return Some(self.cursor.clone()..self.end.clone());
+                    }
+                }
+            }
+        }
+        None
+    }
+}
+
+impl<K: Ord + Clone, V: Clone + PartialEq> FromIterator<(Range<K>, V)> for RangeMap<K, V> {
+    fn from_iter<T: IntoIterator<Item=(Range<K>, V)>>(iter: T) -> RangeMap<K, V> {
+        let mut map = RangeMap::new();
+        map.extend(iter);
+        map
+    }
+}
+
+impl<K: Ord + Clone, V: Clone + PartialEq> Extend<(Range<K>, V)> for RangeMap<K, V> {
+    #[inline]
+    fn extend<T: IntoIterator<Item=(Range<K>, V)>>(&mut self, iter: T) {
+        for (range, val) in iter {
+            self.insert_range(range, val);
+        }
+    }
+}
+
+impl<K: Ord + Clone, V: Clone + PartialEq> Default for RangeMap<K, V> {
+    fn default() -> RangeMap<K, V> {
+        RangeMap::new()
+    }
+}
+
+impl<K: PartialEq, V: PartialEq> PartialEq for RangeMap<K, V> {
+    fn eq(&self, other: &RangeMap<K, V>) -> bool {
+        self.map == other.map
+    }
+}
+
+impl<K: Eq, V: Eq> Eq for RangeMap<K, V> {}
+
+impl<K: Debug, V: Debug> Debug for RangeMap<K, V> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_map().entries(self.iter().map(|(start, end, val)| (start..end, val))).finish()
+    }
+}
@@ -9,27 +9,32 @@
 // except according to those terms.
 
 use core::cmp::Ordering;
+use core::ptr;
 
 use collections::borrow::Borrow;
 
-use super::node::{Handle, NodeRef, marker};
+use super::aggregate::Monoid;
+use super::compare::{Compare, Natural};
+use super::node::{Handle, NodeRef, NoAggregate, marker};
 
 use super::node::ForceResult::*;
 use self::SearchResult::*;
 
-pub enum SearchResult<Lifetime, K, V, Mutability, FoundType, GoDownType> {
-    Found(Handle<NodeRef<Lifetime, K, V, Mutability, FoundType>, marker::KV>),
-    GoDown(Handle<NodeRef<Lifetime, K, V, Mutability, GoDownType>, marker::Edge>)
+pub enum SearchResult<Lifetime, K, V, Mutability, FoundType, GoDownType,
+                      M: Monoid<K, V> = NoAggregate> {
+    Found(Handle<NodeRef<Lifetime, K, V, Mutability, FoundType, M>, marker::KV>),
+    GoDown(Handle<NodeRef<Lifetime, K, V, Mutability, GoDownType, M>, marker::Edge>)
 }
 
-pub fn search_tree<Lifetime, K, V, Mutability, Q: ?Sized>(
-    mut node: NodeRef<Lifetime, K, V, Mutability, marker::LeafOrInternal>,
-    key: &Q
-) -> SearchResult<Lifetime, K, V, Mutability, marker::LeafOrInternal, marker::Leaf>
-        where Q: Ord, K: Borrow<Q> {
+pub fn search_tree<Lifetime, K, V, Mutability, Q: ?Sized, C, M>(
+    mut node: NodeRef<Lifetime, K, V, Mutability, marker::LeafOrInternal, M>,
+    key: &Q,
+    cmp: &C
+) -> SearchResult<Lifetime, K, V, Mutability, marker::LeafOrInternal, marker::Leaf, M>
+        where C: Compare<Q, K>, M: Monoid<K, V> {
 
     loop {
-        match search_node(node, key) {
+        match search_node(node, key, cmp) {
             Found(handle) => return Found(handle),
             GoDown(handle) => match handle.force() {
                 Leaf(leaf) => return GoDown(leaf),
@@ -42,13 +47,14 @@ pub fn search_tree<Lifetime, K, V, Mutability, Q: ?Sized>(
     }
 }
 
-pub fn search_node<Lifetime, K, V, Mutability, Type, Q: ?Sized>(
-    node: NodeRef<Lifetime, K, V, Mutability, Type>,
-    key: &Q
-) -> SearchResult<Lifetime, K, V, Mutability, Type, Type>
-        where Q: Ord, K: Borrow<Q> {
+pub fn search_node<Lifetime, K, V, Mutability, Type, Q: ?Sized, C, M>(
+    node: NodeRef<Lifetime, K, V, Mutability, Type, M>,
+    key: &Q,
+    cmp: &C
+) -> SearchResult<Lifetime, K, V, Mutability, Type, Type, M>
+        where C: Compare<Q, K>, M: Monoid<K, V> {
 
-    match search_linear(&node, key) {
+    match search_linear_by(node.keys(), key, cmp) {
         (idx, true) => Found(
             unsafe { Handle::new(node, idx) }
         ),
@@ -58,19 +64,133 @@ pub fn search_node<Lifetime, K, V, Mutability, Type, Q: ?Sized>(
     }
 }
 
-fn search_linear<Lifetime, K, V, Mutability, Type, Q: ?Sized>(
-    node: &NodeRef<Lifetime, K, V, Mutability, Type>,
-    key: &Q
-) -> (usize, bool)
+/// Like `search_tree`, but starts from `node`, which may be any node of the tree, rather than from
+/// the root. When successive keys are close together, as in a sorted batch, most searches never
+/// go above the leaf the previous one ended in.
+///
+/// The subtree below a node holds exactly the keys between the separators in its ancestors that
+/// lie immediately to either side of it, so this ascends only as far as it takes to find those
+/// two separators, comparing `key` with each. If `key` lies outside one of them, the parent
+/// becomes the candidate and the search for that side's separator carries on above it. The
+/// search then descends from the lowest node whose subtree must contain `key`. Ascending past
+/// nodes that are the first or last child of their parent costs no comparisons, so a key above
+/// every key in the tree is searched for from the rightmost leaf.
+pub fn search_tree_from<Lifetime, K, V, Mutability, Q: ?Sized, C, M>(
+    node: NodeRef<Lifetime, K, V, Mutability, marker::LeafOrInternal, M>,
+    key: &Q,
+    cmp: &C
+) -> SearchResult<Lifetime, K, V, Mutability, marker::LeafOrInternal, marker::Leaf, M>
+        where C: Compare<Q, K>, M: Monoid<K, V> {
+
+    // Whether `key` is known to lie after the separator to the left of `start`'s subtree, and
+    // before the one to its right. A side with no separator up to the root is unbounded.
+    let mut above_lower = false;
+    let mut below_upper = false;
+    let mut cur = unsafe { ptr::read(&node) };
+    let mut start = node;
+
+    while !(above_lower && below_upper) {
+        let parent = match cur.ascend() {
+            Ok(parent) => parent,
+            Err(_) => break
+        };
+        let idx = parent.idx();
+        let parent = parent.into_node();
+
+        if !above_lower && idx > 0 {
+            if cmp.compare(key, &parent.keys()[idx - 1]) == Ordering::Greater {
+                above_lower = true;
+            } else {
+                // `key` is no greater than a key in the parent, so it lies before the
+                // parent's own upper separator.
+                start = unsafe { ptr::read(&parent) }.forget_type();
+                below_upper = true;
+            }
+        }
+        if !below_upper && idx < parent.len() {
+            if cmp.compare(key, &parent.keys()[idx]) == Ordering::Less {
+                below_upper = true;
+            } else {
+                start = unsafe { ptr::read(&parent) }.forget_type();
+                above_lower = true;
+            }
+        }
+        cur = parent.forget_type();
+    }
+
+    search_tree(start, key, cmp)
+}
+
+/// Descends to the leaf edge just before the first key that is not less than `key`. Unlike
+/// `search_tree`, this does not stop at the first equal key it meets, so in a tree that contains
+/// duplicate keys it finds the position before all of the keys equal to `key`.
+pub fn lower_bound<Lifetime, K, V, Mutability, Q: ?Sized, C, M>(
+    mut node: NodeRef<Lifetime, K, V, Mutability, marker::LeafOrInternal, M>,
+    key: &Q,
+    cmp: &C
+) -> Handle<NodeRef<Lifetime, K, V, Mutability, marker::Leaf, M>, marker::Edge>
+        where C: Compare<Q, K>, M: Monoid<K, V> {
+
+    loop {
+        let idx = search_linear_by(node.keys(), key, cmp).0;
+        match unsafe { Handle::new(node, idx) }.force() {
+            Leaf(leaf) => return leaf,
+            Internal(internal) => node = internal.descend()
+        }
+    }
+}
+
+/// Descends to the leaf edge just after the last key that is not greater than `key`. In a tree
+/// that contains duplicate keys, this is the position after all of the keys equal to `key`.
+pub fn upper_bound<Lifetime, K, V, Mutability, Q: ?Sized, C, M>(
+    mut node: NodeRef<Lifetime, K, V, Mutability, marker::LeafOrInternal, M>,
+    key: &Q,
+    cmp: &C
+) -> Handle<NodeRef<Lifetime, K, V, Mutability, marker::Leaf, M>, marker::Edge>
+        where C: Compare<Q, K>, M: Monoid<K, V> {
+
+    loop {
+        let idx = search_linear_upper(node.keys(), key, cmp);
+        match unsafe { Handle::new(node, idx) }.force() {
+            Leaf(leaf) => return leaf,
+            Internal(internal) => node = internal.descend()
+        }
+    }
+}
+
+/// Searches a sorted slice of keys, returning the index of the first key that is not less than
+/// `key` and whether that key is equal to it. This is exposed separately from `search_node` so
+/// that trees not built out of `NodeRef`s can share the same node search strategy.
+pub fn search_linear<K, Q: ?Sized>(keys: &[K], key: &Q) -> (usize, bool)
         where Q: Ord, K: Borrow<Q> {
 
-    for (i, k) in node.keys().iter().enumerate() {
-        match key.cmp(k.borrow()) {
+    search_linear_by(keys, key, &Natural)
+}
+
+/// Like `search_linear`, but with the keys ordered by a comparator.
+pub fn search_linear_by<K, Q: ?Sized, C>(keys: &[K], key: &Q, cmp: &C) -> (usize, bool)
+        where C: Compare<Q, K> {
+
+    for (i, k) in keys.iter().enumerate() {
+        match cmp.compare(key, k) {
             Ordering::Greater => {},
             Ordering::Equal => return (i, true),
             Ordering::Less => return (i, false)
         }
     }
-    (node.keys().len(), false)
+    (keys.len(), false)
 }
 
+/// Searches a sorted slice of keys, which may contain duplicates, returning the index of the
+/// first key that is greater than `key`. Together with `search_linear_by`, this brackets the run of
+/// keys equal to `key`.
+pub fn search_linear_upper<K, Q: ?Sized, C>(keys: &[K], key: &Q, cmp: &C) -> usize
+        where C: Compare<Q, K> {
+
+    for (i, k) in keys.iter().enumerate() {
+        if cmp.compare(key, k) == Ordering::Less {
+            return i;
+        }
+    }
+    keys.len()
+}
@@ -0,0 +1,334 @@
+// Copyright 2015 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// The algorithms that walk and rebalance whole trees of `node.rs` nodes, shared by `BTreeMap`,
+// `AggregateMap` and `PersistentMap`. The node operations they are built on keep the aggregates
+// cached in internal nodes up to date as elements and edges move around, so these only need to
+// refresh them where they move elements between nodes themselves, as in the steal path of
+// `handle_underfull_node`. Aggregates above the nodes they leave behind are left for the caller
+// to refresh, which plain maps, whose aggregates are all `()`, never need to do.
+
+use core::{intrinsics, mem};
+
+use super::aggregate::Monoid;
+use super::node::{Handle, NodeRef, NoAggregate, marker};
+
+use super::node::ForceResult::*;
+use super::node::InsertResult::*;
+use self::UnderflowResult::*;
+
+pub fn first_leaf_edge<Lifetime, K, V, Mutability, M: Monoid<K, V>>(
+        mut node: NodeRef<Lifetime,
+                          K, V,
+                          Mutability,
+                          marker::LeafOrInternal,
+                          M>
+        ) -> Handle<NodeRef<Lifetime, K, V, Mutability, marker::Leaf, M>, marker::Edge> {
+    loop {
+        match node.force() {
+            Leaf(leaf) => return leaf.first_edge(),
+            Internal(internal) => {
+                node = internal.first_edge().descend();
+            }
+        }
+    }
+}
+
+pub fn last_leaf_edge<Lifetime, K, V, Mutability, M: Monoid<K, V>>(
+        mut node: NodeRef<Lifetime,
+                          K, V,
+                          Mutability,
+                          marker::LeafOrInternal,
+                          M>
+        ) -> Handle<NodeRef<Lifetime, K, V, Mutability, marker::Leaf, M>, marker::Edge> {
+    loop {
+        match node.force() {
+            Leaf(leaf) => return leaf.last_edge(),
+            Internal(internal) => {
+                node = internal.last_edge().descend();
+            }
+        }
+    }
+}
+
+/// Finds the key-value pair that immediately follows a leaf edge, which is either the next one in
+/// the same leaf or, if the edge is the last in its leaf, the first ancestor key-value pair to its
+/// right.
+pub fn next_kv<Lifetime, K, V, Mutability, M: Monoid<K, V>>(
+        edge: Handle<NodeRef<Lifetime, K, V, Mutability, marker::Leaf, M>, marker::Edge>
+        ) -> Option<Handle<NodeRef<Lifetime, K, V, Mutability, marker::LeafOrInternal, M>,
+                           marker::KV>> {
+
+    let mut node = match edge.right_kv() {
+        Ok(kv) => return Some(kv.forget_node_type()),
+        Err(last_edge) => last_edge.into_node().forget_type()
+    };
+
+    loop {
+        match node.ascend() {
+            Ok(parent_edge) => match parent_edge.right_kv() {
+                Ok(kv) => {
+                    let idx = kv.idx();
+                    return Some(unsafe { Handle::new(kv.into_node().forget_type(), idx) });
+                },
+                Err(last_edge) => node = last_edge.into_node().forget_type()
+            },
+            Err(_) => return None
+        }
+    }
+}
+
+/// Finds the key-value pair that immediately precedes a leaf edge. This is the mirror image of
+/// `next_kv`.
+pub fn prev_kv<Lifetime, K, V, Mutability, M: Monoid<K, V>>(
+        edge: Handle<NodeRef<Lifetime, K, V, Mutability, marker::Leaf, M>, marker::Edge>
+        ) -> Option<Handle<NodeRef<Lifetime, K, V, Mutability, marker::LeafOrInternal, M>,
+                           marker::KV>> {
+
+    let mut node = match edge.left_kv() {
+        Ok(kv) => return Some(kv.forget_node_type()),
+        Err(first_edge) => first_edge.into_node().forget_type()
+    };
+
+    loop {
+        match node.ascend() {
+            Ok(parent_edge) => match parent_edge.left_kv() {
+                Ok(kv) => {
+                    let idx = kv.idx();
+                    return Some(unsafe { Handle::new(kv.into_node().forget_type(), idx) });
+                },
+                Err(first_edge) => node = first_edge.into_node().forget_type()
+            },
+            Err(_) => return None
+        }
+    }
+}
+
+/// Inserts a key-value pair at a leaf edge, splitting nodes up the tree as needed, and returns a
+/// handle to the newly inserted pair. This leaves updating the map's length to the caller.
+pub fn insert_kv<'a, K: 'a, V: 'a, M: 'a + Monoid<K, V>>(
+        handle: Handle<NodeRef<marker::Borrowed<'a>, K, V, marker::Mut, marker::Leaf, M>,
+                       marker::Edge>,
+        key: K,
+        value: V
+        ) -> Handle<NodeRef<marker::Borrowed<'a>, K, V, marker::Mut, marker::LeafOrInternal, M>,
+                    marker::KV> {
+    let out_handle;
+
+    let mut ins_k;
+    let mut ins_v;
+    let mut ins_edge;
+
+    let mut cur_parent = match handle.insert(key, value) {
+        (Fit(handle), _) => return handle.forget_node_type(),
+        (Split(left, k, v, right), inserted) => {
+            ins_k = k;
+            ins_v = v;
+            ins_edge = right;
+            out_handle = inserted.forget_node_type();
+            left.ascend().map_err(|n| n.into_root_mut())
+        }
+    };
+
+    loop {
+        match cur_parent {
+            Ok(parent) => match parent.insert(ins_k, ins_v, ins_edge) {
+                Fit(_) => break,
+                Split(left, k, v, right) => {
+                    ins_k = k;
+                    ins_v = v;
+                    ins_edge = right;
+                    cur_parent = left.ascend().map_err(|n| n.into_root_mut());
+                }
+            },
+            Err(root) => {
+                root.enlarge().push(ins_k, ins_v, ins_edge);
+                break;
+            }
+        }
+    }
+
+    out_handle
+}
+
+/// Removes a key-value pair from the tree, rebalancing it as needed, and returns the pair along
+/// with a node that survived the rebalancing. That node is the one the removal ended in, or the
+/// root if it rebalanced all the way up. This leaves updating the map's length to the caller.
+pub fn remove_kv<'a, K: 'a, V: 'a, M: 'a + Monoid<K, V>>(
+        handle: Handle<NodeRef<marker::Borrowed<'a>,
+                               K, V,
+                               marker::Mut,
+                               marker::LeafOrInternal,
+                               M>,
+                       marker::KV>
+        ) -> (K, V, NodeRef<marker::Borrowed<'a>, K, V, marker::Mut, marker::LeafOrInternal, M>) {
+    remove_kv_with(handle, |_| {})
+}
+
+/// Like `remove_kv`, but calls `prepare` before each rebalancing step, as described for
+/// `handle_underfull_node_with`.
+pub fn remove_kv_with<'a, K: 'a, V: 'a, M: 'a + Monoid<K, V>, F>(
+        handle: Handle<NodeRef<marker::Borrowed<'a>,
+                               K, V,
+                               marker::Mut,
+                               marker::LeafOrInternal,
+                               M>,
+                       marker::KV>,
+        mut prepare: F
+        ) -> (K, V, NodeRef<marker::Borrowed<'a>, K, V, marker::Mut, marker::LeafOrInternal, M>)
+        where F: FnMut(&mut Handle<NodeRef<marker::Borrowed<'a>,
+                                           K, V,
+                                           marker::Mut,
+                                           marker::Internal,
+                                           M>,
+                                   marker::KV>) {
+    let (small_leaf, old_key, old_val) = match handle.force() {
+        Leaf(leaf) => {
+            let (hole, old_key, old_val) = leaf.remove();
+            (hole.into_node(), old_key, old_val)
+        },
+        Internal(mut internal) => {
+            let key_loc = internal.kv_mut().0 as *mut K;
+            let val_loc = internal.kv_mut().1 as *mut V;
+
+            let to_remove = first_leaf_edge(internal.right_edge().descend()).right_kv().ok();
+            let to_remove = unsafe { unwrap_unchecked(to_remove) };
+
+            let (hole, key, val) = to_remove.remove();
+
+            let old_key = unsafe {
+                mem::replace(&mut *key_loc, key)
+            };
+            let old_val = unsafe {
+                mem::replace(&mut *val_loc, val)
+            };
+
+            (hole.into_node(), old_key, old_val)
+        }
+    };
+
+    // Handle underflow
+    let mut cur_node = small_leaf.forget_type();
+    while cur_node.len() < cur_node.capacity() / 2 {
+        match handle_underfull_node_with(cur_node, &mut prepare) {
+            AtRoot(root) => {
+                cur_node = root;
+                break;
+            },
+            EmptyParent(_) => unreachable!(),
+            Merged(parent) => if parent.len() == 0 {
+                // We must be at the root
+                let root = parent.into_root_mut();
+                root.shrink();
+                cur_node = root.as_mut();
+                break;
+            } else {
+                cur_node = parent.forget_type();
+            },
+            Stole(parent) => {
+                cur_node = parent.forget_type();
+                break;
+            }
+        }
+    }
+
+    (old_key, old_val, cur_node)
+}
+
+pub enum UnderflowResult<'a, K, V, M: Monoid<K, V> = NoAggregate> {
+    AtRoot(NodeRef<marker::Borrowed<'a>, K, V, marker::Mut, marker::LeafOrInternal, M>),
+    EmptyParent(NodeRef<marker::Borrowed<'a>, K, V, marker::Mut, marker::Internal, M>),
+    Merged(NodeRef<marker::Borrowed<'a>, K, V, marker::Mut, marker::Internal, M>),
+    Stole(NodeRef<marker::Borrowed<'a>, K, V, marker::Mut, marker::Internal, M>)
+}
+
+/// Restores the minimum length of a node other than the root by either merging it with a sibling
+/// or stealing an element from one, refreshing the aggregates its parent caches for every child
+/// involved.
+pub fn handle_underfull_node<'a, K, V, M: Monoid<K, V>>(
+        node: NodeRef<marker::Borrowed<'a>, K, V, marker::Mut, marker::LeafOrInternal, M>
+        ) -> UnderflowResult<'a, K, V, M> {
+    handle_underfull_node_with(node, &mut |_| {})
+}
+
+/// Like `handle_underfull_node`, but first passes `prepare` the key-value pair in the parent that
+/// separates the node from the sibling it is about to be merged with or steal from. Trees whose
+/// nodes may be shared use this to make both of them unique before either is changed.
+pub fn handle_underfull_node_with<'a, K, V, M: Monoid<K, V>, F>(
+        node: NodeRef<marker::Borrowed<'a>, K, V, marker::Mut, marker::LeafOrInternal, M>,
+        prepare: &mut F
+        ) -> UnderflowResult<'a, K, V, M>
+        where F: FnMut(&mut Handle<NodeRef<marker::Borrowed<'a>,
+                                           K, V,
+                                           marker::Mut,
+                                           marker::Internal,
+                                           M>,
+                                   marker::KV>) {
+    let parent = match node.ascend() {
+        Ok(parent) => parent,
+        Err(root) => return AtRoot(root)
+    };
+
+    let (is_left, mut handle) = match parent.left_kv() {
+        Ok(left) => (true, left),
+        Err(parent) => match parent.right_kv() {
+            Ok(right) => (false, right),
+            Err(parent) => {
+                return EmptyParent(parent.into_node());
+            }
+        }
+    };
+
+    prepare(&mut handle);
+
+    if handle.can_merge() {
+        return Merged(handle.merge().into_node());
+    } else {
+        unsafe {
+            let (k, v, edge) = if is_left {
+                handle.reborrow_mut().left_edge().descend().pop()
+            } else {
+                handle.reborrow_mut().right_edge().descend().pop_front()
+            };
+
+            let k = mem::replace(handle.reborrow_mut().into_kv_mut().0, k);
+            let v = mem::replace(handle.reborrow_mut().into_kv_mut().1, v);
+
+            // FIXME: reuse cur_node?
+            if is_left {
+                match handle.reborrow_mut().right_edge().descend().force() {
+                    Leaf(mut leaf) => leaf.push_front(k, v),
+                    Internal(mut internal) => internal.push_front(k, v, edge.unwrap())
+                }
+            } else {
+                match handle.reborrow_mut().left_edge().descend().force() {
+                    Leaf(mut leaf) => leaf.push(k, v),
+                    Internal(mut internal) => internal.push(k, v, edge.unwrap())
+                }
+            }
+
+            handle.reborrow_mut().left_edge().refresh_agg();
+            handle.reborrow_mut().right_edge().refresh_agg();
+        }
+
+        return Stole(handle.into_node());
+    }
+}
+
+#[inline(always)]
+pub unsafe fn unwrap_unchecked<T>(val: Option<T>) -> T {
+    val.unwrap_or_else(|| {
+        if cfg!(debug_assertions) {
+            panic!("'unchecked' unwrap on None in BTreeMap");
+        } else {
+            intrinsics::unreachable();
+        }
+    })
+}
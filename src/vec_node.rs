@@ -0,0 +1,115 @@
+// Copyright 2015 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// The node `PagedMap` decodes its pages into. It keeps its elements, and the page numbers of its
+// children, in `Vec`s rather than going through the `NodeRef` machinery in `node.rs`, since a
+// child can only be reached by loading its page. Nodes have the same shape as in `node.rs`,
+// holding between `MIN_LEN` and `CAPACITY` elements, and are split, merged and stolen from at the
+// same points.
+
+use core::mem;
+
+use super::node::{CAPACITY, MIN_LEN};
+
+pub struct Node<K, V, E> {
+    pub keys: Vec<K>,
+    pub vals: Vec<V>,
+    // Empty exactly when this node is a leaf
+    pub edges: Vec<E>
+}
+
+impl<K, V, E> Node<K, V, E> {
+    // Nodes are split only after they overflow, so they get room for one more element than
+    // they can keep.
+    pub fn new_leaf() -> Self {
+        Node {
+            keys: Vec::with_capacity(CAPACITY + 1),
+            vals: Vec::with_capacity(CAPACITY + 1),
+            edges: Vec::new()
+        }
+    }
+
+    pub fn new_internal() -> Self {
+        Node {
+            keys: Vec::with_capacity(CAPACITY + 1),
+            vals: Vec::with_capacity(CAPACITY + 1),
+            edges: Vec::with_capacity(CAPACITY + 2)
+        }
+    }
+
+    pub fn is_leaf(&self) -> bool {
+        self.edges.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    /// Splits an overfull node around its middle element, leaving the left half in place and
+    /// returning the middle element along with the right half.
+    pub fn split(&mut self) -> (K, V, Node<K, V, E>) {
+        let mut right = if self.is_leaf() { Node::new_leaf() } else { Node::new_internal() };
+
+        right.keys.extend(self.keys.drain(MIN_LEN + 2..));
+        right.vals.extend(self.vals.drain(MIN_LEN + 2..));
+        if !self.is_leaf() {
+            right.edges.extend(self.edges.drain(MIN_LEN + 2..));
+        }
+
+        let k = self.keys.pop().unwrap();
+        let v = self.vals.pop().unwrap();
+
+        (k, v, right)
+    }
+
+    /// Appends the element that separated this node from its right sibling, followed by
+    /// everything in that sibling.
+    pub fn merge(&mut self, key: K, val: V, right: Node<K, V, E>) {
+        self.keys.push(key);
+        self.vals.push(val);
+        self.keys.extend(right.keys);
+        self.vals.extend(right.vals);
+        self.edges.extend(right.edges);
+    }
+}
+
+/// Returns whether the children on either side of an element fit in a single node together
+/// with it, and so should be merged rather than stolen from.
+pub fn can_merge<K, V, E>(left: &Node<K, V, E>, right: &Node<K, V, E>) -> bool {
+    left.len() + right.len() + 1 <= CAPACITY
+}
+
+/// Rotates the last element of `left` up into the parent, in place of the element `key` and
+/// `val` that separates `left` from `right`, which moves down to the front of `right` along
+/// with the last edge of `left`.
+pub fn steal_left<K, V, E>(key: &mut K, val: &mut V,
+                           left: &mut Node<K, V, E>, right: &mut Node<K, V, E>) {
+    let k = mem::replace(key, left.keys.pop().unwrap());
+    let v = mem::replace(val, left.vals.pop().unwrap());
+    right.keys.insert(0, k);
+    right.vals.insert(0, v);
+    if let Some(edge) = left.edges.pop() {
+        right.edges.insert(0, edge);
+    }
+}
+
+/// Rotates the first element of `right` up into the parent, in place of the element `key` and
+/// `val` that separates `left` from `right`, which moves down to the back of `left` along with
+/// the first edge of `right`.
+pub fn steal_right<K, V, E>(key: &mut K, val: &mut V,
+                            left: &mut Node<K, V, E>, right: &mut Node<K, V, E>) {
+    let k = mem::replace(key, right.keys.remove(0));
+    let v = mem::replace(val, right.vals.remove(0));
+    left.keys.push(k);
+    left.vals.push(v);
+    if !right.is_leaf() {
+        left.edges.push(right.edges.remove(0));
+    }
+}
@@ -3,6 +3,8 @@
 extern crate btree_rewrite;
 extern crate collections;
 extern crate rand;
+#[cfg(feature = "serde")]
+extern crate serde_json;
 
 use collections::Bound::{self, Included, Excluded, Unbounded};
 use btree_rewrite::map::*;
@@ -22,10 +24,44 @@ fn test_send_sync() {
 
     t::<Range<u32, i32>>();
     t::<RangeMut<u32, i32>>();
+    t::<RangeKeys<u32, i32>>();
+    t::<RangeValues<u32, i32>>();
+
+    t::<ValuesMut<u32, i32>>();
+    t::<IntoKeys<u32, i32>>();
+    t::<IntoValues<u32, i32>>();
 
     t::<Entry<u32, i32>>();
     t::<OccupiedEntry<u32, i32>>();
     t::<VacantEntry<u32, i32>>();
+    t::<EntryRef<u32, i32, u32>>();
+    t::<VacantEntryRef<u32, i32, u32>>();
+
+    t::<btree_rewrite::PersistentMap<u32, i32>>();
+    t::<btree_rewrite::persistent::Iter<u32, i32>>();
+
+    t::<btree_rewrite::ConcurrentBTreeMap<u32, i32>>();
+
+    struct Count;
+    impl btree_rewrite::aggregate::Monoid<u32, i32> for Count {
+        type Summary = usize;
+        fn identity() -> usize { 0 }
+        fn lift(_: &u32, _: &i32) -> usize { 1 }
+        fn combine(left: &usize, right: &usize) -> usize { left + right }
+    }
+    t::<btree_rewrite::AggregateMap<u32, i32, Count>>();
+    t::<btree_rewrite::aggregate::Iter<u32, i32, Count>>();
+    t::<btree_rewrite::aggregate::IterMut<u32, i32, Count>>();
+
+    t::<btree_rewrite::IntervalMap<u32, i32>>();
+    t::<btree_rewrite::interval::Overlaps<u32, i32>>();
+
+    t::<btree_rewrite::RangeMap<u32, i32>>();
+    t::<btree_rewrite::range_map::Gaps<u32, i32>>();
+
+    t::<btree_rewrite::BTreeMultiMap<u32, i32>>();
+    t::<BTreeMap<u32, i32, btree_rewrite::compare::Rev<btree_rewrite::compare::Natural>>>();
+    t::<btree_rewrite::concurrent::Range<u32, i32, u32>>();
 }
 
 #[test]
@@ -195,7 +231,7 @@ fn test_range_small() {
     let map: BTreeMap<_, _> = (0..size).map(|i| (i, i)).collect();
 
     let mut j = 0;
-    for ((&k, &v), i) in map.range(Included(&2), Unbounded).zip(2..size) {
+    for ((&k, &v), i) in map.range::<i32, i32>(Included(&2), Unbounded).zip(2..size) {
         assert_eq!(k, i);
         assert_eq!(v, i);
         j += 1;
@@ -226,6 +262,110 @@ fn test_range_1000() {
     test(&map, size, Unbounded, Unbounded);
 }
 
+#[test]
+fn test_range_bound_combinations() {
+    // Only even keys, so that every bound value is either present or falls between two keys
+    let mut map: BTreeMap<i32, i32> = (0..100).map(|i| (i * 2, i)).collect();
+
+    fn bounds(x: i32) -> Vec<Bound<i32>> {
+        vec![Included(x), Excluded(x)]
+    }
+
+    fn contains(min: &Bound<i32>, max: &Bound<i32>, k: i32) -> (bool, bool) {
+        (match *min {
+            Included(m) => k >= m,
+            Excluded(m) => k > m,
+            Unbounded => true
+        }, match *max {
+            Included(m) => k <= m,
+            Excluded(m) => k < m,
+            Unbounded => true
+        })
+    }
+
+    fn as_ref(bound: &Bound<i32>) -> Bound<&i32> {
+        match *bound {
+            Included(ref k) => Included(k),
+            Excluded(ref k) => Excluded(k),
+            Unbounded => Unbounded
+        }
+    }
+
+    let mut all = vec![Unbounded];
+    for x in -2..202 {
+        if x < 4 || x > 194 || x % 17 < 3 {
+            all.extend(bounds(x));
+        }
+    }
+
+    // Decided from the bounds alone, whatever is in the map
+    fn inverted(min: &Bound<i32>, max: &Bound<i32>) -> bool {
+        match (*min, *max) {
+            (Excluded(min), Excluded(max)) => min >= max,
+            (Included(min), Included(max)) |
+            (Included(min), Excluded(max)) |
+            (Excluded(min), Included(max)) => min > max,
+            _ => false
+        }
+    }
+
+    for min in &all {
+        for max in &all {
+            let keys: Vec<i32> = map.keys().cloned().collect();
+            let inverted = inverted(min, max);
+            let expected: Vec<i32> = keys.into_iter()
+                                         .filter(|&k| contains(min, max, k) == (true, true))
+                                         .collect();
+            assert!(!inverted || expected.is_empty());
+
+            let range = map.range(as_ref(min), as_ref(max));
+            assert!(range.clone().map(|(&k, _)| k).eq(expected.iter().cloned()));
+            assert!(range.rev().map(|(&k, _)| k).eq(expected.iter().rev().cloned()));
+            assert_eq!(map.range_mut(as_ref(min), as_ref(max)).count(), expected.len());
+
+            match map.try_range(as_ref(min), as_ref(max)) {
+                Ok(range) => {
+                    assert!(!inverted);
+                    assert!(range.map(|(&k, _)| k).eq(expected.iter().cloned()));
+                },
+                Err(err) => {
+                    assert!(inverted);
+                    assert_eq!(err, InvertedRange);
+                }
+            }
+
+            match map.try_range_mut(as_ref(min), as_ref(max)) {
+                Ok(range) => {
+                    assert!(!inverted);
+                    assert_eq!(range.count(), expected.len());
+                },
+                Err(_) => assert!(inverted)
+            }
+        }
+    }
+
+    let empty: BTreeMap<i32, i32> = BTreeMap::new();
+    assert_eq!(empty.range(Included(&5), Included(&1)).next(), None);
+    assert_eq!(empty.try_range(Included(&5), Included(&1)).err(), Some(InvertedRange));
+    assert_eq!(format!("{}", InvertedRange), "range start is after range end in BTreeMap");
+}
+
+#[test]
+fn test_range_inverted() {
+    // The same bounds are treated the same way whether or not the map has keys between them
+    let map: BTreeMap<_, _> = (0..10).map(|i| (i, i)).collect();
+    for &x in &[5, 20] {
+        assert_eq!(map.range(Excluded(&x), Excluded(&x)).count(), 0);
+        assert_eq!(map.try_range(Excluded(&x), Excluded(&x)).err(), Some(InvertedRange));
+        assert_eq!(map.range(Included(&x), Excluded(&x)).count(), 0);
+        assert!(map.try_range(Included(&x), Excluded(&x)).is_ok());
+    }
+    for &(min, max) in &[(7, 2), (20, 15), (-5, -10)] {
+        assert_eq!(map.range(Included(&min), Included(&max)).count(), 0);
+        assert_eq!(map.try_range(Included(&min), Included(&max)).err(), Some(InvertedRange));
+    }
+}
+
 #[test]
 fn test_range() {
     let size = 200;
@@ -341,3 +481,1803 @@ fn test_extend_ref() {
     assert_eq!(a[&2], "two");
     assert_eq!(a[&3], "three");
 }
+
+#[test]
+fn test_compact() {
+    let size = 10000;
+    let mut map: BTreeMap<_, _> = (0..size).map(|i| (i, i)).collect();
+
+    for i in 0..size {
+        if i % 7 != 0 {
+            assert_eq!(map.remove(&i), Some(i));
+        }
+    }
+
+    map.compact();
+    assert_eq!(map.len(), (size + 6) / 7);
+    for (kv, i) in map.iter().zip((0..size).filter(|i| i % 7 == 0)) {
+        assert_eq!(kv, (&i, &i));
+    }
+
+    // The rebuilt tree must still be valid for further modification.
+    for i in 0..size {
+        map.insert(i, i * 2);
+    }
+    assert_eq!(map.len(), size);
+    for i in 0..size {
+        assert_eq!(map.remove(&i), Some(i * 2));
+    }
+    assert!(map.is_empty());
+}
+
+#[test]
+fn test_compact_small() {
+    for size in 0..300 {
+        let mut map: BTreeMap<_, _> = (0..size).map(|i| (i, i)).collect();
+        map.compact();
+        assert_eq!(map.len(), size);
+        assert_eq!(map, (0..size).map(|i| (i, i)).collect());
+
+        for i in 0..size {
+            assert_eq!(map.remove(&i), Some(i));
+        }
+        assert!(map.is_empty());
+    }
+}
+
+#[test]
+fn test_compact_density() {
+    // Nodes hold at most 11 elements, and a tree this size is at most 4 levels high.
+    let capacity = 11;
+    let size = 20000;
+
+    for &(percent, fill) in &[(0, 5), (50, 5), (75, 8), (90, 9), (100, 11)] {
+        let mut map: BTreeMap<_, _> = (0..size).map(|i| (i, i)).collect();
+        for i in 0..size {
+            if i % 4 != 0 {
+                map.remove(&i);
+            }
+        }
+        let sparse = map.node_count();
+
+        map.compact_to(percent);
+        assert_eq!(map.len(), size / 4);
+        assert!(map.iter().map(|(&k, _)| k).eq((0..size).filter(|i| i % 4 == 0)));
+
+        // Only the nodes along the right edge may hold a different number of elements than the
+        // target.
+        let nodes = map.node_count();
+        assert!(map.len() >= (nodes - 4) * fill, "{}% gave {} nodes", percent, nodes);
+        assert!(map.len() <= (nodes - 4) * fill + 4 * capacity,
+                "{}% gave {} nodes", percent, nodes);
+        if fill == capacity {
+            assert!(nodes * 3 < sparse * 2, "{} nodes compacted to {}", sparse, nodes);
+        }
+
+        for i in 0..size {
+            map.insert(i, i);
+        }
+        assert!(map.iter().map(|(&k, _)| k).eq(0..size));
+    }
+
+    let mut map: BTreeMap<_, _> = (0..size).map(|i| (i, i)).collect();
+    map.compact();
+    let nodes = map.node_count();
+    assert!(map.len() >= (nodes - 4) * 9);
+    assert!(map.len() <= (nodes - 4) * 9 + 4 * capacity);
+}
+
+#[test]
+fn test_compact_to_small() {
+    for percent in (0..21).map(|i| i * 5) {
+        for size in 0..200 {
+            let mut map: BTreeMap<_, _> = (0..size).map(|i| (i, i)).collect();
+            map.compact_to(percent);
+            assert!(map.iter().map(|(&k, &v)| (k, v)).eq((0..size).map(|i| (i, i))));
+
+            for i in 0..size {
+                assert_eq!(map.remove(&i), Some(i));
+            }
+            assert!(map.is_empty());
+        }
+    }
+}
+
+#[test]
+fn test_persistent_snapshots() {
+    use btree_rewrite::PersistentMap;
+
+    let size = 2000;
+    let mut map: PersistentMap<_, _> = (0..size).map(|i| (i, i)).collect();
+    let snapshot = map.clone();
+
+    for i in 0..size {
+        if i % 3 == 0 {
+            assert_eq!(map.remove(&i), Some(i));
+        } else {
+            assert_eq!(map.insert(i, i * 10), Some(i));
+        }
+    }
+    for i in size..size * 2 {
+        assert_eq!(map.insert(i, i), None);
+    }
+
+    // The snapshot is untouched by everything done to the original
+    assert_eq!(snapshot.len(), size);
+    for (kv, i) in snapshot.iter().zip(0..size) {
+        assert_eq!(kv, (&i, &i));
+    }
+
+    assert_eq!(map.len(), size * 2 - (size + 2) / 3);
+    for i in 0..size * 2 {
+        let expected = if i >= size { Some(i) } else if i % 3 == 0 { None } else { Some(i * 10) };
+        assert_eq!(map.get(&i).cloned(), expected);
+    }
+    assert!(map.iter().rev().map(|(&k, _)| k).eq((0..size * 2).rev().filter(|&i| i >= size || i % 3 != 0)));
+
+    for i in 0..size * 2 {
+        map.remove(&i);
+    }
+    assert!(map.is_empty());
+    assert_eq!(snapshot.len(), size);
+    assert_eq!(snapshot.iter().count(), size);
+}
+
+#[test]
+fn test_persistent_path_copy() {
+    use btree_rewrite::PersistentMap;
+    use std::cell::Cell;
+
+    thread_local!(static CLONES: Cell<usize> = Cell::new(0));
+
+    #[derive(PartialEq, Debug)]
+    struct Counted(usize);
+
+    impl Clone for Counted {
+        fn clone(&self) -> Counted {
+            CLONES.with(|c| c.set(c.get() + 1));
+            Counted(self.0)
+        }
+    }
+
+    let size = 10000;
+    let mut map: PersistentMap<_, _> = (0..size).map(|i| (i, Counted(i))).collect();
+    let clones = || CLONES.with(|c| c.get());
+    let start = clones();
+
+    let mut snapshot = map.clone();
+    assert_eq!(clones(), start);
+
+    // Modifying either copy should only copy a handful of nodes, never the whole map.
+    snapshot.insert(size / 2, Counted(0));
+    map.remove(&(size / 3));
+    snapshot.insert(size, Counted(size));
+    assert!(clones() - start < 200);
+
+    assert_eq!(map.get(&(size / 2)), Some(&Counted(size / 2)));
+    assert_eq!(snapshot.get(&(size / 2)), Some(&Counted(0)));
+    assert_eq!(map.get(&(size / 3)), None);
+    assert_eq!(snapshot.get(&(size / 3)), Some(&Counted(size / 3)));
+    assert_eq!(map.get(&size), None);
+}
+
+#[test]
+fn test_persistent_threads() {
+    use btree_rewrite::PersistentMap;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+
+    // Counts the values alive in every copy of the map, so that each is dropped exactly once
+    #[derive(Debug)]
+    struct Counted(usize, Arc<AtomicUsize>);
+
+    impl Counted {
+        fn new(i: usize, live: &Arc<AtomicUsize>) -> Counted {
+            live.fetch_add(1, Ordering::SeqCst);
+            Counted(i, live.clone())
+        }
+    }
+
+    impl Clone for Counted {
+        fn clone(&self) -> Counted {
+            Counted::new(self.0, &self.1)
+        }
+    }
+
+    impl Drop for Counted {
+        fn drop(&mut self) {
+            self.1.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+
+    let live = Arc::new(AtomicUsize::new(0));
+    let size = 5000;
+    let map: PersistentMap<_, _> = (0..size).map(|i| (i, Counted::new(i, &live))).collect();
+
+    // Each thread changes its own copy of the map, while they all still share most nodes
+    let handles: Vec<_> = (0..4).map(|t| {
+        let mut map = map.clone();
+        let live = live.clone();
+        thread::spawn(move || {
+            for i in (0..size).filter(|i| i % 4 == t) {
+                assert_eq!(map.remove(&i).map(|c| c.0), Some(i));
+            }
+            for i in size..size + 1000 {
+                assert!(map.insert(i, Counted::new(i, &live)).is_none());
+            }
+            map
+        })
+    }).collect();
+    let copies: Vec<_> = handles.into_iter().map(|handle| handle.join().unwrap()).collect();
+
+    assert!(map.keys().cloned().eq(0..size));
+    drop(map);
+    for (t, copy) in copies.iter().enumerate() {
+        assert_eq!(copy.len(), size - size / 4 + 1000);
+        assert!(copy.iter().all(|(&k, v)| k == v.0 && (k % 4 != t || k >= size)));
+    }
+
+    drop(copies);
+    assert_eq!(live.load(Ordering::SeqCst), 0);
+}
+
+#[test]
+fn test_diff() {
+    use btree_rewrite::DiffItem::*;
+
+    let a: BTreeMap<_, _> = (0..1000).map(|i| (i, i)).collect();
+    let mut b = a.clone();
+    assert_eq!(a.diff(&b).next(), None);
+
+    b.remove(&10);
+    b.insert(500, 0);
+    b.insert(1000, 1000);
+    b.insert(-1, -1);
+    assert_eq!(a.diff(&b).collect::<Vec<_>>(),
+               [Added(&-1, &-1), Removed(&10, &10), Changed(&500, &500, &0), Added(&1000, &1000)]);
+    assert_eq!(b.diff(&a).collect::<Vec<_>>(),
+               [Removed(&-1, &-1), Added(&10, &10), Changed(&500, &0, &500), Removed(&1000, &1000)]);
+
+    let empty = BTreeMap::new();
+    assert_eq!(a.diff(&empty).count(), 1000);
+    assert_eq!(empty.diff(&a).count(), 1000);
+}
+
+#[test]
+fn test_persistent_diff() {
+    use btree_rewrite::PersistentMap;
+    use btree_rewrite::DiffItem::*;
+    use std::cell::Cell;
+
+    thread_local!(static COMPARISONS: Cell<usize> = Cell::new(0));
+
+    #[derive(Clone, Debug)]
+    struct Counted(usize);
+
+    impl PartialEq for Counted {
+        fn eq(&self, other: &Counted) -> bool {
+            COMPARISONS.with(|c| c.set(c.get() + 1));
+            self.0 == other.0
+        }
+    }
+
+    let size = 10000;
+    let a: PersistentMap<_, _> = (0..size).map(|i| (i, Counted(i))).collect();
+    let mut b = a.clone();
+    assert_eq!(a.diff(&b).next(), None);
+
+    b.remove(&100);
+    b.insert(5000, Counted(0));
+    b.insert(size + 1, Counted(0));
+
+    let comparisons = || COMPARISONS.with(|c| c.get());
+    let start = comparisons();
+    {
+        let diff: Vec<_> = a.diff(&b).collect();
+        assert_eq!(diff.len(), 3);
+        assert_eq!(diff[0], Removed(&100, &Counted(100)));
+        assert_eq!(diff[1], Changed(&5000, &Counted(5000), &Counted(0)));
+        assert_eq!(diff[2], Added(&(size + 1), &Counted(0)));
+    }
+    // Only the copied paths get compared, not all ten thousand values
+    assert!(comparisons() - start < 500);
+
+    // Maps that share nothing are still diffed correctly
+    let c: PersistentMap<_, _> = (0..size).filter(|&i| i != 7).map(|i| (i, Counted(i))).collect();
+    assert_eq!(a.diff(&c).collect::<Vec<_>>(), [Removed(&7, &Counted(7))]);
+    assert_eq!(c.diff(&b).count(), 4);
+}
+
+#[test]
+fn test_concurrent_basic() {
+    use btree_rewrite::ConcurrentBTreeMap;
+
+    let map = ConcurrentBTreeMap::new();
+    let size = 10000;
+
+    for i in 0..size {
+        assert_eq!(map.insert(i, i), None);
+    }
+    assert_eq!(map.len(), size);
+    assert_eq!(map.insert(5, 50), Some(5));
+    assert_eq!(map.get(&5), Some(50));
+    assert_eq!(map.get(&size), None);
+
+    for i in (0..size).filter(|i| i % 3 == 0) {
+        assert_eq!(map.remove(&i), Some(if i == 5 { 50 } else { i }));
+    }
+    assert_eq!(map.remove(&0), None);
+    assert!(!map.contains_key(&3));
+    assert!(map.contains_key(&4));
+
+    assert_eq!(map.insert(5, 5), Some(50));
+    let expected: Vec<_> = (0..size).filter(|i| i % 3 != 0).map(|i| (i, i)).collect();
+    assert_eq!(map.range::<usize, usize>(Unbounded, Unbounded).collect::<Vec<_>>(), expected);
+    assert_eq!(map.range(Excluded(&100), Included(&110)).collect::<Vec<_>>(),
+               [(101, 101), (103, 103), (104, 104), (106, 106), (107, 107), (109, 109), (110, 110)]);
+    assert_eq!(map.range(Included(&size), Unbounded).next(), None);
+}
+
+#[test]
+fn test_concurrent_threads() {
+    use btree_rewrite::ConcurrentBTreeMap;
+    use std::sync::Arc;
+    use std::thread;
+
+    let map = Arc::new(ConcurrentBTreeMap::new());
+    let threads = 8;
+    let per_thread = 2000;
+
+    let handles: Vec<_> = (0..threads).map(|t| {
+        let map = map.clone();
+        thread::spawn(move || {
+            // Interleave the keys of different threads so they fight over the same leaves
+            for i in 0..per_thread {
+                let key = i * threads + t;
+                assert_eq!(map.insert(key, key), None);
+                assert_eq!(map.get(&key), Some(key));
+            }
+            for i in (0..per_thread).filter(|i| i % 2 == 0) {
+                let key = i * threads + t;
+                assert_eq!(map.remove(&key), Some(key));
+            }
+
+            // Scans must always be sorted, whatever the other threads are doing
+            let mut last = None;
+            for (k, v) in map.range::<usize, usize>(Unbounded, Unbounded) {
+                assert_eq!(k, v);
+                assert!(last < Some(k));
+                last = Some(k);
+            }
+        })
+    }).collect();
+
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    assert_eq!(map.len(), threads * per_thread / 2);
+    let expected: Vec<_> = (0..threads * per_thread)
+        .filter(|k| (k / threads) % 2 == 1)
+        .map(|k| (k, k))
+        .collect();
+    assert_eq!(map.range::<usize, usize>(Unbounded, Unbounded).collect::<Vec<_>>(), expected);
+}
+
+#[test]
+fn test_split_at_middle() {
+    use rand::{thread_rng, Rng};
+
+    fn check<'a>(iter: Iter<'a, i32, i32>, expected: &[(i32, i32)]) {
+        assert_eq!(iter.len(), expected.len());
+        assert_eq!(iter.clone().map(|(&k, &v)| (k, v)).collect::<Vec<_>>(), expected);
+        if expected.len() < 2 {
+            return;
+        }
+
+        let (left, right) = iter.split_at_middle();
+        assert_eq!(left.len(), expected.len() / 2);
+        check(left.clone(), &expected[..left.len()]);
+        check(right.clone(), &expected[left.len()..]);
+    }
+
+    let size = 2000;
+    let map: BTreeMap<_, _> = (0..size).map(|i| (i, i)).collect();
+    let expected: Vec<_> = (0..size).map(|i| (i, i)).collect();
+    check(map.iter(), &expected);
+
+    // Partially consumed iterators, whose ends lie at every possible place in their leaves
+    for skip in 0..50 {
+        let mut iter = map.iter();
+        for _ in 0..skip {
+            iter.next();
+            iter.next_back();
+            iter.next_back();
+        }
+        check(iter, &expected[skip..size as usize - 2 * skip]);
+    }
+
+    let (left, right) = map.range(Included(&100), Excluded(&1500)).split_at_middle();
+    assert_eq!(left.chain(right).map(|(&k, _)| k).collect::<Vec<_>>(),
+               (100..1500).collect::<Vec<_>>());
+
+    // Ranges are split evenly however unevenly filled the nodes they span are
+    let mut rng = thread_rng();
+    let random: BTreeMap<_, _> = (0..5000).map(|_| (rng.gen::<u32>() % 20000, ())).collect();
+    let keys: Vec<_> = random.keys().cloned().collect();
+    for _ in 0..1000 {
+        let a = rng.gen::<usize>() % keys.len();
+        let b = a + rng.gen::<usize>() % (keys.len() - a);
+        let (left, right) = random.range(Included(&keys[a]), Excluded(&keys[b])).split_at_middle();
+        let (left, right) = (left.collect::<Vec<_>>(), right.collect::<Vec<_>>());
+        assert_eq!(left.len(), (b - a) / 2);
+        assert_eq!(right.len(), b - a - left.len());
+        assert!(left.iter().chain(&right).map(|&(&k, _)| k).eq(keys[a..b].iter().cloned()));
+    }
+
+    let mut map = map;
+    let (left, right) = map.range_mut::<i32, i32>(Unbounded, Included(&10)).split_at_middle();
+    for (_, v) in left.chain(right) {
+        *v = -*v;
+    }
+    assert_eq!(map.iter().filter(|&(_, &v)| v <= 0).count(), 11);
+}
+
+#[test]
+fn test_into_iter_split_at_middle() {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+
+    struct Counted(Arc<AtomicUsize>);
+
+    impl Drop for Counted {
+        fn drop(&mut self) {
+            self.0.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    // Splits `iter` recursively, handing each half to its own thread, then takes a third of each
+    // piece from the front and a third from the back and drops the rest. Returns the keys taken.
+    fn consume(mut iter: IntoIter<u32, Counted>, depth: u32) -> Vec<u32> {
+        if depth == 0 {
+            let len = iter.len();
+            let mut keys: Vec<_> = (&mut iter).take(len / 3).map(|(k, _)| k).collect();
+            let back: Vec<_> = (&mut iter).rev().take(len / 3).map(|(k, _)| k).collect();
+            keys.extend(back.into_iter().rev());
+            return keys;
+        }
+
+        let len = iter.len();
+        let (left, right) = iter.split_at_middle();
+        assert_eq!(left.len(), len / 2);
+        assert_eq!(right.len(), len - len / 2);
+        let right = thread::spawn(move || consume(right, depth - 1));
+        let mut keys = consume(left, depth - 1);
+        keys.extend(right.join().unwrap());
+        keys
+    }
+
+    for &size in &[0, 1, 2, 3, 10, 100, 2000] {
+        for skip in 0..3 {
+            let drops = Arc::new(AtomicUsize::new(0));
+            let map: BTreeMap<_, _> = (0..size).map(|i| (i, Counted(drops.clone()))).collect();
+            let mut iter = map.into_iter();
+            for _ in 0..skip {
+                iter.next();
+                iter.next_back();
+            }
+
+            let keys = consume(iter, 4);
+            assert!(keys.windows(2).all(|w| w[0] < w[1]));
+            assert_eq!(drops.load(Ordering::Relaxed), size as usize);
+        }
+    }
+}
+
+#[test]
+fn test_par_for_each() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    let size = 10000;
+    let mut map: BTreeMap<_, _> = (0..size).map(|i| (i, i)).collect();
+
+    let sum = AtomicUsize::new(0);
+    let count = AtomicUsize::new(0);
+    map.iter().par_for_each(64, |(&k, &v)| {
+        assert_eq!(k, v);
+        sum.fetch_add(v, Ordering::Relaxed);
+        count.fetch_add(1, Ordering::Relaxed);
+    });
+    assert_eq!(count.load(Ordering::Relaxed), size);
+    assert_eq!(sum.load(Ordering::Relaxed), size * (size - 1) / 2);
+
+    map.iter_mut().par_for_each(64, |(&k, v)| *v += k);
+    assert!(map.iter().all(|(&k, &v)| v == 2 * k));
+
+    // A grain size of zero still terminates
+    let small: BTreeMap<_, _> = (0..10).map(|i| (i, i)).collect();
+    small.iter().par_for_each(0, |_| ());
+}
+
+#[test]
+fn test_aggregate_fold_range() {
+    use btree_rewrite::aggregate::{AggregateMap, Monoid};
+    use rand::{thread_rng, Rng};
+
+    // Not commutative, so this also checks that everything is combined in order
+    struct Concat;
+    impl Monoid<u32, u32> for Concat {
+        type Summary = Vec<u32>;
+        fn identity() -> Vec<u32> { Vec::new() }
+        fn lift(k: &u32, v: &u32) -> Vec<u32> { vec![*k, *v] }
+        fn combine(left: &Vec<u32>, right: &Vec<u32>) -> Vec<u32> {
+            left.iter().chain(right).cloned().collect()
+        }
+    }
+
+    fn bound<'a>(rng: &mut ::rand::ThreadRng, key: &'a u32) -> Bound<&'a u32> {
+        match rng.gen::<u32>() % 3 {
+            0 => Included(key),
+            1 => Excluded(key),
+            _ => Unbounded
+        }
+    }
+
+    let mut rng = thread_rng();
+    let mut map = AggregateMap::<u32, u32, Concat>::new();
+    let mut expected = BTreeMap::new();
+
+    for round in 0..3000 {
+        let key = rng.gen::<u32>() % 1000;
+        if round % 3 == 2 {
+            assert_eq!(map.remove(&key), expected.remove(&key));
+        } else {
+            assert_eq!(map.insert(key, round), expected.insert(key, round));
+        }
+
+        if round % 10 == 0 {
+            let a = rng.gen::<u32>() % 1100;
+            let b = a + 1 + rng.gen::<u32>() % 300;
+            let (min, max) = (bound(&mut rng, &a), bound(&mut rng, &b));
+            let brute: Vec<u32> = expected.range(min, max)
+                                          .flat_map(|(&k, &v)| vec![k, v])
+                                          .collect();
+            assert_eq!(map.fold_range(min, max), brute);
+        }
+    }
+
+    assert_eq!(map.len(), expected.len());
+    let all: Vec<u32> = expected.iter().flat_map(|(&k, &v)| vec![k, v]).collect();
+    assert_eq!(map.fold_range::<u32, u32>(Unbounded, Unbounded), all);
+}
+
+#[test]
+fn test_aggregate_mutation() {
+    use btree_rewrite::aggregate::{AggregateMap, Monoid};
+
+    struct Sum;
+    impl Monoid<u32, u64> for Sum {
+        type Summary = u64;
+        fn identity() -> u64 { 0 }
+        fn lift(_: &u32, v: &u64) -> u64 { *v }
+        fn combine(left: &u64, right: &u64) -> u64 { left + right }
+    }
+
+    let mut map: AggregateMap<_, _, Sum> = (0..1000).map(|i| (i, 1)).collect();
+    assert_eq!(map.fold_range(Included(&100), Excluded(&200)), 100);
+
+    *map.get_mut(&150).unwrap() += 99;
+    assert_eq!(map.fold_range(Included(&100), Excluded(&200)), 199);
+    assert_eq!(map.fold_range(Included(&151), Excluded(&200)), 49);
+    assert!(map.get_mut(&1000).is_none());
+
+    for (_, v) in &mut map.iter_mut() {
+        *v *= 2;
+    }
+    assert_eq!(map.fold_range(Included(&100), Excluded(&200)), 398);
+
+    // Stopping part way only recomputes what was visited, and leaves the rest correct
+    {
+        let mut iter = map.iter_mut();
+        for (_, v) in (&mut iter).take(150) {
+            *v += 1;
+        }
+    }
+    assert_eq!(map.fold_range(Included(&100), Excluded(&200)), 398 + 50);
+    assert_eq!(map.fold_range(Included(&145), Excluded(&155)), 5 * 3 + 200 + 4 * 2);
+    map.insert(1000, 1);
+    assert_eq!(map.fold_range::<u32, u32>(Unbounded, Unbounded), 2 * 1099 + 150 + 1);
+}
+
+#[test]
+fn test_interval_map() {
+    use btree_rewrite::IntervalMap;
+    use rand::{thread_rng, Rng};
+
+    let mut rng = thread_rng();
+    let mut map = IntervalMap::new();
+    let mut intervals = Vec::new();
+
+    for i in 0..2000 {
+        let start = rng.gen::<u32>() % 10000;
+        let end = start + 1 + rng.gen::<u32>() % 100;
+        if map.insert(start..end, i).is_some() {
+            intervals.retain(|&(s, e, _)| (s, e) != (start, end));
+        }
+        intervals.push((start, end, i));
+    }
+    intervals.sort();
+    for &(start, end, i) in &intervals[..500] {
+        assert_eq!(map.remove(&(start..end)), Some(i));
+    }
+    let intervals = &intervals[500..];
+    assert_eq!(map.len(), intervals.len());
+    assert_eq!(map.get(&(intervals[0].0..intervals[0].1)), Some(&intervals[0].2));
+
+    for _ in 0..200 {
+        let point = rng.gen::<u32>() % 10200;
+        let found: Vec<_> = map.stabbing(&point).map(|(&s, &e, &v)| (s, e, v)).collect();
+        let expected: Vec<_> = intervals.iter()
+                                        .filter(|&&(s, e, _)| s <= point && point < e)
+                                        .cloned()
+                                        .collect();
+        assert_eq!(found, expected);
+
+        let query = point..point + rng.gen::<u32>() % 50;
+        let found: Vec<_> = map.overlapping(&query).map(|(&s, &e, &v)| (s, e, v)).collect();
+        let expected: Vec<_> = intervals.iter()
+                                        .filter(|&&(s, e, _)| s < query.end && query.start < e)
+                                        .filter(|_| query.start < query.end)
+                                        .cloned()
+                                        .collect();
+        assert_eq!(found, expected);
+    }
+}
+
+#[test]
+fn test_range_map() {
+    use btree_rewrite::RangeMap;
+    use rand::{thread_rng, Rng};
+
+    let size = 200;
+    let mut rng = thread_rng();
+    let mut map = RangeMap::new();
+    // What every point should map to
+    let mut points = vec![None; size];
+
+    for round in 0..2000 {
+        let start = rng.gen::<usize>() % size;
+        let end = start + rng.gen::<usize>() % 20;
+        let end = if end > size { size } else { end };
+
+        if round % 3 == 0 {
+            map.remove_range(start..end);
+            for p in start..end {
+                points[p] = None;
+            }
+        } else {
+            let val = rng.gen::<u8>() % 3;
+            map.insert_range(start..end, val);
+            for p in start..end {
+                points[p] = Some(val);
+            }
+        }
+
+        for p in 0..size {
+            assert_eq!(map.get(&p).cloned(), points[p]);
+        }
+
+        // Ranges are nonempty, disjoint and coalesced
+        let ranges: Vec<_> = map.iter().map(|(&s, &e, &v)| (s, e, v)).collect();
+        for &(s, e, _) in &ranges {
+            assert!(s < e);
+        }
+        for pair in ranges.windows(2) {
+            assert!(pair[0].1 < pair[1].0 || (pair[0].1 == pair[1].0 && pair[0].2 != pair[1].2));
+        }
+
+        let query = start / 2..(end + size) / 2;
+        let expected: Vec<_> = (query.start..query.end).filter(|&p| points[p].is_none()).collect();
+        let gaps: Vec<_> = map.gaps(&query).flat_map(|gap| gap).collect();
+        assert_eq!(gaps, expected);
+    }
+
+    map.insert_range(0..size, 7);
+    assert_eq!(map.len(), 1);
+    assert_eq!(map.get_range(&50), Some((&0, &size, &7)));
+    assert_eq!(map.gaps(&(0..size)).next(), None);
+    assert_eq!(map.gaps(&(5..5)).next(), None);
+}
+
+#[test]
+fn test_multimap() {
+    use btree_rewrite::BTreeMultiMap;
+    use rand::{thread_rng, Rng};
+
+    let keys = 50;
+    let mut rng = thread_rng();
+    let mut map = BTreeMultiMap::new();
+    // The values of every key, in insertion order
+    let mut model: Vec<Vec<u32>> = vec![Vec::new(); keys];
+
+    for i in 0..5000 {
+        let key = rng.gen::<usize>() % keys;
+        match rng.gen::<u8>() % 8 {
+            0 => {
+                assert_eq!(map.remove_one(&key), if model[key].is_empty() {
+                    None
+                } else {
+                    Some(model[key].remove(0))
+                });
+            },
+            1 if i % 10 == 1 => {
+                assert_eq!(map.remove_all(&key), model[key]);
+                model[key].clear();
+            },
+            _ => {
+                map.insert(key, i);
+                model[key].push(i);
+            }
+        }
+
+        let key = rng.gen::<usize>() % keys;
+        assert_eq!(map.count(&key), model[key].len());
+        assert_eq!(map.get(&key), model[key].first());
+        assert_eq!(map.contains_key(&key), !model[key].is_empty());
+        assert_eq!(map.get_all(&key).map(|(_, &v)| v).collect::<Vec<_>>(), model[key]);
+    }
+
+    let expected: Vec<_> = model.iter().enumerate()
+                                .flat_map(|(k, vals)| vals.iter().map(move |&v| (k, v)))
+                                .collect();
+    assert_eq!(map.len(), expected.len());
+    assert_eq!(map.iter().map(|(&k, &v)| (k, v)).collect::<Vec<_>>(), expected);
+    assert_eq!(map.get_all(&keys).next(), None);
+    assert_eq!(map.count(&keys), 0);
+}
+
+#[test]
+fn test_comparator() {
+    use btree_rewrite::compare::{Borrowing, Compare, Natural, Rev};
+    use std::cmp::Ordering;
+
+    struct CaseInsensitive;
+
+    impl Compare<str> for CaseInsensitive {
+        fn compare(&self, a: &str, b: &str) -> Ordering {
+            a.to_lowercase().cmp(&b.to_lowercase())
+        }
+    }
+
+    impl Compare<String> for CaseInsensitive {
+        fn compare(&self, a: &String, b: &String) -> Ordering {
+            Compare::<str>::compare(self, a, b)
+        }
+    }
+
+    let mut map = BTreeMap::with_comparator(Borrowing(CaseInsensitive));
+    assert_eq!(map.insert("Banana".to_string(), 1), None);
+    assert_eq!(map.insert("apple".to_string(), 2), None);
+    assert_eq!(map.insert("BANANA".to_string(), 3), Some(1));
+    assert_eq!(map.insert("cherry".to_string(), 4), None);
+
+    assert_eq!(map.len(), 3);
+    assert_eq!(map.get("APPLE"), Some(&2));
+    assert_eq!(map["banana"], 3);
+    assert_eq!(map.keys().map(|k| &k[..]).collect::<Vec<_>>(), ["apple", "Banana", "cherry"]);
+    assert_eq!(map.range::<str, str>(Excluded("APPLE"), Unbounded).count(), 2);
+    assert_eq!(map.remove("CHERRY"), Some(4));
+    assert!(!map.contains_key("cherry"));
+
+    let size = 1000;
+    let mut map = BTreeMap::with_comparator(Rev(Natural));
+    for i in 0..size {
+        map.insert(i, i * 2);
+    }
+    assert_eq!(map.keys().cloned().collect::<Vec<_>>(), (0..size).rev().collect::<Vec<_>>());
+    assert_eq!(map.range(Included(&600), Excluded(&500)).map(|(&k, _)| k).collect::<Vec<_>>(),
+               (501..601).rev().collect::<Vec<_>>());
+    for i in 0..size {
+        if i % 3 == 0 {
+            assert_eq!(map.remove(&i), Some(i * 2));
+        }
+    }
+    for i in 0..size {
+        assert_eq!(map.get(&i).cloned(), if i % 3 == 0 { None } else { Some(i * 2) });
+    }
+
+    let other: BTreeMap<_, _, Rev<Natural>> =
+        map.iter().map(|(&k, &v)| (k, if k == 5 { v + 1 } else { v })).collect();
+    assert_eq!(map.diff(&other).count(), 1);
+
+    let mut by_len = BTreeMap::with_comparator(|a: &&str, b: &&str| a.len().cmp(&b.len()));
+    by_len.extend(vec![("ccc", 3), ("a", 1), ("bb", 2)]);
+    assert_eq!(by_len.values().cloned().collect::<Vec<_>>(), [1, 2, 3]);
+}
+
+#[test]
+fn test_comparable_query() {
+    use btree_rewrite::compare::Comparable;
+    use std::cmp::Ordering;
+
+    // Looks up a `(String, u32)` key without allocating a `String`
+    struct Query<'a>(&'a str, u32);
+
+    impl<'a> Comparable<(String, u32)> for Query<'a> {
+        fn compare(&self, key: &(String, u32)) -> Ordering {
+            (self.0, self.1).cmp(&(&key.0[..], key.1))
+        }
+    }
+
+    let names = ["alice", "bob", "carol", "dave"];
+    let mut map = BTreeMap::new();
+    for (i, name) in names.iter().enumerate() {
+        for n in 0..100 {
+            map.insert((name.to_string(), n), i as u32 * 100 + n);
+        }
+    }
+
+    assert_eq!(map.get(&Query("bob", 42)), Some(&142));
+    assert_eq!(map.get(&Query("bob", 100)), None);
+    assert_eq!(map.get(&Query("eve", 0)), None);
+    assert!(map.contains_key(&Query("dave", 99)));
+    assert_eq!(map[&Query("alice", 7)], 7);
+
+    *map.get_mut(&Query("carol", 5)).unwrap() = 0;
+    assert_eq!(map.get(&("carol".to_string(), 5)), Some(&0));
+
+    let carols: Vec<_> = map.range(Included(&Query("carol", 10)), Excluded(&Query("carol", 20)))
+                            .map(|(_, &v)| v)
+                            .collect();
+    assert_eq!(carols, (210..220).collect::<Vec<_>>());
+    assert_eq!(map.range::<_, (String, u32)>(Excluded(&Query("bob", 99)), Unbounded).count(), 200);
+
+    for n in 0..100 {
+        assert_eq!(map.remove(&Query("bob", n)), Some(100 + n));
+    }
+    assert_eq!(map.remove(&Query("bob", 0)), None);
+    assert_eq!(map.len(), 300);
+}
+
+#[test]
+fn test_entry_ref() {
+    use std::borrow::Borrow;
+    use std::cell::Cell;
+
+    thread_local!(static CONVERSIONS: Cell<usize> = Cell::new(0));
+
+    // A key that counts how many times it is created from a borrowed `str`
+    #[derive(PartialEq, Eq, PartialOrd, Ord, Debug)]
+    struct Word(String);
+
+    impl Borrow<str> for Word {
+        fn borrow(&self) -> &str {
+            &self.0
+        }
+    }
+
+    impl<'a> From<&'a str> for Word {
+        fn from(s: &'a str) -> Word {
+            CONVERSIONS.with(|c| c.set(c.get() + 1));
+            Word(s.to_string())
+        }
+    }
+
+    let text = "a b c a b a d e f a b c g h i j k l m n o p q r s t u v w x y z";
+    let mut count: BTreeMap<Word, usize> = BTreeMap::new();
+    for word in text.split(' ') {
+        *count.entry_ref(word).or_insert(0) += 1;
+    }
+
+    assert_eq!(CONVERSIONS.with(|c| c.get()), 26);
+    assert_eq!(count.len(), 26);
+    assert_eq!(count["a"], 4);
+    assert_eq!(count["b"], 3);
+    assert_eq!(count["z"], 1);
+
+    match count.entry_ref("zz") {
+        EntryRef::Vacant(entry) => {
+            assert_eq!(entry.key(), "zz");
+            assert_eq!(*entry.insert(7), 7);
+        },
+        EntryRef::Occupied(_) => unreachable!()
+    }
+    match count.entry_ref("zz") {
+        EntryRef::Vacant(_) => unreachable!(),
+        EntryRef::Occupied(mut entry) => assert_eq!(entry.insert(8), 7)
+    }
+    assert_eq!(CONVERSIONS.with(|c| c.get()), 27);
+    assert_eq!(count.get("zz"), Some(&8));
+    assert_eq!(*count.entry_ref("y").or_insert_with(|| unreachable!()), 1);
+
+    assert_eq!(count.entry_ref("a").key(), "a");
+    assert_eq!(count.entry_ref("aa").key(), "aa");
+    assert_eq!(*count.entry_ref("a").and_modify(|n| *n *= 10).or_default(), 40);
+    assert_eq!(*count.entry_ref("aa").and_modify(|_| unreachable!()).or_default(), 0);
+    assert_eq!(CONVERSIONS.with(|c| c.get()), 28);
+    assert_eq!(count.len(), 28);
+}
+
+#[test]
+fn test_entry_api() {
+    let mut map: BTreeMap<i32, i32> = BTreeMap::new();
+
+    for i in 0..10 {
+        map.entry(i % 3).and_modify(|v| *v += 1).or_insert(1);
+    }
+    assert_eq!(map.get(&0), Some(&4));
+    assert_eq!(map.get(&1), Some(&3));
+    assert_eq!(map.get(&2), Some(&3));
+
+    assert_eq!(*map.entry(3).or_default(), 0);
+    assert_eq!(*map.entry(4).or_insert_with_key(|k| k * 10), 40);
+    assert_eq!(*map.entry(4).or_insert_with_key(|_| unreachable!()), 40);
+    assert_eq!(*map.entry(3).key(), 3);
+    assert_eq!(*map.entry(5).key(), 5);
+
+    match map.entry(5) {
+        Vacant(entry) => assert_eq!(entry.into_key(), 5),
+        Occupied(_) => unreachable!()
+    }
+    assert_eq!(map.len(), 5);
+
+    match map.entry(1) {
+        Occupied(mut entry) => {
+            assert_eq!(*entry.key(), 1);
+            assert_eq!(entry.replace_key(1), 1);
+            assert_eq!(entry.replace_entry(1, 30), (1, 3));
+            assert_eq!(entry.remove_entry(), (1, 30));
+        },
+        Vacant(_) => unreachable!()
+    }
+    assert_eq!(map.len(), 4);
+    assert_eq!(map.get(&1), None);
+
+    // Inserting through an entry hands back the new element, wherever splits leave it
+    let mut map = BTreeMap::new();
+    for i in 0..1000 {
+        let key = (i * 7919) % 1000;
+        let entry = map.entry(key).insert_entry(i);
+        assert_eq!(*entry.key(), key);
+        assert_eq!(*entry.get(), i);
+    }
+    assert_eq!(map.len(), 1000);
+    assert_eq!(map.entry(0).insert_entry(-1).insert(-2), -1);
+    assert_eq!(map[&0], -2);
+
+    // Walking the whole map from one end to the other through neighbouring entries
+    let mut entry = match map.entry(0) {
+        Occupied(entry) => entry,
+        Vacant(_) => unreachable!()
+    };
+    let mut expected = 0;
+    loop {
+        assert_eq!(*entry.key(), expected);
+        entry = match entry.next() {
+            Ok(next) => next,
+            Err(last) => {
+                entry = last;
+                break;
+            }
+        };
+        expected += 1;
+    }
+    assert_eq!(expected, 999);
+
+    loop {
+        assert_eq!(*entry.key(), expected);
+        if expected % 2 == 0 {
+            *entry.get_mut() = 0;
+        }
+        entry = match entry.prev() {
+            Ok(prev) => prev,
+            Err(first) => {
+                entry = first;
+                break;
+            }
+        };
+        expected -= 1;
+    }
+    assert_eq!(expected, 0);
+    assert_eq!(entry.remove_entry(), (0, 0));
+    assert!(map.iter().all(|(k, v)| k % 2 == 1 || *v == 0));
+    assert_eq!(map.len(), 999);
+}
+
+#[test]
+fn test_sorted_batch() {
+    use rand::{thread_rng, Rng};
+    use std::cell::Cell;
+    use std::collections::BTreeMap as StdMap;
+
+    let mut rng = thread_rng();
+    let mut map = BTreeMap::new();
+    let mut model = StdMap::new();
+
+    for round in 0..20 {
+        // Sorted batches are the fast path, but shuffled ones must give the same answers
+        let mut keys: Vec<u32> = (0..500).map(|_| rng.gen::<u32>() % 2000).collect();
+        if round % 4 != 3 {
+            keys.sort();
+        }
+
+        let batch: Vec<_> = keys.iter().map(|&k| (k, round)).collect();
+        let mut expected = Vec::new();
+        for &(k, v) in &batch {
+            expected.push(model.insert(k, v));
+        }
+        assert_eq!(map.insert_sorted_batch(batch), expected);
+        assert_eq!(map.len(), model.len());
+
+        let mut lookups: Vec<u32> = (0..500).map(|_| rng.gen::<u32>() % 2000).collect();
+        lookups.sort();
+        let expected: Vec<_> = lookups.iter().map(|k| model.get(k)).collect();
+        assert_eq!(map.get_many(&lookups), expected);
+
+        let mut removals: Vec<u32> = (0..300).map(|_| rng.gen::<u32>() % 2000).collect();
+        if round % 4 != 1 {
+            removals.sort();
+        }
+        let expected: Vec<_> = removals.iter().map(|k| model.remove(k)).collect();
+        assert_eq!(map.remove_sorted_batch(&removals), expected);
+        assert_eq!(map.len(), model.len());
+        assert!(map.iter().eq(model.iter()));
+    }
+
+    let all: Vec<_> = model.keys().cloned().collect();
+    assert_eq!(map.remove_sorted_batch(&all).len(), all.len());
+    assert!(map.is_empty());
+    assert_eq!(map.get_many(&[1, 2, 3]), [None, None, None]);
+
+    // Appending searches from the rightmost leaf rather than from the root
+    let comparisons = Cell::new(0);
+    let cmp = |a: &u32, b: &u32| { comparisons.set(comparisons.get() + 1); a.cmp(b) };
+    let mut map = BTreeMap::with_comparator(&cmp);
+    map.insert_sorted_batch((0..100000).map(|i| (i, ())));
+    let start = comparisons.get();
+    map.insert_sorted_batch((100000..101000).map(|i| (i, ())));
+    assert!(comparisons.get() - start < 1000 * 14);
+}
+
+#[test]
+fn test_get_many_mut() {
+    let mut accounts: BTreeMap<String, i64> = (0..100).map(|i| (format!("acct{:02}", i), 100))
+                                                      .collect();
+
+    for i in 0..99 {
+        let from = format!("acct{:02}", i);
+        let to = format!("acct{:02}", i + 1);
+        let (from, to) = accounts.get_pair_mut(&from[..], &to[..]).unwrap();
+        *to += *from;
+        *from = 0;
+    }
+    assert_eq!(accounts["acct99"], 10000);
+    assert_eq!(accounts.values().sum::<i64>(), 10000);
+
+    assert!(accounts.get_pair_mut("acct00", "acct00").is_none());
+    assert!(accounts.get_pair_mut("acct00", "nobody").is_none());
+    assert!(accounts.get_pair_mut("nobody", "acct00").is_none());
+
+    {
+        let [a, b, c] = accounts.get_many_mut(["acct99", "acct10", "acct50"]).unwrap();
+        *a -= 300;
+        *b += 100;
+        *c += 200;
+    }
+    assert_eq!(accounts["acct99"], 9700);
+    assert_eq!(accounts["acct10"], 100);
+    assert_eq!(accounts["acct50"], 200);
+
+    assert!(accounts.get_many_mut(["acct01", "acct02", "acct01"]).is_none());
+    assert!(accounts.get_many_mut(["acct01", "nobody"]).is_none());
+    assert_eq!(accounts.get_many_mut::<str, 0>([]).map(|v| v.len()), Some(0));
+
+    // Zero-sized values all live at the same address, but are still told apart
+    let mut set: BTreeMap<u32, ()> = (0..10).map(|i| (i, ())).collect();
+    assert!(set.get_pair_mut(&1, &2).is_some());
+    assert!(set.get_many_mut([&1, &2, &3]).is_some());
+    assert!(set.get_many_mut([&1, &2, &1]).is_none());
+
+    // Many values from the same nodes, handed back in the order asked for
+    let mut map: BTreeMap<u32, u32> = (0..1000).map(|i| (i, 0)).collect();
+    let keys: Vec<u32> = (0..300).rev().map(|i| i * 3).collect();
+    let key_refs: [&u32; 300] = std::array::from_fn(|i| &keys[i]);
+    for (i, v) in map.get_many_mut(key_refs).unwrap().iter_mut().enumerate() {
+        **v = i as u32 + 1;
+    }
+    assert!(map.iter().all(|(&k, &v)| v == if k % 3 == 0 && k < 900 { 300 - k / 3 } else { 0 }));
+}
+
+#[test]
+fn test_key_value_iters() {
+    let size = 1000;
+    let mut map: BTreeMap<_, _> = (0..size).map(|i| (i, i.to_string())).collect();
+
+    {
+        let mut values = map.values_mut();
+        assert_eq!(values.len(), size);
+        values.next().unwrap().push_str("a");
+        values.next_back().unwrap().push_str("z");
+        assert_eq!(values.len(), size - 2);
+        for v in values {
+            v.push_str("-");
+        }
+    }
+    assert_eq!(map[&0], "0a");
+    assert_eq!(map[&500], "500-");
+    assert_eq!(map[&999], "999z");
+
+    {
+        let mut keys = map.range(Included(&10), Excluded(&20)).keys();
+        assert_eq!(keys.next(), Some(&10));
+        assert_eq!(keys.next_back(), Some(&19));
+        let rest: Vec<_> = keys.clone().cloned().collect();
+        assert_eq!(rest, (11..19).collect::<Vec<_>>());
+        assert_eq!(keys.rev().next(), Some(&18));
+
+        let values: Vec<_> = map.range::<usize, usize>(Excluded(&997), Unbounded)
+                                .values()
+                                .collect();
+        assert_eq!(values, ["998-", "999z"]);
+        let mut empty = map.range(Included(&5), Excluded(&5)).values();
+        assert_eq!(empty.next(), None);
+        assert_eq!(empty.next(), None);
+    }
+
+    let mut keys = map.clone().into_keys();
+    assert_eq!(keys.len(), size);
+    assert_eq!(keys.next(), Some(0));
+    assert_eq!(keys.next_back(), Some(999));
+    assert_eq!(keys.len(), size - 2);
+    assert!(keys.by_ref().eq(1..999));
+    assert_eq!(keys.next(), None);
+    assert_eq!(keys.next_back(), None);
+
+    // Dropping a partly consumed iterator must still free the remaining elements
+    let mut values = map.into_values();
+    assert_eq!(values.next().as_ref().map(|s| &s[..]), Some("0a"));
+    assert_eq!(values.next_back().as_ref().map(|s| &s[..]), Some("999z"));
+    assert_eq!(values.len(), size - 2);
+}
+
+#[test]
+fn test_into_range() {
+    use std::cell::Cell;
+
+    // Counts how many of its values have been dropped
+    struct Tracked<'a>(u32, &'a Cell<usize>);
+
+    impl<'a> Drop for Tracked<'a> {
+        fn drop(&mut self) {
+            self.1.set(self.1.get() + 1);
+        }
+    }
+
+    fn contains(bound: (Bound<u32>, Bound<u32>), k: u32) -> bool {
+        (match bound.0 {
+            Included(min) => k >= min,
+            Excluded(min) => k > min,
+            Unbounded => true
+        }) && (match bound.1 {
+            Included(max) => k <= max,
+            Excluded(max) => k < max,
+            Unbounded => true
+        })
+    }
+
+    fn as_ref(bound: &Bound<u32>) -> Bound<&u32> {
+        match *bound {
+            Included(ref k) => Included(k),
+            Excluded(ref k) => Excluded(k),
+            Unbounded => Unbounded
+        }
+    }
+
+    let size = 1000;
+    let bounds = [
+        (Included(100), Excluded(200)),
+        (Excluded(100), Included(200)),
+        (Unbounded, Included(0)),
+        (Excluded(998), Unbounded),
+        (Unbounded, Unbounded),
+        (Included(500), Excluded(500)),
+        (Included(600), Excluded(400)),
+        (Excluded(2000), Unbounded)
+    ];
+
+    for &bound in &bounds {
+        let expected: Vec<u32> = (0..size).filter(|&k| contains(bound, k)).collect();
+
+        let dropped = Cell::new(0);
+        let map: BTreeMap<u32, Tracked> = (0..size).map(|i| (i, Tracked(i, &dropped))).collect();
+        let mut iter = map.into_range(as_ref(&bound.0), as_ref(&bound.1));
+        assert_eq!(dropped.get(), size as usize - expected.len());
+        assert_eq!(iter.len(), expected.len());
+
+        // Take a few from each end, then drop the rest along with the iterator
+        let front: Vec<u32> = iter.by_ref().take(5).map(|(k, v)| { assert_eq!(k, v.0); k })
+                                  .collect();
+        let back: Vec<u32> = iter.by_ref().rev().take(5).map(|(k, v)| { assert_eq!(k, v.0); k })
+                                 .collect();
+        assert!(front.iter().eq(expected.iter().take(5)));
+        assert!(back.iter().eq(expected.iter().skip(front.len()).rev().take(5)));
+        drop(iter);
+        assert_eq!(dropped.get(), size as usize);
+    }
+}
+
+#[test]
+fn test_transaction() {
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    let original: BTreeMap<u32, String> = (0..200).map(|i| (i, i.to_string())).collect();
+
+    // Enough changes, several to the same keys, to split and merge nodes along the way
+    fn churn(tx: &mut Transaction<u32, String>) {
+        for i in 150..400 {
+            tx.insert(i, format!("new {}", i));
+        }
+        for i in 0..50 {
+            assert_eq!(tx.remove(&(i * 3)).map(|v| v.clone()), Some((i * 3).to_string()));
+        }
+        assert_eq!(tx.insert(5, "five".to_string()).map(|v| &v[..]), Some("5"));
+        tx.get_mut(&7).unwrap().push_str(" changed");
+        match tx.entry(3) {
+            TxEntry::Vacant(entry) => { entry.insert("three".to_string()); },
+            TxEntry::Occupied(_) => panic!("3 was removed")
+        }
+        match tx.entry(5) {
+            TxEntry::Occupied(mut entry) => {
+                assert_eq!(entry.insert("FIVE".to_string()), "five");
+                assert_eq!(entry.remove(), "FIVE");
+            },
+            TxEntry::Vacant(_) => panic!("5 was inserted")
+        }
+        tx.entry(1000).or_insert(String::new()).push_str("thousand");
+        tx.entry(1).or_insert(String::new()).push_str(" one");
+
+        // Everything so far is visible within the transaction
+        assert_eq!(tx.get(&3).map(|v| &v[..]), Some("three"));
+        assert!(!tx.contains_key(&5));
+        assert_eq!(tx.get(&7).map(|v| &v[..]), Some("7 changed"));
+        assert_eq!(tx.get(&1).map(|v| &v[..]), Some("1 one"));
+        assert_eq!(tx.get(&399).map(|v| &v[..]), Some("new 399"));
+        assert_eq!(tx.len(), 400 - 50 + 1 - 1 + 1);
+    }
+
+    // Rolled back by an error
+    let mut map = original.clone();
+    let result: Result<(), &str> = map.transaction(|tx| { churn(tx); Err("rolled back") });
+    assert_eq!(result, Err("rolled back"));
+    assert_eq!(map, original);
+    assert_eq!(map.len(), original.len());
+
+    // Rolled back by a panic
+    let shared = Arc::new(Mutex::new(original.clone()));
+    let thread_shared = shared.clone();
+    let result = thread::spawn(move || {
+        let mut map = thread_shared.lock().unwrap();
+        let _: Result<(), ()> = map.transaction(|tx| { churn(tx); panic!("rolled back") });
+    }).join();
+    assert!(result.is_err());
+    let map = shared.lock().unwrap_err().into_inner();
+    assert_eq!(*map, original);
+    assert_eq!(map.len(), original.len());
+
+    // Committed
+    let mut map = original.clone();
+    let mut expected = original.clone();
+    assert_eq!(map.transaction(|tx| -> Result<u32, ()> { churn(tx); Ok(1) }), Ok(1));
+    for i in 150..400 {
+        expected.insert(i, format!("new {}", i));
+    }
+    for i in 0..50 {
+        expected.remove(&(i * 3));
+    }
+    expected.insert(3, "three".to_string());
+    expected.insert(7, "7 changed".to_string());
+    expected.insert(1000, "thousand".to_string());
+    expected.insert(1, "1 one".to_string());
+    expected.remove(&5);
+    assert_eq!(map, expected);
+    assert_eq!(map.len(), expected.len());
+}
+
+#[test]
+fn test_observed_map() {
+    use rand::{thread_rng, Rng};
+    use std::collections::BTreeMap as StdMap;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    // Keeps its own copy of the map, checking each change against it
+    struct Mirror(StdMap<u32, u32>);
+
+    impl Observer<u32, u32> for Mirror {
+        fn inserted(&mut self, key: &u32, value: &u32) {
+            assert_eq!(self.0.insert(*key, *value), None);
+        }
+        fn replaced(&mut self, key: &u32, old: &u32, new: &u32) {
+            assert_eq!(self.0.insert(*key, *new), Some(*old));
+        }
+        fn removed(&mut self, key: &u32, value: &u32) {
+            assert_eq!(self.0.remove(key), Some(*value));
+        }
+    }
+
+    fn check(map: &ObservedMap<u32, u32, Mirror>) {
+        assert!(map.iter().map(|(&k, &v)| (k, v)).eq(map.observer().0.clone()));
+    }
+
+    let mut rng = thread_rng();
+    let initial: BTreeMap<u32, u32> = (0..100).map(|i| (i * 2, i)).collect();
+    let mut map = ObservedMap::from_map(initial, Mirror(StdMap::new()));
+    check(&map);
+
+    for _ in 0..2000 {
+        let key = rng.gen::<u32>() % 500;
+        let value = rng.gen::<u32>() % 10;
+        match rng.gen::<u32>() % 7 {
+            0 => { map.insert(key, value); },
+            1 => { map.remove(&key); },
+            2 => if let Some(mut v) = map.get_mut(&key) { *v += value; },
+            3 => { *map.entry(key).or_insert(0) += value; },
+            4 => match map.entry(key) {
+                ObservedEntry::Occupied(mut entry) => {
+                    let old = *entry.get();
+                    assert_eq!(entry.insert(value), old);
+                    if value % 2 == 0 {
+                        assert_eq!(entry.remove(), value);
+                    }
+                },
+                ObservedEntry::Vacant(entry) => { entry.insert(value); }
+            },
+            5 => map.extend(vec![(key, value), (key + 1, value)]),
+            _ => map.retain(|&k, &v| (k + v) % 13 != 0)
+        }
+        check(&map);
+    }
+
+    // A panic part way through `retain` leaves every unreported element in the map
+    map.extend((500..600).map(|i| (i, i)));
+    let shared = Arc::new(Mutex::new(map));
+    let thread_shared = shared.clone();
+    let result = thread::spawn(move || {
+        let mut map = thread_shared.lock().unwrap();
+        let mut seen = 0;
+        map.retain(|_, _| {
+            seen += 1;
+            if seen == 50 {
+                panic!("stopped part way");
+            }
+            seen % 2 == 0
+        });
+    }).join();
+    assert!(result.is_err());
+    let mut map = match Arc::try_unwrap(shared).ok().unwrap().into_inner() {
+        Ok(_) => unreachable!(),
+        Err(poisoned) => poisoned.into_inner()
+    };
+    check(&map);
+
+    map.clear();
+    assert!(map.is_empty());
+    check(&map);
+
+    // A guard for a vacant entry reports an insertion with the final value
+    *map.entry(7).or_insert_with(|| 1) += 1;
+    assert_eq!(map.observer().0.get(&7), Some(&2));
+    let (map, mirror) = map.into_inner();
+    assert_eq!(map.len(), mirror.0.len());
+}
+
+#[cfg(feature = "encoding")]
+#[test]
+fn test_encoding() {
+    use btree_rewrite::compare::{Natural, Rev};
+    use btree_rewrite::encoding::{Encode, DecodeErrorKind};
+
+    for &size in &[0, 1, 11, 12, 1000] {
+        let map: BTreeMap<u32, String> = (0..size).map(|i| (i * 3, i.to_string())).collect();
+        let mut bytes = Vec::new();
+        map.encode(&mut bytes);
+
+        let mut decoded = BTreeMap::<u32, String>::decode(&bytes).unwrap();
+        assert_eq!(decoded, map);
+
+        // The bulk-loaded tree must behave like any other
+        for i in 0..size {
+            assert_eq!(decoded.insert(i * 3 + 1, String::new()), None);
+            assert_eq!(decoded.remove(&(i * 3)), Some(i.to_string()));
+        }
+        assert_eq!(decoded.len(), size as usize);
+        assert!(decoded.keys().cloned().eq((0..size).map(|i| i * 3 + 1)));
+
+        // Every truncation is reported as an error rather than a panic or a partial map
+        for len in (0..bytes.len()).filter(|&len| size < 100 || len % 97 == 0) {
+            assert!(BTreeMap::<u32, String>::decode(&bytes[..len]).is_err());
+        }
+    }
+
+    let map: BTreeMap<u32, String> = vec![(1, "a".to_string()), (2, "b".to_string())]
+                                         .into_iter().collect();
+    let mut bytes = Vec::new();
+    map.encode(&mut bytes);
+    // 4 magic bytes, the version, the length, then each entry's u32 key and string value
+    assert_eq!(bytes.len(), 4 + 1 + 8 + 2 * (4 + 8 + 1));
+    let first_key = 13;
+    let second_key = first_key + 13;
+
+    let decode = |bytes: &[u8]| BTreeMap::<u32, String>::decode(bytes).unwrap_err();
+
+    let mut bad = bytes.clone();
+    bad[0] = b'X';
+    assert_eq!(decode(&bad).kind(), DecodeErrorKind::BadMagic);
+
+    let mut bad = bytes.clone();
+    bad[4] = 2;
+    let err = decode(&bad);
+    assert_eq!((err.kind(), err.offset()), (DecodeErrorKind::UnsupportedVersion(2), 4));
+
+    let mut bad = bytes.clone();
+    bad[second_key] = 1;
+    let err = decode(&bad);
+    assert_eq!((err.kind(), err.offset()), (DecodeErrorKind::NotSorted, second_key));
+    assert_eq!(format!("{}", err), format!("keys not in strictly ascending order at byte {}",
+                                           second_key));
+
+    let mut bad = bytes.clone();
+    bad[first_key + 4 + 8] = 0xff;
+    let err = decode(&bad);
+    assert_eq!((err.kind(), err.offset()), (DecodeErrorKind::Invalid("invalid UTF-8"),
+                                            first_key + 4));
+
+    let mut bad = bytes.clone();
+    bad.push(0);
+    let err = decode(&bad);
+    assert_eq!((err.kind(), err.offset()), (DecodeErrorKind::TrailingBytes, bytes.len()));
+
+    let mut bad = bytes.clone();
+    bad.truncate(bytes.len() - 1);
+    let err = decode(&bad);
+    assert_eq!((err.kind(), err.offset()), (DecodeErrorKind::UnexpectedEnd, second_key + 4 + 8));
+
+    // A corrupt length is caught when the input runs out, without allocating for it up front
+    let mut bad = bytes.clone();
+    bad[5..13].copy_from_slice(&[0xff; 8]);
+    assert_eq!(decode(&bad).kind(), DecodeErrorKind::UnexpectedEnd);
+
+    // Maps with comparators decode with the same ordering
+    let mut rev = BTreeMap::with_comparator(Rev(Natural));
+    for i in 0..100u32 {
+        rev.insert(i, (i % 2 == 0, Some(-(i as i64))));
+    }
+    let mut bytes = Vec::new();
+    rev.encode(&mut bytes);
+    assert!(BTreeMap::<u32, (bool, Option<i64>)>::decode(&bytes).is_err());
+    let decoded = BTreeMap::decode_with_comparator(&bytes, *rev.comparator()).unwrap();
+    assert_eq!(decoded, rev);
+
+    let mut out = Vec::new();
+    "hi".encode(&mut out);
+    assert_eq!(out, [2, 0, 0, 0, 0, 0, 0, 0, b'h', b'i']);
+}
+
+#[cfg(feature = "encoding")]
+#[test]
+fn test_frozen() {
+    use btree_rewrite::frozen::FrozenMap;
+    use btree_rewrite::encoding::DecodeErrorKind;
+
+    for &size in &[0, 1, 2, 3, 7, 8, 100, 1000] {
+        let map: BTreeMap<u32, String> = (0..size).map(|i| (i * 3, i.to_string())).collect();
+        let bytes = FrozenMap::build(&map);
+
+        // The layout has no pointers, so it works just the same wherever it is loaded
+        let mut moved = vec![0xff; 5];
+        moved.extend(bytes.iter().cloned());
+        for buf in &[&bytes[..], &moved[5..]] {
+            let frozen = FrozenMap::<u32, String>::new(buf).unwrap();
+            assert_eq!(frozen.len(), map.len());
+            assert!(frozen.iter().eq(map.iter().map(|(&k, v)| (k, v.clone()))));
+
+            for key in 0..size * 3 + 2 {
+                assert_eq!(frozen.get(&key).as_ref(), map.get(&key));
+                assert_eq!(frozen.contains_key(&key), map.contains_key(&key));
+                let floor = map.range::<u32, u32>(Unbounded, Included(&key)).next_back()
+                               .map(|(&k, v)| (k, v.clone()));
+                assert_eq!(frozen.floor(&key), floor);
+                let ceiling = map.range::<u32, u32>(Included(&key), Unbounded).next()
+                                 .map(|(&k, v)| (k, v.clone()));
+                assert_eq!(frozen.ceiling(&key), ceiling);
+            }
+        }
+
+        let frozen = FrozenMap::<u32, String>::new(&bytes).unwrap();
+        let keys = (0..size * 3 + 2).filter(|k| size <= 8 || k % 211 == 0);
+        for (min, max) in keys.clone().flat_map(|min| keys.clone().map(move |max| (min, max))) {
+            for &(lo, hi) in &[(Included(&min), Included(&max)), (Included(&min), Excluded(&max)),
+                               (Excluded(&min), Included(&max)), (Excluded(&min), Excluded(&max)),
+                               (Unbounded, Excluded(&max)), (Excluded(&min), Unbounded)] {
+                let expected: Vec<u32> = map.keys().cloned().filter(|&k| {
+                    (match lo {
+                        Included(&min) => k >= min,
+                        Excluded(&min) => k > min,
+                        Unbounded => true
+                    }) && (match hi {
+                        Included(&max) => k <= max,
+                        Excluded(&max) => k < max,
+                        Unbounded => true
+                    })
+                }).collect();
+                let range = frozen.range(lo, hi);
+                assert_eq!(range.len(), expected.len());
+                assert!(range.clone().map(|(k, _)| k).eq(expected.iter().cloned()));
+                assert!(range.rev().map(|(k, _)| k).eq(expected.iter().rev().cloned()));
+            }
+        }
+
+        // A truncated buffer is caught up front
+        let err = FrozenMap::<u32, String>::new(&bytes[..bytes.len() - 1]).err().unwrap();
+        assert_eq!(err.kind(), DecodeErrorKind::UnexpectedEnd);
+    }
+
+    let err = FrozenMap::<u32, u32>::new(b"BTRM\x01\0\0\0").err().unwrap();
+    assert_eq!(err.kind(), DecodeErrorKind::BadMagic);
+
+    // Ranges of a map can be frozen too, as can maps with string keys
+    let map: BTreeMap<String, u32> = (0..50).map(|i| (format!("{:02}", i), i)).collect();
+    let bytes = FrozenMap::build(map.range(Included("10"), Excluded("20")));
+    let frozen = FrozenMap::<String, u32>::new(&bytes).unwrap();
+    assert_eq!(frozen.len(), 10);
+    assert_eq!(frozen.get("15"), Some(15));
+    assert_eq!(frozen.get("25"), None);
+    assert_eq!(frozen.floor("155"), Some(("15".to_string(), 15)));
+    assert_eq!(frozen.floor("0"), None);
+}
+
+#[cfg(feature = "encoding")]
+#[test]
+#[should_panic(expected = "keys must be in strictly ascending order")]
+fn test_frozen_unsorted_panics() {
+    use btree_rewrite::frozen::FrozenMap;
+
+    let entries = vec![(2, 0), (1, 0)];
+    FrozenMap::<u32, u32>::build(entries.iter().map(|&(ref k, ref v)| (k, v)));
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn test_serde() {
+    use btree_rewrite::compare::{Natural, Rev};
+    use std::collections::BTreeMap as StdMap;
+
+    let map: BTreeMap<String, u32> = (0..1000).map(|i| (format!("{:04}", i), i)).collect();
+    let json = serde_json::to_string(&map).unwrap();
+
+    // Interchangeable with std's map in both directions
+    let std_map: StdMap<String, u32> = serde_json::from_str(&json).unwrap();
+    assert_eq!(serde_json::to_string(&std_map).unwrap(), json);
+    let decoded: BTreeMap<String, u32> = serde_json::from_str(&json).unwrap();
+    assert_eq!(decoded, map);
+
+    // Entries out of order fall off the fast path but are still all inserted
+    let json = r#"{"b": 2, "d": 4, "a": 1, "e": 5, "c": 3}"#;
+    let mut decoded: BTreeMap<String, u32> = serde_json::from_str(json).unwrap();
+    assert!(decoded.iter().map(|(k, &v)| (&k[..], v))
+                   .eq(vec![("a", 1), ("b", 2), ("c", 3), ("d", 4), ("e", 5)]));
+    decoded.insert("f".to_string(), 6);
+    assert_eq!(decoded.len(), 6);
+
+    // Duplicate keys overwrite, whether they arrive in order or not, unless rejected
+    for json in &[r#"{"a": 1, "a": 2, "b": 3}"#, r#"{"b": 3, "a": 1, "a": 2}"#,
+                  r#"{"a": 1, "b": 3, "a": 2}"#] {
+        let decoded: BTreeMap<String, u32> = serde_json::from_str(json).unwrap();
+        assert_eq!(decoded.get("a"), Some(&2));
+        assert_eq!(decoded.len(), 2);
+
+        let mut de = serde_json::Deserializer::from_str(json);
+        let err = deserialize_unique_keys::<_, String, u32, Natural>(&mut de).unwrap_err();
+        assert!(err.to_string().contains("duplicate key in map"));
+    }
+    let mut de = serde_json::Deserializer::from_str(r#"{"b": 3, "a": 1}"#);
+    let decoded = deserialize_unique_keys::<_, String, u32, Natural>(&mut de).unwrap();
+    assert_eq!(decoded.len(), 2);
+
+    // Maps with comparators deserialize into their own order
+    let json = "{\"1\": 1, \"3\": 3, \"2\": 2}";
+    let decoded: BTreeMap<u32, u32, Rev<Natural>> = serde_json::from_str(json).unwrap();
+    assert!(decoded.keys().cloned().eq(vec![3, 2, 1]));
+
+    assert!(serde_json::from_str::<BTreeMap<u32, u32>>("[1, 2]").is_err());
+}
+
+#[cfg(feature = "paged")]
+#[test]
+fn test_paged() {
+    use btree_rewrite::paged::{PagedMap, MAX_ENTRY_SIZE};
+    use rand::{thread_rng, Rng};
+    use std::collections::BTreeMap as StdMap;
+    use std::fs;
+    use std::io::Write;
+
+    let path = std::env::temp_dir().join("btree_rewrite_test_paged");
+    let wal_path = std::env::temp_dir().join("btree_rewrite_test_paged.wal");
+    let mut rng = thread_rng();
+    let mut model = StdMap::new();
+
+    {
+        // A tiny cache, so that pages are constantly evicted and read back
+        let mut map = PagedMap::create(&path, 4).unwrap();
+        for _ in 0..4000 {
+            let key = rng.gen_range(0, 2000u32);
+            if rng.gen_weighted_bool(3) {
+                assert_eq!(map.remove(&key).unwrap(), model.remove(&key));
+            } else {
+                let val = format!("{}", rng.gen::<u64>());
+                assert_eq!(map.insert(key, val.clone()).unwrap(), model.insert(key, val));
+            }
+            assert!(map.cached_pages() <= 4);
+        }
+        assert_eq!(map.len(), model.len());
+
+        for key in 0..2000 {
+            assert_eq!(map.get(&key).unwrap().as_ref(), model.get(&key));
+        }
+
+        let all: Vec<(u32, String)> = map.range(Unbounded, Unbounded).unwrap()
+                                         .map(Result::unwrap).collect();
+        assert!(all.iter().map(|&(k, ref v)| (k, v)).eq(model.iter().map(|(&k, v)| (k, v))));
+
+        for _ in 0..50 {
+            let (a, b) = (rng.gen_range(0, 2100u32), rng.gen_range(0, 2100u32));
+            let got: Vec<u32> = map.range(Excluded(&a), Included(&b)).unwrap()
+                                   .map(|entry| entry.unwrap().0).collect();
+            let expected: Vec<u32> = model.keys().cloned().filter(|&k| a < k && k <= b).collect();
+            assert_eq!(got, expected);
+        }
+
+        let huge: String = std::iter::repeat('x').take(MAX_ENTRY_SIZE).collect();
+        assert!(map.insert(0, huge).is_err());
+        assert_eq!(map.get(&0).unwrap().as_ref(), model.get(&0));
+    }
+
+    // Dropping the map flushed it, so everything is still there after reopening
+    let size = {
+        let mut map = PagedMap::<u32, String>::open(&path, 8).unwrap();
+        assert_eq!(map.len(), model.len());
+        for (k, v) in map.range(Unbounded, Unbounded).unwrap().zip(&model) {
+            assert_eq!(k.unwrap(), (*v.0, v.1.clone()));
+        }
+
+        // Emptying the map puts every page but the root on the free list
+        let keys: Vec<u32> = model.keys().cloned().collect();
+        for key in keys {
+            assert!(map.remove(&key).unwrap().is_some());
+        }
+        assert!(map.is_empty());
+        assert_eq!(map.range(Unbounded, Unbounded).unwrap().count(), 0);
+        map.flush().unwrap();
+        fs::metadata(&path).unwrap().len()
+    };
+
+    {
+        // Partly refilling the map reuses the freed pages rather than growing the file
+        let mut map = PagedMap::<u32, String>::open(&path, 8).unwrap();
+        for (k, v) in model.iter().take(200) {
+            map.insert(*k, v.clone()).unwrap();
+        }
+        map.flush().unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().len(), size);
+    }
+
+    // Without a log to recover it from, a corrupt header is an error
+    fs::remove_file(&wal_path).unwrap();
+    fs::File::create(&path).unwrap().write_all(&[0; 4096]).unwrap();
+    assert!(PagedMap::<u32, String>::open(&path, 8).is_err());
+    fs::remove_file(&path).unwrap();
+    fs::remove_file(&wal_path).unwrap();
+}
+
+#[cfg(feature = "paged")]
+#[test]
+fn test_paged_crash_recovery() {
+    use btree_rewrite::paged::PagedMap;
+    use rand::{thread_rng, Rng};
+    use std::collections::BTreeMap as StdMap;
+    use std::fs::{self, File};
+    use std::io::{Read, Write};
+    use std::path::Path;
+
+    fn read_file(path: &Path) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        File::open(path).unwrap().read_to_end(&mut bytes).unwrap();
+        bytes
+    }
+
+    fn write_file(path: &Path, bytes: &[u8]) {
+        File::create(path).unwrap().write_all(bytes).unwrap();
+    }
+
+    let dir = std::env::temp_dir();
+    let path = dir.join("btree_rewrite_test_crash");
+    let wal_path = dir.join("btree_rewrite_test_crash.wal");
+    let copy_path = dir.join("btree_rewrite_test_crash_copy");
+    let copy_wal_path = dir.join("btree_rewrite_test_crash_copy.wal");
+
+    let recover = |data: &[u8], log: &[u8]| -> StdMap<u32, u64> {
+        write_file(&copy_path, data);
+        write_file(&copy_wal_path, log);
+        let map = PagedMap::<u32, u64>::open(&copy_path, 16).unwrap();
+        let entries: StdMap<u32, u64> = map.range(Unbounded, Unbounded).unwrap()
+                                           .map(Result::unwrap).collect();
+        assert_eq!(map.len(), entries.len());
+        entries
+    };
+
+    let mut rng = thread_rng();
+    let mut map = PagedMap::create(&path, 16).unwrap();
+    map.set_sync_log(false);
+    map.set_checkpoint_interval(10);
+
+    // history[i] is the map after the first i operations
+    let mut model = StdMap::new();
+    let mut history = vec![model.clone()];
+    // The file only changes at checkpoints, when it catches up with the history
+    let mut data = read_file(&path);
+    let mut checkpointed = 0;
+    // The log is synced by each checkpoint, so a crash cannot lose what it held at the time
+    let mut synced = read_file(&wal_path);
+
+    for i in 1..101u64 {
+        let key = rng.gen_range(0, 100u32);
+        if rng.gen_weighted_bool(3) {
+            assert_eq!(map.remove(&key).unwrap(), model.remove(&key));
+        } else {
+            assert_eq!(map.insert(key, i).unwrap(), model.insert(key, i));
+        }
+        history.push(model.clone());
+
+        let log = read_file(&wal_path);
+        let new_data = read_file(&path);
+        let did_checkpoint = new_data != data;
+
+        // Crash at points throughout writing the log, including partway through the checkpoint
+        // this operation may have made, before it got as far as writing the file. Every
+        // recovered map must be a prefix of the history, and never a shorter one than a crash
+        // earlier in the log gave.
+        if did_checkpoint || i % 10 == 0 {
+            let stride = if did_checkpoint { log.len() / 20 + 1 } else { 1 };
+            let start = if log.starts_with(&synced) { synced.len() } else { 0 };
+            let mut last = checkpointed;
+            for end in (start..log.len() + 1).filter(|&end| end % stride == 0 ||
+                                                             end + 16 > log.len()) {
+                let recovered = recover(&data, &log[..end]);
+                match (last..i as usize + 1).find(|&k| history[k] == recovered) {
+                    Some(k) => last = k,
+                    None => panic!("recovered map after operation {} with {} of {} log bytes \
+                                    is not a prefix of the history", i, end, log.len())
+                }
+            }
+            // Nothing is lost when the whole log survives
+            assert_eq!(history[last], model);
+        }
+
+        if did_checkpoint {
+            data = new_data;
+            synced = log.clone();
+            checkpointed = i as usize;
+            // Crashing after the checkpoint reached the file, but before the log was emptied
+            assert_eq!(recover(&data, &log), model);
+        }
+    }
+
+    drop(map);
+    assert_eq!(recover(&read_file(&path), &read_file(&wal_path)), model);
+    for path in &[path, wal_path, copy_path, copy_wal_path] {
+        fs::remove_file(path).unwrap();
+    }
+}